@@ -1,28 +1,220 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::Value;
+
 use result::{Result};
+use users::Role;
 use rand::{Rng, thread_rng};
 use rustc_serialize::base64;
-use rustc_serialize::base64::ToBase64;
+use rustc_serialize::base64::{ToBase64, FromBase64};
+use crypto::hmac::Hmac;
+use crypto::sha2::Sha256;
+use crypto::pbkdf2::pbkdf2;
+use crypto::util::fixed_time_eq;
 
 /// A way of authenticating users against a repository of users.
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AuthenticationStatus {
     PrincipalNotFound,
     IncorrectPassword,
     Success,
-    //Continue, second factor?
+    SecondFactorRequired, // the password was right, but the user must also give a one time password
+}
+
+/// What an authenticator knows about the user it checked.
+/// The repo backend knows the local user id, others may only know the username and a few claims.
+#[derive(Clone, Debug, Default)]
+pub struct UserAttributes {
+    pub user_id: Option<String>,
+    pub username: String,
+    pub email: Option<String>,
+    pub claims: HashMap<String, Value>,
+    pub roles: Vec<Role>, // set by backends that decide the user's roles, so the local user is kept in step
+    pub authenticator: Option<String>, // the backend that vouched for the user, set by AuthenticatorChain
+}
+
+impl UserAttributes {
+    pub fn new<S: Into<String>>(username: S) -> UserAttributes {
+        UserAttributes {
+            username: username.into(),
+            .. UserAttributes::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Authentication {
+    pub status: AuthenticationStatus,
+    pub attributes: Option<UserAttributes>, // set when the principal was found
+}
+
+impl Authentication {
+    pub fn new(status: AuthenticationStatus, attributes: Option<UserAttributes>) -> Authentication {
+        Authentication {
+            status: status,
+            attributes: attributes,
+        }
+    }
+    
+    pub fn not_found() -> Authentication {
+        Authentication::new(AuthenticationStatus::PrincipalNotFound, None)
+    }
+    
+    pub fn is_success(&self) -> bool {
+        self.status == AuthenticationStatus::Success || self.status == AuthenticationStatus::SecondFactorRequired
+    }
 }
 
-pub trait Authenticator {
-    fn authenticate(&self, username: &str, password: &str) -> Result<AuthenticationStatus>;
+pub trait Authenticator where Self: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> Result<Authentication>;
+}
+
+/// Asks each authenticator in turn.
+/// The first one that knows the username decides, so a wrong password in one backend
+/// isn't retried against the ones after it.
+#[derive(Clone)]
+pub struct AuthenticatorChain {
+    authenticators: Vec<(String, Arc<Box<Authenticator>>)>,
+}
+
+impl AuthenticatorChain {
+    pub fn new() -> AuthenticatorChain {
+        AuthenticatorChain {
+            authenticators: vec![],
+        }
+    }
+    
+    pub fn push<S: Into<String>>(&mut self, name: S, authenticator: Arc<Box<Authenticator>>) {
+        self.authenticators.push((name.into(), authenticator));
+    }
+    
+    pub fn names(&self) -> Vec<String> {
+        self.authenticators.iter().map(|a| a.0.clone()).collect()
+    }
+}
+
+impl Authenticator for AuthenticatorChain {
+    fn authenticate(&self, username: &str, password: &str) -> Result<Authentication> {
+        for &(ref name, ref authenticator) in &self.authenticators {
+            let mut authentication = try!(authenticator.authenticate(username, password));
+            
+            if authentication.status != AuthenticationStatus::PrincipalNotFound {
+                debug!("authenticator {} knows user {}", name, username);
+                
+                if let Some(ref mut attributes) = authentication.attributes {
+                    attributes.authenticator = Some(name.clone());
+                }
+                
+                return Ok(authentication);
+            }
+        }
+        
+        verify_dummy_password(password);
+        
+        Ok(Authentication::not_found())
+    }
+}
+
+/// Passwords and client secrets are stored as salted PBKDF2-HMAC-SHA256 hashes,
+/// with the parameters in the hash string so they can be raised without breaking old hashes:
+/// $pbkdf2-sha256$<iterations>$<base64 salt>$<base64 hash>
+
+pub const PASSWORD_HASH_SCHEME: &'static str = "pbkdf2-sha256";
+pub const PASSWORD_HASH_ITERATIONS: u32 = 100000;
+const PASSWORD_SALT_BYTES: usize = 16;
+const PASSWORD_HASH_BYTES: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str, iterations: u32, salt: Vec<u8>) -> PasswordHash {
+        let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+        let mut hash = vec![0u8; PASSWORD_HASH_BYTES];
+        
+        pbkdf2(&mut mac, &salt, iterations, &mut hash);
+        
+        PasswordHash {
+            iterations: iterations,
+            salt: salt,
+            hash: hash,
+        }
+    }
+    
+    pub fn parse(hashed_password: &str) -> Option<PasswordHash> {
+        let parts: Vec<&str> = hashed_password.split('$').collect();
+        
+        if parts.len() != 5 || !parts[0].is_empty() || parts[1] != PASSWORD_HASH_SCHEME {
+            return None;
+        }
+        
+        match (parts[2].parse::<u32>(), parts[3].from_base64(), parts[4].from_base64()) {
+            (Ok(iterations), Ok(salt), Ok(hash)) if iterations > 0 => Some(PasswordHash {
+                iterations: iterations,
+                salt: salt,
+                hash: hash,
+            }),
+            _ => None
+        }
+    }
+    
+    pub fn to_string(&self) -> String {
+        format!("${}${}${}${}", PASSWORD_HASH_SCHEME, self.iterations, self.salt.to_base64(base64::STANDARD), self.hash.to_base64(base64::STANDARD))
+    }
+    
+    pub fn verify(&self, password: &str) -> bool {
+        let candidate = PasswordHash::new(password, self.iterations, self.salt.clone());
+        
+        fixed_time_eq(&candidate.hash, &self.hash)
+    }
 }
 
-// TODO proper password hashing
 pub fn hash_password(password: &str) -> String {
-    password.to_owned()
+    let mut salt = vec![0u8; PASSWORD_SALT_BYTES];
+    thread_rng().fill_bytes(&mut salt);
+    
+    PasswordHash::new(password, PASSWORD_HASH_ITERATIONS, salt).to_string()
+}
+
+/// Hashes that can't be parsed never match
+pub fn verify_password(password: &str, hashed_password: &str) -> bool {
+    PasswordHash::parse(hashed_password).map(|h| h.verify(password)).unwrap_or(false)
+}
+
+/// Does the same work as checking a real password and always fails,
+/// so a login for an unknown user takes as long as one with the wrong password
+pub fn verify_dummy_password(password: &str) -> bool {
+    let dummy = PasswordHash {
+        iterations: PASSWORD_HASH_ITERATIONS,
+        salt: vec![0u8; PASSWORD_SALT_BYTES],
+        hash: vec![0u8; PASSWORD_HASH_BYTES],
+    };
+    
+    dummy.verify(password);
+    
+    false
+}
+
+/// True if the hash was made with weaker parameters than we use now,
+/// in which case it should be replaced next time the password is known
+pub fn password_needs_rehash(hashed_password: &str) -> bool {
+    PasswordHash::parse(hashed_password).map(|h| h.iterations < PASSWORD_HASH_ITERATIONS).unwrap_or(true)
 }
 
 pub fn new_user_id() -> String {
-    let mut bytes = [0u8, 12];
+    let mut bytes = [0u8; 12];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes.to_base64(base64::URL_SAFE)
+}
+
+pub fn new_group_id() -> String {
+    let mut bytes = [0u8; 12];
     thread_rng().fill_bytes(&mut bytes);
     bytes.to_base64(base64::URL_SAFE)
 }
@@ -50,4 +242,30 @@ pub fn new_nonce() -> String {
     let mut bytes = [0u8; 12];
     thread_rng().fill_bytes(&mut bytes);
     bytes.to_base64(base64::URL_SAFE)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_password() {
+        let hashed = hash_password("secret");
+        
+        assert!(hashed.starts_with("$pbkdf2-sha256$100000$"));
+        assert!(hashed != hash_password("secret"));
+        assert!(verify_password("secret", &hashed));
+        assert!(!verify_password("Secret", &hashed));
+        assert!(!verify_password("secret", "secret"));
+        assert!(!password_needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn test_weak_hash_needs_rehash() {
+        let weak = PasswordHash::new("secret", 1000, vec![1, 2, 3, 4]).to_string();
+        
+        assert!(verify_password("secret", &weak));
+        assert!(password_needs_rehash(&weak));
+        assert_eq!(PasswordHash::parse(&weak).unwrap().iterations, 1000);
+    }
+}
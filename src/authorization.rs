@@ -0,0 +1,210 @@
+use iron::prelude::*;
+use iron::{AroundMiddleware, Handler};
+use iron::method::Method;
+use iron::status;
+use iron::modifiers::Redirect;
+use iron::typemap::Key;
+
+use config::Config;
+use result::{Result, OpenIdConnectError};
+use users::{User, Role};
+use csrf::check_origin;
+use oauth2::bearer::find_bearer_token;
+use routes::account::{authenticated_session, account_redirect_url};
+
+/// Role checks for management pages and APIs.
+///
+/// Pages need a logged in user with the role.
+/// APIs also take an access token, which needs the role's name as a scope (e.g. "admin")
+/// and is only honoured while its user still has the role, and its client may still ask for the scope.
+/// Users without the role always get a 403.
+
+/// The user that passed the role check, for handlers behind RequireRole
+pub struct AuthorizedUser;
+
+impl Key for AuthorizedUser { type Value = User; }
+
+fn check_role(user: User, role: Role) -> Result<User> {
+    if user.has_role(role) {
+        Ok(user)
+    } else {
+        debug!("user {} doesn't have role {}", user.id, role.as_str());
+
+        Err(OpenIdConnectError::AccessDenied(format!("{} role required", role.as_str())))
+    }
+}
+
+/// The logged in user, if they have the role
+pub fn require_session_role(req: &mut Request, role: Role) -> Result<User> {
+    let config = try!(Config::get(req));
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::NotAuthenticated));
+
+    let user = try!(try!(config.user_repo.get_user(&user_id)).ok_or(OpenIdConnectError::NotAuthenticated));
+
+    check_role(user, role)
+}
+
+/// The user the access token was issued to, if the token has the scope and the user has the role
+pub fn require_token_scope(req: &mut Request, access_token: &str, scope: &str, role: Role) -> Result<User> {
+    let config = try!(Config::get(req));
+    let token = try!(config.token_repo.verify_access_token(req, access_token));
+
+    if !token.has_scope(scope) {
+        return Err(OpenIdConnectError::InsufficientScope(scope.to_owned()));
+    }
+
+    // an admin may have taken the scope away from the client since the token was issued
+    let client = try!(try!(config.application_repo.find_client_application(&token.client_id)).ok_or(OpenIdConnectError::InvalidToken("client no longer exists".to_owned())));
+
+    if !client.may_request_scope(scope) {
+        return Err(OpenIdConnectError::InsufficientScope(scope.to_owned()));
+    }
+
+    let user = try!(try!(config.user_repo.get_user(&token.user_id)).ok_or(OpenIdConnectError::InvalidToken("user no longer exists".to_owned())));
+
+    check_role(user, role)
+}
+
+/// An access token if one was sent, otherwise the logged in user.
+/// Requests that rely on the session cookie to make changes must come from this site.
+pub fn require_api_role(req: &mut Request, role: Role) -> Result<User> {
+    match find_bearer_token(req) {
+        Ok(access_token) => require_token_scope(req, &access_token, role.as_str(), role),
+        Err(OpenIdConnectError::MissingAccessToken) => {
+            match req.method {
+                Method::Get | Method::Head | Method::Options => {},
+                _ => try!(check_origin(req)),
+            }
+
+            require_session_role(req, role)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// Wraps a route so only users with the role reach it.
+/// Pages send anyone who isn't logged in to the login page and back.
+#[derive(Copy, Clone, Debug)]
+pub struct RequireRole {
+    pub role: Role,
+    pub api: bool,
+}
+
+impl RequireRole {
+    pub fn web(role: Role) -> RequireRole {
+        RequireRole {
+            role: role,
+            api: false,
+        }
+    }
+
+    pub fn api(role: Role) -> RequireRole {
+        RequireRole {
+            role: role,
+            api: true,
+        }
+    }
+}
+
+struct RequireRoleHandler {
+    require: RequireRole,
+    handler: Box<Handler>,
+}
+
+impl Handler for RequireRoleHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let result = if self.require.api {
+            require_api_role(req, self.require.role)
+        } else {
+            require_session_role(req, self.require.role)
+        };
+
+        match result {
+            Ok(user) => {
+                req.extensions.insert::<AuthorizedUser>(user);
+
+                self.handler.handle(req)
+            },
+            Err(OpenIdConnectError::NotAuthenticated) if !self.require.api => {
+                let path = format!("/{}", req.url.path().join("/"));
+                let login_url = try!(account_redirect_url(req, &path, "/login"));
+
+                Ok(Response::with((status::Found, Redirect(login_url))))
+            },
+            Err(err) => Err(IronError::from(err))
+        }
+    }
+}
+
+impl AroundMiddleware for RequireRole {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(RequireRoleHandler {
+            require: self,
+            handler: handler,
+        })
+    }
+}
+
+/// Wraps a route so only access tokens with the scope, issued to users with the role, reach it.
+/// Sessions aren't enough, e.g. for the SCIM API which is only called by provisioning systems.
+#[derive(Copy, Clone, Debug)]
+pub struct RequireScope {
+    pub scope: &'static str,
+    pub role: Role,
+}
+
+impl RequireScope {
+    pub fn new(scope: &'static str, role: Role) -> RequireScope {
+        RequireScope {
+            scope: scope,
+            role: role,
+        }
+    }
+}
+
+struct RequireScopeHandler {
+    require: RequireScope,
+    handler: Box<Handler>,
+}
+
+impl Handler for RequireScopeHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let access_token = try!(find_bearer_token(req));
+        let user = try!(require_token_scope(req, &access_token, self.require.scope, self.require.role));
+
+        req.extensions.insert::<AuthorizedUser>(user);
+
+        self.handler.handle(req)
+    }
+}
+
+impl AroundMiddleware for RequireScope {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(RequireScopeHandler {
+            require: self,
+            handler: handler,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use users::*;
+
+    #[test]
+    fn test_check_role() {
+        let mut user = User::new("1".to_owned(), "alice".to_owned(), None);
+
+        assert!(check_role(user.clone(), Role::User).is_ok());
+
+        match check_role(user.clone(), Role::Admin) {
+            Err(OpenIdConnectError::AccessDenied(_)) => {},
+            other => panic!("expected access denied, got {:?}", other),
+        }
+
+        user.add_role(Role::Admin);
+        assert!(check_role(user, Role::Admin).is_ok());
+    }
+}
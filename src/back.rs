@@ -95,6 +95,15 @@ pub fn redirect_back_url(req: &mut Request, params: &HashMap<String, Vec<String>
     }
 }
 
+/// The params carried in a return token, e.g. the authorize request that sent the user to login
+pub fn load_return_params(req: &mut Request, params: &HashMap<String, Vec<String>>) -> Result<Option<HashMap<String, Vec<String>>>> {
+    if let Some(token) = try!(load_token(req, params, "return")) {
+        Ok(try!(token.claims.get_value::<HashMap<String, Vec<String>>>("params")))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn return_params<S: Into<String>>(return_state: S) -> HashMap<String, Vec<String>> {
     let mut params = HashMap::new();
     
@@ -14,6 +14,7 @@ extern crate jsonwebtoken;
 #[macro_use] extern crate log;
 extern crate env_logger;
 
+use std::env;
 use std::sync::Arc;
 use std::path::Path;
 
@@ -31,14 +32,26 @@ use openid_connect::routes::application_api::*;
 use openid_connect::routes::session::*;
 use openid_connect::routes::applications;
 use openid_connect::routes::grants;
+use openid_connect::routes::account;
+use openid_connect::routes::users;
+use openid_connect::routes::email;
+use openid_connect::routes::federation;
+use openid_connect::routes::account_api::*;
+use openid_connect::routes::user_api::*;
 use openid_connect::users::*;
+use openid_connect::groups::*;
 use openid_connect::config::*;
 use openid_connect::oauth2;
+use openid_connect::scim;
 use openid_connect::oauth2::routes::openid_config;
 use openid_connect::oauth2::models::client::*;
+use openid_connect::oauth2::backchannel::BackchannelSessionEndListener;
 use openid_connect::sessions;
+use openid_connect::authentication::{Authenticator, new_secret};
 use openid_connect::login_manager;
+use openid_connect::mailer;
 use openid_connect::site_config::*;
+use openid_connect::i18n::Translations;
 use openid_connect::oauth2::*;
 use openid_connect::service::routes::login::*;
 
@@ -60,13 +73,29 @@ impl AfterMiddleware for ErrorRenderer {
 }
 
 
+/// The salt has to stay secret, or pairwise subs can be reversed by trying each user id.
+/// Without one in the environment a random salt is used, but then pairwise subs change on every restart.
+fn pairwise_subject_salt() -> String {
+    match env::var("PAIRWISE_SUBJECT_SALT") {
+        Ok(salt) => salt,
+        Err(_) => {
+            warn!("PAIRWISE_SUBJECT_SALT is not set, using a random salt");
+            new_secret()
+        }
+    }
+}
+
 pub fn main() {
     env_logger::init().unwrap();
     let format = Format::new(FORMAT);
     let (logger_before, logger_after) = Logger::new(format);
     
     let user_repo = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
-    user_repo.add_user(User::new("1".to_owned(), "admin".to_owned(), Some("admin".to_owned()))).unwrap();
+    let mut admin = User::new("1".to_owned(), "admin".to_owned(), Some("admin".to_owned()));
+    admin.add_role(Role::Admin);
+    user_repo.add_user(admin).unwrap();
+    
+    let group_repo = Arc::new(Box::new(InMemoryGroupRepo::new()) as Box<GroupRepo>);
     
     let application_repo = Arc::new(Box::new(repos::InMemoryClientApplicationRepo::new()) as Box<repos::ClientApplicationRepo>);
   
@@ -91,15 +120,29 @@ pub fn main() {
     let mac_signer = MacSigner::new("secret").unwrap();
     
     let login_manager = login_manager::LoginManager::new(cookie_signing_key);
-    let sessions_controller = sessions::SessionController::new(user_repo.clone(), login_manager.clone());
-    
-    let config = Config::new(mac_signer, user_repo.clone(), application_repo.clone(), grant_repo.clone(), token_repo.clone(), sessions_controller.clone());
+    let session_store = Arc::new(Box::new(sessions::InMemorySessionStore::new()) as Box<sessions::SessionStore>);
     
     let mut site_config = SiteConfig::new();
     //TODO load site config from file
     site_config.token_issuer = Some("https://localhost:3000".to_owned());
+    site_config.pairwise_subject_salt = Some(pairwise_subject_salt());
+    site_config.mail_dir = Some("./mail/".to_owned());
+    
+    let mut sessions_controller = sessions::SessionController::new(user_repo.clone(), login_manager.clone(), session_store);
+    sessions_controller.authenticator = Arc::new(Box::new(sessions::authenticators_for_site(&site_config, user_repo.clone()).unwrap()) as Box<Authenticator>);
+    
+    let mut config = Config::new(mac_signer, user_repo.clone(), group_repo.clone(), application_repo.clone(), grant_repo.clone(), token_repo.clone(), sessions_controller.clone());
+    
+    config.mailer = mailer::mailer_for_site(&site_config);
     
-    let woidc = openid_config::WellKnownOpenIdConfiguration::new_for_site(&site_config);
+    let session_end_listener = BackchannelSessionEndListener::new(config.backchannel_logout.clone(), config.mac_signer.clone(), site_config.clone(), application_repo.clone());
+    sessions_controller.session_end_listener = Some(Arc::new(Box::new(session_end_listener) as Box<sessions::SessionEndListener>));
+    config.session_controller = sessions_controller.clone();
+    
+    let translations = Translations::load_dir("./locales/", "en").unwrap();
+    
+    let mut woidc = openid_config::WellKnownOpenIdConfiguration::new_for_site(&site_config);
+    woidc.ui_locales_supported = translations.supported_locales();
     
     let mut router = Router::new();
     router.get("/", web_handler(&config, home_handler), "home");
@@ -107,14 +150,34 @@ pub fn main() {
     router.post("/register", web_handler(&config, register_post_handler), "register_post");
     router.get("/login", web_handler(&config, login_get_handler), "login_get");
     router.post("/login", web_handler(&config, login_post_handler), "login_post");
-    router.get("/applications", web_handler(&config, applications::applications_index_handler), "applications_index");
-    router.get("/applications/new", web_handler(&config, applications::applications_new_handler), "applications_new");
-    router.get("/applications/:id", web_handler(&config, applications::applications_show_handler), "applications_show");
-    router.get("/applications/:id/edit", web_handler(&config, applications::applications_edit_handler), "applications_edit");
-    router.post("/applications/:id", web_handler(&config, applications::applications_update_handler), "applications_update");
-    router.post("/applications", web_handler(&config, applications::applications_create_handler), "applications_create");
+    router.get("/login/otp", web_handler(&config, second_factor_get_handler), "login_otp_get");
+    router.post("/login/otp", web_handler(&config, second_factor_post_handler), "login_otp_post");
+    router.get("/verify-email", web_handler(&config, email::verify_email_handler), "verify_email");
+    router.post("/verify-email", web_handler(&config, email::verify_email_resend_handler), "verify_email_resend");
+    router.get("/password-reset", web_handler(&config, email::password_reset_get_handler), "password_reset_get");
+    router.post("/password-reset", web_handler(&config, email::password_reset_post_handler), "password_reset_post");
+    router.get("/password-reset/confirm", web_handler(&config, email::password_reset_confirm_get_handler), "password_reset_confirm_get");
+    router.post("/password-reset/confirm", web_handler(&config, email::password_reset_confirm_post_handler), "password_reset_confirm_post");
+    router.get("/login/upstream/:id", web_handler(&config, federation::upstream_login_handler), "login_upstream");
+    router.get("/login/upstream/:id/callback", web_handler(&config, federation::upstream_callback_handler), "login_upstream_callback");
+    router.get("/logout", web_handler(&config, oauth2::routes::end_session_get_handler), "logout");
+    router.get("/applications", admin_web_handler(&config, applications::applications_index_handler), "applications_index");
+    router.get("/applications/new", admin_web_handler(&config, applications::applications_new_handler), "applications_new");
+    router.get("/applications/:id", admin_web_handler(&config, applications::applications_show_handler), "applications_show");
+    router.get("/applications/:id/edit", admin_web_handler(&config, applications::applications_edit_handler), "applications_edit");
+    router.post("/applications/:id", admin_web_handler(&config, applications::applications_update_handler), "applications_update");
+    router.post("/applications", admin_web_handler(&config, applications::applications_create_handler), "applications_create");
     /*router.get("/applications/:id/delete", web_handler(&config, applications::applications_delete_handler));
-    router.post("/applications/:id/delete", web_handler(&config, applications::applications_delete_handler));*/
+    router.post("/applications/:id/delete", admin_web_handler(&config, applications::applications_delete_handler));*/
+    
+    router.get("/users", admin_web_handler(&config, users::users_index_handler), "users_index");
+    router.get("/users/new", admin_web_handler(&config, users::users_new_handler), "users_new");
+    router.post("/users", admin_web_handler(&config, users::users_create_handler), "users_create");
+    router.get("/users/:id", admin_web_handler(&config, users::users_show_handler), "users_show");
+    router.post("/users/:id", admin_web_handler(&config, users::users_update_handler), "users_update");
+    router.post("/users/:id/password-reset", admin_web_handler(&config, users::users_password_reset_handler), "users_password_reset");
+    router.post("/users/:id/logout", admin_web_handler(&config, users::users_logout_handler), "users_logout");
+    router.post("/users/:id/delete", admin_web_handler(&config, users::users_delete_handler), "users_delete");
     
     router.get("/grants", web_handler(&config, grants::grants_index_handler), "grants_index");
     router.get("/grants/:id", web_handler(&config, grants::grants_show_handler), "grants_show");
@@ -122,6 +185,17 @@ pub fn main() {
     router.post("/grants/:id", web_handler(&config, grants::grants_update_handler), "grants_update");
     //TODO delete
     
+    router.get("/account", web_handler(&config, account::account_profile_handler), "account_profile");
+    router.post("/account", web_handler(&config, account::account_profile_update_handler), "account_profile_update");
+    router.post("/account/password", web_handler(&config, account::account_password_handler), "account_password");
+    router.post("/account/delete", web_handler(&config, account::account_delete_handler), "account_delete");
+    router.get("/account/sessions", web_handler(&config, account::account_sessions_handler), "account_sessions");
+    router.post("/account/sessions/:id/revoke", web_handler(&config, account::account_session_revoke_handler), "account_session_revoke");
+    router.post("/account/tokens/:id/revoke", web_handler(&config, account::account_token_revoke_handler), "account_token_revoke");
+    router.get("/account/otp", web_handler(&config, account::account_otp_handler), "account_otp");
+    router.post("/account/otp", web_handler(&config, account::account_otp_enroll_handler), "account_otp_enroll");
+    router.post("/account/otp/disable", web_handler(&config, account::account_otp_disable_handler), "account_otp_disable");
+    
     
     
     let mut api_router = Router::new();
@@ -129,20 +203,38 @@ pub fn main() {
     api_router.post("/session", api_handler(&config, session_post_handler), "session_post");
     api_router.delete("/session", api_handler(&config, session_delete_handler), "session_delete");
     
-    api_router.get("/applications", api_handler(&config, applications_get_handler), "api_applications_get");
-    api_router.post("/applications", api_handler(&config, applications_post_handler), "api_applications_post");
-    api_router.put("/applications/:id", api_handler(&config, applications_put_handler), "api_applications_put");
-    api_router.delete("/applications/:id", api_handler(&config, applications_delete_handler), "api_applications_delete");
+    api_router.get("/applications", admin_api_handler(&config, applications_get_handler), "api_applications_get");
+    api_router.post("/applications", admin_api_handler(&config, applications_post_handler), "api_applications_post");
+    api_router.put("/applications/:id", admin_api_handler(&config, applications_put_handler), "api_applications_put");
+    api_router.delete("/applications/:id", admin_api_handler(&config, applications_delete_handler), "api_applications_delete");
+    
+    api_router.get("/account/sessions", api_handler(&config, account_sessions_get_handler), "api_account_sessions_get");
+    api_router.delete("/account/sessions/:id", api_handler(&config, account_sessions_delete_handler), "api_account_sessions_delete");
+    api_router.get("/account/tokens", api_handler(&config, account_tokens_get_handler), "api_account_tokens_get");
+    api_router.delete("/account/tokens/:id", api_handler(&config, account_tokens_delete_handler), "api_account_tokens_delete");
+    
+    api_router.get("/users", admin_api_handler(&config, users_get_handler), "api_users_get");
+    api_router.post("/users", admin_api_handler(&config, users_post_handler), "api_users_post");
+    api_router.get("/users/:id", admin_api_handler(&config, user_get_handler), "api_user_get");
+    api_router.put("/users/:id", admin_api_handler(&config, user_put_handler), "api_user_put");
+    api_router.delete("/users/:id", admin_api_handler(&config, user_delete_handler), "api_user_delete");
+    api_router.delete("/users/:id/sessions", admin_api_handler(&config, user_sessions_delete_handler), "api_user_sessions_delete");
+    api_router.post("/users/:id/password-reset", admin_api_handler(&config, user_password_reset_post_handler), "api_user_password_reset");
+    api_router.delete("/users/:id/otp", admin_api_handler(&config, user_otp_delete_handler), "api_user_otp_delete");
+    api_router.delete("/users/:id/lockout", admin_api_handler(&config, user_lockout_delete_handler), "api_user_lockout_delete");
     
     let well_known_router = oauth2::well_known_router(&config);
     
     let oidc_router = oauth2::oauth2_router(&config);
     
+    let scim_router = scim::scim_router(&config);
+    
     let mut mount = Mount::new();
     mount.mount("/", router);
     mount.mount("/.well-known", well_known_router);
     mount.mount("/api", api_router);
     mount.mount("/connect", oidc_router);
+    mount.mount("/scim/v2", scim_router);
     mount.mount("/js", Static::new(Path::new("web/priv/js/")));
     mount.mount("/css", Static::new(Path::new("web/priv/css")));
     mount.mount("/images", Static::new(Path::new("web/priv/images")));
@@ -150,7 +242,8 @@ pub fn main() {
     mount.mount("/robots.txt", Static::new(Path::new("web/priv/robots.txt")));
     
     let mut chain = Chain::new(mount);
-    chain.link_before(sessions_controller);
+    chain.link_before(sessions_controller.clone());
+    chain.link_after(sessions_controller);
     
     let mut outer_chain = Chain::new(chain);
     outer_chain.around(login_manager);
@@ -162,6 +255,7 @@ pub fn main() {
     
     outer_chain.link(persistent::Read::<Config>::both(config));
     outer_chain.link(persistent::Read::<SiteConfig>::both(site_config));
+    outer_chain.link(persistent::Read::<Translations>::both(translations));
     
     Iron::new(outer_chain).http("0.0.0.0:8080").unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,15 @@
+use chrono::*;
+
+/// Where the current time comes from, so time dependent code can be tested with a clock it controls
+pub trait Clock where Self: Send + Sync {
+    fn now(&self) -> DateTime<UTC>;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
@@ -6,8 +6,14 @@ use persistent;
 
 use site_config::*;
 use users::UserRepo;
+use groups::GroupRepo;
 use oauth2::repos::{ClientApplicationRepo, GrantRepo, TokenRepo};
 use sessions::SessionController;
+use http_client::{HttpClient, HyperHttpClient};
+use oauth2::subject::{SectorIdentifierFetcher, HttpSectorIdentifierFetcher};
+use oauth2::backchannel::{BackchannelLogout, LogoutTokenSender, HttpLogoutTokenSender};
+use mailer::{Mailer, LogMailer};
+use federation::UpstreamClient;
 use result::*;
 use jsonwebtoken::crypto::mac_signer::MacSigner;
 
@@ -16,10 +22,16 @@ pub struct Config
 {
     pub mac_signer: MacSigner,
     pub user_repo: Arc<Box<UserRepo>>,
+    pub group_repo: Arc<Box<GroupRepo>>,
     pub application_repo: Arc<Box<ClientApplicationRepo>>,
     pub grant_repo: Arc<Box<GrantRepo>>,
     pub token_repo: Arc<Box<TokenRepo>>,
     pub session_controller: SessionController,
+    pub http_client: Arc<Box<HttpClient>>,
+    pub sector_identifier_fetcher: Arc<Box<SectorIdentifierFetcher>>,
+    pub backchannel_logout: BackchannelLogout,
+    pub mailer: Arc<Box<Mailer>>,
+    pub upstream: UpstreamClient,
     pub site_config: SiteConfig,
 }
 
@@ -27,17 +39,29 @@ impl Config {
     pub fn new(
             mac_signer: MacSigner,
             user_repo: Arc<Box<UserRepo>>, 
+            group_repo: Arc<Box<GroupRepo>>,
             application_repo: Arc<Box<ClientApplicationRepo>>,
             grant_repo: Arc<Box<GrantRepo>>,
             token_repo: Arc<Box<TokenRepo>>,
             session_controller: SessionController) -> Config {
+        let http_client = Arc::new(Box::new(HyperHttpClient::new()) as Box<HttpClient>);
+        let sector_identifier_fetcher = Arc::new(Box::new(HttpSectorIdentifierFetcher::new(http_client.clone())) as Box<SectorIdentifierFetcher>);
+        let logout_token_sender = Arc::new(Box::new(HttpLogoutTokenSender::new(http_client.clone())) as Box<LogoutTokenSender>);
+        let upstream = UpstreamClient::new(http_client.clone());
+        
         Config {
             mac_signer: mac_signer,
             user_repo: user_repo,
+            group_repo: group_repo,
             application_repo: application_repo,
             grant_repo: grant_repo,
             token_repo: token_repo,
             session_controller: session_controller,
+            http_client: http_client,
+            sector_identifier_fetcher: sector_identifier_fetcher,
+            backchannel_logout: BackchannelLogout::new(logout_token_sender),
+            mailer: Arc::new(Box::new(LogMailer) as Box<Mailer>),
+            upstream: upstream,
             site_config: SiteConfig::default(),
         }
     }
@@ -0,0 +1,290 @@
+use std::str;
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::BeforeMiddleware;
+use iron::method::Method;
+use iron::typemap::Key;
+use iron_sessionstorage;
+use iron_sessionstorage::traits::*;
+use urlencoded::*;
+use serde_json;
+use crypto::util::fixed_time_eq;
+use url;
+
+use result::*;
+use urls::get_absolute_url;
+use authentication;
+use rbvt::params::*;
+
+/// Cross-site request forgery protection for html forms.
+///
+/// Each browser session gets a random token, kept in the signed session cookie
+/// and put into every View for the forms to send back as the csrf_token field.
+/// Posts must come back with the same token, and from our own origin if the browser says where they came from.
+
+pub const CSRF_TOKEN_PARAM: &'static str = "csrf_token";
+pub const CSRF_TOKEN_HEADER: &'static str = "X-CSRF-Token";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CsrfToken {
+    pub token: String,
+}
+
+impl CsrfToken {
+    pub fn new() -> CsrfToken {
+        CsrfToken {
+            token: authentication::new_token(),
+        }
+    }
+}
+
+impl iron_sessionstorage::Value for CsrfToken {
+    fn get_key() -> &'static str { "csrf_token" }
+    fn into_raw(self) -> String { serde_json::to_string(&self).unwrap_or(String::new()) }
+    fn from_raw(value: String) -> Option<Self> {
+        if value.is_empty() {
+            None
+        } else {
+            serde_json::from_str(&value).map(|s| Some(s)).unwrap_or(None)
+        }
+    }
+}
+
+impl Key for CsrfToken { type Value = CsrfToken; }
+
+/// The token for this request's session, if the middleware has run
+pub fn csrf_token(req: &Request) -> Option<String> {
+    req.extensions.get::<CsrfToken>().map(|t| t.token.clone())
+}
+
+/// Loads the session's token, or starts a new one
+pub fn ensure_csrf_token(req: &mut Request) -> Result<String> {
+    if let Some(token) = csrf_token(req) {
+        return Ok(token);
+    }
+    
+    let token = match try!(req.session().get::<CsrfToken>()) {
+        Some(token) => token,
+        None => {
+            let token = CsrfToken::new();
+            try!(req.session().set(token.clone()));
+            token
+        }
+    };
+    
+    req.extensions.insert::<CsrfToken>(token.clone());
+    
+    Ok(token.token)
+}
+
+fn raw_header(req: &Request, name: &str) -> Option<String> {
+    req.headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().to_owned())
+}
+
+/// Where the browser says the request came from, from Origin or else Referer
+pub fn request_origin(req: &Request) -> Option<String> {
+    if let Some(origin) = raw_header(req, "Origin") {
+        return Some(origin);
+    }
+    
+    raw_header(req, "Referer").map(|referer| {
+        url::Url::parse(&referer)
+            .map(|u| u.origin().ascii_serialization())
+            .unwrap_or("null".to_owned())
+    })
+}
+
+/// Defence in depth behind the token.
+/// Requests that don't say where they came from are let through, the token still has to match.
+pub fn check_origin(req: &mut Request) -> Result<()> {
+    if let Some(origin) = request_origin(req) {
+        let expected = try!(get_absolute_url(req)).into_generic_url().origin().ascii_serialization();
+        
+        if origin != expected {
+            return Err(OpenIdConnectError::CrossSiteRequest(origin));
+        }
+    }
+    
+    Ok(())
+}
+
+/// True if the form, or the X-CSRF-Token header for scripts, carries this session's token
+pub fn csrf_token_matches(req: &mut Request, params: &HashMap<String, Vec<String>>) -> Result<bool> {
+    let expected = try!(ensure_csrf_token(req));
+    
+    let given = match multimap_get_maybe_one(params, CSRF_TOKEN_PARAM).unwrap_or(None) {
+        Some(token) => Some(token.to_owned()),
+        None => raw_header(req, CSRF_TOKEN_HEADER),
+    };
+    
+    Ok(given.map(|token| fixed_time_eq(token.as_bytes(), expected.as_bytes())).unwrap_or(false))
+}
+
+pub fn verify_csrf_token(req: &mut Request) -> Result<()> {
+    let params = match req.get::<UrlEncodedBody>() {
+        Ok(params) => params,
+        Err(_) => HashMap::new(), // e.g. a json body, which has to use the header
+    };
+    
+    if try!(csrf_token_matches(req, &params)) {
+        Ok(())
+    } else {
+        Err(OpenIdConnectError::InvalidCsrfToken)
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    match *method {
+        Method::Get | Method::Head | Method::Options | Method::Trace => true,
+        _ => false,
+    }
+}
+
+/// Issues tokens on every web route, and checks them on posts unless told not to
+#[derive(Copy, Clone, Debug)]
+pub struct CsrfProtection {
+    pub enforce: bool,
+}
+
+impl CsrfProtection {
+    pub fn new() -> CsrfProtection {
+        CsrfProtection {
+            enforce: true,
+        }
+    }
+    
+    /// For forms that other sites may legitimately post to.
+    /// The handler must check the token itself before doing anything on the user's behalf.
+    pub fn exempt() -> CsrfProtection {
+        CsrfProtection {
+            enforce: false,
+        }
+    }
+}
+
+impl BeforeMiddleware for CsrfProtection {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        try!(ensure_csrf_token(req));
+        
+        if self.enforce && !is_safe_method(&req.method) {
+            try!(check_origin(req));
+            try!(verify_csrf_token(req));
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use iron::prelude::*;
+    use iron::status;
+    use hyper::Client;
+    use hyper::header::{Headers, ContentType, SetCookie};
+    use hyper::server::Listening;
+    use hyper::status::StatusCode;
+    use persistent;
+    use url::form_urlencoded;
+
+    use super::*;
+    use login_manager::LoginManager;
+    use site_config::SiteConfig;
+
+    fn token_handler(req: &mut Request) -> IronResult<Response> {
+        Ok(Response::with((status::Ok, csrf_token(req).unwrap_or(String::new()))))
+    }
+
+    /// Serves the token handler behind the middleware, with the session cookie and site config around it as in the server
+    fn serve(csrf: CsrfProtection) -> (Listening, String) {
+        let mut chain = Chain::new(token_handler);
+        chain.link_before(csrf);
+
+        let mut outer_chain = Chain::new(chain);
+        outer_chain.around(LoginManager::new(b"key".to_vec()));
+        outer_chain.link(persistent::Read::<SiteConfig>::both(SiteConfig::new()));
+
+        let listening = Iron::new(outer_chain).http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listening.socket);
+
+        (listening, url)
+    }
+
+    /// The session cookie and its token, from a get
+    fn start_session(url: &str) -> (String, String) {
+        let mut res = Client::new().get(url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let cookie = res.headers.get::<SetCookie>().unwrap().iter()
+            .map(|c| c.split(';').next().unwrap().to_owned())
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        let mut token = String::new();
+        res.read_to_string(&mut token).unwrap();
+
+        (cookie, token)
+    }
+
+    fn post(url: &str, cookie: &str, body: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let mut request_headers = Headers::new();
+        request_headers.set(ContentType::form_url_encoded());
+        request_headers.set_raw("Cookie", vec![cookie.as_bytes().to_vec()]);
+
+        for &(name, value) in headers {
+            request_headers.set_raw(name.to_owned(), vec![value.as_bytes().to_vec()]);
+        }
+
+        Client::new().post(url).headers(request_headers).body(body).send().unwrap().status
+    }
+
+    fn form(token: &str) -> String {
+        form_urlencoded::Serializer::new(String::new()).append_pair(CSRF_TOKEN_PARAM, token).finish()
+    }
+
+    #[test]
+    fn test_posts_need_the_token() {
+        let (mut listening, url) = serve(CsrfProtection::new());
+        let (cookie, token) = start_session(&url);
+        assert!(!token.is_empty());
+
+        assert_eq!(post(&url, &cookie, "", &[]), StatusCode::Forbidden);
+        assert_eq!(post(&url, &cookie, &form("wrong"), &[]), StatusCode::Forbidden);
+        assert_eq!(post(&url, "", &form(&token), &[]), StatusCode::Forbidden);
+
+        assert_eq!(post(&url, &cookie, &form(&token), &[]), StatusCode::Ok);
+        assert_eq!(post(&url, &cookie, "", &[(CSRF_TOKEN_HEADER, &token)]), StatusCode::Ok);
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_posts_from_other_sites_are_rejected() {
+        let (mut listening, url) = serve(CsrfProtection::new());
+        let (cookie, token) = start_session(&url);
+        let origin = url.trim_right_matches('/').to_owned();
+
+        assert_eq!(post(&url, &cookie, &form(&token), &[("Origin", &origin)]), StatusCode::Ok);
+        assert_eq!(post(&url, &cookie, &form(&token), &[("Referer", &url)]), StatusCode::Ok);
+
+        assert_eq!(post(&url, &cookie, &form(&token), &[("Origin", "https://evil.example.com")]), StatusCode::Forbidden);
+        assert_eq!(post(&url, &cookie, &form(&token), &[("Referer", "https://evil.example.com/form")]), StatusCode::Forbidden);
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_exempt_routes_are_let_through() {
+        let (mut listening, url) = serve(CsrfProtection::exempt());
+        let (cookie, _token) = start_session(&url);
+
+        assert_eq!(post(&url, &cookie, "", &[]), StatusCode::Ok);
+        assert_eq!(post(&url, &cookie, &form("wrong"), &[("Origin", "https://evil.example.com")]), StatusCode::Ok);
+
+        listening.close().unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// How the authorization server displays the login and consent pages.
+/// See the display parameter in http://openid.net/specs/openid-connect-core-1_0.html#AuthRequest
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Display {
+    Page,
+    Popup,
+    Touch,
+    Wap,
+}
+
+impl Display {
+    /// unrecognised values get the full page
+    pub fn from_str(s: &str) -> Display {
+        match s {
+            "popup" => Display::Popup,
+            "touch" => Display::Touch,
+            "wap" => Display::Wap,
+            _ => Display::Page,
+        }
+    }
+    
+    pub fn from_param(display: Option<&str>) -> Display {
+        display.map(Display::from_str).unwrap_or(Display::Page)
+    }
+    
+    pub fn supported_values() -> Vec<String> {
+        vec!["page".to_owned(), "popup".to_owned(), "touch".to_owned()]
+    }
+    
+    /// layout template for pages shown in this display mode
+    pub fn layout(&self) -> &'static str {
+        match *self {
+            Display::Popup => "_layout_popup.html",
+            Display::Touch => "_layout_touch.html",
+            // no wap templates, so fall back to the page layout
+            Display::Page | Display::Wap => "_layout.html",
+        }
+    }
+}
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Display::Page => "page",
+            Display::Popup => "popup",
+            Display::Touch => "touch",
+            Display::Wap => "wap",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    #[test]
+    fn test_display_layout() {
+        assert_eq!(Display::from_param(Some("popup")).layout(), "_layout_popup.html");
+        assert_eq!(Display::from_param(Some("touch")).layout(), "_layout_touch.html");
+        assert_eq!(Display::from_param(Some("wap")).layout(), "_layout.html");
+        assert_eq!(Display::from_param(Some("bogus")), Display::Page);
+        assert_eq!(Display::from_param(None), Display::Page);
+    }
+}
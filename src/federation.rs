@@ -0,0 +1,775 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::*;
+use serde_json;
+use serde_json::{Map, Value};
+use url::Url;
+use rustc_serialize::base64::FromBase64;
+use openssl::bn::BigNum;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use jsonwebtoken::jwt::Jwt;
+use jsonwebtoken::crypto::mac_signer::MacSigner;
+
+use result::{Result, OpenIdConnectError};
+use http_client::HttpClient;
+use users::*;
+use authentication::new_user_id;
+
+/// Logging in through upstream OpenID Connect or OAuth2 providers, e.g. a corporate IdP.
+///
+/// We act as an ordinary relying party towards the upstream: send the user to its authorization endpoint,
+/// exchange the code it sends back, and check the id_token (or ask userinfo, for plain OAuth2 providers).
+/// The upstream identity is then linked to a local User, who is logged in as usual.
+
+fn upstream_error<S: Into<String>>(msg: S) -> OpenIdConnectError {
+    OpenIdConnectError::UpstreamLoginError(msg.into())
+}
+
+/// A configured upstream provider
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamProvider {
+    pub id: String, // used in our urls, e.g. /login/upstream/corp/callback
+    pub name: String, // shown on the login page button
+    pub issuer: String, // endpoints are discovered from here if not set below
+    pub client_id: String,
+    pub client_secret: String, // also the key HS256 id_tokens are signed with
+    #[serde(default)]
+    pub scopes: Vec<String>, // default openid email profile
+    #[serde(default)]
+    pub plain_oauth2: bool, // no id_token, identify the user with the userinfo endpoint instead
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub jwks_uri: Option<String>, // keys RS256 id_tokens are signed with
+    #[serde(default)]
+    pub provision_users: bool, // create local users for identities that aren't linked yet
+    #[serde(default)]
+    pub link_by_email: bool, // link identities to local users with the same verified email
+}
+
+impl UpstreamProvider {
+    pub fn new<A: Into<String>, B: Into<String>, C: Into<String>, D: Into<String>, E: Into<String>>(id: A, name: B, issuer: C, client_id: D, client_secret: E) -> UpstreamProvider {
+        UpstreamProvider {
+            id: id.into(),
+            name: name.into(),
+            issuer: issuer.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: vec![],
+            plain_oauth2: false,
+            authorization_endpoint: None,
+            token_endpoint: None,
+            userinfo_endpoint: None,
+            jwks_uri: None,
+            provision_users: false,
+            link_by_email: false,
+        }
+    }
+
+    pub fn get_scopes(&self) -> Vec<String> {
+        if !self.scopes.is_empty() {
+            self.scopes.clone()
+        } else if self.plain_oauth2 {
+            vec![]
+        } else {
+            vec!["openid".to_owned(), "email".to_owned(), "profile".to_owned()]
+        }
+    }
+}
+
+/// The parts of a discovery document we use
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+}
+
+/// A key from the provider's JWK Set. Only RSA keys are used.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamJwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamJwkSet {
+    pub keys: Vec<UpstreamJwk>,
+}
+
+impl UpstreamJwkSet {
+    /// The RSA signing key with this kid, or the only one if the id_token doesn't name a kid
+    pub fn find_rsa_key(&self, kid: Option<&str>) -> Option<&UpstreamJwk> {
+        let mut keys = self.keys.iter()
+            .filter(|k| k.kty == "RSA" && k.key_use.as_ref().map(|u| u == "sig").unwrap_or(true));
+
+        match kid {
+            Some(kid) => keys.find(|k| k.kid.as_ref().map(|k| k == kid).unwrap_or(false)),
+            None => {
+                let first = keys.next();
+                if keys.next().is_some() { None } else { first }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamTokenResponse {
+    pub access_token: String,
+    pub token_type: Option<String>,
+    pub id_token: Option<String>,
+}
+
+/// Who the upstream provider says the user is
+#[derive(Clone, Debug)]
+pub struct UpstreamIdentity {
+    pub provider_id: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub claims: Map<String, Value>,
+}
+
+impl UpstreamIdentity {
+    /// From id_token or userinfo claims.
+    /// OAuth2 providers without sub usually have a numeric id instead.
+    pub fn from_claims(provider_id: &str, claims: Map<String, Value>) -> Result<UpstreamIdentity> {
+        let subject = match claims.get("sub").or_else(|| claims.get("id")) {
+            Some(&Value::String(ref s)) => s.to_owned(),
+            Some(&Value::Number(ref n)) => format!("{}", n),
+            _ => return Err(upstream_error("no subject in upstream claims")),
+        };
+
+        Ok(UpstreamIdentity {
+            provider_id: provider_id.to_owned(),
+            subject: subject,
+            email: claims.get("email").and_then(|v| v.as_str()).map(|s| s.to_owned()),
+            email_verified: claims.get("email_verified").and_then(|v| v.as_bool()).unwrap_or(false),
+            claims: claims,
+        })
+    }
+
+    pub fn get_claim_str(&self, name: &str) -> Option<&str> {
+        self.claims.get(name).and_then(|v| v.as_str())
+    }
+}
+
+fn decode_part(part: &str, name: &str) -> Result<Map<String, Value>> {
+    let json = try!(part.from_base64().map_err(|e| upstream_error(format!("id_token {}: {}", name, e))));
+
+    serde_json::from_slice(&json).map_err(|e| upstream_error(format!("id_token {}: {}", name, e)))
+}
+
+/// The alg and kid from an id_token's header
+pub fn id_token_key_id(id_token: &str) -> Result<(String, Option<String>)> {
+    let header = try!(decode_part(try!(id_token.split('.').next().ok_or(upstream_error("id_token is not a jwt"))), "header"));
+
+    let alg = try!(header.get("alg").and_then(|v| v.as_str()).ok_or(upstream_error("id_token header has no alg")));
+    let kid = header.get("kid").and_then(|v| v.as_str()).map(|s| s.to_owned());
+
+    Ok((alg.to_owned(), kid))
+}
+
+fn verify_rs256(key: &UpstreamJwk, signing_input: &str, signature: &[u8]) -> Result<bool> {
+    let n = try!(try!(key.n.as_ref().ok_or(upstream_error("jwk has no modulus"))).from_base64().map_err(|e| upstream_error(format!("jwk modulus: {}", e))));
+    let e = try!(try!(key.e.as_ref().ok_or(upstream_error("jwk has no exponent"))).from_base64().map_err(|e| upstream_error(format!("jwk exponent: {}", e))));
+
+    let ssl_error = |e: ErrorStack| upstream_error(format!("jwk: {}", e));
+
+    let rsa = try!(Rsa::from_public_components(try!(BigNum::from_slice(&n).map_err(&ssl_error)), try!(BigNum::from_slice(&e).map_err(&ssl_error))).map_err(&ssl_error));
+    let pkey = try!(PKey::from_rsa(rsa).map_err(&ssl_error));
+
+    let mut verifier = try!(Verifier::new(MessageDigest::sha256(), &pkey).map_err(&ssl_error));
+    try!(verifier.update(signing_input.as_bytes()).map_err(&ssl_error));
+
+    Ok(verifier.finish(signature).unwrap_or(false))
+}
+
+/// Checks the id_token's signature: HS256 with the client_secret, or RS256 with a key from the provider's JWK Set
+pub fn verify_id_token_signature(provider: &UpstreamProvider, jwks: Option<&UpstreamJwkSet>, id_token: &str) -> Result<()> {
+    let (alg, kid) = try!(id_token_key_id(id_token));
+
+    match &alg[..] {
+        "HS256" => {
+            let mac_signer = try!(MacSigner::new(&provider.client_secret).map_err(|e| upstream_error(format!("bad client_secret: {:?}", e))));
+
+            try!(Jwt::decode(id_token, &mac_signer).map_err(|e| upstream_error(format!("id_token not verified: {}", e))));
+        },
+        "RS256" => {
+            let jwks = try!(jwks.ok_or(upstream_error("provider has no jwks_uri to check RS256 id_tokens with")));
+            let key = try!(jwks.find_rsa_key(kid.as_ref().map(|s| &s[..])).ok_or(upstream_error(format!("no key {:?} in the provider's jwks", kid))));
+
+            let dot = try!(id_token.rfind('.').ok_or(upstream_error("id_token is not a jwt")));
+            let signature = try!(id_token[dot + 1..].from_base64().map_err(|e| upstream_error(format!("id_token signature: {}", e))));
+
+            if !try!(verify_rs256(key, &id_token[..dot], &signature)) {
+                return Err(upstream_error("id_token not verified: bad signature"));
+            }
+        },
+        other => return Err(upstream_error(format!("id_token alg {} is not supported", other))),
+    }
+
+    Ok(())
+}
+
+/// Checks an id_token from the upstream provider and returns its claims.
+/// RS256 id_tokens are checked against jwks, the provider's keys.
+pub fn validate_id_token(provider: &UpstreamProvider, jwks: Option<&UpstreamJwkSet>, id_token: &str, nonce: &str, now: DateTime<UTC>) -> Result<Map<String, Value>> {
+    try!(verify_id_token_signature(provider, jwks, id_token));
+
+    let payload = try!(id_token.split('.').nth(1).ok_or(upstream_error("id_token is not a jwt")));
+    let claims = try!(decode_part(payload, "payload"));
+
+    if claims.get("iss").and_then(|v| v.as_str()) != Some(&provider.issuer[..]) {
+        return Err(upstream_error("id_token iss doesn't match the provider's issuer"));
+    }
+
+    let audiences: Vec<&str> = match claims.get("aud") {
+        Some(&Value::String(ref aud)) => vec![&aud[..]],
+        Some(&Value::Array(ref auds)) => auds.iter().filter_map(|a| a.as_str()).collect(),
+        _ => vec![],
+    };
+
+    if !audiences.contains(&&provider.client_id[..]) {
+        return Err(upstream_error("id_token wasn't issued to us"));
+    }
+
+    if audiences.len() > 1 && claims.get("azp").and_then(|v| v.as_str()) != Some(&provider.client_id[..]) {
+        return Err(upstream_error("id_token azp doesn't match our client_id"));
+    }
+
+    match claims.get("exp").and_then(|v| v.as_i64()) {
+        Some(exp) if exp > now.timestamp() => {},
+        _ => return Err(upstream_error("id_token has expired")),
+    }
+
+    if claims.get("nonce").and_then(|v| v.as_str()) != Some(nonce) {
+        return Err(upstream_error("id_token nonce doesn't match"));
+    }
+
+    Ok(claims)
+}
+
+/// Talks to upstream providers.
+/// Discovery documents are cached, so each provider is only asked once.
+/// JWK Sets are cached too, and fetched again when an id_token names a key we haven't seen, since keys are rotated.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    http_client: Arc<Box<HttpClient>>,
+    metadata: Arc<Mutex<HashMap<String, ProviderMetadata>>>,
+    jwks: Arc<Mutex<HashMap<String, UpstreamJwkSet>>>,
+}
+
+impl UpstreamClient {
+    pub fn new(http_client: Arc<Box<HttpClient>>) -> UpstreamClient {
+        UpstreamClient {
+            http_client: http_client,
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+            jwks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Endpoints set in the provider's config take precedence over discovered ones
+    pub fn metadata(&self, provider: &UpstreamProvider) -> Result<ProviderMetadata> {
+        if let (Some(authorization_endpoint), Some(token_endpoint)) = (provider.authorization_endpoint.clone(), provider.token_endpoint.clone()) {
+            return Ok(ProviderMetadata {
+                issuer: provider.issuer.clone(),
+                authorization_endpoint: authorization_endpoint,
+                token_endpoint: token_endpoint,
+                userinfo_endpoint: provider.userinfo_endpoint.clone(),
+                jwks_uri: provider.jwks_uri.clone(),
+            });
+        }
+
+        if let Some(metadata) = self.metadata.lock().unwrap().get(&provider.id) {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_right_matches('/'));
+        let res = try!(self.http_client.get(&url));
+
+        if !res.is_success() {
+            return Err(upstream_error(format!("discovery document returned status {}", res.status)));
+        }
+
+        let mut metadata: ProviderMetadata = try!(serde_json::from_str(&res.body).map_err(|e| upstream_error(format!("discovery document: {}", e))));
+
+        if metadata.issuer != provider.issuer {
+            return Err(upstream_error(format!("discovery document is for issuer {}", metadata.issuer)));
+        }
+
+        if let Some(ref authorization_endpoint) = provider.authorization_endpoint {
+            metadata.authorization_endpoint = authorization_endpoint.clone();
+        }
+
+        if let Some(ref token_endpoint) = provider.token_endpoint {
+            metadata.token_endpoint = token_endpoint.clone();
+        }
+
+        if provider.userinfo_endpoint.is_some() {
+            metadata.userinfo_endpoint = provider.userinfo_endpoint.clone();
+        }
+
+        if provider.jwks_uri.is_some() {
+            metadata.jwks_uri = provider.jwks_uri.clone();
+        }
+
+        self.metadata.lock().unwrap().insert(provider.id.clone(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    fn fetch_jwks(&self, provider: &UpstreamProvider, jwks_uri: &str) -> Result<UpstreamJwkSet> {
+        let res = try!(self.http_client.get(jwks_uri));
+
+        if !res.is_success() {
+            return Err(upstream_error(format!("jwks_uri returned status {}", res.status)));
+        }
+
+        let jwks: UpstreamJwkSet = try!(serde_json::from_str(&res.body).map_err(|e| upstream_error(format!("jwks document: {}", e))));
+
+        self.jwks.lock().unwrap().insert(provider.id.clone(), jwks.clone());
+
+        Ok(jwks)
+    }
+
+    /// The provider's keys for checking an RS256 id_token signed with kid, or None for other id_tokens
+    pub fn jwks_for(&self, provider: &UpstreamProvider, id_token: &str) -> Result<Option<UpstreamJwkSet>> {
+        let (alg, kid) = try!(id_token_key_id(id_token));
+
+        if alg != "RS256" {
+            return Ok(None);
+        }
+
+        let metadata = try!(self.metadata(provider));
+        let jwks_uri = try!(metadata.jwks_uri.ok_or(upstream_error("provider has no jwks_uri")));
+
+        if let Some(jwks) = self.jwks.lock().unwrap().get(&provider.id) {
+            if jwks.find_rsa_key(kid.as_ref().map(|s| &s[..])).is_some() {
+                return Ok(Some(jwks.clone()));
+            }
+        }
+
+        self.fetch_jwks(provider, &jwks_uri).map(Some)
+    }
+
+    /// Where to send the user to log in upstream
+    pub fn authorization_url(&self, provider: &UpstreamProvider, redirect_uri: &str, state: &str, nonce: &str) -> Result<Url> {
+        let metadata = try!(self.metadata(provider));
+
+        let mut url = try!(Url::parse(&metadata.authorization_endpoint).map_err(|e| upstream_error(format!("authorization_endpoint: {}", e))));
+
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("response_type", "code");
+            query.append_pair("client_id", &provider.client_id);
+            query.append_pair("redirect_uri", redirect_uri);
+            query.append_pair("state", state);
+
+            let scopes = provider.get_scopes();
+            if !scopes.is_empty() {
+                query.append_pair("scope", &scopes.join(" "));
+            }
+
+            if !provider.plain_oauth2 {
+                query.append_pair("nonce", nonce);
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// Swaps the code for tokens, authenticating with client_secret_post
+    pub fn exchange_code(&self, provider: &UpstreamProvider, code: &str, redirect_uri: &str) -> Result<UpstreamTokenResponse> {
+        let metadata = try!(self.metadata(provider));
+
+        let params = vec![
+            ("grant_type".to_owned(), "authorization_code".to_owned()),
+            ("code".to_owned(), code.to_owned()),
+            ("redirect_uri".to_owned(), redirect_uri.to_owned()),
+            ("client_id".to_owned(), provider.client_id.clone()),
+            ("client_secret".to_owned(), provider.client_secret.clone()),
+        ];
+
+        let res = try!(self.http_client.post_form(&metadata.token_endpoint, &params));
+
+        if !res.is_success() {
+            return Err(upstream_error(format!("token endpoint returned status {}: {}", res.status, res.body)));
+        }
+
+        serde_json::from_str(&res.body).map_err(|e| upstream_error(format!("token response: {}", e)))
+    }
+
+    pub fn fetch_userinfo(&self, provider: &UpstreamProvider, access_token: &str) -> Result<Map<String, Value>> {
+        let metadata = try!(self.metadata(provider));
+        let userinfo_endpoint = try!(metadata.userinfo_endpoint.ok_or(upstream_error("provider has no userinfo endpoint")));
+
+        let res = try!(self.http_client.get_with_bearer(&userinfo_endpoint, access_token));
+
+        if !res.is_success() {
+            return Err(upstream_error(format!("userinfo endpoint returned status {}", res.status)));
+        }
+
+        serde_json::from_str(&res.body).map_err(|e| upstream_error(format!("userinfo response: {}", e)))
+    }
+
+    /// The second half of the flow, once the user comes back with a code
+    pub fn identity(&self, provider: &UpstreamProvider, code: &str, redirect_uri: &str, nonce: &str, now: DateTime<UTC>) -> Result<UpstreamIdentity> {
+        let tokens = try!(self.exchange_code(provider, code, redirect_uri));
+
+        let claims = if provider.plain_oauth2 {
+            try!(self.fetch_userinfo(provider, &tokens.access_token))
+        } else {
+            let id_token = try!(tokens.id_token.ok_or(upstream_error("token response has no id_token")));
+            let jwks = try!(self.jwks_for(provider, &id_token));
+
+            try!(validate_id_token(provider, jwks.as_ref(), &id_token, nonce, now))
+        };
+
+        UpstreamIdentity::from_claims(&provider.id, claims)
+    }
+}
+
+/// A username for a new user, preferring what the upstream calls them
+fn available_username(user_repo: &UserRepo, identity: &UpstreamIdentity) -> Result<String> {
+    let base = identity.get_claim_str("preferred_username")
+        .or(identity.email.as_ref().map(|s| &s[..]))
+        .map(|s| s.to_owned())
+        .unwrap_or(format!("{}-{}", identity.provider_id, identity.subject));
+
+    if try!(user_repo.find_user(&base)).is_none() {
+        return Ok(base);
+    }
+
+    for i in 2..100 {
+        let username = format!("{}-{}", base, i);
+
+        if try!(user_repo.find_user(&username)).is_none() {
+            return Ok(username);
+        }
+    }
+
+    Err(OpenIdConnectError::UserAlreadyExists)
+}
+
+/// The local user for an upstream identity.
+/// Identities already linked to a user log in as that user.
+/// Otherwise, depending on the provider's settings, they're linked to a user with the same verified email,
+/// or a new user is created for them.
+pub fn link_user(user_repo: &UserRepo, provider: &UpstreamProvider, identity: &UpstreamIdentity, now: DateTime<UTC>) -> Result<User> {
+    if let Some(user) = try!(user_repo.find_user_by_upstream_link(&provider.id, &identity.subject)) {
+        return Ok(user);
+    }
+
+    if provider.link_by_email && identity.email_verified {
+        if let Some(ref email) = identity.email {
+            if let Some(mut user) = try!(user_repo.find_user_by_email(email)) {
+                // an unverified local address could have been typed in by anyone
+                if user.email_verified {
+                    info!("linking {} identity {} to user {} by email", provider.id, identity.subject, user.id);
+
+                    user.add_upstream_link(&provider.id, &identity.subject);
+                    try!(user_repo.update_user(user.clone()));
+
+                    return Ok(user);
+                }
+            }
+        }
+    }
+
+    if !provider.provision_users {
+        return Err(upstream_error(format!("no local account is linked to this {} account", provider.name)));
+    }
+
+    let mut user = User::new(new_user_id(), try!(available_username(user_repo, identity)), None);
+
+    if let Some(ref email) = identity.email {
+        if !is_valid_email(email) {
+            info!("not copying {} identity {}'s email address, it isn't valid", provider.id, identity.subject);
+        } else if try!(user_repo.find_user_by_email(email)).is_none() {
+            user.set_email(Some(email.clone()));
+            user.email_verified = identity.email_verified;
+        }
+    }
+
+    for name in PROFILE_CLAIMS {
+        if let Some(value) = identity.get_claim_str(name) {
+            user.set_claim(name, Some(value.to_owned()));
+        }
+    }
+
+    user.touch(now);
+    user.add_upstream_link(&provider.id, &identity.subject);
+
+    info!("provisioning user {} for {} identity {}", user.id, provider.id, identity.subject);
+
+    try!(user_repo.add_user(user.clone()));
+
+    Ok(user)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::*;
+    use serde_json;
+    use serde_json::{Map, Value};
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use jsonwebtoken::jwt::Jwt;
+    use jsonwebtoken::json::*;
+    use jsonwebtoken::crypto::mac_signer::MacSigner;
+
+    use super::*;
+    use result::{Result, OpenIdConnectError};
+    use http_client::{HttpClient, HttpResponse};
+    use users::*;
+
+    const ISSUER: &'static str = "https://idp.example.com";
+
+    /// An RSA key the stand-in IdP signs RS256 id_tokens with, published in its jwks
+    struct IdpKey {
+        kid: String,
+        pkey: PKey,
+        n: Vec<u8>,
+        e: Vec<u8>,
+    }
+
+    impl IdpKey {
+        fn generate(kid: &str) -> IdpKey {
+            let rsa = Rsa::generate(2048).unwrap();
+            let n = rsa.n().unwrap().to_vec();
+            let e = rsa.e().unwrap().to_vec();
+
+            IdpKey {
+                kid: kid.to_owned(),
+                pkey: PKey::from_rsa(rsa).unwrap(),
+                n: n,
+                e: e,
+            }
+        }
+
+        fn jwk(&self) -> String {
+            format!(r#"{{"kty": "RSA", "use": "sig", "kid": "{}", "n": "{}", "e": "{}"}}"#, self.kid, self.n.to_base64(URL_SAFE), self.e.to_base64(URL_SAFE))
+        }
+
+        fn sign(&self, claims: &Map<String, Value>) -> String {
+            let header = format!(r#"{{"alg": "RS256", "typ": "JWT", "kid": "{}"}}"#, self.kid);
+            let signing_input = format!("{}.{}", header.as_bytes().to_base64(URL_SAFE), serde_json::to_string(claims).unwrap().as_bytes().to_base64(URL_SAFE));
+
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.pkey).unwrap();
+            signer.update(signing_input.as_bytes()).unwrap();
+            let signature = signer.finish().unwrap();
+
+            format!("{}.{}", signing_input, signature.to_base64(URL_SAFE))
+        }
+    }
+
+    /// Stands in for the upstream IdP's discovery, token, userinfo and jwks endpoints.
+    /// It signs id_tokens with rsa_key if it has one, or HS256 with the secret otherwise.
+    struct StandInIdp {
+        nonce: Arc<Mutex<String>>,
+        audience: String,
+        secret: String,
+        rsa_key: Option<IdpKey>,
+    }
+
+    impl StandInIdp {
+        fn rs256_id_token(&self, key: &IdpKey) -> String {
+            let now = UTC::now().timestamp();
+
+            let mut claims = Map::new();
+            claims.insert("iss".to_owned(), Value::String(ISSUER.to_owned()));
+            claims.insert("aud".to_owned(), Value::String(self.audience.clone()));
+            claims.insert("sub".to_owned(), Value::String("upstream-1".to_owned()));
+            claims.insert("exp".to_owned(), Value::Number((now + 300).into()));
+            claims.insert("iat".to_owned(), Value::Number(now.into()));
+            claims.insert("nonce".to_owned(), Value::String(self.nonce.lock().unwrap().clone()));
+            claims.insert("email".to_owned(), Value::String("alice@example.com".to_owned()));
+            claims.insert("email_verified".to_owned(), Value::Bool(true));
+
+            key.sign(&claims)
+        }
+
+        fn id_token(&self) -> String {
+            if let Some(ref key) = self.rsa_key {
+                return self.rs256_id_token(key);
+            }
+
+            let now = UTC::now().timestamp();
+
+            let mut t = Jwt::default();
+            t.claims.set_value("iss", &ISSUER.to_owned());
+            t.claims.set_value("aud", &self.audience);
+            t.claims.set_value("sub", &"upstream-1".to_owned());
+            t.claims.set_value("exp", &(now + 300));
+            t.claims.set_value("iat", &now);
+            t.claims.set_value("nonce", &*self.nonce.lock().unwrap());
+            t.claims.set_value("email", &"alice@example.com".to_owned());
+            t.claims.set_value("email_verified", &true);
+            t.claims.set_value("preferred_username", &"alice".to_owned());
+            t.claims.set_value("given_name", &"Alice".to_owned());
+
+            t.encode(&MacSigner::new(&self.secret).unwrap()).unwrap()
+        }
+    }
+
+    impl HttpClient for StandInIdp {
+        fn get(&self, url: &str) -> Result<HttpResponse> {
+            if url == "https://idp.example.com/.well-known/openid-configuration" {
+                let body = format!(r#"{{"issuer": "{0}", "authorization_endpoint": "{0}/authorize", "token_endpoint": "{0}/token", "userinfo_endpoint": "{0}/userinfo", "jwks_uri": "{0}/jwks"}}"#, ISSUER);
+
+                Ok(HttpResponse::new(200, Some("application/json".to_owned()), body))
+            } else if url == "https://idp.example.com/jwks" {
+                let keys: Vec<String> = self.rsa_key.iter().map(|k| k.jwk()).collect();
+
+                Ok(HttpResponse::new(200, Some("application/json".to_owned()), format!(r#"{{"keys": [{}]}}"#, keys.join(", "))))
+            } else {
+                Ok(HttpResponse::new(404, None, String::new()))
+            }
+        }
+
+        fn get_with_bearer(&self, url: &str, access_token: &str) -> Result<HttpResponse> {
+            if url == "https://idp.example.com/userinfo" && access_token == "upstream-access-token" {
+                Ok(HttpResponse::new(200, Some("application/json".to_owned()), r#"{"id": 42, "login": "octo"}"#.to_owned()))
+            } else {
+                Ok(HttpResponse::new(401, None, String::new()))
+            }
+        }
+
+        fn post_form(&self, url: &str, params: &[(String, String)]) -> Result<HttpResponse> {
+            let code = params.iter().find(|p| p.0 == "code").map(|p| &p.1[..]);
+
+            if url == "https://idp.example.com/token" && code == Some("upstream-code") {
+                let body = format!(r#"{{"access_token": "upstream-access-token", "token_type": "Bearer", "id_token": "{}"}}"#, self.id_token());
+
+                Ok(HttpResponse::new(200, Some("application/json".to_owned()), body))
+            } else {
+                Err(OpenIdConnectError::HttpClientError("status 400".to_owned()))
+            }
+        }
+    }
+
+    fn upstream(audience: &str) -> (UpstreamClient, Arc<Mutex<String>>) {
+        let nonce = Arc::new(Mutex::new(String::new()));
+        let idp = StandInIdp { nonce: nonce.clone(), audience: audience.to_owned(), secret: "upstream secret".to_owned(), rsa_key: None };
+
+        (UpstreamClient::new(Arc::new(Box::new(idp) as Box<HttpClient>)), nonce)
+    }
+
+    fn provider() -> UpstreamProvider {
+        let mut provider = UpstreamProvider::new("corp", "Corp", ISSUER, "our-client", "upstream secret");
+        provider.provision_users = true;
+        provider
+    }
+
+    #[test]
+    fn test_upstream_login_provisions_then_reuses_user() {
+        let (upstream, nonce) = upstream("our-client");
+        let provider = provider();
+        let user_repo = InMemoryUserRepo::new();
+        *nonce.lock().unwrap() = "n-1".to_owned();
+
+        let url = upstream.authorization_url(&provider, "https://op.example.com/cb", "s-1", "n-1").unwrap();
+        assert_eq!(url.path(), "/authorize");
+        assert!(url.query_pairs().any(|(k, v)| k == "nonce" && v == "n-1"));
+        assert!(url.query_pairs().any(|(k, v)| k == "scope" && v == "openid email profile"));
+
+        let identity = upstream.identity(&provider, "upstream-code", "https://op.example.com/cb", "n-1", UTC::now()).unwrap();
+        assert_eq!(identity.subject, "upstream-1");
+        assert!(identity.email_verified);
+
+        let user = link_user(&user_repo, &provider, &identity, UTC::now()).unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.email, Some("alice@example.com".to_owned()));
+        assert_eq!(user.get_claim("given_name"), Some(Value::String("Alice".to_owned())));
+        assert!(user.hashed_password.is_none());
+
+        let again = link_user(&user_repo, &provider, &identity, UTC::now()).unwrap();
+        assert_eq!(again.id, user.id);
+
+        assert!(upstream.identity(&provider, "upstream-code", "https://op.example.com/cb", "n-2", UTC::now()).is_err());
+    }
+
+    #[test]
+    fn test_rs256_id_token_checked_with_provider_jwks() {
+        let nonce = Arc::new(Mutex::new("n-1".to_owned()));
+        let idp = StandInIdp { nonce: nonce.clone(), audience: "our-client".to_owned(), secret: "upstream secret".to_owned(), rsa_key: Some(IdpKey::generate("k1")) };
+        let upstream = UpstreamClient::new(Arc::new(Box::new(idp) as Box<HttpClient>));
+
+        let identity = upstream.identity(&provider(), "upstream-code", "https://op.example.com/cb", "n-1", UTC::now()).unwrap();
+        assert_eq!(identity.subject, "upstream-1");
+
+        // signed by a key that isn't in the provider's jwks
+        let other = IdpKey::generate("k1");
+        let forged = StandInIdp { nonce: nonce, audience: "our-client".to_owned(), secret: String::new(), rsa_key: None }.rs256_id_token(&other);
+        let jwks = upstream.jwks_for(&provider(), &forged).unwrap();
+        assert!(jwks.is_some());
+        assert!(validate_id_token(&provider(), jwks.as_ref(), &forged, "n-1", UTC::now()).is_err());
+
+        // without the provider's keys
+        assert!(validate_id_token(&provider(), None, &forged, "n-1", UTC::now()).is_err());
+    }
+
+    #[test]
+    fn test_id_token_for_another_client_rejected() {
+        let (upstream, nonce) = upstream("someone-else");
+        *nonce.lock().unwrap() = "n-1".to_owned();
+
+        assert!(upstream.identity(&provider(), "upstream-code", "https://op.example.com/cb", "n-1", UTC::now()).is_err());
+    }
+
+    #[test]
+    fn test_plain_oauth2_uses_userinfo() {
+        let (upstream, _) = upstream("our-client");
+        let mut provider = provider();
+        provider.plain_oauth2 = true;
+
+        let url = upstream.authorization_url(&provider, "https://op.example.com/cb", "s-1", "n-1").unwrap();
+        assert!(!url.query_pairs().any(|(k, _)| k == "nonce"));
+
+        let identity = upstream.identity(&provider, "upstream-code", "https://op.example.com/cb", "n-1", UTC::now()).unwrap();
+        assert_eq!(identity.subject, "42");
+    }
+
+    #[test]
+    fn test_link_by_verified_email_only() {
+        let (upstream, nonce) = upstream("our-client");
+        let mut provider = provider();
+        provider.provision_users = false;
+        provider.link_by_email = true;
+        *nonce.lock().unwrap() = "n-1".to_owned();
+
+        let user_repo = InMemoryUserRepo::new();
+        let mut local = User::new("1".to_owned(), "alice.local".to_owned(), Some("password".to_owned()));
+        local.set_email(Some("alice@example.com".to_owned()));
+        user_repo.add_user(local.clone()).unwrap();
+
+        let identity = upstream.identity(&provider, "upstream-code", "https://op.example.com/cb", "n-1", UTC::now()).unwrap();
+
+        assert!(link_user(&user_repo, &provider, &identity, UTC::now()).is_err());
+
+        local.email_verified = true;
+        user_repo.update_user(local).unwrap();
+
+        let user = link_user(&user_repo, &provider, &identity, UTC::now()).unwrap();
+        assert_eq!(user.id, "1");
+        assert!(user_repo.find_user_by_upstream_link("corp", "upstream-1").unwrap().is_some());
+    }
+}
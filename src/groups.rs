@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::*;
+
+use result::{Result, OpenIdConnectError};
+
+/// A named set of users, e.g. a team provisioned from an HR system over SCIM
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub id: String,
+    pub display_name: String,
+    pub external_id: Option<String>, // the provisioning system's id for the group
+    pub members: Vec<String>, // user ids
+    pub created_at: DateTime<UTC>,
+    pub updated_at: DateTime<UTC>,
+}
+
+impl Group {
+    pub fn new(id: String, display_name: String, now: DateTime<UTC>) -> Group {
+        Group {
+            id: id,
+            display_name: display_name,
+            external_id: None,
+            members: vec![],
+            created_at: now,
+            updated_at: now,
+        }
+    }
+    
+    pub fn has_member(&self, user_id: &str) -> bool {
+        self.members.iter().any(|m| m == user_id)
+    }
+    
+    pub fn add_member(&mut self, user_id: &str) {
+        if !self.has_member(user_id) {
+            self.members.push(user_id.to_owned());
+        }
+    }
+    
+    pub fn remove_member(&mut self, user_id: &str) {
+        self.members = self.members.iter().filter(|m| *m != user_id).map(|m| m.clone()).collect();
+    }
+}
+
+pub trait GroupRepo where Self: Send + Sync {
+    fn add_group(&self, g: Group) -> Result<()>;
+    
+    fn get_group(&self, id: &str) -> Result<Option<Group>>;
+    
+    fn find_group(&self, display_name: &str) -> Result<Option<Group>>;
+    
+    fn update_group(&self, g: Group) -> Result<()>;
+    
+    fn remove_group(&self, id: &str) -> Result<()>;
+    
+    /// Every group, in display name order
+    fn get_groups(&self) -> Result<Vec<Group>>;
+    
+    fn get_user_groups(&self, user_id: &str) -> Result<Vec<Group>>;
+    
+    /// Takes a deleted user out of every group they were in
+    fn remove_member_everywhere(&self, user_id: &str) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct InMemoryGroupRepo {
+    groups: Arc<Mutex<Vec<Group>>>,
+}
+
+impl InMemoryGroupRepo {
+    pub fn new() -> InMemoryGroupRepo {
+        InMemoryGroupRepo {
+            groups: Arc::new(Mutex::new(vec![])),
+        }
+    }
+    
+    fn find_index(groups: &Vec<Group>, id: &str) -> Option<usize> {
+        groups.iter().position(|g| g.id == id)
+    }
+}
+
+impl GroupRepo for InMemoryGroupRepo {
+    fn add_group(&self, g: Group) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        
+        if groups.iter().any(|other| other.id == g.id || other.display_name == g.display_name) {
+            Err(OpenIdConnectError::GroupAlreadyExists)
+        } else {
+            groups.push(g);
+            
+            Ok(())
+        }
+    }
+    
+    fn get_group(&self, id: &str) -> Result<Option<Group>> {
+        let groups = self.groups.lock().unwrap();
+        
+        Ok(Self::find_index(&groups, id).map(|i| groups[i].clone()))
+    }
+    
+    fn find_group(&self, display_name: &str) -> Result<Option<Group>> {
+        let groups = self.groups.lock().unwrap();
+        
+        Ok(groups.iter().find(|g| g.display_name == display_name).map(|g| g.clone()))
+    }
+    
+    fn update_group(&self, g: Group) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        
+        if groups.iter().any(|other| other.id != g.id && other.display_name == g.display_name) {
+            return Err(OpenIdConnectError::GroupAlreadyExists);
+        }
+        
+        let index = try!(Self::find_index(&groups, &g.id).ok_or(OpenIdConnectError::GroupNotFound));
+        
+        groups[index] = g;
+        
+        Ok(())
+    }
+    
+    fn remove_group(&self, id: &str) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        
+        let index = try!(Self::find_index(&groups, id).ok_or(OpenIdConnectError::GroupNotFound));
+        
+        groups.remove(index);
+        
+        Ok(())
+    }
+    
+    fn get_groups(&self) -> Result<Vec<Group>> {
+        let groups = self.groups.lock().unwrap();
+        
+        let mut all: Vec<Group> = groups.iter().map(|g| g.clone()).collect();
+        all.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        
+        Ok(all)
+    }
+    
+    fn get_user_groups(&self, user_id: &str) -> Result<Vec<Group>> {
+        let groups = self.groups.lock().unwrap();
+        
+        Ok(groups.iter().filter(|g| g.has_member(user_id)).map(|g| g.clone()).collect())
+    }
+    
+    fn remove_member_everywhere(&self, user_id: &str) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        
+        for group in groups.iter_mut() {
+            group.remove_member(user_id);
+        }
+        
+        Ok(())
+    }
+}
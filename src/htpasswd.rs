@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crypto::bcrypt::bcrypt;
+use crypto::util::fixed_time_eq;
+
+use result::Result;
+use authentication::*;
+use users::Role;
+
+/// Users kept in an Apache htpasswd file, e.g. a few admin accounts managed by ops outside the database.
+///
+/// Only bcrypt entries are understood ($2y$, as made by `htpasswd -B`, and $2a$/$2b$).
+/// Lines with other hash schemes are skipped with a warning.
+/// The file is read again whenever it changes, so accounts can be added without a restart.
+/// Usernames listed in `admins` (the site's htpasswd_admins) get the admin role, and lose it when they're taken off.
+
+const BCRYPT_ALPHABET: &'static [u8] = b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const BCRYPT_SALT_BYTES: usize = 16;
+const BCRYPT_HASH_BYTES: usize = 23; // the last byte of the output isn't encoded
+
+/// bcrypt's base64, which has its own alphabet and no padding
+fn bcrypt_base64_decode(s: &str, len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = match BCRYPT_ALPHABET.iter().position(|&a| a == c) {
+            Some(value) => value as u32,
+            None => return None,
+        };
+
+        acc = (acc << 6) | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+
+        if out.len() == len {
+            return Some(out);
+        }
+    }
+
+    None
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BcryptHash {
+    pub cost: u32,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl BcryptHash {
+    /// $2y$<cost>$<22 chars of salt><31 chars of hash>
+    pub fn parse(s: &str) -> Option<BcryptHash> {
+        let parts: Vec<&str> = s.split('$').collect();
+
+        if parts.len() != 4 || !parts[0].is_empty() || !["2a", "2b", "2y"].contains(&parts[1]) || parts[3].len() != 53 || !parts[3].bytes().all(|b| b < 128) {
+            return None;
+        }
+
+        let cost = match parts[2].parse::<u32>() {
+            Ok(cost) if cost >= 4 && cost <= 31 => cost,
+            _ => return None,
+        };
+
+        match (bcrypt_base64_decode(&parts[3][..22], BCRYPT_SALT_BYTES), bcrypt_base64_decode(&parts[3][22..], BCRYPT_HASH_BYTES)) {
+            (Some(salt), Some(hash)) => Some(BcryptHash {
+                cost: cost,
+                salt: salt,
+                hash: hash,
+            }),
+            _ => None
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        // the password is hashed with its nul terminator, and only the first 72 bytes count
+        let mut key: Vec<u8> = password.as_bytes().to_vec();
+        key.push(0);
+        key.truncate(72);
+
+        let mut output = [0u8; 24];
+        bcrypt(self.cost, &self.salt, &key, &mut output);
+
+        fixed_time_eq(&output[..BCRYPT_HASH_BYTES], &self.hash)
+    }
+}
+
+/// username -> hash, from the lines of an htpasswd file
+pub fn parse_htpasswd(contents: &str) -> HashMap<String, BcryptHash> {
+    let mut entries = HashMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ':');
+
+        match (parts.next(), parts.next().and_then(BcryptHash::parse)) {
+            (Some(username), Some(hash)) => {
+                entries.insert(username.to_owned(), hash);
+            },
+            _ => {
+                warn!("htpasswd line {} skipped: not a bcrypt entry", i + 1);
+            }
+        }
+    }
+
+    entries
+}
+
+struct HtpasswdEntries {
+    modified: Option<SystemTime>,
+    users: HashMap<String, BcryptHash>,
+}
+
+pub struct HtpasswdAuthenticator {
+    path: Option<PathBuf>,
+    entries: Mutex<HtpasswdEntries>,
+    pub admins: Vec<String>,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<HtpasswdAuthenticator> {
+        let authenticator = HtpasswdAuthenticator {
+            path: Some(path.into()),
+            entries: Mutex::new(HtpasswdEntries { modified: None, users: HashMap::new() }),
+            admins: vec![],
+        };
+
+        try!(authenticator.reload_if_changed());
+
+        Ok(authenticator)
+    }
+
+    /// Entries that don't come from a file, e.g. for tests
+    pub fn from_contents(contents: &str) -> HtpasswdAuthenticator {
+        HtpasswdAuthenticator {
+            path: None,
+            entries: Mutex::new(HtpasswdEntries { modified: None, users: parse_htpasswd(contents) }),
+            admins: vec![],
+        }
+    }
+
+    fn roles(&self, username: &str) -> Vec<Role> {
+        if self.admins.iter().any(|a| a == username) {
+            vec![Role::User, Role::Admin]
+        } else {
+            vec![Role::User]
+        }
+    }
+
+    fn reload_if_changed(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let modified = try!(fs::metadata(path)).modified().ok();
+        let mut entries = self.entries.lock().unwrap();
+
+        if modified.is_none() || modified != entries.modified {
+            debug!("reading htpasswd file {:?}", path);
+
+            let mut contents = String::new();
+            try!(try!(fs::File::open(path)).read_to_string(&mut contents));
+
+            entries.users = parse_htpasswd(&contents);
+            entries.modified = modified;
+        }
+
+        Ok(())
+    }
+}
+
+impl Authenticator for HtpasswdAuthenticator {
+    fn authenticate(&self, username: &str, password: &str) -> Result<Authentication> {
+        try!(self.reload_if_changed());
+
+        let entries = self.entries.lock().unwrap();
+
+        match entries.users.get(username) {
+            Some(hash) => {
+                let mut attributes = UserAttributes::new(username);
+                attributes.roles = self.roles(username);
+                let attributes = Some(attributes);
+
+                if hash.verify(password) {
+                    Ok(Authentication::new(AuthenticationStatus::Success, attributes))
+                } else {
+                    Ok(Authentication::new(AuthenticationStatus::IncorrectPassword, attributes))
+                }
+            },
+            None => Ok(Authentication::not_found())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use authentication::*;
+    use users::*;
+
+    // openwall test vector for "U*U"
+    const HTPASSWD: &'static str = "# admins\nadmin:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\nlegacy:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n";
+
+    #[test]
+    fn test_bcrypt_hash() {
+        let hash = BcryptHash::parse("$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW").unwrap();
+
+        assert_eq!(hash.cost, 5);
+        assert_eq!(hash.salt, vec![0x10, 0x41, 0x04, 0x10, 0x41, 0x04, 0x10, 0x41, 0x04, 0x10, 0x41, 0x04, 0x10, 0x41, 0x04, 0x10]);
+        assert!(hash.verify("U*U"));
+        assert!(!hash.verify("U*U*"));
+        assert!(BcryptHash::parse("$2a$05$tooshort").is_none());
+    }
+
+    #[test]
+    fn test_htpasswd_authenticator() {
+        let mut htpasswd = HtpasswdAuthenticator::from_contents(HTPASSWD);
+
+        let authentication = htpasswd.authenticate("admin", "U*U").unwrap();
+        assert_eq!(authentication.status, AuthenticationStatus::Success);
+        assert_eq!(authentication.attributes.unwrap().roles, vec![Role::User]);
+
+        htpasswd.admins = vec!["admin".to_owned()];
+        let attributes = htpasswd.authenticate("admin", "U*U").unwrap().attributes.unwrap();
+        assert_eq!(attributes.username, "admin");
+        assert_eq!(attributes.roles, vec![Role::User, Role::Admin]);
+
+        assert_eq!(htpasswd.authenticate("admin", "wrong").unwrap().status, AuthenticationStatus::IncorrectPassword);
+        assert_eq!(htpasswd.authenticate("legacy", "password").unwrap().status, AuthenticationStatus::PrincipalNotFound);
+        assert_eq!(htpasswd.authenticate("nobody", "U*U").unwrap().status, AuthenticationStatus::PrincipalNotFound);
+    }
+
+    #[test]
+    fn test_first_authenticator_that_knows_the_user_decides() {
+        let user_repo = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        user_repo.add_user(User::new("1".to_owned(), "admin".to_owned(), Some("repo password".to_owned()))).unwrap();
+        user_repo.add_user(User::new("2".to_owned(), "alice".to_owned(), Some("alice password".to_owned()))).unwrap();
+
+        let mut chain = AuthenticatorChain::new();
+        chain.push("htpasswd", Arc::new(Box::new(HtpasswdAuthenticator::from_contents(HTPASSWD)) as Box<Authenticator>));
+        chain.push("users", Arc::new(Box::new(UserRepoAuthenticator::new(user_repo)) as Box<Authenticator>));
+
+        assert_eq!(chain.authenticate("admin", "U*U").unwrap().status, AuthenticationStatus::Success);
+        assert_eq!(chain.authenticate("admin", "repo password").unwrap().status, AuthenticationStatus::IncorrectPassword);
+
+        let alice = chain.authenticate("alice", "alice password").unwrap();
+        assert_eq!(alice.status, AuthenticationStatus::Success);
+        assert_eq!(alice.attributes.unwrap().user_id, Some("2".to_owned()));
+
+        assert_eq!(chain.authenticate("bob", "password").unwrap().status, AuthenticationStatus::PrincipalNotFound);
+    }
+
+    #[test]
+    fn test_htpasswd_users_after_repo_users() {
+        let user_repo = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        user_repo.add_user(User::new("2".to_owned(), "alice".to_owned(), Some("alice password".to_owned()))).unwrap();
+        // the local user made for admin at their first htpasswd login, which has no password of its own
+        let mut admin = User::new("3".to_owned(), "admin".to_owned(), None);
+        admin.authenticator = Some("htpasswd".to_owned());
+        user_repo.add_user(admin).unwrap();
+
+        let mut chain = AuthenticatorChain::new();
+        chain.push("users", Arc::new(Box::new(UserRepoAuthenticator::new(user_repo)) as Box<Authenticator>));
+        chain.push("htpasswd", Arc::new(Box::new(HtpasswdAuthenticator::from_contents(HTPASSWD)) as Box<Authenticator>));
+
+        let admin = chain.authenticate("admin", "U*U").unwrap();
+        assert_eq!(admin.status, AuthenticationStatus::Success);
+        let attributes = admin.attributes.unwrap();
+        assert_eq!(attributes.user_id, None);
+        assert_eq!(attributes.authenticator, Some("htpasswd".to_owned()));
+        assert_eq!(chain.authenticate("admin", "wrong").unwrap().status, AuthenticationStatus::IncorrectPassword);
+
+        assert_eq!(chain.authenticate("alice", "alice password").unwrap().status, AuthenticationStatus::Success);
+        assert_eq!(chain.authenticate("alice", "U*U").unwrap().status, AuthenticationStatus::IncorrectPassword);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::Read;
+use std::time::Duration;
+
+use hyper::Client;
+use hyper::net::HttpsConnector;
+use hyper::header::{Accept, ContentType, Authorization, Bearer};
+use hyper_native_tls::NativeTlsClient;
+use url::form_urlencoded;
+
+use result::{Result, OpenIdConnectError};
+
+/// Outgoing HTTP requests to other parties, e.g. fetching documents published by relying parties.
+/// Kept behind a trait so tests can supply canned responses.
+
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: Option<String>, body: String) -> HttpResponse {
+        HttpResponse {
+            status: status,
+            content_type: content_type,
+            body: body,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+pub trait HttpClient where Self: Send + Sync {
+    fn get(&self, url: &str) -> Result<HttpResponse>;
+
+    fn get_with_bearer(&self, url: &str, access_token: &str) -> Result<HttpResponse>;
+
+    fn post_form(&self, url: &str, params: &[(String, String)]) -> Result<HttpResponse>;
+}
+
+#[derive(Clone, Debug)]
+pub struct HyperHttpClient {
+    pub timeout: Duration,
+}
+
+impl HyperHttpClient {
+    pub fn new() -> HyperHttpClient {
+        HyperHttpClient {
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn client(&self) -> Result<Client> {
+        let ssl = try!(NativeTlsClient::new().map_err(|e| OpenIdConnectError::HttpClientError(format!("{}", e))));
+        let connector = HttpsConnector::new(ssl);
+
+        let mut client = Client::with_connector(connector);
+        client.set_read_timeout(Some(self.timeout));
+        client.set_write_timeout(Some(self.timeout));
+
+        Ok(client)
+    }
+
+    fn read_response(mut res: ::hyper::client::Response) -> Result<HttpResponse> {
+        let mut body = String::new();
+        try!(res.read_to_string(&mut body));
+
+        let content_type = res.headers.get::<ContentType>().map(|ct| format!("{}", ct));
+
+        Ok(HttpResponse::new(res.status.to_u16(), content_type, body))
+    }
+}
+
+impl HttpClient for HyperHttpClient {
+    fn get(&self, url: &str) -> Result<HttpResponse> {
+        debug!("http client: GET {}", url);
+
+        let client = try!(self.client());
+
+        let res = try!(client.get(url)
+            .header(Accept::json())
+            .send()
+            .map_err(|e| OpenIdConnectError::HttpClientError(format!("{}", e))));
+
+        Self::read_response(res)
+    }
+
+    fn get_with_bearer(&self, url: &str, access_token: &str) -> Result<HttpResponse> {
+        debug!("http client: GET {} with bearer token", url);
+
+        let client = try!(self.client());
+
+        let res = try!(client.get(url)
+            .header(Accept::json())
+            .header(Authorization(Bearer { token: access_token.to_owned() }))
+            .send()
+            .map_err(|e| OpenIdConnectError::HttpClientError(format!("{}", e))));
+
+        Self::read_response(res)
+    }
+
+    fn post_form(&self, url: &str, params: &[(String, String)]) -> Result<HttpResponse> {
+        debug!("http client: POST {}", url);
+
+        let client = try!(self.client());
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter())
+            .finish();
+
+        let res = try!(client.post(url)
+            .header(ContentType::form_url_encoded())
+            .body(&body[..])
+            .send()
+            .map_err(|e| OpenIdConnectError::HttpClientError(format!("{}", e))));
+
+        Self::read_response(res)
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use iron::prelude::*;
+use iron::typemap;
+use iron::headers::AcceptLanguage;
+use persistent;
+use serde_json;
+
+use result::{Result, OpenIdConnectError};
+
+/// Translation catalogs for the html pages, one flat json object of message keys per locale,
+/// loaded from e.g. locales/en.json and locales/fr.json.
+/// Missing messages fall back to the default locale.
+
+#[derive(Clone, Debug)]
+pub struct Translations {
+    pub default_locale: String,
+    pub catalogs: HashMap<String, HashMap<String, String>>,
+}
+
+impl Translations {
+    pub fn new<S: Into<String>>(default_locale: S) -> Translations {
+        Translations {
+            default_locale: default_locale.into(),
+            catalogs: HashMap::new(),
+        }
+    }
+
+    pub fn add_catalog<S: Into<String>>(&mut self, locale: S, catalog: HashMap<String, String>) {
+        self.catalogs.insert(locale.into().to_lowercase(), catalog);
+    }
+
+    pub fn load_dir<P: AsRef<Path>, S: Into<String>>(path: P, default_locale: S) -> Result<Translations> {
+        let mut translations = Translations::new(default_locale);
+
+        for entry in try!(fs::read_dir(path)) {
+            let path = try!(entry).path();
+
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                let locale = try!(path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .ok_or(OpenIdConnectError::ConfigError(format!("bad catalog file name {:?}", path))));
+
+                let f = try!(File::open(&path));
+                let catalog: HashMap<String, String> = try!(serde_json::from_reader(f));
+
+                debug!("loaded {} messages for locale {}", catalog.len(), locale);
+                translations.add_catalog(locale, catalog);
+            }
+        }
+
+        if !translations.catalogs.contains_key(&translations.default_locale) {
+            return Err(OpenIdConnectError::ConfigError(format!("no catalog for default locale {}", translations.default_locale)));
+        }
+
+        Ok(translations)
+    }
+
+    pub fn supported_locales(&self) -> Vec<String> {
+        let mut locales: Vec<String> = self.catalogs.keys().map(|k| k.to_owned()).collect();
+        locales.sort();
+        locales
+    }
+
+    /// The first supported locale out of the preferred ones, in order,
+    /// matching either the whole tag or just its language
+    pub fn negotiate(&self, preferred: &[String]) -> String {
+        for tag in preferred {
+            let tag = tag.to_lowercase();
+
+            if self.catalogs.contains_key(&tag) {
+                return tag;
+            }
+
+            let language = primary_language(&tag);
+            if self.catalogs.contains_key(language) {
+                return language.to_owned();
+            }
+        }
+
+        self.default_locale.clone()
+    }
+
+    /// All the messages for a locale, with gaps filled from the default locale
+    pub fn catalog(&self, locale: &str) -> HashMap<String, String> {
+        let mut messages = self.catalogs.get(&self.default_locale).map(|c| c.clone()).unwrap_or(HashMap::new());
+
+        if let Some(catalog) = self.catalogs.get(locale) {
+            for (k, v) in catalog {
+                messages.insert(k.to_owned(), v.to_owned());
+            }
+        }
+
+        messages
+    }
+
+    pub fn translate(&self, locale: &str, key: &str) -> Option<String> {
+        self.catalogs.get(locale).and_then(|c| c.get(key))
+            .or_else(|| self.catalogs.get(&self.default_locale).and_then(|c| c.get(key)))
+            .map(|s| s.to_owned())
+    }
+
+    pub fn get(req: &mut Request) -> Result<Arc<Translations>> {
+        req.get::<persistent::Read<Translations>>().map_err(OpenIdConnectError::from)
+    }
+}
+
+impl typemap::Key for Translations {
+    type Value = Translations;
+}
+
+fn primary_language(tag: &str) -> &str {
+    tag.split('-').next().unwrap_or(tag)
+}
+
+/// ui_locales is a space separated list of BCP47 tags in order of preference
+pub fn parse_ui_locales(ui_locales: &str) -> Vec<String> {
+    ui_locales.split(' ').filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect()
+}
+
+/// Accept-Language tags, most preferred first
+pub fn accept_languages(req: &Request) -> Vec<String> {
+    let mut items = req.headers.get::<AcceptLanguage>()
+        .map(|h| h.0.clone())
+        .unwrap_or(vec![]);
+
+    // stable sort keeps the header order for equal weights
+    items.sort_by(|a, b| b.quality.cmp(&a.quality));
+
+    items.into_iter()
+        .filter(|i| i.quality.0 > 0)
+        .map(|i| format!("{}", i.item))
+        .collect()
+}
+
+/// ui_locales from the authorize request takes precedence over the browser's languages
+pub fn requested_locales(req: &Request, ui_locales: Option<&str>) -> Vec<String> {
+    let mut locales = ui_locales.map(parse_ui_locales).unwrap_or(vec![]);
+
+    locales.extend(accept_languages(req));
+
+    locales
+}
+
+/// Picks the best match for the preferred locales out of a localized client metadata field,
+/// such as client_name#ja-Jpan-JP, falling back to the unlocalized value
+pub fn localized<'a>(default: Option<&'a String>, i18n: &'a HashMap<String, String>, preferred: &[String]) -> Option<&'a String> {
+    for tag in preferred {
+        let tag = tag.to_lowercase();
+
+        if let Some((_, v)) = i18n.iter().find(|&(k, _)| k.to_lowercase() == tag) {
+            return Some(v);
+        }
+
+        let language = primary_language(&tag);
+        if let Some((_, v)) = i18n.iter().find(|&(k, _)| primary_language(&k.to_lowercase()) == language) {
+            return Some(v);
+        }
+    }
+
+    default
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn translations() -> Translations {
+        let mut t = Translations::new("en");
+
+        let mut en = HashMap::new();
+        en.insert("login_title".to_owned(), "Login".to_owned());
+        en.insert("cancel_button".to_owned(), "Cancel".to_owned());
+        t.add_catalog("en", en);
+
+        let mut fr = HashMap::new();
+        fr.insert("login_title".to_owned(), "Connexion".to_owned());
+        t.add_catalog("fr", fr);
+
+        t
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let t = translations();
+
+        assert_eq!(t.negotiate(&parse_ui_locales("fr-CA en")), "fr");
+        assert_eq!(t.negotiate(&parse_ui_locales("de")), "en");
+        assert_eq!(t.negotiate(&[]), "en");
+    }
+
+    #[test]
+    fn test_catalog_fallback() {
+        let t = translations();
+        let fr = t.catalog("fr");
+
+        assert_eq!(fr.get("login_title").unwrap(), "Connexion");
+        assert_eq!(fr.get("cancel_button").unwrap(), "Cancel");
+    }
+
+    #[test]
+    fn test_localized_client_metadata() {
+        let name = "Example".to_owned();
+        let mut i18n = HashMap::new();
+        i18n.insert("ja-Jpan-JP".to_owned(), "サンプル".to_owned());
+        i18n.insert("fr".to_owned(), "Exemple".to_owned());
+
+        assert_eq!(localized(Some(&name), &i18n, &parse_ui_locales("fr-CA")).unwrap(), "Exemple");
+        assert_eq!(localized(Some(&name), &i18n, &parse_ui_locales("ja-jpan-jp")).unwrap(), "サンプル");
+        assert_eq!(localized(Some(&name), &i18n, &parse_ui_locales("de")).unwrap(), "Example");
+    }
+}
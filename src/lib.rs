@@ -25,12 +25,25 @@ extern crate chrono;
 extern crate cast;
 extern crate openssl;
 extern crate crypto;
+extern crate hyper;
+extern crate hyper_native_tls;
 
 pub mod result;
 pub mod urls;
 pub mod routes;
 pub mod authentication;
+pub mod authorization;
+pub mod totp;
+pub mod clock;
+pub mod throttle;
+pub mod csrf;
+pub mod mailer;
+pub mod verification;
+pub mod federation;
+pub mod htpasswd;
 pub mod users;
+pub mod groups;
+pub mod scim;
 pub mod config;
 pub mod handlers;
 pub mod login_manager;
@@ -47,6 +60,9 @@ pub mod site_config;
 pub mod x_headers;
 pub mod grant_type;
 pub mod truthy;
+pub mod http_client;
+pub mod i18n;
+pub mod display;
 
 
 #[cfg(test)]
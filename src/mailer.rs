@@ -0,0 +1,313 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::*;
+
+use result::{Result, OpenIdConnectError};
+use site_config::SiteConfig;
+use authentication;
+use users::is_valid_email;
+
+/// Outgoing email, e.g. verification and password reset links
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn new<A: Into<String>, B: Into<String>, C: Into<String>, D: Into<String>>(from: A, to: B, subject: C, body: D) -> Result<Email> {
+        let email = Email {
+            from: from.into(),
+            to: to.into(),
+            subject: subject.into(),
+            body: body.into(),
+        };
+        
+        try!(email.check());
+        
+        Ok(email)
+    }
+    
+    /// The addresses and subject go into headers and SMTP commands, so a line break or
+    /// other control character in them could add headers or recipients
+    pub fn check(&self) -> Result<()> {
+        if !is_valid_email(&self.from) {
+            return Err(OpenIdConnectError::MailError(format!("invalid from address {:?}", self.from)));
+        }
+        
+        if !is_valid_email(&self.to) {
+            return Err(OpenIdConnectError::MailError(format!("invalid to address {:?}", self.to)));
+        }
+        
+        if self.subject.chars().any(|c| c.is_control()) {
+            return Err(OpenIdConnectError::MailError("control characters in subject".to_owned()));
+        }
+        
+        if self.body.chars().any(|c| c.is_control() && c != '\n' && c != '\r' && c != '\t') {
+            return Err(OpenIdConnectError::MailError("control characters in body".to_owned()));
+        }
+        
+        Ok(())
+    }
+    
+    /// Plain text RFC 5322 message, with CRLF line endings
+    pub fn to_message(&self) -> String {
+        let mut message = String::new();
+        
+        message.push_str(&format!("From: {}\r\n", self.from));
+        message.push_str(&format!("To: {}\r\n", self.to));
+        message.push_str(&format!("Subject: {}\r\n", self.subject));
+        message.push_str(&format!("Date: {}\r\n", UTC::now().to_rfc2822()));
+        message.push_str("MIME-Version: 1.0\r\n");
+        message.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+        message.push_str("\r\n");
+        
+        for line in self.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        
+        message
+    }
+}
+
+pub trait Mailer where Self: Send + Sync {
+    fn send(&self, email: &Email) -> Result<()>;
+}
+
+/// Writes messages to the log instead of sending them, for development
+#[derive(Copy, Clone, Debug)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> Result<()> {
+        info!("email to {}: {}\n{}", email.to, email.subject, email.body);
+        
+        Ok(())
+    }
+}
+
+/// Writes each message to its own .eml file in a directory
+#[derive(Clone, Debug)]
+pub struct FileMailer {
+    pub dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileMailer {
+        FileMailer {
+            dir: dir.into(),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<()> {
+        try!(fs::create_dir_all(&self.dir));
+        
+        let name = format!("{}-{}.eml", UTC::now().format("%Y%m%d%H%M%S"), authentication::new_nonce());
+        let mut f = try!(File::create(self.dir.join(name)));
+        
+        try!(f.write_all(email.to_message().as_bytes()));
+        
+        Ok(())
+    }
+}
+
+/// Hands messages to an SMTP relay, such as the local MTA.
+/// There's no TLS or authentication, so the relay should be on the same host or network.
+#[derive(Clone, Debug)]
+pub struct SmtpMailer {
+    pub host: String,
+    pub port: u16,
+    pub helo_name: String,
+    pub timeout: Duration,
+}
+
+impl SmtpMailer {
+    pub fn new<S: Into<String>>(host: S, port: u16) -> SmtpMailer {
+        SmtpMailer {
+            host: host.into(),
+            port: port,
+            helo_name: "localhost".to_owned(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+    
+    fn read_reply<R: BufRead>(reader: &mut R) -> Result<(u16, String)> {
+        let mut text = String::new();
+        
+        loop {
+            let mut line = String::new();
+            
+            if try!(reader.read_line(&mut line)) == 0 {
+                return Err(OpenIdConnectError::MailError("connection closed".to_owned()));
+            }
+            
+            text.push_str(&line);
+            
+            // the last line of a reply has a space after the code, the others a dash
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                let code = try!(line.chars().take(3).collect::<String>().parse::<u16>()
+                    .map_err(|_| OpenIdConnectError::MailError(format!("bad reply: {}", line.trim()))));
+                
+                return Ok((code, text));
+            }
+        }
+    }
+    
+    fn command<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, command: &str, expected: &[u16]) -> Result<()> {
+        try!(writer.write_all(command.as_bytes()));
+        try!(writer.write_all(b"\r\n"));
+        try!(writer.flush());
+        
+        Self::expect(reader, expected)
+    }
+    
+    fn expect<R: BufRead>(reader: &mut R, expected: &[u16]) -> Result<()> {
+        let (code, text) = try!(Self::read_reply(reader));
+        
+        if expected.contains(&code) {
+            Ok(())
+        } else {
+            Err(OpenIdConnectError::MailError(text.trim().to_owned()))
+        }
+    }
+    
+    /// Lines starting with a dot get another one, so they can't end the DATA
+    fn dot_stuff(message: &str) -> String {
+        message.split("\r\n")
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_owned() })
+            .collect::<Vec<String>>()
+            .join("\r\n")
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<()> {
+        try!(email.check());
+        
+        let stream = try!(TcpStream::connect((&self.host[..], self.port)));
+        try!(stream.set_read_timeout(Some(self.timeout)));
+        try!(stream.set_write_timeout(Some(self.timeout)));
+        
+        let mut writer = try!(stream.try_clone());
+        let mut reader = BufReader::new(stream);
+        
+        try!(Self::expect(&mut reader, &[220]));
+        try!(Self::command(&mut reader, &mut writer, &format!("EHLO {}", self.helo_name), &[250]));
+        try!(Self::command(&mut reader, &mut writer, &format!("MAIL FROM:<{}>", email.from), &[250]));
+        try!(Self::command(&mut reader, &mut writer, &format!("RCPT TO:<{}>", email.to), &[250, 251]));
+        try!(Self::command(&mut reader, &mut writer, "DATA", &[354]));
+        try!(Self::command(&mut reader, &mut writer, &format!("{}.", Self::dot_stuff(&email.to_message())), &[250]));
+        try!(Self::command(&mut reader, &mut writer, "QUIT", &[221]));
+        
+        Ok(())
+    }
+}
+
+/// SMTP if a relay is configured, else files if there's a mail directory, else the log
+pub fn mailer_for_site(site_config: &SiteConfig) -> Arc<Box<Mailer>> {
+    if let Some(ref smtp_host) = site_config.smtp_host {
+        Arc::new(Box::new(SmtpMailer::new(smtp_host.to_owned(), site_config.smtp_port.unwrap_or(25))) as Box<Mailer>)
+    } else if let Some(ref mail_dir) = site_config.mail_dir {
+        Arc::new(Box::new(FileMailer::new(mail_dir.to_owned())) as Box<Mailer>)
+    } else {
+        Arc::new(Box::new(LogMailer) as Box<Mailer>)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_smtp_mailer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // stands in for the relay, replying to each command and keeping what it was sent
+        let relay = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = vec![];
+            let mut in_data = false;
+
+            writer.write_all(b"220 relay ready\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_right().to_owned();
+                received.push(line.clone());
+
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        continue;
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 8BITMIME\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+
+                writer.write_all(reply).unwrap();
+            }
+
+            received
+        });
+
+        let email = Email::new("op@example.com", "user@example.com", "Hello", "first line\n.hidden dot").unwrap();
+        SmtpMailer::new("127.0.0.1", port).send(&email).unwrap();
+
+        let received = relay.join().unwrap();
+        assert_eq!(received[1], "MAIL FROM:<op@example.com>");
+        assert_eq!(received[2], "RCPT TO:<user@example.com>");
+        assert!(received.contains(&"Subject: Hello".to_owned()));
+        assert!(received.contains(&"..hidden dot".to_owned()));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+
+    #[test]
+    fn test_header_injection_rejected() {
+        assert!(Email::new("op@example.com", "user@example.com\r\nBcc: everyone@example.com", "Hello", "body").is_err());
+        assert!(Email::new("op@example.com", "user@example.com>\r\nRCPT TO:<other@example.com", "Hello", "body").is_err());
+        assert!(Email::new("op@example.com", "user@example.com", "Hello\r\nBcc: everyone@example.com", "body").is_err());
+
+        let email = Email {
+            from: "op@example.com".to_owned(),
+            to: "user@example.com\nBcc: everyone@example.com".to_owned(),
+            subject: "Hello".to_owned(),
+            body: "body".to_owned(),
+        };
+        // refused before connecting, so nothing needs to be listening
+        match SmtpMailer::new("127.0.0.1", 1).send(&email) {
+            Err(OpenIdConnectError::MailError(_)) => {},
+            other => panic!("expected a mail error, got {:?}", other),
+        }
+    }
+}
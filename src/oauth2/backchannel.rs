@@ -0,0 +1,466 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono;
+use chrono::*;
+use serde_json::{Map, Value};
+use jsonwebtoken::jwt::*;
+use jsonwebtoken::json::*;
+use jsonwebtoken::header::*;
+use jsonwebtoken::crypto::mac_signer::MacSigner;
+
+use result::{Result, OpenIdConnectError};
+use site_config::*;
+use authentication;
+use http_client::HttpClient;
+use serialisation::*;
+use oauth2::models::client::*;
+use oauth2::repos::ClientApplicationRepo;
+use oauth2::subject::subject_for_client;
+use sessions::{SessionRecord, SessionEndListener};
+
+/// Back-Channel Logout
+/// See http://openid.net/specs/openid-connect-backchannel-1_0.html
+
+pub const BACKCHANNEL_LOGOUT_EVENT: &'static str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Logout tokens are sent straight away, so they don't need to live long
+pub const LOGOUT_TOKEN_LIFETIME_SECONDS: i64 = 120;
+
+/// How many deliveries the log keeps before dropping the oldest
+pub const MAX_LOGOUT_DELIVERIES: usize = 1000;
+
+/// Posts a logout token to a client's backchannel_logout_uri
+pub trait LogoutTokenSender where Self: Send + Sync {
+    fn send_logout_token(&self, backchannel_logout_uri: &str, logout_token: &str) -> Result<()>;
+}
+
+pub struct HttpLogoutTokenSender {
+    http_client: Arc<Box<HttpClient>>,
+}
+
+impl HttpLogoutTokenSender {
+    pub fn new(http_client: Arc<Box<HttpClient>>) -> HttpLogoutTokenSender {
+        HttpLogoutTokenSender {
+            http_client: http_client,
+        }
+    }
+}
+
+impl LogoutTokenSender for HttpLogoutTokenSender {
+    fn send_logout_token(&self, backchannel_logout_uri: &str, logout_token: &str) -> Result<()> {
+        let params = vec![("logout_token".to_owned(), logout_token.to_owned())];
+
+        let res = try!(self.http_client.post_form(backchannel_logout_uri, &params));
+
+        if res.is_success() {
+            Ok(())
+        } else {
+            Err(OpenIdConnectError::HttpClientError(format!("backchannel logout returned status {}", res.status)))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    Delivered,
+    Failed(String),
+}
+
+/// One logout token sent to one client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogoutDelivery {
+    pub client_id: String,
+    pub backchannel_logout_uri: String,
+    pub jti: String,
+    pub sid: Option<String>,
+    pub attempts: u32,
+    pub status: DeliveryStatus,
+    pub finished_at: UTCDateTime,
+}
+
+impl LogoutDelivery {
+    pub fn is_delivered(&self) -> bool {
+        self.status == DeliveryStatus::Delivered
+    }
+}
+
+/// Builds the logout token for a client.
+/// It carries the events claim and no nonce, so it can't be mistaken for an id token.
+pub fn logout_token_claims(site_config: &SiteConfig, client: &ClientApplication, user_id: &str, session_id: Option<&str>, jti: &str) -> Result<JwtClaims> {
+    let now = UTC::now();
+    let iat = UTCDateTime::new(now);
+    let exp = UTCDateTime::new(now + chrono::Duration::seconds(LOGOUT_TOKEN_LIFETIME_SECONDS));
+
+    let mut events = Map::new();
+    events.insert(BACKCHANNEL_LOGOUT_EVENT.to_owned(), Value::Object(Map::new()));
+
+    let mut claims = JwtClaims::new();
+    claims.set_value("iss", &site_config.get_issuer());
+    claims.set_value("aud", &client.client_id);
+    claims.set_value("iat", &iat);
+    claims.set_value("exp", &exp);
+    claims.set_value("jti", &jti);
+    claims.set_value("sub", &try!(subject_for_client(site_config, client, user_id)));
+    claims.set_value("events", &Value::Object(events));
+
+    if let Some(sid) = session_id {
+        claims.set_value("sid", &sid);
+    }
+
+    Ok(claims)
+}
+
+pub fn create_logout_token(mac_signer: &MacSigner, claims: JwtClaims) -> Result<String> {
+    let mut header = Header::new(Algorithm::HS256);
+    header.typ = Some("logout+jwt".to_owned());
+
+    let jwt = Jwt::new(header, claims);
+
+    jwt.encode(mac_signer).map_err(OpenIdConnectError::from)
+}
+
+/// A signed logout token waiting to be sent
+struct PendingLogout {
+    client_id: String,
+    backchannel_logout_uri: String,
+    jti: String,
+    sid: Option<String>,
+    logout_token: String,
+}
+
+/// Sends logout tokens to the clients of an ended session, retrying failures,
+/// and keeps a log of the most recent deliveries
+#[derive(Clone)]
+pub struct BackchannelLogout {
+    sender: Arc<Box<LogoutTokenSender>>,
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+    pub max_deliveries: usize,
+    deliveries: Arc<Mutex<VecDeque<LogoutDelivery>>>,
+}
+
+impl BackchannelLogout {
+    pub fn new(sender: Arc<Box<LogoutTokenSender>>) -> BackchannelLogout {
+        BackchannelLogout {
+            sender: sender,
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(500),
+            max_deliveries: MAX_LOGOUT_DELIVERIES,
+            deliveries: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Clients only get a logout token if they registered a backchannel_logout_uri,
+    /// and if they need the sid there has to be one
+    pub fn wants_logout_token(client: &ClientApplication, session_id: Option<&str>) -> bool {
+        client.backchannel_logout_uri.is_some() && (session_id.is_some() || !client.backchannel_logout_session_required)
+    }
+
+    fn pending_logout(mac_signer: &MacSigner, site_config: &SiteConfig, client: &ClientApplication, user_id: &str, session_id: Option<&str>) -> Result<Option<PendingLogout>> {
+        if !Self::wants_logout_token(client, session_id) {
+            return Ok(None);
+        }
+
+        let backchannel_logout_uri = try!(client.backchannel_logout_uri.as_ref().ok_or(OpenIdConnectError::InvalidRedirectUri));
+        let jti = authentication::new_token();
+        let claims = try!(logout_token_claims(site_config, client, user_id, session_id, &jti));
+        let logout_token = try!(create_logout_token(mac_signer, claims));
+
+        Ok(Some(PendingLogout {
+            client_id: client.client_id.clone(),
+            backchannel_logout_uri: backchannel_logout_uri.to_owned(),
+            jti: jti,
+            sid: session_id.map(|s| s.to_owned()),
+            logout_token: logout_token,
+        }))
+    }
+
+    fn deliver(&self, pending: PendingLogout) -> LogoutDelivery {
+        let mut attempts = 0;
+        let mut status = DeliveryStatus::Failed("not attempted".to_owned());
+
+        while attempts < self.max_attempts {
+            if attempts > 0 {
+                thread::sleep(self.retry_delay);
+            }
+
+            attempts += 1;
+
+            match self.sender.send_logout_token(&pending.backchannel_logout_uri, &pending.logout_token) {
+                Ok(()) => {
+                    status = DeliveryStatus::Delivered;
+                    break;
+                },
+                Err(e) => {
+                    info!("backchannel logout to {} failed on attempt {}: {}", pending.client_id, attempts, e);
+                    status = DeliveryStatus::Failed(format!("{}", e));
+                }
+            }
+        }
+
+        let delivery = LogoutDelivery {
+            client_id: pending.client_id,
+            backchannel_logout_uri: pending.backchannel_logout_uri,
+            jti: pending.jti,
+            sid: pending.sid,
+            attempts: attempts,
+            status: status,
+            finished_at: UTCDateTime::new(UTC::now()),
+        };
+
+        let mut deliveries = self.deliveries.lock().unwrap();
+        while deliveries.len() >= self.max_deliveries && !deliveries.is_empty() {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(delivery.clone());
+
+        delivery
+    }
+
+    /// Sends the client its logout token and waits until it's delivered or given up on
+    pub fn logout_client(&self, mac_signer: &MacSigner, site_config: &SiteConfig, client: &ClientApplication, user_id: &str, session_id: Option<&str>) -> Result<Option<LogoutDelivery>> {
+        let pending = try!(Self::pending_logout(mac_signer, site_config, client, user_id, session_id));
+
+        Ok(pending.map(|pending| self.deliver(pending)))
+    }
+
+    /// Like logout_client, but the token is sent from a background thread,
+    /// so a slow or unreachable client doesn't hold up the user's logout.
+    /// The outcome ends up in the delivery log. Returns whether a token is being sent.
+    pub fn logout_client_in_background(&self, mac_signer: &MacSigner, site_config: &SiteConfig, client: &ClientApplication, user_id: &str, session_id: Option<&str>) -> Result<bool> {
+        let pending = match try!(Self::pending_logout(mac_signer, site_config, client, user_id, session_id)) {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+
+        let backchannel = self.clone();
+
+        thread::spawn(move || {
+            let delivery = backchannel.deliver(pending);
+            debug!("backchannel logout: {:?}", delivery);
+        });
+
+        Ok(true)
+    }
+
+    pub fn deliveries(&self) -> Vec<LogoutDelivery> {
+        self.deliveries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Sends logout tokens to the clients of every session that ends, see SessionController::session_ended
+pub struct BackchannelSessionEndListener {
+    pub backchannel: BackchannelLogout,
+    pub mac_signer: MacSigner,
+    pub site_config: SiteConfig,
+    pub application_repo: Arc<Box<ClientApplicationRepo>>,
+}
+
+impl BackchannelSessionEndListener {
+    pub fn new(backchannel: BackchannelLogout, mac_signer: MacSigner, site_config: SiteConfig, application_repo: Arc<Box<ClientApplicationRepo>>) -> BackchannelSessionEndListener {
+        BackchannelSessionEndListener {
+            backchannel: backchannel,
+            mac_signer: mac_signer,
+            site_config: site_config,
+            application_repo: application_repo,
+        }
+    }
+
+    fn logout_client(&self, record: &SessionRecord, client_id: &str) -> Result<bool> {
+        match try!(self.application_repo.find_client_application(client_id)) {
+            Some(client) => self.backchannel.logout_client_in_background(&self.mac_signer, &self.site_config, &client, &record.user_id, Some(&record.sid)),
+            None => Ok(false),
+        }
+    }
+}
+
+/// A client that can't be told isn't a reason to keep the session,
+/// so failures are only logged
+impl SessionEndListener for BackchannelSessionEndListener {
+    fn session_ended(&self, record: &SessionRecord) {
+        for client_id in &record.clients {
+            match self.logout_client(record, client_id) {
+                Ok(true) => debug!("backchannel logout to {} started", client_id),
+                Ok(false) => {},
+                Err(e) => info!("backchannel logout to {} not sent: {}", client_id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Sender, Receiver};
+    use std::time::Duration;
+
+    use jsonwebtoken::jwt::*;
+    use jsonwebtoken::json::*;
+    use jsonwebtoken::crypto::mac_signer::MacSigner;
+    use serde_json::{Map, Value};
+
+    use super::*;
+    use result::{Result, OpenIdConnectError};
+    use site_config::*;
+    use oauth2::models::client::*;
+
+    /// Stands in for the RP's logout endpoint, failing the first few requests.
+    /// With a gate it waits for the test to open it before answering,
+    /// and it reports each token it accepts on the done channel.
+    struct StandInReceiver {
+        failures: Mutex<u32>,
+        attempts: Arc<Mutex<u32>>,
+        received: Arc<Mutex<Vec<(String, String)>>>,
+        gate: Mutex<Option<Receiver<()>>>,
+        done: Mutex<Option<Sender<()>>>,
+    }
+
+    impl StandInReceiver {
+        fn new(failures: u32, received: Arc<Mutex<Vec<(String, String)>>>) -> StandInReceiver {
+            StandInReceiver {
+                failures: Mutex::new(failures),
+                attempts: Arc::new(Mutex::new(0)),
+                received: received,
+                gate: Mutex::new(None),
+                done: Mutex::new(None),
+            }
+        }
+    }
+
+    impl LogoutTokenSender for StandInReceiver {
+        fn send_logout_token(&self, backchannel_logout_uri: &str, logout_token: &str) -> Result<()> {
+            if let Some(gate) = self.gate.lock().unwrap().take() {
+                try!(gate.recv().map_err(|_| OpenIdConnectError::HttpClientError("gate dropped".to_owned())));
+            }
+
+            *self.attempts.lock().unwrap() += 1;
+
+            let mut failures = self.failures.lock().unwrap();
+
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(OpenIdConnectError::HttpClientError("status 503".to_owned()));
+            }
+
+            self.received.lock().unwrap().push((backchannel_logout_uri.to_owned(), logout_token.to_owned()));
+
+            if let Some(ref done) = *self.done.lock().unwrap() {
+                let _ = done.send(());
+            }
+
+            Ok(())
+        }
+    }
+
+    fn backchannel(failures: u32) -> (BackchannelLogout, Arc<Mutex<Vec<(String, String)>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let receiver = StandInReceiver::new(failures, received.clone());
+
+        let mut backchannel = BackchannelLogout::new(Arc::new(Box::new(receiver) as Box<LogoutTokenSender>));
+        backchannel.retry_delay = Duration::from_millis(0);
+
+        (backchannel, received)
+    }
+
+    fn client() -> ClientApplication {
+        let mut client = ClientApplication::new("rp".to_owned()).unwrap();
+        client.redirect_uris = vec!["https://rp.example.com/cb".to_owned()];
+        client.backchannel_logout_uri = Some("https://rp.example.com/logout".to_owned());
+        client
+    }
+
+    #[test]
+    fn test_logout_token_delivered() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let site_config = SiteConfig::new();
+        let (backchannel, received) = backchannel(1);
+
+        let delivery = backchannel.logout_client(&mac_signer, &site_config, &client(), "user", Some("sid")).unwrap().unwrap();
+
+        assert!(delivery.is_delivered());
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(backchannel.deliveries().len(), 1);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "https://rp.example.com/logout");
+
+        let jwt = Jwt::decode(&received[0].1, &mac_signer).unwrap();
+        assert_eq!(jwt.claims.get_value::<String>("sub").unwrap().unwrap(), "user");
+        assert_eq!(jwt.claims.get_value::<String>("sid").unwrap().unwrap(), "sid");
+        assert_eq!(jwt.claims.get_value::<String>("jti").unwrap().unwrap(), delivery.jti);
+        assert!(jwt.claims.get_value::<String>("nonce").unwrap().is_none());
+
+        let iat = jwt.claims.get_value::<i64>("iat").unwrap().unwrap();
+        let exp = jwt.claims.get_value::<i64>("exp").unwrap().unwrap();
+        assert_eq!(exp - iat, LOGOUT_TOKEN_LIFETIME_SECONDS);
+
+        let events = jwt.claims.get_value::<Map<String, Value>>("events").unwrap().unwrap();
+        assert!(events.contains_key(BACKCHANNEL_LOGOUT_EVENT));
+    }
+
+    #[test]
+    fn test_background_logout_doesnt_wait() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let site_config = SiteConfig::new();
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let receiver = StandInReceiver::new(2, received.clone());
+        let attempts = receiver.attempts.clone();
+        let (open_gate, gate) = channel();
+        let (done, delivered) = channel();
+        *receiver.gate.lock().unwrap() = Some(gate);
+        *receiver.done.lock().unwrap() = Some(done);
+
+        let mut backchannel = BackchannelLogout::new(Arc::new(Box::new(receiver) as Box<LogoutTokenSender>));
+        backchannel.retry_delay = Duration::from_millis(0);
+
+        // the receiver is held at the gate, so this only returns if it didn't wait for the delivery
+        assert!(backchannel.logout_client_in_background(&mac_signer, &site_config, &client(), "user", Some("sid")).unwrap());
+        assert!(received.lock().unwrap().is_empty());
+
+        open_gate.send(()).unwrap();
+        delivered.recv().unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(*attempts.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_delivery_log_is_capped() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let site_config = SiteConfig::new();
+        let (mut backchannel, _received) = backchannel(0);
+        backchannel.max_deliveries = 2;
+
+        let mut jtis = vec![];
+        for _ in 0..3 {
+            jtis.push(backchannel.logout_client(&mac_signer, &site_config, &client(), "user", Some("sid")).unwrap().unwrap().jti);
+        }
+
+        let deliveries = backchannel.deliveries();
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[0].jti, jtis[1]);
+        assert_eq!(deliveries[1].jti, jtis[2]);
+    }
+
+    #[test]
+    fn test_logout_token_gives_up() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let site_config = SiteConfig::new();
+        let (backchannel, received) = backchannel(5);
+
+        let delivery = backchannel.logout_client(&mac_signer, &site_config, &client(), "user", Some("sid")).unwrap().unwrap();
+
+        assert!(!delivery.is_delivered());
+        assert_eq!(delivery.attempts, 3);
+        assert!(received.lock().unwrap().is_empty());
+
+        let mut session_required = client();
+        session_required.backchannel_logout_session_required = true;
+        assert!(backchannel.logout_client(&mac_signer, &site_config, &session_required, "user", None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::status;
+use iron::method::Method;
+use iron::headers::{Authorization, Bearer, ContentType};
+use iron::mime::{Mime, TopLevel, SubLevel};
+use urlencoded::{UrlEncodedBody, UrlEncodedQuery, UrlDecodingError};
+use serde_json;
+
+use rbvt::params::*;
+use result::{Result, OpenIdConnectError};
+use site_config::*;
+use x_headers::WwwAuthenticate;
+
+/// Bearer token usage as described in RFC 6750
+/// https://tools.ietf.org/html/rfc6750
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BearerErrorView {
+    pub error: String,
+    pub error_description: Option<String>,
+}
+
+fn is_form_post(req: &Request) -> bool {
+    if req.method != Method::Post {
+        return false;
+    }
+
+    match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _))) => true,
+        _ => false,
+    }
+}
+
+fn access_token_param(params: &HashMap<String, Vec<String>>) -> Result<Option<String>> {
+    multimap_get_maybe_one(params, "access_token")
+        .map(|token| token.map(|t| t.to_owned()))
+        .map_err(|_e| OpenIdConnectError::InvalidRequest("access_token given more than once".to_owned()))
+}
+
+/// Picks the access token from wherever it was sent: the Authorization header, the form body or the query string.
+/// Clients must use only one method per request.
+pub fn select_bearer_token(header: Option<String>, body: Option<&HashMap<String, Vec<String>>>, query: Option<&HashMap<String, Vec<String>>>) -> Result<String> {
+    let mut tokens: Vec<String> = header.into_iter().collect();
+
+    if let Some(body) = body {
+        tokens.extend(try!(access_token_param(body)));
+    }
+
+    if let Some(query) = query {
+        tokens.extend(try!(access_token_param(query)));
+    }
+
+    match tokens.len() {
+        0 => Err(OpenIdConnectError::MissingAccessToken),
+        1 => Ok(tokens.remove(0)),
+        _ => Err(OpenIdConnectError::InvalidRequest("more than one method used to supply the access token".to_owned())),
+    }
+}
+
+fn decoding_result(result: ::std::result::Result<HashMap<String, Vec<String>>, UrlDecodingError>) -> Result<Option<HashMap<String, Vec<String>>>> {
+    match result {
+        Ok(params) => Ok(Some(params)),
+        Err(UrlDecodingError::EmptyQuery) => Ok(None),
+        Err(e) => Err(OpenIdConnectError::InvalidRequest(format!("{}", e))),
+    }
+}
+
+/// Finds the access token in the Authorization header, the form body or the query string
+pub fn find_bearer_token(req: &mut Request) -> Result<String> {
+    let header = req.headers.get::<Authorization<Bearer>>().map(|auth| auth.token.clone());
+
+    let body = if is_form_post(req) {
+        try!(decoding_result(req.get::<UrlEncodedBody>()))
+    } else {
+        None
+    };
+
+    let query = try!(decoding_result(req.get::<UrlEncodedQuery>()));
+
+    select_bearer_token(header, body.as_ref(), query.as_ref())
+}
+
+pub fn bearer_error_code(err: &OpenIdConnectError) -> Option<&'static str> {
+    match *err {
+        OpenIdConnectError::MissingAccessToken => None,
+        OpenIdConnectError::InvalidRequest(_) => Some("invalid_request"),
+        OpenIdConnectError::InsufficientScope(_) => Some("insufficient_scope"),
+        _ => Some("invalid_token"),
+    }
+}
+
+pub fn bearer_challenge(realm: &str, err: &OpenIdConnectError) -> String {
+    let mut challenge = format!("Bearer realm=\"{}\"", realm);
+
+    if let Some(code) = bearer_error_code(err) {
+        let description = format!("{}", err).replace("\"", "'");
+        challenge.push_str(&format!(", error=\"{}\", error_description=\"{}\"", code, description));
+
+        if let OpenIdConnectError::InsufficientScope(ref scope) = *err {
+            challenge.push_str(&format!(", scope=\"{}\"", scope));
+        }
+    }
+
+    challenge
+}
+
+pub fn bearer_error_status(err: &OpenIdConnectError) -> status::Status {
+    match bearer_error_code(err) {
+        Some("invalid_request") => status::BadRequest,
+        Some("insufficient_scope") => status::Forbidden,
+        _ => status::Unauthorized,
+    }
+}
+
+/// Renders an error as a response with a WWW-Authenticate challenge
+pub fn bearer_error_response(req: &mut Request, err: &OpenIdConnectError) -> Result<Response> {
+    let site_config = try!(SiteConfig::get(req));
+
+    let status_code = bearer_error_status(err);
+
+    let mut res = Response::with(status_code);
+    res.headers.set(WwwAuthenticate::new(bearer_challenge(&site_config.get_issuer(), err)));
+
+    if let Some(code) = bearer_error_code(err) {
+        let error_view = BearerErrorView {
+            error: code.to_owned(),
+            error_description: Some(format!("{}", err)),
+        };
+
+        let content_type = "application/json".parse::<Mime>().unwrap();
+        res.set_mut(try!(serde_json::to_string(&error_view))).set_mut(content_type);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use iron::status;
+
+    use super::*;
+    use result::OpenIdConnectError;
+
+    fn params(values: &[&str]) -> HashMap<String, Vec<String>> {
+        let mut params = HashMap::new();
+        params.insert("access_token".to_owned(), values.iter().map(|v| v.to_string()).collect());
+        params
+    }
+
+    #[test]
+    fn test_token_locations() {
+        assert_eq!(select_bearer_token(Some("header".to_owned()), None, None).unwrap(), "header");
+        assert_eq!(select_bearer_token(None, Some(&params(&["body"])), None).unwrap(), "body");
+        assert_eq!(select_bearer_token(None, None, Some(&params(&["query"]))).unwrap(), "query");
+
+        match select_bearer_token(None, Some(&HashMap::new()), None) {
+            Err(OpenIdConnectError::MissingAccessToken) => {},
+            other => panic!("expected a missing token, got {:?}", other),
+        }
+
+        match select_bearer_token(Some("header".to_owned()), None, Some(&params(&["query"]))) {
+            Err(OpenIdConnectError::InvalidRequest(_)) => {},
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+
+        match select_bearer_token(None, Some(&params(&["a", "b"])), None) {
+            Err(OpenIdConnectError::InvalidRequest(_)) => {},
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_revoked_and_expired_tokens_get_challenged() {
+        for err in &[OpenIdConnectError::InvalidToken("access token has been revoked".to_owned()),
+                     OpenIdConnectError::InvalidToken("access token has expired".to_owned())] {
+            assert_eq!(bearer_error_status(err), status::Unauthorized);
+            assert!(bearer_challenge("example", err).contains(r#"error="invalid_token""#));
+        }
+
+        assert_eq!(bearer_error_status(&OpenIdConnectError::MissingAccessToken), status::Unauthorized);
+        assert_eq!(bearer_error_status(&OpenIdConnectError::InsufficientScope("openid".to_owned())), status::Forbidden);
+    }
+
+    #[test]
+    fn test_bearer_challenge() {
+        assert_eq!(bearer_challenge("example", &OpenIdConnectError::MissingAccessToken), r#"Bearer realm="example""#);
+
+        let challenge = bearer_challenge("example", &OpenIdConnectError::InvalidToken("expired".to_owned()));
+        assert!(challenge.starts_with(r#"Bearer realm="example", error="invalid_token""#));
+
+        let challenge = bearer_challenge("example", &OpenIdConnectError::InsufficientScope("openid".to_owned()));
+        assert!(challenge.ends_with(r#"scope="openid""#));
+    }
+}
@@ -0,0 +1,50 @@
+use iron::prelude::*;
+use iron::headers::{Authorization, Basic};
+use urlencoded::{UrlEncodedBody, UrlDecodingError};
+
+use rbvt::params::*;
+use result::{Result, OpenIdConnectError};
+use config::Config;
+use oauth2::models::client::*;
+
+/// Client authentication at the back channel endpoints, using either
+/// client_secret_basic or client_secret_post.
+/// See http://openid.net/specs/openid-connect-core-1_0.html#ClientAuthentication
+
+fn find_client_credentials(req: &mut Request) -> Result<(String, String)> {
+    if let Some(auth) = req.headers.get::<Authorization<Basic>>() {
+        let password = try!(auth.password.as_ref().ok_or(OpenIdConnectError::InvalidClient));
+        
+        return Ok((auth.username.clone(), password.clone()));
+    }
+    
+    match req.get_ref::<UrlEncodedBody>() {
+        Ok(params) => {
+            let client_id = try!(multimap_get_maybe_one(params, "client_id"));
+            let client_secret = try!(multimap_get_maybe_one(params, "client_secret"));
+            
+            match (client_id, client_secret) {
+                (Some(id), Some(secret)) => Ok((id.to_owned(), secret.to_owned())),
+                _ => Err(OpenIdConnectError::InvalidClient),
+            }
+        },
+        Err(UrlDecodingError::EmptyQuery) => Err(OpenIdConnectError::InvalidClient),
+        Err(e) => Err(OpenIdConnectError::InvalidRequest(format!("{}", e))),
+    }
+}
+
+pub fn authenticate_client(req: &mut Request) -> Result<ClientApplication> {
+    let config = try!(Config::get(req));
+    
+    let (client_id, client_secret) = try!(find_client_credentials(req));
+    
+    let maybe_client = try!(config.application_repo.find_client_application(&client_id));
+    let client = try!(maybe_client.ok_or(OpenIdConnectError::InvalidClient));
+    
+    if client.verify_secret(&client_secret) {
+        Ok(client)
+    } else {
+        debug!("client authentication failed for {}", client_id);
+        Err(OpenIdConnectError::InvalidClient)
+    }
+}
@@ -0,0 +1,54 @@
+use iron::prelude::*;
+use iron::status;
+use iron::middleware::AfterMiddleware;
+use serde_json::value;
+
+use result::*;
+use sessions::UserSession;
+use view::View;
+
+/// The message for errors users can do something about, e.g. error_account_disabled.
+/// Anything else gets the message for its status code, e.g. error_404.
+fn error_message_key(err: &IronError, status_code: status::Status) -> String {
+    match err.error.downcast_ref::<OpenIdConnectError>() {
+        Some(&OpenIdConnectError::AccountDisabled) => "error_account_disabled".to_owned(),
+        Some(&OpenIdConnectError::ClientApplicationNotFound) => "error_client_not_found".to_owned(),
+        Some(&OpenIdConnectError::InvalidRedirectUri) => "error_invalid_redirect_uri".to_owned(),
+        _ => format!("error_{}", status_code.to_u16()),
+    }
+}
+
+pub struct HtmlErrorRenderer;
+
+impl AfterMiddleware for HtmlErrorRenderer {
+    /// render error as a page in the user's language
+    /// the handlebars engine further down the chain turns it into html
+    /// the error itself is only logged, since it can describe our internals
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        debug!("{:?} caught in HtmlErrorRenderer AfterMiddleware.", &err);
+        
+        let status_code = err.response.status.unwrap_or(status::InternalServerError);
+        
+        if status_code.is_server_error() {
+            error!("{} {}: {}", req.method, req.url, err);
+        } else {
+            info!("{} {}: {}", req.method, req.url, err);
+        }
+        
+        let session = req.get::<UserSession>().unwrap_or(None);
+        let mut view = View::new("error.html", session);
+        view.localize(req, None);
+        
+        let message = view.messages.get(&error_message_key(&err, status_code))
+            .or_else(|| view.messages.get(&format!("error_{}", status_code.to_u16())))
+            .or_else(|| view.messages.get("error_500"))
+            .map(|s| s.to_owned())
+            .unwrap_or(format!("{}", status_code));
+        
+        view.data.insert("message".to_owned(), try!(value::to_value(&message).map_err(OpenIdConnectError::from)));
+        
+        let template = try!(view.template());
+        
+        Ok(err.response.set(template))
+    }
+}
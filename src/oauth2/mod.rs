@@ -4,20 +4,53 @@ use handlebars_iron::{HandlebarsEngine, DirectorySource};
 use router::Router;
 
 use config::Config;
+use csrf::CsrfProtection;
+use authorization::RequireRole;
+use users::Role;
 use oauth2::json_error::*;
+use oauth2::html_error::*;
 
 pub mod routes;
 pub mod repos;
 pub mod models;
 pub mod json_error;
+pub mod html_error;
+pub mod bearer;
+pub mod subject;
+pub mod client_auth;
+pub mod backchannel;
+pub mod session_state;
 
 // html content type;
-// html error pages
+// localized html error pages
 // urlencoded_form accept type?
 // form request forgery protection
 // TODO move the hbse out to be reused
 // TODO macro syntax to wrap several routes similarly
-pub fn web_handler<T>(_config: &Config, route: T) -> Chain
+pub fn web_handler<T>(config: &Config, route: T) -> Chain
+where T: Handler
+{
+    web_chain(config, route, CsrfProtection::new())
+}
+
+/// For form posts that may come from other sites, like an RP's logout form.
+/// Tokens are still issued, but the handler has to check them.
+pub fn cross_site_web_handler<T>(config: &Config, route: T) -> Chain
+where T: Handler
+{
+    web_chain(config, route, CsrfProtection::exempt())
+}
+
+/// Management pages, for admins only
+pub fn admin_web_handler<T>(config: &Config, route: T) -> Chain
+where T: Handler
+{
+    let mut chain = web_chain(config, route, CsrfProtection::new());
+    chain.around(RequireRole::web(Role::Admin));
+    chain
+}
+
+fn web_chain<T>(_config: &Config, route: T, csrf: CsrfProtection) -> Chain
 where T: Handler
 {
     let mut hbse = HandlebarsEngine::new();
@@ -27,13 +60,14 @@ where T: Handler
     }
  
     let mut chain = Chain::new(route);
+    chain.link_before(csrf);
+    chain.link_after(HtmlErrorRenderer);
     chain.link_after(hbse);
     chain
 }
     
 // json accept and content types
 // json error page
-// jwt validation
 pub fn api_handler<T>(_config: &Config, route: T) -> Chain
 where T: Handler
 {
@@ -44,6 +78,15 @@ where T: Handler
     chain
 }
 
+/// Management APIs, for an admin's session or an access token with the admin scope
+pub fn admin_api_handler<T>(config: &Config, route: T) -> Chain
+where T: Handler
+{
+    let mut chain = api_handler(config, route);
+    chain.around(RequireRole::api(Role::Admin));
+    chain
+}
+
 
 pub fn well_known_router(config: &Config) -> Router {
     let mut well_known_router = Router::new();
@@ -60,7 +103,13 @@ pub fn oauth2_router(config: &Config) -> Router {
     oidc_router.post("/consent", web_handler(&config, routes::consent_post_handler), "consent");
     oidc_router.post("/token", api_handler(&config, routes::token_post_handler), "token");
     oidc_router.get("/userinfo", api_handler(&config, routes::userinfo_get_handler), "userinfo");
+    oidc_router.post("/userinfo", api_handler(&config, routes::userinfo_post_handler), "userinfo_post");
     oidc_router.get("/identity", api_handler(&config, routes::identity_get_handler), "identity");
+    oidc_router.post("/identity", api_handler(&config, routes::identity_post_handler), "identity_post");
+    oidc_router.get("/endsession", web_handler(&config, routes::end_session_get_handler), "endsession");
+    oidc_router.post("/endsession", cross_site_web_handler(&config, routes::end_session_post_handler), "endsession_post");
+    oidc_router.get("/checksession", web_handler(&config, routes::check_session_get_handler), "checksession");
+    oidc_router.post("/introspect", api_handler(&config, routes::introspect_post_handler), "introspect");
     oidc_router.post("/register", api_handler(&config, routes::register_application_post_handler), "register");
     oidc_router
 }
\ No newline at end of file
@@ -17,6 +17,7 @@ use response_mode::*;
 use config::Config;
 use site_config::*;
 use oauth2::models::ClientApplication;
+use oauth2::models::claims_request::ClaimsRequest;
 use oauth2::repos::ClientApplicationRepo;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -57,6 +58,11 @@ impl AuthorizeRequestState {
             if !client.match_redirect_uri(&self.request.redirect_uri) {
                 validation_state.reject("redirect_uri", ValidationError::InvalidValue("redirect_uri does not match".to_owned()));
             }
+            
+            // e.g. admin, which an admin must allow the client to ask for first
+            for scope in self.request.scopes.iter().filter(|s| !client.may_request_scope(s)) {
+                validation_state.reject("scope", ValidationError::InvalidValue(format!("client may not request scope {}", scope)));
+            }
         } else {
             validation_state.reject("client_id", ValidationError::InvalidValue("client not found for client_id".to_owned()));
         }
@@ -118,7 +124,9 @@ pub struct AuthorizeRequest {
     pub response_mode: Option<ResponseMode>, // optional
     pub prompt: Option<String>,
     pub display: Option<String>,
-    // other stuff: max_age, ui_locales, id_token_hint, login_hint, acr_values
+    pub ui_locales: Option<String>, // optional. space separated BCP47 tags, most preferred first
+    pub claims: Option<ClaimsRequest>, // optional. individually requested claims
+    // other stuff: max_age, id_token_hint, login_hint, acr_values
 }
 
 impl AuthorizeRequest {
@@ -135,6 +143,8 @@ impl AuthorizeRequest {
             response_mode: None,
             prompt: None,
             display: None,
+            ui_locales: None,
+            claims: None,
         }
     }
     
@@ -165,6 +175,14 @@ impl AuthorizeRequest {
         if self.display.is_some() {
             params.insert("display".to_owned(), vec![self.display.as_ref().unwrap().to_owned()]);
         }
+        if self.ui_locales.is_some() {
+            params.insert("ui_locales".to_owned(), vec![self.ui_locales.as_ref().unwrap().to_owned()]);
+        }
+        if let Some(ref claims) = self.claims {
+            if let Ok(claims_json) = claims.to_string() {
+                params.insert("claims".to_owned(), vec![claims_json]);
+            }
+        }
 
         params
     }
@@ -179,6 +197,7 @@ impl AuthorizeRequest {
         let redirect_uri = try!(multimap_get_one(hashmap, "redirect_uri"));
         let prompt = try!(multimap_get_maybe_one(hashmap, "prompt"));
         let display = try!(multimap_get_maybe_one(hashmap, "display"));
+        let ui_locales = try!(multimap_get_maybe_one(hashmap, "ui_locales"));
         let nonce = try!(multimap_get_maybe_one(hashmap, "nonce"));
         let maybe_response_mode_str = try!(multimap_get_maybe_one(hashmap, "response_mode"));
         let response_mode = if let Some(response_mode_str) = maybe_response_mode_str {
@@ -186,6 +205,11 @@ impl AuthorizeRequest {
         } else {
             None
         };
+        let claims = if let Some(claims_str) = try!(multimap_get_maybe_one(hashmap, "claims")) {
+            Some(try!(ClaimsRequest::from_str(claims_str)))
+        } else {
+            None
+        };
     
         Ok(AuthorizeRequest {
             iss: iss.map(|s| s.to_owned()),
@@ -197,8 +221,10 @@ impl AuthorizeRequest {
             redirect_uri: redirect_uri.to_owned(),
             prompt: prompt.map(|s| s.to_owned()),
             display: display.map(|s| s.to_owned()),
+            ui_locales: ui_locales.map(|s| s.to_owned()),
             nonce: nonce.map(|s| s.to_owned()),
             response_mode: response_mode.clone(),
+            claims: claims,
         })
     }
     
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use serde_json;
+use serde_json::{Map, Value};
+
+use result::{Result, OpenIdConnectError};
+use users::User;
+
+/// Where a set of claims is going to be released.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClaimsTarget {
+    IdToken,
+    UserInfo,
+}
+
+/// An individually requested claim from the OIDC `claims` request parameter.
+/// A claim requested with a `null` body is represented by `None` in `ClaimsRequest`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClaimRequest {
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub essential: Option<bool>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub values: Option<Vec<Value>>,
+}
+
+impl ClaimRequest {
+    pub fn new() -> ClaimRequest {
+        ClaimRequest {
+            essential: None,
+            value: None,
+            values: None,
+        }
+    }
+
+    pub fn is_essential(&self) -> bool {
+        self.essential.unwrap_or(false)
+    }
+
+    /// true if the claim value satisfies the value or values constraint
+    pub fn accepts(&self, v: &Value) -> bool {
+        if let Some(ref value) = self.value {
+            if value != v {
+                return false;
+            }
+        }
+
+        if let Some(ref values) = self.values {
+            if !values.contains(v) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The OIDC `claims` request parameter.
+/// See http://openid.net/specs/openid-connect-core-1_0.html#ClaimsParameter
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClaimsRequest {
+    #[serde(default)]
+    pub userinfo: HashMap<String, Option<ClaimRequest>>,
+    #[serde(default)]
+    pub id_token: HashMap<String, Option<ClaimRequest>>,
+}
+
+impl ClaimsRequest {
+    pub fn new() -> ClaimsRequest {
+        ClaimsRequest {
+            userinfo: HashMap::new(),
+            id_token: HashMap::new(),
+        }
+    }
+
+    pub fn from_str(s: &str) -> Result<ClaimsRequest> {
+        serde_json::from_str(s).map_err(OpenIdConnectError::from)
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(OpenIdConnectError::from)
+    }
+
+    pub fn for_target(&self, target: ClaimsTarget) -> &HashMap<String, Option<ClaimRequest>> {
+        match target {
+            ClaimsTarget::IdToken => &self.id_token,
+            ClaimsTarget::UserInfo => &self.userinfo,
+        }
+    }
+
+    /// names of all the individually requested claims, for both targets
+    pub fn claim_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+
+        for name in self.id_token.keys().chain(self.userinfo.keys()) {
+            if !names.contains(name) {
+                names.push(name.to_owned());
+            }
+        }
+
+        names.sort();
+        names
+    }
+}
+
+/// Standard claims released for each of the OIDC scope values
+pub fn claims_for_scope(scope: &str) -> &'static [&'static str] {
+    match scope {
+        "profile" => &["name", "family_name", "given_name", "middle_name", "nickname",
+            "preferred_username", "profile", "picture", "website", "gender",
+            "birthdate", "zoneinfo", "locale", "updated_at"],
+        "email" => &["email", "email_verified"],
+        "address" => &["address"],
+        "phone" => &["phone_number", "phone_number_verified"],
+        _ => &[],
+    }
+}
+
+pub fn claims_for_scopes(scopes: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+
+    for scope in scopes {
+        for name in claims_for_scope(scope) {
+            let name = (*name).to_owned();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names
+}
+
+/// Collects the claim values for a user that were asked for,
+/// either through the scope values or individually using the claims request parameter.
+/// Claims the user doesn't have are left out, as are individually requested claims whose values
+/// don't meet the requested constraints, unless a granted scope releases them anyway.
+pub fn select_user_claims(user: &User, scopes: &[String], claims_request: Option<&ClaimsRequest>, target: ClaimsTarget) -> Map<String, Value> {
+    let mut result = Map::new();
+
+    for name in claims_for_scopes(scopes) {
+        if let Some(value) = user.get_claim(&name) {
+            result.insert(name, value);
+        }
+    }
+
+    if let Some(claims_request) = claims_request {
+        for (name, maybe_claim_request) in claims_request.for_target(target) {
+            match user.get_claim(name) {
+                Some(value) => {
+                    let accepted = maybe_claim_request.as_ref().map(|c| c.accepts(&value)).unwrap_or(true);
+
+                    if accepted {
+                        result.insert(name.to_owned(), value);
+                    } else {
+                        debug!("claim {} does not have requested value", name);
+                    }
+                },
+                None => {
+                    if maybe_claim_request.as_ref().map(|c| c.is_essential()).unwrap_or(false) {
+                        debug!("essential claim {} not available for user {}", name, user.id);
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+    use users::User;
+
+    #[test]
+    fn test_claims_request_de() {
+        let js = r#"{
+            "userinfo": {
+                "given_name": {"essential": true},
+                "email": null,
+                "phone_number": null
+            },
+            "id_token": {
+                "email_verified": {"essential": true},
+                "acr": {"values": ["urn:mace:incommon:iap:silver"]},
+                "sub": {"value": "248289761001"}
+            }
+        }"#;
+
+        let claims = ClaimsRequest::from_str(js).unwrap();
+
+        assert_eq!(claims.userinfo.len(), 3);
+        assert_eq!(claims.userinfo.get("email"), Some(&None));
+        assert!(claims.userinfo.get("given_name").unwrap().as_ref().unwrap().is_essential());
+        assert!(claims.id_token.get("sub").unwrap().as_ref().unwrap().accepts(&Value::String("248289761001".to_owned())));
+        assert!(!claims.id_token.get("acr").unwrap().as_ref().unwrap().accepts(&Value::String("other".to_owned())));
+
+        let round_trip = ClaimsRequest::from_str(&claims.to_string().unwrap()).unwrap();
+        assert_eq!(round_trip, claims);
+    }
+
+    #[test]
+    fn test_claims_request_missing_target() {
+        let claims = ClaimsRequest::from_str(r#"{"id_token": {"email_verified": null}}"#).unwrap();
+
+        assert!(claims.userinfo.is_empty());
+        assert_eq!(claims.claim_names(), vec!["email_verified".to_owned()]);
+    }
+
+    #[test]
+    fn test_select_user_claims() {
+        let mut user = User::new("1".to_owned(), "admin".to_owned(), None);
+        user.claims.insert("email_verified".to_owned(), Value::Bool(true));
+        user.claims.insert("phone_number".to_owned(), Value::String("+1 555 0100".to_owned()));
+
+        let claims = ClaimsRequest::from_str(r#"{"id_token": {"email_verified": {"essential": true}}, "userinfo": {"phone_number": null}}"#).unwrap();
+
+        let id_token_claims = select_user_claims(&user, &["openid".to_owned()], Some(&claims), ClaimsTarget::IdToken);
+        assert_eq!(id_token_claims.get("email_verified"), Some(&Value::Bool(true)));
+        assert!(id_token_claims.get("phone_number").is_none());
+
+        let userinfo_claims = select_user_claims(&user, &["openid".to_owned()], Some(&claims), ClaimsTarget::UserInfo);
+        assert!(userinfo_claims.get("email_verified").is_none());
+        assert!(userinfo_claims.get("phone_number").is_some());
+    }
+
+    #[test]
+    fn test_unmatched_value_keeps_scope_claims() {
+        let mut user = User::new("1".to_owned(), "admin".to_owned(), None);
+        user.claims.insert("name".to_owned(), Value::String("Barbara Jensen".to_owned()));
+
+        let claims = ClaimsRequest::from_str(r#"{"userinfo": {"name": {"value": "Someone Else"}}}"#).unwrap();
+
+        // profile releases name whatever value was asked for
+        let userinfo_claims = select_user_claims(&user, &["openid".to_owned(), "profile".to_owned()], Some(&claims), ClaimsTarget::UserInfo);
+        assert_eq!(userinfo_claims.get("name"), Some(&Value::String("Barbara Jensen".to_owned())));
+
+        let userinfo_claims = select_user_claims(&user, &["openid".to_owned()], Some(&claims), ClaimsTarget::UserInfo);
+        assert!(userinfo_claims.get("name").is_none());
+    }
+}
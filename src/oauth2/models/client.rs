@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::fmt;
 
 use serde::{Serializer, Deserializer};
+use serde_json::Value;
 use rbvt::params::*;
 use jsonwebtoken::validation::*;
 use chrono::*;
@@ -45,6 +46,14 @@ impl fmt::Display for TokenEndpointAuthMethod {
     }
 }
 
+/// Scopes that reach the management APIs, e.g. "admin" for /api/users and "scim" for /scim/v2.
+/// Clients can only ask for these once an admin has allowed them to.
+pub const PRIVILEGED_SCOPES: &'static [&'static str] = &["admin", "scim"];
+
+pub fn is_privileged_scope(scope: &str) -> bool {
+    PRIVILEGED_SCOPES.contains(&scope)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientApplication {
     pub client_id: String,
@@ -70,7 +79,7 @@ pub struct ClientApplication {
     pub tos_uri: Option<String>,
     pub tos_uri_i18n: HashMap<String, String>,
     pub jwks_uri: Option<String>,
-    pub jwks: Option<String>,
+    pub jwks: Option<Value>,
     pub sector_identifier_uri: Option<String>,
     pub subject_type: Option<String>,
     pub id_token_signed_response_alg: Option<String>,
@@ -84,11 +93,23 @@ pub struct ClientApplication {
     pub request_object_encryption_enc: Option<String>,
     pub token_endpoint_auth_method: Option<String>,
     pub token_endpoint_auth_signing_alg: Option<String>,
-    pub default_max_age: Option<String>,
+    pub default_max_age: Option<u64>,
     pub require_auth_time: Option<bool>,
     pub default_acr_values: Vec<String>,
     pub initiate_login_uri: Option<String>,
     pub request_uris: Vec<String>,
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub frontchannel_logout_uri: Option<String>,
+    #[serde(default)]
+    pub frontchannel_logout_session_required: bool,
+    #[serde(default)]
+    pub backchannel_logout_uri: Option<String>,
+    #[serde(default)]
+    pub backchannel_logout_session_required: bool,
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>, // privileged scopes an admin has allowed this client to ask for
 }
 
 impl ClientApplication {
@@ -138,6 +159,12 @@ impl ClientApplication {
             default_acr_values: vec![],
             initiate_login_uri: None,
             request_uris: vec![],
+            post_logout_redirect_uris: vec![],
+            frontchannel_logout_uri: None,
+            frontchannel_logout_session_required: false,
+            backchannel_logout_uri: None,
+            backchannel_logout_session_required: false,
+            allowed_scopes: vec![],
         })
     }
     
@@ -145,6 +172,19 @@ impl ClientApplication {
         self.redirect_uris.iter().find(|s| &s[..] == redirect_uri).is_some()
     }
 
+    /// Ordinary scopes can always be asked for, privileged ones only if allowed
+    pub fn may_request_scope(&self, scope: &str) -> bool {
+        !is_privileged_scope(scope) || self.allowed_scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        self.hashed_secret.as_ref().map(|h| verify_password(secret, h)).unwrap_or(false)
+    }
+
+    pub fn match_post_logout_redirect_uri(&self, post_logout_redirect_uri: &str) -> bool {
+        self.post_logout_redirect_uris.iter().find(|s| &s[..] == post_logout_redirect_uri).is_some()
+    }
+
     pub fn get_token_endpoint_auth_method(&self) -> Result<TokenEndpointAuthMethod> {
         if let Some(ref auth_method) = self.token_endpoint_auth_method {
             TokenEndpointAuthMethod::from_str(auth_method)
@@ -173,6 +213,15 @@ pub struct ClientApplicationBuilder {
     pub client_id: Option<String>,
     pub secret: Option<String>,
     pub redirect_uris: Option<Vec<String>>,
+    pub subject_type: Option<String>,
+    pub sector_identifier_uri: Option<String>,
+    pub post_logout_redirect_uris: Option<Vec<String>>,
+    pub frontchannel_logout_uri: Option<String>,
+    pub frontchannel_logout_session_required: bool,
+    pub backchannel_logout_uri: Option<String>,
+    pub backchannel_logout_session_required: bool,
+    pub token_endpoint_auth_method: Option<String>,
+    pub allowed_scopes: Option<Vec<String>>,
     
     pub validation_state: ValidationState,
 }
@@ -184,6 +233,15 @@ impl ClientApplicationBuilder {
             client_id: None,
             secret: None,
             redirect_uris: None,
+            subject_type: None,
+            sector_identifier_uri: None,
+            post_logout_redirect_uris: None,
+            frontchannel_logout_uri: None,
+            frontchannel_logout_session_required: false,
+            backchannel_logout_uri: None,
+            backchannel_logout_session_required: false,
+            token_endpoint_auth_method: None,
+            allowed_scopes: None,
             validation_state: ValidationState::new(),
         }
     }
@@ -193,6 +251,15 @@ impl ClientApplicationBuilder {
         let mut app = try!(ClientApplication::new(client_id));
         app.client_name = self.client_name;
         app.redirect_uris = self.redirect_uris.unwrap_or(vec![]);
+        app.subject_type = self.subject_type;
+        app.sector_identifier_uri = self.sector_identifier_uri;
+        app.post_logout_redirect_uris = self.post_logout_redirect_uris.unwrap_or(vec![]);
+        app.frontchannel_logout_uri = self.frontchannel_logout_uri;
+        app.frontchannel_logout_session_required = self.frontchannel_logout_session_required;
+        app.backchannel_logout_uri = self.backchannel_logout_uri;
+        app.backchannel_logout_session_required = self.backchannel_logout_session_required;
+        app.token_endpoint_auth_method = self.token_endpoint_auth_method;
+        app.allowed_scopes = self.allowed_scopes.unwrap_or(vec![]);
 
         Ok(app)
     }
@@ -208,6 +275,22 @@ impl ClientApplicationBuilder {
         
         self.redirect_uris = params.get("redirect_uris").map(|r| r.to_owned().into_iter().filter(|r| !r.is_empty()).collect());
         
+        self.subject_type = try!(multimap_get_maybe_one(params, "subject_type")).and_then(|s| if s.is_empty() { None } else { Some(s.to_owned()) });
+        
+        self.sector_identifier_uri = try!(multimap_get_maybe_one(params, "sector_identifier_uri")).and_then(|s| if s.is_empty() { None } else { Some(s.to_owned()) });
+        
+        self.post_logout_redirect_uris = params.get("post_logout_redirect_uris").map(|r| r.to_owned().into_iter().filter(|r| !r.is_empty()).collect());
+        
+        self.frontchannel_logout_uri = try!(multimap_get_maybe_one(params, "frontchannel_logout_uri")).and_then(|s| if s.is_empty() { None } else { Some(s.to_owned()) });
+        
+        self.frontchannel_logout_session_required = try!(multimap_get_maybe_one(params, "frontchannel_logout_session_required")).map(|s| s == "true").unwrap_or(false);
+        
+        self.backchannel_logout_uri = try!(multimap_get_maybe_one(params, "backchannel_logout_uri")).and_then(|s| if s.is_empty() { None } else { Some(s.to_owned()) });
+        
+        self.backchannel_logout_session_required = try!(multimap_get_maybe_one(params, "backchannel_logout_session_required")).map(|s| s == "true").unwrap_or(false);
+        
+        self.allowed_scopes = params.get("allowed_scopes").map(|s| s.to_owned().into_iter().filter(|s| is_privileged_scope(s)).collect());
+        
         Ok(())
     }
     
@@ -231,3 +314,26 @@ impl ClientApplicationBuilder {
         builder.build()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_privileged_scopes_need_allowing() {
+        let mut params = HashMap::new();
+        params.insert("client_id".to_owned(), vec!["app".to_owned()]);
+        params.insert("allowed_scopes".to_owned(), vec!["scim".to_owned(), "email".to_owned()]);
+
+        let client = ClientApplicationBuilder::build_from_params(&params).unwrap();
+
+        assert_eq!(client.allowed_scopes, vec!["scim".to_owned()]);
+        assert!(client.may_request_scope("openid"));
+        assert!(client.may_request_scope("scim"));
+        assert!(!client.may_request_scope("admin"));
+
+        assert!(!ClientApplication::new("other".to_owned()).unwrap().may_request_scope("scim"));
+    }
+}
@@ -6,11 +6,13 @@ use serde;
 use jsonwebtoken::validation::*;
 use chrono::*;
 use result::OpenIdConnectError;
+use oauth2::models::claims_request::*;
 
 #[derive(Clone, Debug)]
 pub struct GrantUpdate {
     pub user_id: String,
     pub client_id: String,
+    pub permissions_requested: Vec<String>,
     pub permissions_added: Vec<String>,
     pub permissions_removed: Vec<String>,
 }
@@ -20,6 +22,7 @@ impl GrantUpdate {
         GrantUpdate {
             user_id: user_id.into(),
             client_id: client_id.into(),
+            permissions_requested: vec![],
             permissions_added: vec![],
             permissions_removed: vec![],
         }
@@ -32,6 +35,7 @@ pub struct Grant {
     pub client_id: String,
     pub permissions_allowed: Vec<String>,
     pub permissions_denied: Vec<String>,
+    pub permissions_requested: Vec<String>, // what the client asked for at the last consent
     
   //  #[serde(serialize_with="SerializeWith::serialize_with", deserialize_with="DeserializeWith::deserialize_with")]
     // #[serde(deserialize_with="DeserializeWith::deserialize_with")]
@@ -47,6 +51,8 @@ pub struct Grant {
     // #[serde(deserialize_with="DeserializeWith::deserialize_with")]
     // #[serde(serialize_with="SerializeWith::serialize_with")]
     pub accessed_at: DateTime<UTC>, // updated when client accesses user data
+    
+    pub consented_at: DateTime<UTC>, // updated when the user answers the consent page
 }
 
 impl Grant {
@@ -57,29 +63,36 @@ impl Grant {
             client_id: client_id,
             permissions_allowed: vec![],
             permissions_denied: vec![],
+            permissions_requested: vec![],
             created_at: now,
             modified_at: now,
             accessed_at: now,
+            consented_at: now,
         }
     }
     
     pub fn new_for_update(update: GrantUpdate) -> Grant {
-        let mut g = Grant::new(update.user_id, update.client_id);
-        g.permissions_allowed = update.permissions_added;
-        g.permissions_denied = update.permissions_removed;
+        let mut g = Grant::new(update.user_id.clone(), update.client_id.clone());
+        g.update(update);
         g
     }
     
+    /// a permission is either allowed or denied, whichever the user chose most recently
     pub fn update(&mut self, update: GrantUpdate) {
         let now = UTC::now();
         self.modified_at = now;
         self.accessed_at = now;
+        if !update.permissions_requested.is_empty() {
+            self.consented_at = now;
+            self.permissions_requested = update.permissions_requested;
+        }
+        Self::remove(&mut self.permissions_denied, &update.permissions_added);
+        Self::remove(&mut self.permissions_allowed, &update.permissions_removed);
         Self::merge(&mut self.permissions_allowed, update.permissions_added);
         Self::merge(&mut self.permissions_denied, update.permissions_removed);
     }
     
     pub fn merge(a: &mut Vec<String>, b: Vec<String>) {
-        //TODO fix array merge
         for s in b {
             if !a.contains(&s) {
                 a.push(s)
@@ -87,15 +100,61 @@ impl Grant {
         }
     }
     
+    pub fn remove(a: &mut Vec<String>, b: &[String]) {
+        a.retain(|s| !b.contains(s));
+    }
+    
     pub fn allowed_permissions(&self, requested_perms: &[String]) -> Vec<String> {
         let mut result = vec![];
         for p in requested_perms {
-            if self.permissions_allowed.contains(p) {
+            if self.permissions_allowed.contains(p) && !self.permissions_denied.contains(p) {
                 result.push(p.to_owned());
             }
         }
         result
     }
+    
+    /// the requested permissions the user has never been asked about.
+    /// Ones they denied stay denied until they change their mind on the grants page or the client forces the prompt
+    pub fn unanswered_permissions(&self, requested_perms: &[String]) -> Vec<String> {
+        requested_perms.iter()
+            .filter(|p| !self.permissions_allowed.contains(p) && !self.permissions_denied.contains(p))
+            .map(|p| p.to_owned())
+            .collect()
+    }
+    
+    /// true if the user allowed only some of what the client asked for at the last consent
+    pub fn is_partial(&self) -> bool {
+        self.permissions_requested.iter().any(|p| !self.permissions_allowed.contains(p))
+    }
+    
+    /// Drops individually requested claims unless the user allowed the claim itself
+    /// or a scope which includes it
+    pub fn allowed_claims(&self, scopes: &[String], claims_request: &ClaimsRequest) -> ClaimsRequest {
+        let allowed_scopes = self.allowed_permissions(scopes);
+        let scope_claims = claims_for_scopes(&allowed_scopes);
+        
+        let is_allowed = |name: &String| -> bool {
+            !self.permissions_denied.contains(name) &&
+                (self.permissions_allowed.contains(name) || scope_claims.contains(name))
+        };
+        
+        let mut result = ClaimsRequest::new();
+        
+        for (name, c) in &claims_request.id_token {
+            if is_allowed(name) {
+                result.id_token.insert(name.to_owned(), c.clone());
+            }
+        }
+        
+        for (name, c) in &claims_request.userinfo {
+            if is_allowed(name) {
+                result.userinfo.insert(name.to_owned(), c.clone());
+            }
+        }
+        
+        result
+    }
 }
 
 struct GrantSerVisitor<'a>(&'a Grant);
@@ -106,15 +165,18 @@ impl serde::Serialize for Grant {
     {
         use serde::ser::SerializeStruct;
 
-        let mut struc = try!(serializer.serialize_struct("grant", 7));
+        let mut struc = try!(serializer.serialize_struct("grant", 10));
 
         try!(struc.serialize_field("user_id", &self.user_id));
         try!(struc.serialize_field("client_id", &self.client_id));
         try!(struc.serialize_field("permissions_allowed", &self.permissions_allowed));
         try!(struc.serialize_field("permissions_denied", &self.permissions_denied));
+        try!(struc.serialize_field("permissions_requested", &self.permissions_requested));
+        try!(struc.serialize_field("partial", &self.is_partial()));
         try!(struc.serialize_field("created_at", &self.created_at.timestamp()));
         try!(struc.serialize_field("modified_at", &self.modified_at.timestamp()));
         try!(struc.serialize_field("accessed_at", &self.accessed_at.timestamp()));
+        try!(struc.serialize_field("consented_at", &self.consented_at.timestamp()));
 
         struc.end()
     }
@@ -142,9 +204,11 @@ impl serde::de::Visitor for GrantDeVisitor {
         let mut client_id: Option<String> = None;
         let mut permissions_allowed: Option<Vec<String>> = None;
         let mut permissions_denied: Option<Vec<String>> = None;
+        let mut permissions_requested: Option<Vec<String>> = None;
         let mut created_at: Option<i64> = None;
         let mut modified_at: Option<i64> = None;
         let mut accessed_at: Option<i64> = None;
+        let mut consented_at: Option<i64> = None;
         
         loop {
             if let Some(key) = try!(visitor.visit_key::<String>()) {
@@ -153,10 +217,12 @@ impl serde::de::Visitor for GrantDeVisitor {
                     "client_id" => { client_id = try!(visitor.visit_value()); },
                     "permissions_allowed" => { permissions_allowed = try!(visitor.visit_value()); },
                     "permissions_denied" => { permissions_denied = try!(visitor.visit_value()); },
+                    "permissions_requested" => { permissions_requested = try!(visitor.visit_value()); },
                     "created_at" => { created_at = try!(visitor.visit_value()); },
                     "modified_at" => { modified_at = try!(visitor.visit_value()); },
                     "accessed_at" => { accessed_at = try!(visitor.visit_value()); },
-                    _ => {},
+                    "consented_at" => { consented_at = try!(visitor.visit_value()); },
+                    _ => { let _ignored: serde::de::impls::IgnoredAny = try!(visitor.visit_value()); },
                 }
             } else {
                 break;
@@ -184,6 +250,10 @@ impl serde::de::Visitor for GrantDeVisitor {
                 g.permissions_denied = p;
             }
             
+            if let Some(p) = permissions_requested {
+                g.permissions_requested = p;
+            }
+            
             if let Some(t) = created_at {
                 g.created_at = UTC.timestamp(t, 0);
             }
@@ -196,9 +266,95 @@ impl serde::de::Visitor for GrantDeVisitor {
                 g.accessed_at = UTC.timestamp(t, 0);
             }
             
+            if let Some(t) = consented_at {
+                g.consented_at = UTC.timestamp(t, 0);
+            }
+            
             Ok(g)
         } else {
             Err(serde::de::Error::custom(format!("{}", OpenIdConnectError::from(ValidationError::ValidationError(vs)))))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oauth2::models::claims_request::*;
+    
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+    
+    fn grant(allowed: &[&str], denied: &[&str]) -> Grant {
+        let mut grant = Grant::new("user".to_owned(), "client".to_owned());
+        grant.permissions_allowed = strings(allowed);
+        grant.permissions_denied = strings(denied);
+        grant
+    }
+    
+    #[test]
+    fn test_allowed_permissions() {
+        let requested = strings(&["openid", "profile", "email"]);
+        
+        assert_eq!(grant(&["openid", "profile"], &[]).allowed_permissions(&requested), strings(&["openid", "profile"]));
+        assert_eq!(grant(&["openid", "profile"], &["profile"]).allowed_permissions(&requested), strings(&["openid"]));
+        assert!(Grant::new("user".to_owned(), "client".to_owned()).allowed_permissions(&requested).is_empty());
+    }
+    
+    #[test]
+    fn test_unanswered_permissions() {
+        let requested = strings(&["openid", "profile", "email"]);
+        
+        assert_eq!(grant(&["openid"], &["profile"]).unanswered_permissions(&requested), strings(&["email"]));
+        assert_eq!(Grant::new("user".to_owned(), "client".to_owned()).unanswered_permissions(&requested), requested);
+    }
+    
+    #[test]
+    fn test_update_flips_allow_and_deny() {
+        let mut update = GrantUpdate::new("user", "client");
+        update.permissions_requested = strings(&["openid", "email"]);
+        update.permissions_added = strings(&["openid"]);
+        update.permissions_removed = strings(&["email"]);
+        
+        let mut g = Grant::new_for_update(update);
+        assert_eq!(g.permissions_allowed, strings(&["openid"]));
+        assert_eq!(g.permissions_denied, strings(&["email"]));
+        assert!(g.is_partial());
+        
+        let mut update = GrantUpdate::new("user", "client");
+        update.permissions_added = strings(&["email"]);
+        update.permissions_removed = strings(&["openid"]);
+        g.update(update);
+        
+        assert_eq!(g.permissions_allowed, strings(&["email"]));
+        assert_eq!(g.permissions_denied, strings(&["openid"]));
+        assert_eq!(g.permissions_requested, strings(&["openid", "email"]));
+    }
+    
+    #[test]
+    fn test_allowed_claims() {
+        let mut claims_request = ClaimsRequest::new();
+        claims_request.userinfo.insert("name".to_owned(), None);
+        claims_request.userinfo.insert("email".to_owned(), None);
+        claims_request.id_token.insert("phone_number".to_owned(), None);
+        claims_request.id_token.insert("address".to_owned(), None);
+        
+        let scopes = strings(&["openid", "profile"]);
+        
+        // name comes with profile, phone_number was allowed on its own, address was denied
+        let allowed = grant(&["openid", "profile", "phone_number"], &["address"]).allowed_claims(&scopes, &claims_request);
+        assert!(allowed.userinfo.contains_key("name"));
+        assert!(!allowed.userinfo.contains_key("email"));
+        assert!(allowed.id_token.contains_key("phone_number"));
+        assert!(!allowed.id_token.contains_key("address"));
+        
+        // a denied claim stays out even though its scope was allowed
+        let allowed = grant(&["openid", "profile"], &["name"]).allowed_claims(&scopes, &claims_request);
+        assert!(allowed.userinfo.is_empty());
+        
+        let allowed = Grant::new("user".to_owned(), "client".to_owned()).allowed_claims(&scopes, &claims_request);
+        assert!(allowed.userinfo.is_empty());
+        assert!(allowed.id_token.is_empty());
+    }
+}
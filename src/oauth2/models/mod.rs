@@ -4,10 +4,12 @@ pub mod grant;
 pub mod authorize_request;
 pub mod webfinger_request;
 pub mod registration;
+pub mod claims_request;
 
 pub use self::client::*;
 pub use self::tokens::*;
 pub use self::grant::*;
 pub use self::authorize_request::*;
 pub use self::webfinger_request::*;
-pub use self::registration::*;
\ No newline at end of file
+pub use self::registration::*;
+pub use self::claims_request::*;
\ No newline at end of file
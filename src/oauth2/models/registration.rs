@@ -1,48 +1,160 @@
 use std::collections::HashMap;
 
-use serde_json;
-use rbvt::params::*;
+use serde_json::Value;
+use url::Url;
 
 use result::*;
 use oauth2::models::client::*;
+use oauth2::subject::{SectorIdentifierFetcher, validate_subject_type};
+
+/// Dynamic client registration as described in RFC 7591
+/// and http://openid.net/specs/openid-connect-registration-1_0.html
+
+pub const INVALID_REDIRECT_URI: &'static str = "invalid_redirect_uri";
+pub const INVALID_CLIENT_METADATA: &'static str = "invalid_client_metadata";
+
+/// Algorithms we can sign ID tokens, userinfo responses and request objects with
+pub const SUPPORTED_SIGNING_ALGS: &'static [&'static str] = &["HS256"];
+
+/// client_credentials isn't offered until the token endpoint implements it
+pub const SUPPORTED_GRANT_TYPES: &'static [&'static str] = &["authorization_code", "implicit"];
+
+fn invalid_metadata<S: Into<String>>(description: S) -> OpenIdConnectError {
+    OpenIdConnectError::RegistrationError(INVALID_CLIENT_METADATA, description.into())
+}
+
+fn invalid_redirect_uri<S: Into<String>>(description: S) -> OpenIdConnectError {
+    OpenIdConnectError::RegistrationError(INVALID_REDIRECT_URI, description.into())
+}
+
+fn parse_uri(name: &str, uri: &str) -> Result<Url> {
+    Url::parse(uri).map_err(|e| invalid_metadata(format!("{} {} is not a valid URI: {}", name, uri, e)))
+}
+
+fn check_https_uri(name: &str, uri: &Option<String>) -> Result<()> {
+    if let Some(ref uri) = *uri {
+        if try!(parse_uri(name, uri)).scheme() != "https" {
+            return Err(invalid_metadata(format!("{} must use https", name)));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_uri(name: &str, uri: &Option<String>) -> Result<()> {
+    if let Some(ref uri) = *uri {
+        try!(parse_uri(name, uri));
+    }
+
+    Ok(())
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host_str() {
+        Some("localhost") | Some("127.0.0.1") | Some("[::1]") => true,
+        _ => false,
+    }
+}
+
+fn check_signing_alg(name: &str, alg: &Option<String>, allow_none: bool) -> Result<()> {
+    match *alg {
+        Some(ref alg) if alg == "none" && allow_none => Ok(()),
+        Some(ref alg) if !SUPPORTED_SIGNING_ALGS.contains(&&alg[..]) => Err(invalid_metadata(format!("{} {} is not supported", name, alg))),
+        _ => Ok(())
+    }
+}
+
+/// We don't encrypt responses or decrypt request objects
+fn check_no_encryption(name: &str, alg: &Option<String>, enc: &Option<String>) -> Result<()> {
+    match (alg, enc) {
+        (&Some(ref alg), _) => Err(invalid_metadata(format!("{} {} is not supported", name, alg))),
+        (&None, &Some(_)) => Err(invalid_metadata(format!("{} is required when an encryption enc is given", name))),
+        _ => Ok(())
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RegistrationRequest {
+    #[serde(default)]
     pub redirect_uris: Vec<String>,
+    #[serde(default)]
     pub response_types: Vec<String>,
+    #[serde(default)]
     pub grant_types: Vec<String>,
+    #[serde(default)]
     pub application_type: Option<String>,
+    #[serde(default)]
     pub contacts: Vec<String>,
+    #[serde(default)]
     pub client_name: Option<String>,
+    #[serde(default)]
     pub client_name_i18n: HashMap<String, String>,
+    #[serde(default)]
     pub logo_uri: Option<String>,
+    #[serde(default)]
     pub logo_uri_i18n: HashMap<String, String>,
+    #[serde(default)]
     pub client_uri: Option<String>,
+    #[serde(default)]
     pub client_uri_i18n: HashMap<String, String>,
+    #[serde(default)]
     pub policy_uri: Option<String>,
+    #[serde(default)]
     pub policy_uri_i18n: HashMap<String, String>,
+    #[serde(default)]
     pub tos_uri: Option<String>,
+    #[serde(default)]
     pub tos_uri_i18n: HashMap<String, String>,
+    #[serde(default)]
     pub jwks_uri: Option<String>,
-    pub jwks: Option<String>,
+    #[serde(default)]
+    pub jwks: Option<Value>,
+    #[serde(default)]
     pub sector_identifier_uri: Option<String>,
+    #[serde(default)]
     pub subject_type: Option<String>,
+    #[serde(default)]
     pub id_token_signed_response_alg: Option<String>,
+    #[serde(default)]
     pub id_token_encrypted_response_alg: Option<String>,
+    #[serde(default)]
     pub id_token_encrypted_response_enc: Option<String>,
+    #[serde(default)]
     pub userinfo_signed_response_alg: Option<String>,
+    #[serde(default)]
     pub userinfo_encrypted_response_alg: Option<String>,
+    #[serde(default)]
     pub userinfo_encrypted_response_enc: Option<String>,
+    #[serde(default)]
     pub request_object_signing_alg: Option<String>,
+    #[serde(default)]
     pub request_object_encryption_alg: Option<String>,
+    #[serde(default)]
     pub request_object_encryption_enc: Option<String>,
+    #[serde(default)]
     pub token_endpoint_auth_method: Option<String>,
+    #[serde(default)]
     pub token_endpoint_auth_signing_alg: Option<String>,
-    pub default_max_age: Option<String>,
+    #[serde(default)]
+    pub default_max_age: Option<u64>,
+    #[serde(default)]
     pub require_auth_time: Option<bool>,
+    #[serde(default)]
     pub default_acr_values: Vec<String>,
+    #[serde(default)]
     pub initiate_login_uri: Option<String>,
+    #[serde(default)]
     pub request_uris: Vec<String>,
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub frontchannel_logout_uri: Option<String>,
+    #[serde(default)]
+    pub frontchannel_logout_session_required: bool,
+    #[serde(default)]
+    pub backchannel_logout_uri: Option<String>,
+    #[serde(default)]
+    pub backchannel_logout_session_required: bool,
 }
 
 impl RegistrationRequest {
@@ -83,54 +195,487 @@ impl RegistrationRequest {
             default_acr_values: vec![],
             initiate_login_uri: None,
             request_uris: vec![],
+            post_logout_redirect_uris: vec![],
+            frontchannel_logout_uri: None,
+            frontchannel_logout_session_required: false,
+            backchannel_logout_uri: None,
+            backchannel_logout_session_required: false,
+        }
+    }
+
+    fn has_grant_type(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|g| g == grant_type)
+    }
+
+    fn has_response_type_part(&self, part: &str) -> bool {
+        self.response_types.iter().any(|r| r.split_whitespace().any(|p| p == part))
+    }
+
+    /// Fills in the defaults for missing metadata, then checks it's all consistent and supported.
+    /// Problems with redirect uris are invalid_redirect_uri errors, anything else is invalid_client_metadata.
+    /// The sector_identifier_uri document is fetched to check the redirect uris are listed in it.
+    pub fn validate(&mut self, fetcher: &SectorIdentifierFetcher) -> Result<()> {
+        if self.response_types.is_empty() {
+            self.response_types = vec!["code".to_owned()];
+        }
+        if self.grant_types.is_empty() {
+            self.grant_types = vec!["authorization_code".to_owned()];
+        }
+        if self.application_type.is_none() {
+            self.application_type = Some("web".to_owned());
+        }
+        if self.token_endpoint_auth_method.is_none() {
+            self.token_endpoint_auth_method = Some("client_secret_basic".to_owned());
         }
+
+        try!(self.validate_types());
+        try!(self.validate_redirect_uris());
+        try!(self.validate_uris());
+        try!(self.validate_algorithms());
+
+        try!(validate_subject_type(fetcher,
+                self.subject_type.as_ref().map(|s| &s[..]),
+                self.sector_identifier_uri.as_ref().map(|s| &s[..]),
+                &self.redirect_uris)
+            .map_err(|e| match e {
+                OpenIdConnectError::InvalidRequest(msg) => invalid_metadata(msg),
+                e => invalid_metadata(format!("{}", e)),
+            }));
+
+        if self.jwks.is_some() && self.jwks_uri.is_some() {
+            return Err(invalid_metadata("jwks and jwks_uri can't both be given"));
+        }
+
+        if let Some(ref jwks) = self.jwks {
+            if !jwks.get("keys").map(|k| k.is_array()).unwrap_or(false) {
+                return Err(invalid_metadata("jwks must be a JWK Set"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_types(&self) -> Result<()> {
+        for response_type in self.response_types.iter() {
+            if response_type == "none" {
+                continue;
+            }
+
+            if !response_type.split_whitespace().all(|p| p == "code" || p == "token" || p == "id_token") {
+                return Err(invalid_metadata(format!("response_type {} is not supported", response_type)));
+            }
+        }
+
+        for grant_type in self.grant_types.iter() {
+            if !SUPPORTED_GRANT_TYPES.contains(&&grant_type[..]) {
+                return Err(invalid_metadata(format!("grant_type {} is not supported", grant_type)));
+            }
+        }
+
+        // e.g. response type code needs the authorization_code grant, and id_token needs implicit
+        let uses_code = self.has_response_type_part("code");
+        let uses_implicit = self.has_response_type_part("token") || self.has_response_type_part("id_token");
+
+        if uses_code != self.has_grant_type("authorization_code") {
+            return Err(invalid_metadata("response type code and the authorization_code grant type go together"));
+        }
+
+        if uses_implicit != self.has_grant_type("implicit") {
+            return Err(invalid_metadata("response types token and id_token need the implicit grant type, and it needs one of them"));
+        }
+
+        match try!(TokenEndpointAuthMethod::from_str(self.token_endpoint_auth_method.as_ref().map(|s| &s[..]).unwrap_or("client_secret_basic"))
+                .map_err(|e| invalid_metadata(format!("{}", e)))) {
+            TokenEndpointAuthMethod::ClientSecretBasic | TokenEndpointAuthMethod::ClientSecretPost | TokenEndpointAuthMethod::None => {},
+            other => return Err(invalid_metadata(format!("token_endpoint_auth_method {} is not supported", other))),
+        }
+
+        Ok(())
     }
 
-    pub fn load_params(&mut self, params: &HashMap<String, Vec<String>>) -> Result<()> {
-        self.redirect_uris = params.get("redirect_uris")
-            .map(|v| v.to_owned())
-            .unwrap_or(vec![])
-            .into_iter()
-            .filter(|r| !r.is_empty())
-            .collect();
+    /// Web clients using the implicit flow must use https and not localhost.
+    /// Native clients use a custom scheme or a loopback address over http.
+    fn validate_redirect_uris(&self) -> Result<()> {
+        if self.redirect_uris.is_empty() && (self.has_grant_type("authorization_code") || self.has_grant_type("implicit")) {
+            return Err(invalid_redirect_uri("redirect_uris is required"));
+        }
+
+        let native = match self.application_type.as_ref().map(|s| &s[..]) {
+            Some("native") => true,
+            Some("web") | None => false,
+            Some(other) => return Err(invalid_metadata(format!("application_type {} is not supported", other))),
+        };
+
+        for redirect_uri in self.redirect_uris.iter() {
+            let url = try!(Url::parse(redirect_uri).map_err(|e| invalid_redirect_uri(format!("{} is not a valid URI: {}", redirect_uri, e))));
 
-        self.client_name = try!(multimap_get_maybe_one(params, "client_name")).map(|s| s.to_owned());
+            if url.fragment().is_some() {
+                return Err(invalid_redirect_uri(format!("{} must not have a fragment", redirect_uri)));
+            }
+
+            if native {
+                if url.scheme() == "https" || (url.scheme() == "http" && !is_loopback(&url)) {
+                    return Err(invalid_redirect_uri(format!("native clients must use a custom scheme or http on localhost, not {}", redirect_uri)));
+                }
+            } else if self.has_grant_type("implicit") && (url.scheme() != "https" || is_loopback(&url)) {
+                return Err(invalid_redirect_uri(format!("web clients using the implicit flow must use https and not localhost, not {}", redirect_uri)));
+            }
+        }
 
-        // ...
-        
         Ok(())
     }
 
-    pub fn to_client_builder(self) -> ClientApplicationBuilder {
-        unimplemented!()
+    fn validate_uris(&self) -> Result<()> {
+        for uri in self.post_logout_redirect_uris.iter() {
+            try!(parse_uri("post_logout_redirect_uri", uri));
+        }
+
+        for uri in self.request_uris.iter() {
+            try!(parse_uri("request_uri", uri));
+        }
+
+        try!(check_uri("logo_uri", &self.logo_uri));
+        try!(check_uri("client_uri", &self.client_uri));
+        try!(check_uri("policy_uri", &self.policy_uri));
+        try!(check_uri("tos_uri", &self.tos_uri));
+        try!(check_uri("frontchannel_logout_uri", &self.frontchannel_logout_uri));
+        try!(check_uri("backchannel_logout_uri", &self.backchannel_logout_uri));
+        try!(check_https_uri("jwks_uri", &self.jwks_uri));
+        try!(check_https_uri("sector_identifier_uri", &self.sector_identifier_uri));
+        try!(check_https_uri("initiate_login_uri", &self.initiate_login_uri));
+
+        Ok(())
+    }
+
+    fn validate_algorithms(&self) -> Result<()> {
+        // unsigned ID tokens are only allowed when they come back from the token endpoint
+        let id_token_unsigned_ok = !self.has_response_type_part("id_token");
+
+        try!(check_signing_alg("id_token_signed_response_alg", &self.id_token_signed_response_alg, id_token_unsigned_ok));
+        try!(check_signing_alg("userinfo_signed_response_alg", &self.userinfo_signed_response_alg, false));
+        try!(check_signing_alg("request_object_signing_alg", &self.request_object_signing_alg, true));
+        try!(check_signing_alg("token_endpoint_auth_signing_alg", &self.token_endpoint_auth_signing_alg, false));
+
+        try!(check_no_encryption("id_token_encrypted_response_alg", &self.id_token_encrypted_response_alg, &self.id_token_encrypted_response_enc));
+        try!(check_no_encryption("userinfo_encrypted_response_alg", &self.userinfo_encrypted_response_alg, &self.userinfo_encrypted_response_enc));
+        try!(check_no_encryption("request_object_encryption_alg", &self.request_object_encryption_alg, &self.request_object_encryption_enc));
+
+        Ok(())
+    }
+
+    /// Whether the client authenticates with a secret, so needs one issued
+    pub fn uses_secret(&self) -> bool {
+        self.token_endpoint_auth_method.as_ref().map(|m| m != "none").unwrap_or(true)
+    }
+
+    pub fn to_client_builder(&self) -> ClientApplicationBuilder {
+        let mut builder = ClientApplicationBuilder::new();
+
+        builder.client_name = self.client_name.clone();
+        builder.redirect_uris = Some(self.redirect_uris.clone());
+        builder.subject_type = self.subject_type.clone();
+        builder.sector_identifier_uri = self.sector_identifier_uri.clone();
+        builder.post_logout_redirect_uris = Some(self.post_logout_redirect_uris.clone());
+        builder.frontchannel_logout_uri = self.frontchannel_logout_uri.clone();
+        builder.frontchannel_logout_session_required = self.frontchannel_logout_session_required;
+        builder.backchannel_logout_uri = self.backchannel_logout_uri.clone();
+        builder.backchannel_logout_session_required = self.backchannel_logout_session_required;
+        builder.token_endpoint_auth_method = self.token_endpoint_auth_method.clone();
+
+        builder
+    }
+
+    /// Copies the metadata the builder doesn't carry onto a newly created client
+    pub fn apply_to(self, client: &mut ClientApplication) {
+        client.response_types = self.response_types;
+        client.grant_types = self.grant_types;
+        client.application_type = self.application_type;
+        client.contacts = self.contacts;
+        client.client_name_i18n = self.client_name_i18n;
+        client.logo_uri = self.logo_uri;
+        client.logo_uri_i18n = self.logo_uri_i18n;
+        client.client_uri = self.client_uri;
+        client.client_uri_i18n = self.client_uri_i18n;
+        client.policy_uri = self.policy_uri;
+        client.policy_uri_i18n = self.policy_uri_i18n;
+        client.tos_uri = self.tos_uri;
+        client.tos_uri_i18n = self.tos_uri_i18n;
+        client.jwks_uri = self.jwks_uri;
+        client.jwks = self.jwks;
+        client.id_token_signed_response_alg = self.id_token_signed_response_alg;
+        client.id_token_encrypted_response_alg = self.id_token_encrypted_response_alg;
+        client.id_token_encrypted_response_enc = self.id_token_encrypted_response_enc;
+        client.userinfo_signed_response_alg = self.userinfo_signed_response_alg;
+        client.userinfo_encrypted_response_alg = self.userinfo_encrypted_response_alg;
+        client.userinfo_encrypted_response_enc = self.userinfo_encrypted_response_enc;
+        client.request_object_signing_alg = self.request_object_signing_alg;
+        client.request_object_encryption_alg = self.request_object_encryption_alg;
+        client.request_object_encryption_enc = self.request_object_encryption_enc;
+        client.token_endpoint_auth_signing_alg = self.token_endpoint_auth_signing_alg;
+        client.default_max_age = self.default_max_age;
+        client.require_auth_time = self.require_auth_time;
+        client.default_acr_values = self.default_acr_values;
+        client.initiate_login_uri = self.initiate_login_uri;
+        client.request_uris = self.request_uris;
     }
 }
 
+/// The error response for a rejected registration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistrationErrorView {
+    pub error: String,
+    pub error_description: String,
+}
+
+/// The registered client, with all its metadata
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RegistrationResult {
     pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub registration_access_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub registration_client_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub client_id_issued_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub client_secret_expires_at: Option<u64>, // required if secret issued
 
-    // plus any other registered metadata
     pub redirect_uris: Vec<String>,
-    // ...
+    pub response_types: Vec<String>,
+    pub grant_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application_type: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub contacts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub client_name_i18n: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub logo_uri_i18n: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_uri: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub client_uri_i18n: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_uri: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub policy_uri_i18n: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tos_uri: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tos_uri_i18n: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sector_identifier_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token_signed_response_alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userinfo_signed_response_alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_object_signing_alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_endpoint_auth_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_endpoint_auth_signing_alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_max_age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_auth_time: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub default_acr_values: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initiate_login_uri: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub request_uris: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub post_logout_redirect_uris: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frontchannel_logout_uri: Option<String>,
+    pub frontchannel_logout_session_required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backchannel_logout_uri: Option<String>,
+    pub backchannel_logout_session_required: bool,
 }
 
 impl RegistrationResult {
     pub fn from_client(client: ClientApplication, secret: Option<String>) -> RegistrationResult {
         RegistrationResult {
             client_id: client.client_id,
-            client_secret: secret,
             client_id_issued_at: client.client_id_issued_at,
-            client_secret_expires_at: client.client_secret_expires_at,
+            client_secret_expires_at: if secret.is_some() { client.client_secret_expires_at } else { None },
+            client_secret: secret,
             registration_access_token: None,
             registration_client_uri: None,
 
             redirect_uris: client.redirect_uris,
+            response_types: client.response_types,
+            grant_types: client.grant_types,
+            application_type: client.application_type,
+            contacts: client.contacts,
+            client_name: client.client_name,
+            client_name_i18n: client.client_name_i18n,
+            logo_uri: client.logo_uri,
+            logo_uri_i18n: client.logo_uri_i18n,
+            client_uri: client.client_uri,
+            client_uri_i18n: client.client_uri_i18n,
+            policy_uri: client.policy_uri,
+            policy_uri_i18n: client.policy_uri_i18n,
+            tos_uri: client.tos_uri,
+            tos_uri_i18n: client.tos_uri_i18n,
+            jwks_uri: client.jwks_uri,
+            jwks: client.jwks,
+            sector_identifier_uri: client.sector_identifier_uri,
+            subject_type: client.subject_type,
+            id_token_signed_response_alg: client.id_token_signed_response_alg,
+            userinfo_signed_response_alg: client.userinfo_signed_response_alg,
+            request_object_signing_alg: client.request_object_signing_alg,
+            token_endpoint_auth_method: client.token_endpoint_auth_method,
+            token_endpoint_auth_signing_alg: client.token_endpoint_auth_signing_alg,
+            default_max_age: client.default_max_age,
+            require_auth_time: client.require_auth_time,
+            default_acr_values: client.default_acr_values,
+            initiate_login_uri: client.initiate_login_uri,
+            request_uris: client.request_uris,
+            post_logout_redirect_uris: client.post_logout_redirect_uris,
+            frontchannel_logout_uri: client.frontchannel_logout_uri,
+            frontchannel_logout_session_required: client.frontchannel_logout_session_required,
+            backchannel_logout_uri: client.backchannel_logout_uri,
+            backchannel_logout_session_required: client.backchannel_logout_session_required,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+
+    use super::*;
+    use result::OpenIdConnectError;
+    use oauth2::subject::StaticSectorIdentifierFetcher;
+
+    fn request(json: &str) -> RegistrationRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn fetcher() -> StaticSectorIdentifierFetcher {
+        let fetcher = StaticSectorIdentifierFetcher::new();
+        fetcher.insert("https://client.example.org/sectors.json", vec!["https://client.example.org/cb".to_owned(), "https://app.example.net/cb".to_owned()]);
+        fetcher
+    }
+
+    fn error_code(json: &str) -> &'static str {
+        match request(json).validate(&fetcher()) {
+            Err(OpenIdConnectError::RegistrationError(code, _)) => code,
+            other => panic!("expected a registration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let mut reg_req = request(r#"{"redirect_uris": ["https://client.example.org/callback"]}"#);
+
+        reg_req.validate(&fetcher()).unwrap();
+
+        assert_eq!(reg_req.response_types, vec!["code".to_owned()]);
+        assert_eq!(reg_req.grant_types, vec!["authorization_code".to_owned()]);
+        assert_eq!(reg_req.application_type, Some("web".to_owned()));
+        assert!(reg_req.uses_secret());
+    }
+
+    #[test]
+    fn test_valid_implicit_and_native_clients() {
+        request(r#"{
+            "redirect_uris": ["https://client.example.org/callback"],
+            "response_types": ["code id_token", "id_token"],
+            "grant_types": ["authorization_code", "implicit"],
+            "id_token_signed_response_alg": "HS256",
+            "default_max_age": 3600,
+            "jwks": {"keys": []}
+        }"#).validate(&fetcher()).unwrap();
+
+        let mut native = request(r#"{
+            "redirect_uris": ["com.example.app:/callback", "http://localhost:8080/callback"],
+            "application_type": "native",
+            "token_endpoint_auth_method": "none"
+        }"#);
+        native.validate(&fetcher()).unwrap();
+        assert!(!native.uses_secret());
+    }
+
+    #[test]
+    fn test_invalid_redirect_uris() {
+        assert_eq!(error_code(r#"{}"#), INVALID_REDIRECT_URI);
+        assert_eq!(error_code(r#"{"redirect_uris": ["not a uri"]}"#), INVALID_REDIRECT_URI);
+        assert_eq!(error_code(r#"{"redirect_uris": ["https://client.example.org/cb#frag"]}"#), INVALID_REDIRECT_URI);
+        assert_eq!(error_code(r#"{"redirect_uris": ["http://client.example.org/cb"], "response_types": ["id_token"], "grant_types": ["implicit"]}"#), INVALID_REDIRECT_URI);
+        assert_eq!(error_code(r#"{"redirect_uris": ["https://client.example.org/cb"], "application_type": "native"}"#), INVALID_REDIRECT_URI);
+    }
+
+    #[test]
+    fn test_invalid_metadata() {
+        let uri = r#""redirect_uris": ["https://client.example.org/cb"]"#;
+
+        for extra in &[
+            r#""response_types": ["code"], "grant_types": ["implicit"]"#,
+            r#""response_types": ["code token"], "grant_types": ["authorization_code"]"#,
+            r#""response_types": ["device"]"#,
+            r#""grant_types": ["authorization_code", "refresh_token"]"#,
+            r#""grant_types": ["authorization_code", "client_credentials"]"#,
+            r#""id_token_signed_response_alg": "RS256""#,
+            r#""userinfo_encrypted_response_alg": "RSA1_5""#,
+            r#""id_token_encrypted_response_enc": "A128CBC-HS256""#,
+            r#""token_endpoint_auth_method": "private_key_jwt""#,
+            r#""subject_type": "anonymous""#,
+            r#""sector_identifier_uri": "http://client.example.org/sectors.json""#,
+            r#""jwks_uri": "https://client.example.org/jwks", "jwks": {"keys": []}"#,
+        ] {
+            assert!(error_code(&format!("{{{}, {}}}", uri, extra)) == INVALID_CLIENT_METADATA, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn test_sector_identifier() {
+        let uris = r#""redirect_uris": ["https://client.example.org/cb", "https://app.example.net/cb"]"#;
+
+        request(&format!(r#"{{{}, "subject_type": "pairwise", "sector_identifier_uri": "https://client.example.org/sectors.json"}}"#, uris)).validate(&fetcher()).unwrap();
+
+        // pairwise across hosts needs a sector identifier document listing them
+        assert_eq!(error_code(&format!(r#"{{{}, "subject_type": "pairwise"}}"#, uris)), INVALID_CLIENT_METADATA);
+
+        // a victim's sector can't be claimed without the victim listing our redirect uris
+        assert_eq!(error_code(r#"{"redirect_uris": ["https://evil.example.com/cb"], "subject_type": "pairwise", "sector_identifier_uri": "https://client.example.org/sectors.json"}"#), INVALID_CLIENT_METADATA);
+        assert_eq!(error_code(r#"{"redirect_uris": ["https://evil.example.com/cb"], "sector_identifier_uri": "https://missing.example.org/sectors.json"}"#), INVALID_CLIENT_METADATA);
+    }
+
+    #[test]
+    fn test_result_includes_metadata() {
+        let mut reg_req = request(r#"{"redirect_uris": ["https://client.example.org/cb"], "client_name": "Example", "contacts": ["ops@example.org"]}"#);
+        reg_req.validate(&fetcher()).unwrap();
+
+        let mut builder = reg_req.to_client_builder();
+        builder.client_id = Some("client".to_owned());
+        let mut client = builder.build().unwrap();
+        reg_req.apply_to(&mut client);
+
+        let result = serde_json::to_value(&RegistrationResult::from_client(client, Some("secret".to_owned()))).unwrap();
+
+        assert_eq!(result["client_id"], "client");
+        assert_eq!(result["client_secret"], "secret");
+        assert!(result["client_secret_expires_at"].is_u64());
+        assert_eq!(result["client_name"], "Example");
+        assert_eq!(result["contacts"][0], "ops@example.org");
+        assert_eq!(result["grant_types"][0], "authorization_code");
+        assert_eq!(result["token_endpoint_auth_method"], "client_secret_basic");
+        assert!(result.get("registration_access_token").is_none());
+    }
+}
@@ -119,21 +119,23 @@ impl ClientApplicationRepo for InMemoryClientApplicationRepo {
 }
 
 impl Authenticator for InMemoryClientApplicationRepo {
-    fn authenticate(&self, client_id: &str, secret: &str) -> Result<AuthenticationStatus> {
+    fn authenticate(&self, client_id: &str, secret: &str) -> Result<Authentication> {
         let client_applications = self.client_applications.lock().unwrap();
         
         match client_applications.iter().find(|u| {
             u.client_id == client_id
         }) {
             Some(user) => {
-                if user.hashed_secret == Some(hash_password(secret)) {
-                    Ok(AuthenticationStatus::Success)
+                let attributes = Some(UserAttributes::new(client_id));
+                
+                if user.verify_secret(secret) {
+                    Ok(Authentication::new(AuthenticationStatus::Success, attributes))
                 } else {
-                    Ok(AuthenticationStatus::IncorrectPassword)
+                    Ok(Authentication::new(AuthenticationStatus::IncorrectPassword, attributes))
                 }
             },
             None => {
-                Ok(AuthenticationStatus::PrincipalNotFound)
+                Ok(Authentication::not_found())
             }
         }
     }
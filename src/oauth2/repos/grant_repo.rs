@@ -15,6 +15,8 @@ pub trait GrantRepo where Self: Send + Sync {
     fn touch_grant(&self, g: &Grant) -> Result<()>;
     
     fn remove_grant(&self, user_id: &str, client_id: &str) -> Result<()>;
+    
+    fn remove_user_grants(&self, user_id: &str) -> Result<usize>;
 }
 
 #[derive(Clone)]
@@ -95,4 +97,16 @@ impl GrantRepo for InMemoryGrantRepo {
         
         Ok(())
     }
+    
+    fn remove_user_grants(&self, user_id: &str) -> Result<usize> {
+        debug!("grants: delete all for user_id {}", user_id);
+        
+        let mut grants = self.grants.lock().unwrap();
+        
+        let before = grants.len();
+        let remaining: Vec<Grant> = grants.iter().filter(|g| g.user_id != user_id).map(|g| g.clone()).collect();
+        *grants = remaining;
+        
+        Ok(before - grants.len())
+    }
 }
@@ -3,6 +3,7 @@ use std::sync::Mutex;
 
 use chrono::*;
 use iron::prelude::*;
+use serde_json::{Map, Value};
 use oauth2::repos::GrantRepo;
 use users::UserRepo;
 use rustc_serialize::base64::{ToBase64, FromBase64, URL_SAFE};
@@ -10,24 +11,37 @@ use jsonwebtoken::jwt::*;
 use jsonwebtoken::json::*;
 use jsonwebtoken::header::*;
 use jsonwebtoken::signer::*;
+use jsonwebtoken::crypto::mac_signer::MacSigner;
 use result::*;
 use site_config::*;
 use config::*;
 use authentication;
 use serialisation::*;
 use oauth2::models::*;
+use oauth2::subject::subject_for_client_id;
+use sessions::UserSession;
+use plugin::Plugin as PluginPlugin;
 
 pub trait TokenRepo where Self: Send + Sync  {
     // dao
-    fn get_user_claims(&self, req: &mut Request, user_id: &str, client_id: &str, scopes: &[String]) -> Result<JwtClaims>;
+    fn get_user_claim_values(&self, req: &mut Request, user_id: &str, client_id: &str, scopes: &[String], claims_request: Option<&ClaimsRequest>, target: ClaimsTarget) -> Result<Map<String, Value>>;
+    fn get_user_claims(&self, req: &mut Request, user_id: &str, client_id: &str, scopes: &[String], claims_request: Option<&ClaimsRequest>, target: ClaimsTarget) -> Result<JwtClaims>;
     fn create_auth_code(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest) -> Result<Token>;
     
     // token provider stuff
     fn create_code_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest) -> Result<Token>;
-    fn create_auth_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest, code_token: Token) -> Result<Token>;
+    fn create_auth_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest, code_token: Token, sid: Option<&str>) -> Result<Token>;
 
     // mixture
     fn exchange_auth_code(&self, req: &mut Request, code: &str) -> Result<Token>;
+    
+    // resource server
+    fn verify_access_token(&self, req: &mut Request, access_token: &str) -> Result<AccessTokenEntry>;
+    
+    // self service
+    fn get_user_refresh_tokens(&self, user_id: &str) -> Result<Vec<RefreshTokenEntry>>;
+    fn revoke_refresh_token(&self, user_id: &str, token_id: &str) -> Result<()>;
+    fn revoke_user_tokens(&self, user_id: &str) -> Result<()>;
 }
 
 #[derive(Clone, Debug)]
@@ -55,16 +69,90 @@ pub struct AuthEntry {
     pub user_id: String,
     pub authorize_request: AuthorizeRequest,
     pub token: Token,
+    pub sid: Option<String>, // the sid of the user's session at the OP when the code was issued
     pub revoked: bool,
 }
 
 impl AuthEntry {
-    pub fn new(user_id: String, code: AuthCode, authorize_request: AuthorizeRequest, token: Token) -> AuthEntry {
+    pub fn new(user_id: String, code: AuthCode, authorize_request: AuthorizeRequest, token: Token, sid: Option<String>) -> AuthEntry {
         AuthEntry {
             user_id: user_id,
             code: code,
             authorize_request: authorize_request,
             token: token,
+            sid: sid,
+            revoked: false,
+        }
+    }
+}
+
+/// Record of an issued access token, looked up by its jti claim
+#[derive(Clone, Debug)]
+pub struct AccessTokenEntry {
+    pub token_id: String,
+    pub user_id: String,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    pub claims_request: Option<ClaimsRequest>,
+    pub created_at: DateTime<UTC>,
+    pub expires_at: DateTime<UTC>,
+    pub revoked: bool,
+}
+
+impl AccessTokenEntry {
+    pub fn new(token_id: String, user_id: String, client_id: String, scopes: Vec<String>, claims_request: Option<ClaimsRequest>, created_at: DateTime<UTC>, expires_at: DateTime<UTC>) -> AccessTokenEntry {
+        AccessTokenEntry {
+            token_id: token_id,
+            user_id: user_id,
+            client_id: client_id,
+            scopes: scopes,
+            claims_request: claims_request,
+            created_at: created_at,
+            expires_at: expires_at,
+            revoked: false,
+        }
+    }
+    
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().find(|s| *s == scope).is_some()
+    }
+    
+    /// Revoked and expired tokens are rejected as invalid_token
+    pub fn check_live(&self, now: DateTime<UTC>) -> Result<()> {
+        if self.revoked {
+            Err(OpenIdConnectError::InvalidToken("access token has been revoked".to_owned()))
+        } else if self.expires_at < now {
+            Err(OpenIdConnectError::InvalidToken("access token has expired".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Record of an issued refresh token and the access tokens issued with it.
+/// token_id is a handle for showing the token to its user, so the token itself never leaves the token endpoint.
+#[derive(Clone, Debug)]
+pub struct RefreshTokenEntry {
+    pub token_id: String,
+    pub refresh_token: String,
+    pub user_id: String,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    pub access_token_ids: Vec<String>,
+    pub created_at: DateTime<UTC>,
+    pub revoked: bool,
+}
+
+impl RefreshTokenEntry {
+    pub fn new(refresh_token: String, user_id: String, client_id: String, scopes: Vec<String>, access_token_ids: Vec<String>, created_at: DateTime<UTC>) -> RefreshTokenEntry {
+        RefreshTokenEntry {
+            token_id: authentication::new_token(),
+            refresh_token: refresh_token,
+            user_id: user_id,
+            client_id: client_id,
+            scopes: scopes,
+            access_token_ids: access_token_ids,
+            created_at: created_at,
             revoked: false,
         }
     }
@@ -74,6 +162,8 @@ pub struct InMemoryTokenRepo {
     user_repo: Arc<Box<UserRepo>>,
     grant_repo: Arc<Box<GrantRepo>>,
     auth_entries: Arc<Mutex<Vec<AuthEntry>>>,
+    access_tokens: Arc<Mutex<Vec<AccessTokenEntry>>>,
+    refresh_tokens: Arc<Mutex<Vec<RefreshTokenEntry>>>,
 }
 
 impl InMemoryTokenRepo {
@@ -82,6 +172,8 @@ impl InMemoryTokenRepo {
             user_repo: user_repo,
             grant_repo: grant_repo,
             auth_entries: Arc::new(Mutex::new(vec![])),
+            access_tokens: Arc::new(Mutex::new(vec![])),
+            refresh_tokens: Arc::new(Mutex::new(vec![])),
         }
     }
     
@@ -109,7 +201,7 @@ impl InMemoryTokenRepo {
         
         claims.set_value("iss", &site_config.get_issuer());
         claims.set_value("aud", &client_id);
-        claims.set_value("sub", &user_id);
+        claims.set_value("sub", &try!(subject_for_client_id(req, user_id, client_id)));
         claims.set_value("nonce", &authentication::new_nonce());
         claims.set_value("exp", &later);
         claims.set_value("nbf", &now);
@@ -118,35 +210,93 @@ impl InMemoryTokenRepo {
         Ok(claims)
     }
     
-    pub fn create_access_token(req: &mut Request, user_id: &str, client_id: &str) -> Result<String> {
+    /// The sid claim identifies the user's session at the OP, for logout
+    fn current_sid(req: &mut Request) -> Option<String> {
+        UserSession::eval(req).unwrap_or(None).and_then(|s| s.sid)
+    }
+    
+    fn encode_id_token(mac_signer: &MacSigner, header: Header, mut claims: JwtClaims, sid: Option<&str>) -> Result<String> {
+        if let Some(sid) = sid {
+            claims.set_value("sid", &sid);
+        }
+        
+        Jwt::new(header, claims).encode(mac_signer).map_err(OpenIdConnectError::from)
+    }
+    
+    /// The jti of one of our access tokens
+    fn access_token_id(config: &Config, access_token: &str) -> Result<String> {
+        let jwt = try!(Jwt::decode(access_token, &config.mac_signer).map_err(|e| OpenIdConnectError::InvalidToken(format!("{}", e))));
+        let maybe_token_id = try!(jwt.claims.get_value::<String>("jti").map_err(|e| OpenIdConnectError::InvalidToken(format!("{}", e))));
+        
+        maybe_token_id.ok_or(OpenIdConnectError::InvalidToken("access token has no jti".to_owned()))
+    }
+    
+    /// Disabled users get no new tokens and their old ones stop working
+    fn user_enabled(&self, user_id: &str) -> Result<bool> {
+        Ok(try!(self.user_repo.get_user(user_id)).map(|u| !u.disabled).unwrap_or(false))
+    }
+    
+    /// The user's consent for the client.
+    /// If there isn't one, nothing has been allowed
+    fn find_grant(&self, user_id: &str, client_id: &str) -> Result<Grant> {
+        let maybe_grant = try!(self.grant_repo.find_grant(user_id, client_id));
+        
+        Ok(maybe_grant.unwrap_or_else(|| Grant::new(user_id.to_owned(), client_id.to_owned())))
+    }
+    
+    pub fn create_access_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest) -> Result<String> {
         let site_config = try!(SiteConfig::get(req));
         let config = try!(Config::get(req));
         
         let duration = site_config.get_token_duration();
+        let now = UTC::now();
+        let later = try!(now.checked_add(duration).ok_or(OpenIdConnectError::DateError));
+        let token_id = authentication::new_token();
+        
+        let grant = try!(self.find_grant(user_id, &authorize_request.client_id));
+        let scopes = grant.allowed_permissions(&authorize_request.scopes);
+        let claims_request = authorize_request.claims.as_ref().map(|c| grant.allowed_claims(&authorize_request.scopes, c));
         
         let header = Header::default();
-        let claims = try!(Self::get_basic_claims(req, user_id, client_id, duration));
+        let mut claims = try!(Self::get_basic_claims(req, user_id, &authorize_request.client_id, duration));
+        claims.set_value("jti", &token_id);
+        claims.set_value("scope", &scopes.join(" "));
         
         let jwt = Jwt::new(header, claims);
-        jwt.encode(&config.mac_signer).map_err(OpenIdConnectError::from)
+        let access_token = try!(jwt.encode(&config.mac_signer).map_err(OpenIdConnectError::from));
+        
+        let entry = AccessTokenEntry::new(token_id, user_id.to_owned(), authorize_request.client_id.clone(), scopes, claims_request, now, later);
+        self.access_tokens.lock().unwrap().push(entry);
+        
+        Ok(access_token)
     }
 }
 
 impl TokenRepo for InMemoryTokenRepo {    
-    fn get_user_claims(&self, req: &mut Request, user_id: &str, client_id: &str, _scopes: &[String]) -> Result<JwtClaims> {
-        let site_config = try!(SiteConfig::get(req));
-        let duration = site_config.get_token_duration();
-        
+    /// Claims are masked by the permissions the user granted to the client.
+    /// Anything the user denied is never released.
+    fn get_user_claim_values(&self, _req: &mut Request, user_id: &str, client_id: &str, scopes: &[String], claims_request: Option<&ClaimsRequest>, target: ClaimsTarget) -> Result<Map<String, Value>> {
         let maybe_user = try!(self.user_repo.get_user(user_id));
         let user = try!(maybe_user.ok_or(OpenIdConnectError::UserNotFound));
         
+        let grant = try!(self.find_grant(user_id, client_id));
+        let allowed_scopes = grant.allowed_permissions(scopes);
+        let allowed_claims = claims_request.map(|c| grant.allowed_claims(scopes, c));
+        
+        Ok(select_user_claims(&user, &allowed_scopes, allowed_claims.as_ref(), target))
+    }
+    
+    fn get_user_claims(&self, req: &mut Request, user_id: &str, client_id: &str, scopes: &[String], claims_request: Option<&ClaimsRequest>, target: ClaimsTarget) -> Result<JwtClaims> {
+        let site_config = try!(SiteConfig::get(req));
+        let duration = site_config.get_token_duration();
+        
+        let claim_values = try!(self.get_user_claim_values(req, user_id, client_id, scopes, claims_request, target));
 
         let mut claims = try!(Self::get_basic_claims(req, user_id, client_id, duration));
         
-        claims.set_value("name", &user.username);
-        //TODO more claims
-        
-        //TODO mask claims with granted permissions
+        for (name, value) in claim_values {
+            claims.set_value(&name, &value);
+        }
         
         Ok(claims)
     }
@@ -160,10 +310,13 @@ impl TokenRepo for InMemoryTokenRepo {
         
         let token = try!(self.create_code_token(req, user_id, authorize_request));
         
-        let auth_code = AuthCode::new(token.code.clone().unwrap(), now, later);
-        let auth_entry = AuthEntry::new(user_id.to_owned(), auth_code, authorize_request.to_owned(), token.clone());
+        // the code entry carries the authorize request, including any claims request, through to /token
+        if let Some(ref code) = token.code {
+            let auth_code = AuthCode::new(code.clone(), now, later);
+            let auth_entry = AuthEntry::new(user_id.to_owned(), auth_code, authorize_request.to_owned(), token.clone(), Self::current_sid(req));
         
-        auth_entries.push(auth_entry);
+            auth_entries.push(auth_entry);
+        }
         
         Ok(token)
     }
@@ -183,7 +336,7 @@ impl TokenRepo for InMemoryTokenRepo {
         };
         
         let access_token = if authorize_request.response_type.token {
-            Some(try!(Self::create_access_token(req, user_id, &authorize_request.client_id)))
+            Some(try!(self.create_access_token(req, user_id, authorize_request)))
         } else {
             None
         };
@@ -199,15 +352,15 @@ impl TokenRepo for InMemoryTokenRepo {
         
         let id_token = if authorize_request.response_type.id_token {
             let header = Header::default();
-            let mut claims = try!(self.get_user_claims(req, user_id, &authorize_request.client_id, &authorize_request.scopes));
+            let mut claims = try!(self.get_user_claims(req, user_id, &authorize_request.client_id, &authorize_request.scopes, authorize_request.claims.as_ref(), ClaimsTarget::IdToken));
             if let Some(ref at) = access_token {
                 claims.set_value("at_hash", &try!(Self::half_hash_value(config.clone(), &header, at)));
             }
             if let Some(ref c) = code {
                 claims.set_value("c_hash", &try!(Self::half_hash_value(config.clone(), &header, c)));
             }
-            let jwt = Jwt::new(header, claims);
-            Some(try!(jwt.encode(&config.mac_signer).map_err(OpenIdConnectError::from)))
+            let sid = Self::current_sid(req);
+            Some(try!(Self::encode_id_token(&config.mac_signer, header, claims, sid.as_ref().map(|s| &s[..]))))
         } else {
             None
         };
@@ -219,28 +372,30 @@ impl TokenRepo for InMemoryTokenRepo {
     
     /// Called from /token to exchange a code for a token and id_token 
     /// in the Authorization Code flow and hybrid flow.
-    fn create_auth_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest, code_token: Token) -> Result<Token> {
+    fn create_auth_token(&self, req: &mut Request, user_id: &str, authorize_request: &AuthorizeRequest, code_token: Token, sid: Option<&str>) -> Result<Token> {
         let config = try!(Config::get(req));
         let site_config = try!(SiteConfig::get(req));
         let expires_in = site_config.get_token_duration();
         let state = authorize_request.state.clone();
         let code = None;
-        let access_token = try!(code_token.access_token.map(|at| Ok(at)).or_else(|| Some(Self::create_access_token(req, user_id, &authorize_request.client_id))).unwrap());
-        let refresh_token = code_token.refresh_token.or_else(|| Some(authentication::new_token()));
+        let access_token = try!(code_token.access_token.map(|at| Ok(at)).or_else(|| Some(self.create_access_token(req, user_id, authorize_request))).unwrap());
+        let refresh_token = code_token.refresh_token.unwrap_or_else(|| authentication::new_token());
         let token_type = code_token.token_type.or_else(|| Some(TokenType::Bearer));
         
         let id_token = if authorize_request.scopes.contains(&"openid".to_owned()) {
             let header = Header::default();
-            let mut claims = try!(self.get_user_claims(req, user_id, &authorize_request.client_id, &authorize_request.scopes));
+            let mut claims = try!(self.get_user_claims(req, user_id, &authorize_request.client_id, &authorize_request.scopes, authorize_request.claims.as_ref(), ClaimsTarget::IdToken));
             claims.set_value("at_hash", &try!(Self::half_hash_value(config.clone(), &header, &access_token)));
-
-            let jwt = Jwt::new(header, claims);
-            Some(try!(jwt.encode(&config.mac_signer).map_err(OpenIdConnectError::from)))
+            Some(try!(Self::encode_id_token(&config.mac_signer, header, claims, sid)))
         } else {
             None
         };
         
-        let token = Token::new(code, token_type, Some(access_token), refresh_token, expires_in.into(), id_token, state);
+        let access_token_id = try!(Self::access_token_id(&config, &access_token));
+        let refresh_entry = RefreshTokenEntry::new(refresh_token.clone(), user_id.to_owned(), authorize_request.client_id.clone(), authorize_request.scopes.clone(), vec![access_token_id], UTC::now());
+        self.refresh_tokens.lock().unwrap().push(refresh_entry);
+        
+        let token = Token::new(code, token_type, Some(access_token), Some(refresh_token), expires_in.into(), id_token, state);
         
         Ok(token)
     }
@@ -253,12 +408,18 @@ impl TokenRepo for InMemoryTokenRepo {
                 .find(|c| c.code.code == code);
         
         if let Some(auth_entry) = auth_entry {
+            if !try!(self.user_enabled(&auth_entry.user_id)) {
+                auth_entry.revoked = true;
+                
+                return Err(OpenIdConnectError::AuthCodeError);
+            }
+            
             if auth_entry.code.exchanged == false && !auth_entry.revoked {
                 auth_entry.code.exchanged = true;
             
                 let authorize_request = &auth_entry.authorize_request;
                          
-                auth_entry.token = try!(self.create_auth_token(req, &auth_entry.user_id, &authorize_request, auth_entry.token.clone()));
+                auth_entry.token = try!(self.create_auth_token(req, &auth_entry.user_id, &authorize_request, auth_entry.token.clone(), auth_entry.sid.as_ref().map(|s| &s[..])));
                 
                 Ok(auth_entry.token.clone())
             } else {
@@ -272,4 +433,156 @@ impl TokenRepo for InMemoryTokenRepo {
             Err(OpenIdConnectError::AuthCodeError)
         }
     }
-}
\ No newline at end of file
+    
+    fn verify_access_token(&self, req: &mut Request, access_token: &str) -> Result<AccessTokenEntry> {
+        let config = try!(Config::get(req));
+        
+        let token_id = try!(Self::access_token_id(&config, access_token));
+        
+        let access_tokens = self.access_tokens.lock().unwrap();
+        
+        let entry = try!(access_tokens
+                .iter()
+                .find(|t| t.token_id == token_id)
+                .ok_or(OpenIdConnectError::InvalidToken("access token not recognised".to_owned())));
+        
+        try!(entry.check_live(UTC::now()));
+        
+        if !try!(self.user_enabled(&entry.user_id)) {
+            Err(OpenIdConnectError::InvalidToken("user has been disabled".to_owned()))
+        } else {
+            Ok(entry.clone())
+        }
+    }
+    
+    /// Live refresh tokens, i.e. not revoked
+    fn get_user_refresh_tokens(&self, user_id: &str) -> Result<Vec<RefreshTokenEntry>> {
+        let refresh_tokens = self.refresh_tokens.lock().unwrap();
+        
+        Ok(refresh_tokens.iter().filter(|t| t.user_id == user_id && !t.revoked).map(|t| t.clone()).collect())
+    }
+    
+    /// Revoking a refresh token also revokes the access tokens issued with it
+    fn revoke_refresh_token(&self, user_id: &str, token_id: &str) -> Result<()> {
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        
+        let entry = try!(refresh_tokens.iter_mut()
+                .find(|t| t.token_id == token_id && t.user_id == user_id)
+                .ok_or(OpenIdConnectError::TokenNotFound));
+        
+        entry.revoked = true;
+        
+        let mut access_tokens = self.access_tokens.lock().unwrap();
+        
+        for access_token in access_tokens.iter_mut().filter(|t| entry.access_token_ids.contains(&t.token_id)) {
+            access_token.revoked = true;
+        }
+        
+        Ok(())
+    }
+    
+    /// Revokes everything issued to the user and drops their unexchanged codes
+    fn revoke_user_tokens(&self, user_id: &str) -> Result<()> {
+        let mut auth_entries = self.auth_entries.lock().unwrap();
+        let remaining: Vec<AuthEntry> = auth_entries.iter().filter(|e| e.user_id != user_id).map(|e| e.clone()).collect();
+        *auth_entries = remaining;
+        
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        for refresh_token in refresh_tokens.iter_mut().filter(|t| t.user_id == user_id) {
+            refresh_token.revoked = true;
+        }
+        
+        let mut access_tokens = self.access_tokens.lock().unwrap();
+        for access_token in access_tokens.iter_mut().filter(|t| t.user_id == user_id) {
+            access_token.revoked = true;
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    
+    use chrono::*;
+    use jsonwebtoken::jwt::*;
+    use jsonwebtoken::json::*;
+    use jsonwebtoken::header::*;
+    use jsonwebtoken::crypto::mac_signer::MacSigner;
+    
+    use super::*;
+    use result::OpenIdConnectError;
+    use oauth2::models::*;
+    use users::{UserRepo, InMemoryUserRepo};
+    use oauth2::repos::{GrantRepo, InMemoryGrantRepo};
+    
+    fn entry(expires_at: DateTime<UTC>) -> AccessTokenEntry {
+        AccessTokenEntry::new("jti".to_owned(), "user".to_owned(), "client".to_owned(), vec!["openid".to_owned()], None, UTC::now(), expires_at)
+    }
+    
+    #[test]
+    fn test_check_live() {
+        let now = UTC::now();
+        
+        assert!(entry(now + Duration::minutes(5)).check_live(now).is_ok());
+        
+        match entry(now - Duration::minutes(5)).check_live(now) {
+            Err(OpenIdConnectError::InvalidToken(_)) => {},
+            other => panic!("expected an invalid token, got {:?}", other),
+        }
+        
+        let mut revoked = entry(now + Duration::minutes(5));
+        revoked.revoked = true;
+        
+        match revoked.check_live(now) {
+            Err(OpenIdConnectError::InvalidToken(_)) => {},
+            other => panic!("expected an invalid token, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_find_grant() {
+        let grant_repo = Arc::new(Box::new(InMemoryGrantRepo::new()) as Box<GrantRepo>);
+        let user_repo = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        let repo = InMemoryTokenRepo::new(user_repo, grant_repo.clone());
+        
+        let requested = vec!["openid".to_owned(), "email".to_owned()];
+        
+        let grant = repo.find_grant("user", "client").unwrap();
+        assert!(grant.allowed_permissions(&requested).is_empty());
+        
+        let mut update = GrantUpdate::new("user", "client");
+        update.permissions_requested = requested.clone();
+        update.permissions_added = vec!["openid".to_owned()];
+        update.permissions_removed = vec!["email".to_owned()];
+        grant_repo.create_or_update_grant(update).unwrap();
+        
+        let grant = repo.find_grant("user", "client").unwrap();
+        assert_eq!(grant.allowed_permissions(&requested), vec!["openid".to_owned()]);
+        
+        let mut claims_request = ClaimsRequest::new();
+        claims_request.userinfo.insert("email".to_owned(), None);
+        assert!(grant.allowed_claims(&requested, &claims_request).userinfo.is_empty());
+        
+        assert!(repo.find_grant("user", "other client").unwrap().allowed_permissions(&requested).is_empty());
+    }
+    
+    #[test]
+    fn test_id_token_sid() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let claims = || {
+            let mut claims = JwtClaims::new();
+            claims.set_value("sub", &"user");
+            claims
+        };
+        
+        let id_token = InMemoryTokenRepo::encode_id_token(&mac_signer, Header::default(), claims(), Some("session")).unwrap();
+        let jwt = Jwt::decode(&id_token, &mac_signer).unwrap();
+        assert_eq!(jwt.claims.get_value::<String>("sid").unwrap().unwrap(), "session");
+        
+        let id_token = InMemoryTokenRepo::encode_id_token(&mac_signer, Header::default(), claims(), None).unwrap();
+        let jwt = Jwt::decode(&id_token, &mac_signer).unwrap();
+        assert!(jwt.claims.get_value::<String>("sid").unwrap().is_none());
+    }
+}
@@ -13,7 +13,9 @@ use response_mode::*;
 use config::Config;
 use sessions::UserSession;
 use oauth2::models::authorize_request::*;
-use oauth2::routes::consent::consent_path;
+use oauth2::models::grant::Grant;
+use oauth2::session_state::session_state;
+use oauth2::routes::consent::{consent_path, consent_permissions};
 use service::routes::login::login_path;
 
 pub fn auth_redirect_url(req: &mut Request, path: &str, authorize_request: &AuthorizeRequest) -> Result<iron::Url> {
@@ -37,9 +39,15 @@ pub fn auth_return_to_client_url(req: &mut Request, user_id: &str, authorize_req
     let base_uri = &authorize_request.redirect_uri;
     let mut uri = try!(url::Url::parse(base_uri));
     
-    let token = try!(config.token_repo.create_code_token(req, user_id, authorize_request));
+    let token = try!(config.token_repo.create_auth_code(req, user_id, authorize_request));
     
-    let query_pairs = try!(token.query_pairs());
+    let mut query_pairs = try!(token.query_pairs());
+    
+    if authorize_request.scopes.iter().any(|s| s == "openid") {
+        let browser_state = config.session_controller.ensure_browser_state(req);
+        
+        query_pairs.push(("session_state".to_owned(), try!(session_state(&authorize_request.client_id, base_uri, &browser_state))));
+    }
     
     if ResponseMode::Query == authorize_request.response_mode.unwrap_or(
             ResponseMode::default_for_response_type(authorize_request.response_type)) {
@@ -51,15 +59,20 @@ pub fn auth_return_to_client_url(req: &mut Request, user_id: &str, authorize_req
     Ok(uri.to_string())
 }
 
-pub fn should_prompt(authorize_request: &AuthorizeRequest) -> bool {
+/// true if the client asked for the consent page to be shown regardless of any existing grant
+pub fn prompt_for_consent(authorize_request: &AuthorizeRequest) -> bool {
     // TODO match boolean truthy strings?
-    if authorize_request.prompt.as_ref().map(|s| &s[..]).unwrap_or("") == "true" {
+    authorize_request.prompt.as_ref()
+        .map(|s| s == "true" || s.split(' ').any(|p| p == "consent"))
+        .unwrap_or(false)
+}
+
+pub fn should_prompt(authorize_request: &AuthorizeRequest, grant: Option<&Grant>) -> bool {
+    if prompt_for_consent(authorize_request) {
         return true;
     }
     
-    // TODO match requested scopes against granted scopes
-    
-    true
+    !consent_permissions(authorize_request, grant).is_empty()
 }
 
 pub fn authorize_path() -> &'static str {
@@ -82,17 +95,29 @@ pub fn authorize_handler(req: &mut Request) -> IronResult<Response> {
     debug!("authorize: {:?}", authorize_request);
     
     let session = try!(UserSession::eval(req));
-    let authenticated = session.map(|s| s.authenticated).unwrap_or(false);
+    let authenticated = session.as_ref().map(|s| s.authenticated).unwrap_or(false);
     
     if !authenticated {
         let url = try!(auth_redirect_url(req, login_path(), &authorize_request.request));
     
-        Ok(Response::with((status::Found, Redirect(url))))
-    } else if should_prompt(&authorize_request.request) {
+        return Ok(Response::with((status::Found, Redirect(url))));
+    }
+    
+    let config = try!(Config::get(req));
+    let user_session = try!(session.ok_or(OpenIdConnectError::NoSessionLoaded));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+    let grant = try!(config.grant_repo.find_grant(&user_id, &authorize_request.request.client_id));
+    
+    if should_prompt(&authorize_request.request, grant.as_ref()) {
         let consent_url = try!(auth_consent_url(req, &authorize_request.request));
         
         Ok(Response::with((status::Found, Redirect(consent_url))))
     } else {
+        // everything requested was allowed before
+        if let Some(ref grant) = grant {
+            try!(config.grant_repo.touch_grant(grant));
+        }
+        
         let complete_url = try!(auth_complete_url(req, &authorize_request.request));
         
         Ok(Response::with((status::Found, Redirect(complete_url))))
@@ -117,10 +142,15 @@ pub fn complete_handler(req: &mut Request) -> IronResult<Response> {
     
         Ok(Response::with((status::Found, Redirect(url))))
     } else {
+        let config = try!(Config::get(req));
         let user_session = try!(session.ok_or(OpenIdConnectError::NoSessionLoaded));
-        let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+        let user_id = try!(user_session.user_id.clone().ok_or(OpenIdConnectError::UserNotFound));
+        
+        let return_url = try!(auth_return_to_client_url(req, &user_id, &authorize_request.request));
+        
+        try!(config.session_controller.add_session_client(&user_session, &authorize_request.request.client_id));
     
-        Ok(Response::with((status::Found, RoidcRedirectRaw(try!(auth_return_to_client_url(req, &user_id, &authorize_request.request))))))
+        Ok(Response::with((status::Found, RoidcRedirectRaw(return_url))))
     }
 }
 
@@ -130,6 +160,7 @@ mod test {
     use oauth2::models::*;
     use serde_json;
     use response_type::*;
+    use oauth2::routes::consent::consent_permissions;
     
     #[test]
     fn test_auth_serialisation() {
@@ -150,4 +181,41 @@ mod test {
         
         assert_eq!(auth.client_id, "foo");
     }
+    
+    #[test]
+    fn test_should_prompt() {
+        let mut auth = AuthorizeRequest::new(ResponseType::new(true, false, false), "client_id#1234567".to_owned(), "redirect_uri#oob".to_owned());
+        auth.scopes = vec!["openid".to_owned(), "email".to_owned()];
+        
+        assert!(should_prompt(&auth, None));
+        
+        let mut update = GrantUpdate::new("user_id", "client_id#1234567");
+        update.permissions_added = vec!["openid".to_owned()];
+        let mut grant = Grant::new_for_update(update);
+        
+        assert!(should_prompt(&auth, Some(&grant)));
+        
+        grant.permissions_allowed.push("email".to_owned());
+        
+        assert!(!should_prompt(&auth, Some(&grant)));
+        
+        // a denied scope has been answered, so asking for it again doesn't bring the consent page back
+        let mut update = GrantUpdate::new("user_id", "client_id#1234567");
+        update.permissions_added = vec!["openid".to_owned()];
+        update.permissions_removed = vec!["email".to_owned()];
+        let denied = Grant::new_for_update(update);
+        
+        assert!(!should_prompt(&auth, Some(&denied)));
+        
+        auth.scopes.push("phone".to_owned());
+        
+        assert!(should_prompt(&auth, Some(&denied)));
+        assert_eq!(consent_permissions(&auth, Some(&denied)), vec!["phone".to_owned()]);
+        
+        auth.scopes.pop();
+        
+        auth.prompt = Some("login consent".to_owned());
+        
+        assert!(should_prompt(&auth, Some(&grant)));
+    }
 }
\ No newline at end of file
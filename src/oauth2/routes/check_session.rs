@@ -0,0 +1,28 @@
+use iron::prelude::*;
+use iron::status;
+use serde_json::value;
+
+use result::Result;
+use view::View;
+use sessions::BROWSER_STATE_COOKIE;
+
+/// OP iframe for Session Management
+/// See http://openid.net/specs/openid-connect-session-1_0.html#OPiframe
+/// Loaded by the RP's own iframe, which polls it with postMessage("client_id session_state").
+/// All the work happens in the browser, so there's no session lookup or layout.
+fn check_session_view() -> Result<View> {
+    let mut view = View::new("check_session.html", None);
+    view.layout = None;
+
+    view.data.insert("browser_state_cookie".to_owned(), try!(value::to_value(&BROWSER_STATE_COOKIE)));
+
+    Ok(view)
+}
+
+pub fn check_session_get_handler(_req: &mut Request) -> IronResult<Response> {
+    debug!("/connect/checksession");
+
+    let view = try!(check_session_view());
+
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
@@ -16,20 +16,56 @@ use config::Config;
 use view::View;
 use back::*;
 use sessions::UserSession;
-use oauth2::routes::authorize::{authorize_path, complete_path, auth_redirect_url};
+use oauth2::routes::authorize::{authorize_path, complete_path, auth_redirect_url, prompt_for_consent};
 use oauth2::models::*;
 use oauth2::repos::*;
 use service::routes::login::login_path;
+use i18n::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Permission {
     pub name: String,
+    pub description: String,
 }
 
 impl Permission {
     pub fn new(name: &str) -> Permission {
         Permission {
             name: name.to_owned(),
+            description: name.to_owned(),
+        }
+    }
+    
+    /// uses the permission_<name> message if there is one
+    pub fn localize(&mut self, messages: &HashMap<String, String>) {
+        if let Some(description) = messages.get(&format!("permission_{}", self.name)) {
+            self.description = description.to_owned();
+        }
+    }
+}
+
+/// Client metadata for the consent page, in the user's language if the client registered it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentClientView {
+    pub client_id: String,
+    pub client_name: String,
+    pub logo_uri: Option<String>,
+    pub client_uri: Option<String>,
+    pub policy_uri: Option<String>,
+    pub tos_uri: Option<String>,
+}
+
+impl ConsentClientView {
+    pub fn new(client: &ClientApplication, preferred: &[String]) -> ConsentClientView {
+        ConsentClientView {
+            client_id: client.client_id.clone(),
+            client_name: localized(client.client_name.as_ref(), &client.client_name_i18n, preferred)
+                .map(|s| s.to_owned())
+                .unwrap_or(client.client_id.clone()),
+            logo_uri: localized(client.logo_uri.as_ref(), &client.logo_uri_i18n, preferred).map(|s| s.to_owned()),
+            client_uri: localized(client.client_uri.as_ref(), &client.client_uri_i18n, preferred).map(|s| s.to_owned()),
+            policy_uri: localized(client.policy_uri.as_ref(), &client.policy_uri_i18n, preferred).map(|s| s.to_owned()),
+            tos_uri: localized(client.tos_uri.as_ref(), &client.tos_uri_i18n, preferred).map(|s| s.to_owned()),
         }
     }
 }
@@ -38,6 +74,34 @@ pub fn permissions_for_scopes(scopes: &[String]) -> Vec<Permission> {
     scopes.iter().map(|s| Permission::new(s)).collect()
 }
 
+/// The scopes asked for, plus any individually requested claims not already covered by them
+pub fn requested_permissions(authorize_request: &AuthorizeRequest) -> Vec<String> {
+    let mut perms = authorize_request.scopes.clone();
+    
+    if let Some(ref claims) = authorize_request.claims {
+        let scope_claims = claims_for_scopes(&authorize_request.scopes);
+        
+        for name in claims.claim_names() {
+            if !scope_claims.contains(&name) && !perms.contains(&name) {
+                perms.push(name);
+            }
+        }
+    }
+    
+    perms
+}
+
+/// What the consent page asks about: everything requested when the client is forcing the prompt,
+/// otherwise only the permissions the user hasn't already allowed or denied
+pub fn consent_permissions(authorize_request: &AuthorizeRequest, grant: Option<&Grant>) -> Vec<String> {
+    let requested = requested_permissions(authorize_request);
+    
+    match grant {
+        Some(grant) if !prompt_for_consent(authorize_request) => grant.unanswered_permissions(&requested),
+        _ => requested,
+    }
+}
+
 pub fn consent_redirect_url(req: &mut Request, path: &str, authorize_request: &AuthorizeRequest) -> Result<iron::Url> {
     redirect_forwards_url(req, consent_path(), path, authorize_request.to_params())
 }
@@ -54,7 +118,7 @@ pub fn consent_path() -> &'static str {
 pub fn consent_get_handler(req: &mut Request) -> IronResult<Response> {
     let config = try!(Config::get(req));
     let session = try!(UserSession::eval(req));
-    let authenticated = session.map(|s| s.authenticated).unwrap_or(false); 
+    let authenticated = session.as_ref().map(|s| s.authenticated).unwrap_or(false); 
     let mut view = try!(View::new_for_session("consent.html", req));
      
     let params = try!(req.get::<UrlEncodedQuery>().map_err(OpenIdConnectError::from));
@@ -68,8 +132,25 @@ pub fn consent_get_handler(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with((status::Found, Redirect(url))));
     }
        
-    view.data.insert("permissions".to_owned(), try!(value::to_value(&permissions_for_scopes(&authorize_request.request.scopes)).map_err(OpenIdConnectError::from)));
-    view.data.insert("client".to_owned(), try!(value::to_value(&authorize_request.client).map_err(OpenIdConnectError::from)));
+    let user_session = try!(session.ok_or(OpenIdConnectError::NoSessionLoaded));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+    let grant = try!(config.grant_repo.find_grant(&user_id, &authorize_request.request.client_id));
+    let permissions = consent_permissions(&authorize_request.request, grant.as_ref());
+    
+    let ui_locales = authorize_request.request.ui_locales.clone();
+    view.localize(req, ui_locales.as_ref().map(|s| &s[..]));
+    view.set_display(authorize_request.request.display.as_ref().map(|s| &s[..]));
+    let preferred_locales = requested_locales(req, ui_locales.as_ref().map(|s| &s[..]));
+    
+    let mut permissions = permissions_for_scopes(&permissions);
+    for p in permissions.iter_mut() {
+        p.localize(&view.messages);
+    }
+    
+    let client = try!(authorize_request.client.as_ref().ok_or(OpenIdConnectError::ClientApplicationNotFound));
+       
+    view.data.insert("permissions".to_owned(), try!(value::to_value(&permissions).map_err(OpenIdConnectError::from)));
+    view.data.insert("client".to_owned(), try!(value::to_value(&ConsentClientView::new(client, &preferred_locales)).map_err(OpenIdConnectError::from)));
     
     let return_token = RedirectToken::new_for_path_and_params(authorize_path(), &authorize_request.request.to_params());
     // view.data.insert("return".to_owned(), value::to_value(&return_token));
@@ -106,12 +187,18 @@ pub fn consent_post_handler(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with((status::Found, Redirect(url))));
     }
     
-    // TODO save granted permissions
+    // whatever was offered on the consent page and left unchecked is denied
     let user_session = try!(session.ok_or(OpenIdConnectError::NoSessionLoaded));
     let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+    let grant = try!(config.grant_repo.find_grant(&user_id, &authorize_request.request.client_id));
+    let shown = consent_permissions(&authorize_request.request, grant.as_ref());
+    let checked = multimap_get_maybe(&params, "permissions").map(|p| p.clone()).unwrap_or(vec![]);
     let mut update = GrantUpdate::new(user_id, authorize_request.request.client_id.clone());
-    update.permissions_added = multimap_get_maybe(&params, "permissions").map(|p| p.clone()).unwrap_or(vec![]);
-    try!(config.grant_repo.create_or_update_grant(update));
+    update.permissions_added = shown.iter().filter(|p| checked.contains(p)).map(|p| p.to_owned()).collect();
+    update.permissions_removed = shown.iter().filter(|p| !checked.contains(p)).map(|p| p.to_owned()).collect();
+    update.permissions_requested = requested_permissions(&authorize_request.request);
+    let grant = try!(config.grant_repo.create_or_update_grant(update));
+    debug!("consent saved: {:?} partial: {}", grant, grant.is_partial());
     
     let redirect_params = return_params(try!(authorize_request.request.encode("authorize", &config.mac_signer)));
     let return_uri = try!(relative_url(req, complete_path(), Some(redirect_params)));
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::status;
+use iron::modifiers::Redirect;
+use urlencoded::*;
+use serde_json::value;
+use url;
+use jsonwebtoken::jwt::*;
+use jsonwebtoken::json::*;
+
+use rbvt::params::*;
+use result::{Result, OpenIdConnectError};
+use urls::*;
+use config::Config;
+use site_config::*;
+use view::View;
+use csrf::csrf_token_matches;
+use sessions::UserSession;
+use plugin::Plugin as PluginPlugin;
+use oauth2::models::*;
+use oauth2::subject::subject_for_client;
+
+/// RP-Initiated Logout
+/// See http://openid.net/specs/openid-connect-session-1_0.html#RPLogout
+
+#[derive(Clone, Debug)]
+pub struct EndSessionRequest {
+    pub id_token_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub post_logout_redirect_uri: Option<String>,
+    pub state: Option<String>,
+    pub ui_locales: Option<String>,
+}
+
+impl EndSessionRequest {
+    pub fn new() -> EndSessionRequest {
+        EndSessionRequest {
+            id_token_hint: None,
+            client_id: None,
+            post_logout_redirect_uri: None,
+            state: None,
+            ui_locales: None,
+        }
+    }
+
+    pub fn from_params(params: &HashMap<String, Vec<String>>) -> Result<EndSessionRequest> {
+        Ok(EndSessionRequest {
+            id_token_hint: try!(multimap_get_maybe_one(params, "id_token_hint")).map(|s| s.to_owned()),
+            client_id: try!(multimap_get_maybe_one(params, "client_id")).map(|s| s.to_owned()),
+            post_logout_redirect_uri: try!(multimap_get_maybe_one(params, "post_logout_redirect_uri")).map(|s| s.to_owned()),
+            state: try!(multimap_get_maybe_one(params, "state")).map(|s| s.to_owned()),
+            ui_locales: try!(multimap_get_maybe_one(params, "ui_locales")).map(|s| s.to_owned()),
+        })
+    }
+
+    pub fn to_params(&self) -> HashMap<String, Vec<String>> {
+        let mut params = HashMap::new();
+        if let Some(ref id_token_hint) = self.id_token_hint {
+            params.insert("id_token_hint".to_owned(), vec![id_token_hint.to_owned()]);
+        }
+        if let Some(ref client_id) = self.client_id {
+            params.insert("client_id".to_owned(), vec![client_id.to_owned()]);
+        }
+        if let Some(ref post_logout_redirect_uri) = self.post_logout_redirect_uri {
+            params.insert("post_logout_redirect_uri".to_owned(), vec![post_logout_redirect_uri.to_owned()]);
+        }
+        if let Some(ref state) = self.state {
+            params.insert("state".to_owned(), vec![state.to_owned()]);
+        }
+        if let Some(ref ui_locales) = self.ui_locales {
+            params.insert("ui_locales".to_owned(), vec![ui_locales.to_owned()]);
+        }
+        params
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HiddenField {
+    pub name: String,
+    pub value: String,
+}
+
+pub fn end_session_path() -> &'static str {
+    "/connect/endsession"
+}
+
+/// The client the logout is for, and the sub from the id_token_hint.
+/// The hint may have expired, but it must be one of ours.
+fn resolve_client(req: &mut Request, end_session_request: &EndSessionRequest) -> Result<(Option<ClientApplication>, Option<String>)> {
+    let config = try!(Config::get(req));
+
+    let mut client_id = end_session_request.client_id.clone();
+    let mut hint_subject = None;
+
+    if let Some(ref id_token_hint) = end_session_request.id_token_hint {
+        let jwt = try!(Jwt::decode(id_token_hint, &config.mac_signer).map_err(|e| OpenIdConnectError::InvalidRequest(format!("id_token_hint is not valid: {}", e))));
+        let aud = try!(jwt.claims.get_value::<String>("aud").map_err(|e| OpenIdConnectError::InvalidRequest(format!("id_token_hint is not valid: {}", e))));
+
+        if let (Some(aud), Some(client_id)) = (aud.as_ref(), client_id.as_ref()) {
+            if aud != client_id {
+                return Err(OpenIdConnectError::InvalidRequest("client_id does not match id_token_hint".to_owned()));
+            }
+        }
+
+        client_id = client_id.or(aud);
+        hint_subject = try!(jwt.claims.get_value::<String>("sub").map_err(|e| OpenIdConnectError::InvalidRequest(format!("id_token_hint is not valid: {}", e))));
+    }
+
+    let client = match client_id {
+        Some(ref client_id) => Some(try!(try!(config.application_repo.find_client_application(client_id)).ok_or(OpenIdConnectError::ClientApplicationNotFound))),
+        None => None,
+    };
+
+    Ok((client, hint_subject))
+}
+
+/// post_logout_redirect_uri must be registered by the client, and state is passed back on it
+pub fn post_logout_redirect_url(end_session_request: &EndSessionRequest, client: Option<&ClientApplication>) -> Result<Option<String>> {
+    if let Some(ref post_logout_redirect_uri) = end_session_request.post_logout_redirect_uri {
+        let client = try!(client.ok_or(OpenIdConnectError::InvalidRequest("post_logout_redirect_uri needs an id_token_hint or client_id".to_owned())));
+
+        if !client.match_post_logout_redirect_uri(post_logout_redirect_uri) {
+            return Err(OpenIdConnectError::InvalidRedirectUri);
+        }
+
+        let mut uri = try!(url::Url::parse(post_logout_redirect_uri));
+
+        if let Some(ref state) = end_session_request.state {
+            uri.query_pairs_mut().append_pair("state", state);
+        }
+
+        Ok(Some(uri.to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// true if the id_token_hint was issued to the user,
+/// comparing subjects as the client sees them
+pub fn hint_matches_user(site_config: &SiteConfig, client: Option<&ClientApplication>, hint_subject: Option<&String>, user_id: Option<&String>) -> Result<bool> {
+    match (client, hint_subject, user_id) {
+        (Some(client), Some(hint_subject), Some(user_id)) => {
+            Ok(*hint_subject == try!(subject_for_client(site_config, client, user_id)))
+        },
+        _ => Ok(false)
+    }
+}
+
+/// true if the id_token_hint was issued to the logged in user,
+/// in which case the RP is trusted to have asked for the logout on the user's behalf
+fn hint_matches_session(req: &mut Request, client: Option<&ClientApplication>, hint_subject: Option<&String>, session: &UserSession) -> Result<bool> {
+    let site_config = try!(SiteConfig::get(req));
+
+    hint_matches_user(&site_config, client, hint_subject, session.user_id.as_ref())
+}
+
+/// Front-Channel Logout
+/// See http://openid.net/specs/openid-connect-frontchannel-1_0.html
+pub fn frontchannel_logout_url(client: &ClientApplication, issuer: &str, session: &UserSession) -> Result<Option<String>> {
+    if let Some(ref frontchannel_logout_uri) = client.frontchannel_logout_uri {
+        let mut uri = try!(url::Url::parse(frontchannel_logout_uri));
+        
+        if let Some(ref sid) = session.sid {
+            uri.query_pairs_mut()
+                .append_pair("iss", issuer)
+                .append_pair("sid", sid);
+        } else if client.frontchannel_logout_session_required {
+            return Ok(None);
+        }
+        
+        Ok(Some(uri.to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Logout urls for the clients that were issued tokens in the session, to be loaded in iframes
+pub fn frontchannel_logout_urls(req: &mut Request, session: &UserSession) -> Result<Vec<String>> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    let issuer = site_config.get_issuer();
+    
+    let mut urls = vec![];
+    
+    for client_id in &session.clients {
+        if let Some(client) = try!(config.application_repo.find_client_application(client_id)) {
+            if let Some(url) = try!(frontchannel_logout_url(&client, &issuer, session)) {
+                urls.push(url);
+            }
+        }
+    }
+    
+    Ok(urls)
+}
+
+/// Ends the user's session at the OP.
+/// The session controller sends the back-channel logout tokens, as it does however a session ends.
+pub fn end_user_session(req: &mut Request, session: &UserSession) -> Result<()> {
+    let config = try!(Config::get(req));
+
+    debug!("ending session {:?}", session.session_id);
+
+    try!(config.session_controller.clear_session(req));
+
+    Ok(())
+}
+
+fn confirmation_view(req: &mut Request, end_session_request: &EndSessionRequest, client: Option<&ClientApplication>) -> Result<Response> {
+    let mut view = try!(View::new_for_session("logout.html", req));
+    view.localize(req, end_session_request.ui_locales.as_ref().map(|s| &s[..]));
+
+    let fields: Vec<HiddenField> = end_session_request.to_params().into_iter()
+        .flat_map(|(name, values)| values.into_iter().map(move |value| HiddenField { name: name.clone(), value: value }))
+        .collect();
+
+    view.data.insert("fields".to_owned(), try!(value::to_value(&fields)));
+    view.data.insert("client_name".to_owned(), try!(value::to_value(&client.and_then(|c| c.client_name.clone()))));
+
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+fn logged_out_view(req: &mut Request, end_session_request: &EndSessionRequest, logout_urls: &[String], redirect_url: Option<&String>) -> Result<Response> {
+    let mut view = try!(View::new_for_session("logged_out.html", req));
+    view.localize(req, end_session_request.ui_locales.as_ref().map(|s| &s[..]));
+    
+    view.data.insert("frontchannel_logout_urls".to_owned(), try!(value::to_value(&logout_urls)));
+    view.data.insert("redirect_url".to_owned(), try!(value::to_value(&redirect_url)));
+
+    // the cookie has been cleared
+    view.session = None;
+
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+pub fn end_session_response(req: &mut Request, params: &HashMap<String, Vec<String>>) -> Result<Response> {
+    let end_session_request = try!(EndSessionRequest::from_params(params));
+    debug!("end session: {:?}", end_session_request);
+
+    if try!(multimap_get_maybe_one(params, "cancel")).is_some() {
+        let home_url = try!(relative_url(req, "/", None));
+
+        return Ok(Response::with((status::Found, Redirect(home_url))));
+    }
+
+    // only our own confirmation page can confirm
+    let confirmed = try!(multimap_get_maybe_one(params, "confirm")).is_some() && try!(csrf_token_matches(req, params));
+
+    let (client, hint_subject) = try!(resolve_client(req, &end_session_request));
+    let redirect_url = try!(post_logout_redirect_url(&end_session_request, client.as_ref()));
+
+    let session = try!(UserSession::eval(req));
+    let mut logout_urls = vec![];
+
+    if let Some(session) = session {
+        if session.authenticated && !confirmed && !try!(hint_matches_session(req, client.as_ref(), hint_subject.as_ref(), &session)) {
+            return confirmation_view(req, &end_session_request, client.as_ref());
+        }
+
+        logout_urls = try!(frontchannel_logout_urls(req, &session));
+
+        try!(end_user_session(req, &session));
+    }
+
+    // the RPs' logout pages have to be loaded in the browser before redirecting
+    match redirect_url {
+        Some(redirect_url) if logout_urls.is_empty() => Ok(Response::with((status::Found, RoidcRedirectRaw(redirect_url)))),
+        redirect_url => logged_out_view(req, &end_session_request, &logout_urls, redirect_url.as_ref()),
+    }
+}
+
+/// called by user agent, redirected from RP
+/// if the RP's id_token_hint doesn't match the session, ask the user before logging out
+/// then redirect to post_logout_redirect_uri if given
+pub fn end_session_get_handler(req: &mut Request) -> IronResult<Response> {
+    debug!("/connect/endsession");
+    let params = try!(match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(e)
+    }.map_err(OpenIdConnectError::from));
+
+    Ok(try!(end_session_response(req, &params)))
+}
+
+/// called by user agent form post, either from the RP or the confirmation page
+pub fn end_session_post_handler(req: &mut Request) -> IronResult<Response> {
+    debug!("/connect/endsession");
+    let params = try!(match req.get::<UrlEncodedBody>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(e)
+    }.map_err(OpenIdConnectError::from));
+
+    Ok(try!(end_session_response(req, &params)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use site_config::SiteConfig;
+    use result::OpenIdConnectError;
+    use oauth2::models::ClientApplication;
+    use sessions::UserSession;
+
+    fn client() -> ClientApplication {
+        let mut client = ClientApplication::new("rp".to_owned()).unwrap();
+        client.post_logout_redirect_uris = vec!["https://rp.example.com/logged_out".to_owned()];
+        client
+    }
+
+    fn request(post_logout_redirect_uri: &str, state: Option<&str>) -> EndSessionRequest {
+        let mut end_session_request = EndSessionRequest::new();
+        end_session_request.post_logout_redirect_uri = Some(post_logout_redirect_uri.to_owned());
+        end_session_request.state = state.map(|s| s.to_owned());
+        end_session_request
+    }
+
+    #[test]
+    fn test_post_logout_redirect_uri() {
+        let client = client();
+
+        assert!(post_logout_redirect_url(&EndSessionRequest::new(), Some(&client)).unwrap().is_none());
+
+        let url = post_logout_redirect_url(&request("https://rp.example.com/logged_out", None), Some(&client)).unwrap();
+        assert_eq!(url, Some("https://rp.example.com/logged_out".to_owned()));
+
+        match post_logout_redirect_url(&request("https://evil.example.com/", None), Some(&client)) {
+            Err(OpenIdConnectError::InvalidRedirectUri) => {},
+            other => panic!("expected an invalid redirect uri, got {:?}", other),
+        }
+
+        // without a hint or client_id there's nothing to check the uri against
+        match post_logout_redirect_url(&request("https://rp.example.com/logged_out", None), None) {
+            Err(OpenIdConnectError::InvalidRequest(_)) => {},
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_state_is_echoed() {
+        let url = post_logout_redirect_url(&request("https://rp.example.com/logged_out", Some("abc 123")), Some(&client())).unwrap().unwrap();
+
+        assert_eq!(url, "https://rp.example.com/logged_out?state=abc+123");
+    }
+
+    #[test]
+    fn test_mismatched_hint_needs_confirmation() {
+        let site_config = SiteConfig::new();
+        let client = client();
+        let alice = "alice".to_owned();
+        let bob = "bob".to_owned();
+
+        assert!(hint_matches_user(&site_config, Some(&client), Some(&alice), Some(&alice)).unwrap());
+        assert!(!hint_matches_user(&site_config, Some(&client), Some(&bob), Some(&alice)).unwrap());
+        assert!(!hint_matches_user(&site_config, Some(&client), None, Some(&alice)).unwrap());
+        assert!(!hint_matches_user(&site_config, None, Some(&alice), Some(&alice)).unwrap());
+    }
+
+    #[test]
+    fn test_request_params_round_trip() {
+        let mut params = HashMap::new();
+        params.insert("client_id".to_owned(), vec!["rp".to_owned()]);
+        params.insert("state".to_owned(), vec!["abc".to_owned()]);
+
+        let end_session_request = EndSessionRequest::from_params(&params).unwrap();
+        assert_eq!(end_session_request.to_params(), params);
+    }
+
+    #[test]
+    fn test_frontchannel_logout_url() {
+        let mut client = client();
+        let mut session = UserSession::new("alice".to_owned(), "alice".to_owned(), "session".to_owned());
+        session.sid = Some("sid".to_owned());
+
+        assert!(frontchannel_logout_url(&client, "https://op.example.com", &session).unwrap().is_none());
+
+        client.frontchannel_logout_uri = Some("https://rp.example.com/frontchannel_logout".to_owned());
+        client.frontchannel_logout_session_required = true;
+
+        let url = frontchannel_logout_url(&client, "https://op.example.com", &session).unwrap().unwrap();
+        assert_eq!(url, "https://rp.example.com/frontchannel_logout?iss=https%3A%2F%2Fop.example.com&sid=sid");
+
+        // without a sid the client can't tell which session ended
+        session.sid = None;
+        assert!(frontchannel_logout_url(&client, "https://op.example.com", &session).unwrap().is_none());
+
+        client.frontchannel_logout_session_required = false;
+        let url = frontchannel_logout_url(&client, "https://op.example.com", &session).unwrap().unwrap();
+        assert_eq!(url, "https://rp.example.com/frontchannel_logout");
+    }
+}
@@ -1,9 +1,14 @@
 use iron::prelude::*;
-use iron::status;
+
+use oauth2::routes::userinfo::userinfo_handler;
 
 /// used by identityserver3 samples. similar to userinfo
 /// must be protected by tls
-pub fn identity_get_handler(_req: &mut Request) -> IronResult<Response> {
-    //TODO return some user info
-    Ok(Response::with((status::Ok, "")))
-}
\ No newline at end of file
+pub fn identity_get_handler(req: &mut Request) -> IronResult<Response> {
+    userinfo_handler(req)
+}
+
+/// must be protected by tls
+pub fn identity_post_handler(req: &mut Request) -> IronResult<Response> {
+    userinfo_handler(req)
+}
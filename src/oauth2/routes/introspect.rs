@@ -0,0 +1,137 @@
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use urlencoded::UrlEncodedBody;
+use serde_json;
+
+use rbvt::params::*;
+use result::{Result, OpenIdConnectError};
+use config::Config;
+use site_config::*;
+use oauth2::client_auth::*;
+use oauth2::subject::subject_for_client;
+
+/// Token introspection as described in RFC 7662
+/// https://tools.ietf.org/html/rfc7662
+///
+/// Clients can introspect their own tokens. Resource servers named in the site's
+/// introspection_clients can introspect anyone's. Other tokens look inactive to the caller.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub iss: Option<String>,
+}
+
+impl IntrospectionResponse {
+    pub fn inactive() -> IntrospectionResponse {
+        IntrospectionResponse {
+            active: false,
+            scope: None,
+            client_id: None,
+            token_type: None,
+            exp: None,
+            iat: None,
+            sub: None,
+            aud: None,
+            iss: None,
+        }
+    }
+}
+
+pub fn may_introspect(site_config: &SiteConfig, caller_id: &str, token_client_id: &str) -> bool {
+    caller_id == token_client_id || site_config.introspection_clients.iter().any(|c| c == caller_id)
+}
+
+pub fn introspect_path() -> &'static str {
+    "/connect/introspect"
+}
+
+pub fn introspect_response(req: &mut Request) -> Result<IntrospectionResponse> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    
+    let caller = try!(authenticate_client(req));
+    debug!("introspection requested by {}", caller.client_id);
+    
+    let token = {
+        let params = try!(req.get_ref::<UrlEncodedBody>().map_err(OpenIdConnectError::from));
+        try!(multimap_get_one(params, "token")).to_owned()
+    };
+    
+    let entry = match config.token_repo.verify_access_token(req, &token) {
+        Ok(entry) => entry,
+        Err(OpenIdConnectError::InvalidToken(msg)) => {
+            debug!("introspected token is not active: {}", msg);
+            return Ok(IntrospectionResponse::inactive());
+        },
+        Err(e) => return Err(e),
+    };
+    
+    if !may_introspect(&site_config, &caller.client_id, &entry.client_id) {
+        debug!("{} may not introspect tokens issued to {}", caller.client_id, entry.client_id);
+        return Ok(IntrospectionResponse::inactive());
+    }
+    
+    // the sub is the one the token's own client sees
+    let maybe_client = try!(config.application_repo.find_client_application(&entry.client_id));
+    let client = match maybe_client {
+        Some(client) => client,
+        None => return Ok(IntrospectionResponse::inactive()),
+    };
+    
+    Ok(IntrospectionResponse {
+        active: true,
+        scope: Some(entry.scopes.join(" ")),
+        client_id: Some(entry.client_id.clone()),
+        token_type: Some("Bearer".to_owned()),
+        exp: Some(entry.expires_at.timestamp()),
+        iat: Some(entry.created_at.timestamp()),
+        sub: Some(try!(subject_for_client(&site_config, &client, &entry.user_id))),
+        aud: Some(entry.client_id.clone()),
+        iss: Some(site_config.get_issuer()),
+    })
+}
+
+/// called by resource servers
+/// must be protected by tls
+pub fn introspect_post_handler(req: &mut Request) -> IronResult<Response> {
+    debug!("/connect/introspect");
+    let introspection = try!(introspect_response(req));
+    
+    let body = try!(serde_json::to_string(&introspection).map_err(OpenIdConnectError::from));
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    
+    Ok(Response::with((content_type, status::Ok, body)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use site_config::*;
+
+    #[test]
+    fn test_may_introspect() {
+        let mut site_config = SiteConfig::new();
+        site_config.introspection_clients = vec!["api".to_owned()];
+
+        assert!(may_introspect(&site_config, "rp", "rp"));
+        assert!(may_introspect(&site_config, "api", "rp"));
+        assert!(!may_introspect(&site_config, "other", "rp"));
+    }
+}
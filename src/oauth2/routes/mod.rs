@@ -6,6 +6,9 @@ pub mod openid_config;
 pub mod webfinger;
 pub mod consent;
 pub mod register;
+pub mod introspect;
+pub mod end_session;
+pub mod check_session;
 
 pub use self::authorize::*;
 pub use self::token::*;
@@ -15,3 +18,6 @@ pub use self::openid_config::*;
 pub use self::webfinger::*;
 pub use self::consent::*;
 pub use self::register::*;
+pub use self::introspect::*;
+pub use self::end_session::*;
+pub use self::check_session::*;
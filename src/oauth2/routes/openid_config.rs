@@ -9,6 +9,9 @@ use serde_json;
 
 use result::*;
 use site_config::*;
+use oauth2::models::claims_request::claims_for_scope;
+use oauth2::models::registration::SUPPORTED_GRANT_TYPES;
+use display::Display;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WellKnownOpenIdConfiguration {
@@ -17,6 +20,9 @@ pub struct WellKnownOpenIdConfiguration {
     pub token_endpoint: Option<String>,
     pub userinfo_endpoint: Option<String>,
     pub revocation_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+    pub check_session_iframe: Option<String>,
     pub jwks_uri: Option<String>,
     pub registration_endpoint: Option<String>,
     pub response_types_supported: Vec<String>,
@@ -27,7 +33,14 @@ pub struct WellKnownOpenIdConfiguration {
     pub scopes_supported: Vec<String>,
     pub token_endpoint_auth_methods_supported: Vec<String>,
     pub claims_supported: Vec<String>,
+    pub claims_parameter_supported: bool,
     pub code_challenge_methods_supported: Vec<String>,
+    pub ui_locales_supported: Vec<String>,
+    pub display_values_supported: Vec<String>,
+    pub frontchannel_logout_supported: bool,
+    pub frontchannel_logout_session_supported: bool,
+    pub backchannel_logout_supported: bool,
+    pub backchannel_logout_session_supported: bool,
 }
 
 impl WellKnownOpenIdConfiguration {
@@ -38,6 +51,9 @@ impl WellKnownOpenIdConfiguration {
             token_endpoint: None,
             userinfo_endpoint: None,
             revocation_endpoint: None,
+            introspection_endpoint: None,
+            end_session_endpoint: None,
+            check_session_iframe: None,
             jwks_uri: None,
             registration_endpoint: None,
             response_types_supported: vec![],
@@ -48,7 +64,14 @@ impl WellKnownOpenIdConfiguration {
             scopes_supported: vec![],
             token_endpoint_auth_methods_supported: vec![],
             claims_supported: vec![],
+            claims_parameter_supported: false,
             code_challenge_methods_supported: vec![],
+            ui_locales_supported: vec![],
+            display_values_supported: vec![],
+            frontchannel_logout_supported: false,
+            frontchannel_logout_session_supported: false,
+            backchannel_logout_supported: false,
+            backchannel_logout_session_supported: false,
         }
     }
     
@@ -59,6 +82,13 @@ impl WellKnownOpenIdConfiguration {
             c.authorization_endpoint = Some(format!("{}/connect/authorize", issuer));
             c.token_endpoint = Some(format!("{}/connect/token", issuer));
             c.userinfo_endpoint = Some(format!("{}/connect/userinfo", issuer));
+            c.introspection_endpoint = Some(format!("{}/connect/introspect", issuer));
+            c.end_session_endpoint = Some(format!("{}/connect/endsession", issuer));
+            c.check_session_iframe = Some(format!("{}/connect/checksession", issuer));
+            c.frontchannel_logout_supported = true;
+            c.frontchannel_logout_session_supported = true;
+            c.backchannel_logout_supported = true;
+            c.backchannel_logout_session_supported = true;
             c.jwks_uri = Some(format!("{}/jwks", issuer));
             if site_config.enable_dynamic_client_registration {
                 c.registration_endpoint = Some(format!("{}/connect/register", issuer));
@@ -77,13 +107,21 @@ impl WellKnownOpenIdConfiguration {
                 "query".to_owned(),
                 "fragment".to_owned(),
             ];
-            c.grant_types_supported = vec![
-                "authorization_code".to_owned(),
-                "implicit".to_owned(),
+            c.grant_types_supported = SUPPORTED_GRANT_TYPES.iter().map(|g| (*g).to_owned()).collect();
+            c.token_endpoint_auth_methods_supported = vec![
+                "client_secret_basic".to_owned(),
+                "client_secret_post".to_owned(),
+                "none".to_owned(),
             ];
             c.id_token_signing_alg_values_supported = vec!["HS256".to_owned()]; // must include rs256
             c.scopes_supported = vec!["openid".to_owned()];
             c.subject_types_supported = vec!["pairwise".to_owned(), "public".to_owned()];
+            c.claims_supported = vec!["sub".to_owned(), "iss".to_owned(), "aud".to_owned(), "exp".to_owned(), "iat".to_owned()];
+            for scope in &["profile", "email", "address", "phone"] {
+                c.claims_supported.extend(claims_for_scope(scope).iter().map(|s| (*s).to_owned()));
+            }
+            c.claims_parameter_supported = true;
+            c.display_values_supported = Display::supported_values();
         }
         c
     }
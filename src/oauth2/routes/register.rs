@@ -1,10 +1,8 @@
-use std::collections::HashMap;
-
 use iron::prelude::*;
 use iron::status;
 use iron::mime::Mime;
+use bodyparser;
 use serde_json;
-use urlencoded::UrlEncodedBody;
 
 use result::*;
 use site_config::*;
@@ -12,37 +10,65 @@ use config::*;
 use oauth2::models::registration::*;
 use authentication::*;
 
-pub fn register_client_application(req: &mut Request, params: HashMap<String, Vec<String>>) -> Result<RegistrationResult> {
+fn read_registration_request(req: &mut Request) -> Result<RegistrationRequest> {
+    let body = try!(try!(req.get::<bodyparser::Raw>()).ok_or(OpenIdConnectError::RegistrationError(INVALID_CLIENT_METADATA, "a JSON body is required".to_owned())));
+    debug!("dynamic application registration request body: {}", body);
+
+    serde_json::from_str(&body).map_err(|e| OpenIdConnectError::RegistrationError(INVALID_CLIENT_METADATA, format!("{}", e)))
+}
+
+pub fn register_client_application(req: &mut Request, mut reg_req: RegistrationRequest) -> Result<RegistrationResult> {
     let config = try!(Config::get(req));
 
-    let mut reg_req = RegistrationRequest::new();
-    try!(reg_req.load_params(&params));
-    
-    let secret = Some(new_secret());
+    try!(reg_req.validate(&**config.sector_identifier_fetcher));
+
+    let secret = if reg_req.uses_secret() { Some(new_secret()) } else { None };
     let mut client_builder = reg_req.to_client_builder();
     client_builder.secret = secret.clone();
 
-    let client = try!(config.application_repo.create_client_application(client_builder));
+    let mut client = try!(config.application_repo.create_client_application(client_builder));
+    reg_req.apply_to(&mut client);
+    try!(config.application_repo.update_client_application(&client));
+
+    info!("registered client {}", client.client_id);
 
     Ok(RegistrationResult::from_client(client, secret))
 }
 
-/*
-example request:
-DEBUG:openid_connect::oauth2::routes::register: dynamic application registration request body: {"{\"response_types\": [\
-"code\"], \"redirect_uris\": [\"oob://localhost/callback\"], \"application_type\": \"web\"}": [""]}
-*/
+/// Renders rejected registrations as RFC 7591 error responses
+fn registration_error_response(code: &str, description: &str) -> Result<Response> {
+    let error_view = RegistrationErrorView {
+        error: code.to_owned(),
+        error_description: description.to_owned(),
+    };
+
+    let body = try!(serde_json::to_string(&error_view));
+    let content_type = "application/json".parse::<Mime>().unwrap();
+
+    Ok(Response::with((content_type, status::BadRequest, body)))
+}
 
 pub fn register_application_post_handler(req: &mut Request) -> IronResult<Response> {
     debug!("/connect/register");
-    let config = try!(Config::get(req));
     let site_config = try!(SiteConfig::get(req));
-    let hashmap = try!(req.get::<UrlEncodedBody>().map_err(OpenIdConnectError::from));
-    debug!("dynamic application registration request body: {:?}", hashmap);
-    let reg_result = try!(register_client_application(req, hashmap));
-    
-    let reg_result_json = try!(serde_json::to_string(&reg_result).map_err(OpenIdConnectError::from));
-    let content_type = "application/json".parse::<Mime>().unwrap();
 
-    Ok(Response::with((content_type, status::Ok, reg_result_json)))
+    if !site_config.enable_dynamic_client_registration {
+        return Err(IronError::from(OpenIdConnectError::AccessDenied("dynamic client registration is disabled".to_owned())));
+    }
+
+    let result = match read_registration_request(req) {
+        Ok(reg_req) => register_client_application(req, reg_req),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(reg_result) => {
+            let reg_result_json = try!(serde_json::to_string(&reg_result).map_err(OpenIdConnectError::from));
+            let content_type = "application/json".parse::<Mime>().unwrap();
+
+            Ok(Response::with((content_type, status::Created, reg_result_json)))
+        },
+        Err(OpenIdConnectError::RegistrationError(code, description)) => Ok(try!(registration_error_response(code, &description))),
+        Err(err) => Err(IronError::from(err))
+    }
 }
@@ -153,7 +153,7 @@ pub fn token_post_handler(req: &mut Request) -> IronResult<Response> {
                 }
             },
             GrantType::ClientCredentials => {
-                unimplemented!()
+                Err(OpenIdConnectError::UnsupportedGrantType(GrantType::ClientCredentials).into())
             }
         }
     } else {
@@ -1,8 +1,103 @@
 use iron::prelude::*;
 use iron::status;
+use iron::mime::Mime;
+use serde_json;
+use serde_json::Value;
+use jsonwebtoken::jwt::*;
+use jsonwebtoken::json::*;
+use jsonwebtoken::header::*;
+use jsonwebtoken::algorithm::*;
+
+use result::{Result, OpenIdConnectError};
+use config::Config;
+use site_config::*;
+use oauth2::bearer::*;
+use oauth2::models::*;
+use oauth2::subject::subject_for_client;
+
+pub fn userinfo_response(req: &mut Request) -> Result<Response> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    
+    let access_token = try!(find_bearer_token(req));
+    let token = try!(config.token_repo.verify_access_token(req, &access_token));
+    debug!("userinfo for access token {:?}", token);
+    
+    if !token.has_scope("openid") {
+        return Err(OpenIdConnectError::InsufficientScope("openid".to_owned()));
+    }
+    
+    let maybe_client = try!(config.application_repo.find_client_application(&token.client_id));
+    let client = try!(maybe_client.ok_or(OpenIdConnectError::InvalidToken("client no longer exists".to_owned())));
+    
+    if try!(config.user_repo.get_user(&token.user_id)).is_none() {
+        return Err(OpenIdConnectError::InvalidToken("user no longer exists".to_owned()));
+    }
+    
+    let mut claims = try!(config.token_repo.get_user_claim_values(req, &token.user_id, &token.client_id, &token.scopes, token.claims_request.as_ref(), ClaimsTarget::UserInfo));
+    claims.insert("sub".to_owned(), Value::String(try!(subject_for_client(&site_config, &client, &token.user_id))));
+    
+    // registration turns these clients away, and the admin forms can't set either alg,
+    // so these are only a guard against a client record that was edited by hand
+    if let Some(ref alg) = client.userinfo_encrypted_response_alg {
+        return Err(OpenIdConnectError::UnsupportedAlgorithm(alg.to_owned()));
+    }
+    
+    if let Some(ref alg) = client.userinfo_signed_response_alg {
+        if alg != "HS256" {
+            return Err(OpenIdConnectError::UnsupportedAlgorithm(alg.to_owned()));
+        }
+        
+        let mut jwt_claims = JwtClaims::new();
+        jwt_claims.set_value("iss", &site_config.get_issuer());
+        jwt_claims.set_value("aud", &client.client_id);
+        for (name, value) in claims {
+            jwt_claims.set_value(&name, &value);
+        }
+        
+        let jwt = Jwt::new(Header::new(Algorithm::HS256), jwt_claims);
+        let body = try!(jwt.encode(&config.mac_signer).map_err(OpenIdConnectError::from));
+        
+        let content_type = "application/jwt".parse::<Mime>().unwrap();
+        
+        Ok(Response::with((content_type, status::Ok, body)))
+    } else {
+        let body = try!(serde_json::to_string(&Value::Object(claims)).map_err(OpenIdConnectError::from));
+        
+        let content_type = "application/json".parse::<Mime>().unwrap();
+        
+        Ok(Response::with((content_type, status::Ok, body)))
+    }
+}
+
+/// renders token errors as bearer challenges
+/// and everything else via the usual error handling
+pub fn userinfo_handler(req: &mut Request) -> IronResult<Response> {
+    match userinfo_response(req) {
+        Ok(res) => Ok(res),
+        Err(err) => {
+            debug!("userinfo error: {:?}", err);
+            
+            match err {
+                OpenIdConnectError::MissingAccessToken |
+                OpenIdConnectError::InvalidRequest(_) |
+                OpenIdConnectError::InvalidToken(_) |
+                OpenIdConnectError::InsufficientScope(_) => {
+                    Ok(try!(bearer_error_response(req, &err)))
+                },
+                _ => Err(IronError::from(err))
+            }
+        }
+    }
+}
+
+/// must be protected by tls
+pub fn userinfo_get_handler(req: &mut Request) -> IronResult<Response> {
+    userinfo_handler(req)
+}
 
 /// must be protected by tls
-pub fn userinfo_get_handler(_req: &mut Request) -> IronResult<Response> {
-    //TODO return some user info
-    Ok(Response::with((status::Ok, "")))
-}
\ No newline at end of file
+/// the access token may be sent in a form encoded body
+pub fn userinfo_post_handler(req: &mut Request) -> IronResult<Response> {
+    userinfo_handler(req)
+}
@@ -0,0 +1,70 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use url;
+
+use result::{Result, OpenIdConnectError};
+use authentication;
+
+/// Session Management
+/// See http://openid.net/specs/openid-connect-session-1_0.html
+///
+/// session_state is a salted hash of the client, its origin and the browser state cookie.
+/// The RP's iframe sends it to the check_session_iframe, which recomputes it from the cookie
+/// to tell whether the user's session at the OP has changed.
+
+pub fn check_session_path() -> &'static str {
+    "/connect/checksession"
+}
+
+/// The origin the RP's frame runs on, e.g. https://rp.example.com:8443
+pub fn client_origin(redirect_uri: &str) -> Result<String> {
+    let uri = try!(url::Url::parse(redirect_uri));
+    let origin = uri.origin();
+
+    if origin.is_tuple() {
+        Ok(origin.ascii_serialization())
+    } else {
+        Err(OpenIdConnectError::UrlError(format!("{} has no origin", redirect_uri)))
+    }
+}
+
+/// hex(sha256(client_id + " " + origin + " " + browser_state + " " + salt)) + "." + salt
+pub fn session_state_with_salt(client_id: &str, origin: &str, browser_state: &str, salt: &str) -> String {
+    let mut sha = Sha256::new();
+
+    sha.input_str(client_id);
+    sha.input_str(" ");
+    sha.input_str(origin);
+    sha.input_str(" ");
+    sha.input_str(browser_state);
+    sha.input_str(" ");
+    sha.input_str(salt);
+
+    format!("{}.{}", sha.result_str(), salt)
+}
+
+pub fn session_state(client_id: &str, redirect_uri: &str, browser_state: &str) -> Result<String> {
+    let origin = try!(client_origin(redirect_uri));
+
+    Ok(session_state_with_salt(client_id, &origin, browser_state, &authentication::new_nonce()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_state() {
+        let origin = client_origin("https://rp.example.com:8443/cb?x=1").unwrap();
+        assert_eq!(origin, "https://rp.example.com:8443");
+
+        let state = session_state_with_salt("client", &origin, "browser state", "salt");
+        let (hash, salt) = state.split_at(state.find('.').unwrap());
+
+        assert_eq!(salt, ".salt");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(state, session_state_with_salt("client", &origin, "browser state", "salt"));
+        assert!(state != session_state_with_salt("client", &origin, "rotated", "salt"));
+        assert!(state != session_state_with_salt("other", &origin, "browser state", "salt"));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use iron::prelude::*;
+use serde_json;
+use url;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+use result::{Result, OpenIdConnectError};
+use config::Config;
+use site_config::*;
+use http_client::HttpClient;
+use oauth2::models::client::*;
+
+/// Subject identifier types
+/// See http://openid.net/specs/openid-connect-core-1_0.html#SubjectIDTypes
+
+pub const SUBJECT_TYPE_PUBLIC: &'static str = "public";
+pub const SUBJECT_TYPE_PAIRWISE: &'static str = "pairwise";
+
+/// Fetches the JSON array of redirect_uris published at a client's sector_identifier_uri
+pub trait SectorIdentifierFetcher where Self: Send + Sync {
+    fn fetch_redirect_uris(&self, sector_identifier_uri: &str) -> Result<Vec<String>>;
+}
+
+pub struct HttpSectorIdentifierFetcher {
+    http_client: Arc<Box<HttpClient>>,
+}
+
+impl HttpSectorIdentifierFetcher {
+    pub fn new(http_client: Arc<Box<HttpClient>>) -> HttpSectorIdentifierFetcher {
+        HttpSectorIdentifierFetcher {
+            http_client: http_client,
+        }
+    }
+}
+
+impl SectorIdentifierFetcher for HttpSectorIdentifierFetcher {
+    fn fetch_redirect_uris(&self, sector_identifier_uri: &str) -> Result<Vec<String>> {
+        let res = try!(self.http_client.get(sector_identifier_uri));
+
+        if !res.is_success() {
+            return Err(OpenIdConnectError::InvalidRequest(format!("sector_identifier_uri returned status {}", res.status)));
+        }
+
+        serde_json::from_str(&res.body).map_err(|e| OpenIdConnectError::InvalidRequest(format!("sector_identifier_uri document is not a JSON array of uris: {}", e)))
+    }
+}
+
+/// Serves sector identifier documents from memory, for tests and local setups
+#[derive(Clone)]
+pub struct StaticSectorIdentifierFetcher {
+    documents: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl StaticSectorIdentifierFetcher {
+    pub fn new() -> StaticSectorIdentifierFetcher {
+        StaticSectorIdentifierFetcher {
+            documents: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn insert(&self, sector_identifier_uri: &str, redirect_uris: Vec<String>) {
+        self.documents.lock().unwrap().insert(sector_identifier_uri.to_owned(), redirect_uris);
+    }
+}
+
+impl SectorIdentifierFetcher for StaticSectorIdentifierFetcher {
+    fn fetch_redirect_uris(&self, sector_identifier_uri: &str) -> Result<Vec<String>> {
+        let documents = self.documents.lock().unwrap();
+
+        documents.get(sector_identifier_uri)
+            .map(|uris| uris.clone())
+            .ok_or(OpenIdConnectError::InvalidRequest(format!("no sector identifier document at {}", sector_identifier_uri)))
+    }
+}
+
+fn uri_host(uri: &str) -> Result<String> {
+    let parsed = try!(url::Url::parse(uri));
+
+    parsed.host_str()
+        .map(|h| h.to_owned())
+        .ok_or(OpenIdConnectError::UrlError(format!("{} has no host", uri)))
+}
+
+/// Checks subject_type and sector_identifier_uri registration metadata.
+/// Every redirect_uri must appear in the sector identifier document,
+/// and pairwise clients without one must keep their redirect_uris on a single host.
+pub fn validate_subject_type(fetcher: &SectorIdentifierFetcher, subject_type: Option<&str>, sector_identifier_uri: Option<&str>, redirect_uris: &[String]) -> Result<()> {
+    match subject_type {
+        None | Some(SUBJECT_TYPE_PUBLIC) | Some(SUBJECT_TYPE_PAIRWISE) => {},
+        Some(other) => return Err(OpenIdConnectError::InvalidRequest(format!("unsupported subject_type {}", other))),
+    }
+
+    if let Some(sector_identifier_uri) = sector_identifier_uri {
+        let parsed = try!(url::Url::parse(sector_identifier_uri));
+
+        if parsed.scheme() != "https" {
+            return Err(OpenIdConnectError::InvalidRequest("sector_identifier_uri must use https".to_owned()));
+        }
+
+        let sector_redirect_uris = try!(fetcher.fetch_redirect_uris(sector_identifier_uri));
+
+        for redirect_uri in redirect_uris {
+            if !sector_redirect_uris.contains(redirect_uri) {
+                return Err(OpenIdConnectError::InvalidRequest(format!("redirect_uri {} is not listed at the sector_identifier_uri", redirect_uri)));
+            }
+        }
+    } else if subject_type == Some(SUBJECT_TYPE_PAIRWISE) {
+        let mut hosts: Vec<String> = vec![];
+
+        for redirect_uri in redirect_uris {
+            let host = try!(uri_host(redirect_uri));
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+
+        if hosts.len() > 1 {
+            return Err(OpenIdConnectError::InvalidRequest("sector_identifier_uri is required when redirect_uris use more than one host".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_client_subject_type(fetcher: &SectorIdentifierFetcher, client: &ClientApplication) -> Result<()> {
+    validate_subject_type(fetcher,
+        client.subject_type.as_ref().map(|s| &s[..]),
+        client.sector_identifier_uri.as_ref().map(|s| &s[..]),
+        &client.redirect_uris)
+}
+
+/// The host component of the sector_identifier_uri, or of the redirect_uris if there isn't one
+pub fn sector_identifier(client: &ClientApplication) -> Result<String> {
+    if let Some(ref sector_identifier_uri) = client.sector_identifier_uri {
+        return uri_host(sector_identifier_uri);
+    }
+
+    let redirect_uri = try!(client.redirect_uris.first().ok_or(OpenIdConnectError::InvalidRedirectUri));
+
+    uri_host(redirect_uri)
+}
+
+/// Derives a subject identifier that is stable for a user within a sector
+/// but can't be correlated across sectors without the salt
+pub fn pairwise_subject(sector_identifier: &str, user_id: &str, salt: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), salt.as_bytes());
+
+    hmac.input(sector_identifier.as_bytes());
+    hmac.input(b"\0");
+    hmac.input(user_id.as_bytes());
+
+    hmac.result().code().to_base64(URL_SAFE)
+}
+
+pub fn is_pairwise(client: &ClientApplication) -> bool {
+    client.subject_type.as_ref().map(|s| s == SUBJECT_TYPE_PAIRWISE).unwrap_or(false)
+}
+
+/// The sub claim value for a user as seen by a client
+pub fn subject_for_client(site_config: &SiteConfig, client: &ClientApplication, user_id: &str) -> Result<String> {
+    if is_pairwise(client) {
+        let sector = try!(sector_identifier(client));
+        let salt = try!(site_config.get_pairwise_subject_salt());
+
+        Ok(pairwise_subject(&sector, user_id, &salt))
+    } else {
+        Ok(user_id.to_owned())
+    }
+}
+
+pub fn subject_for_client_id(req: &mut Request, user_id: &str, client_id: &str) -> Result<String> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+
+    let maybe_client = try!(config.application_repo.find_client_application(client_id));
+    let client = try!(maybe_client.ok_or(OpenIdConnectError::ClientApplicationNotFound));
+
+    subject_for_client(&site_config, &client, user_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oauth2::models::client::*;
+    use site_config::*;
+
+    fn pairwise_client(client_id: &str, redirect_uris: Vec<&str>, sector_identifier_uri: Option<&str>) -> ClientApplication {
+        let mut client = ClientApplication::new(client_id.to_owned()).unwrap();
+        client.subject_type = Some("pairwise".to_owned());
+        client.redirect_uris = redirect_uris.into_iter().map(|s| s.to_owned()).collect();
+        client.sector_identifier_uri = sector_identifier_uri.map(|s| s.to_owned());
+        client
+    }
+
+    #[test]
+    fn test_pairwise_subject() {
+        let mut site_config = SiteConfig::new();
+        site_config.pairwise_subject_salt = Some("salt".to_owned());
+
+        let a = pairwise_client("a", vec!["https://a.example.com/cb"], None);
+        let a2 = pairwise_client("a2", vec!["https://a.example.com/other"], None);
+        let b = pairwise_client("b", vec!["https://b.example.com/cb"], None);
+        let mut public = pairwise_client("p", vec!["https://a.example.com/cb"], None);
+        public.subject_type = None;
+
+        let sub_a = subject_for_client(&site_config, &a, "1").unwrap();
+
+        assert_eq!(sub_a, subject_for_client(&site_config, &a, "1").unwrap());
+        assert_eq!(sub_a, subject_for_client(&site_config, &a2, "1").unwrap());
+        assert!(sub_a != subject_for_client(&site_config, &b, "1").unwrap());
+        assert!(sub_a != subject_for_client(&site_config, &a, "2").unwrap());
+        assert_eq!(subject_for_client(&site_config, &public, "1").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_validate_sector_identifier() {
+        let fetcher = StaticSectorIdentifierFetcher::new();
+        fetcher.insert("https://example.com/sector.json", vec!["https://a.example.com/cb".to_owned(), "https://b.example.net/cb".to_owned()]);
+
+        let spread = pairwise_client("c", vec!["https://a.example.com/cb", "https://b.example.net/cb"], None);
+        assert!(validate_client_subject_type(&fetcher, &spread).is_err());
+
+        let sectored = pairwise_client("c", vec!["https://a.example.com/cb", "https://b.example.net/cb"], Some("https://example.com/sector.json"));
+        assert!(validate_client_subject_type(&fetcher, &sectored).is_ok());
+        assert_eq!(sector_identifier(&sectored).unwrap(), "example.com");
+
+        let unlisted = pairwise_client("c", vec!["https://evil.example.org/cb"], Some("https://example.com/sector.json"));
+        assert!(validate_client_subject_type(&fetcher, &unlisted).is_err());
+    }
+}
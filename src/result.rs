@@ -243,6 +243,116 @@ quick_error! {
             display("unknown token_endpoint_auth_method: {}", s)
         }
 
+        InvalidRequest(msg: String) {
+            description("invalid request")
+            display("Invalid request: {}", msg)
+        }
+
+        RegistrationError(error: &'static str, description: String) {
+            description("client registration error")
+            display("{}: {}", error, description)
+        }
+
+        MissingAccessToken {
+            description("no access token supplied")
+            display("No access token supplied")
+        }
+
+        InvalidToken(msg: String) {
+            description("invalid token")
+            display("Invalid token: {}", msg)
+        }
+
+        InsufficientScope(scope: String) {
+            description("insufficient scope")
+            display("Insufficient scope: {} is required", scope)
+        }
+
+        UnsupportedAlgorithm(alg: String) {
+            description("unsupported algorithm")
+            display("Unsupported algorithm: {}", alg)
+        }
+
+        InvalidClient {
+            description("client authentication failed")
+            display("Client authentication failed")
+        }
+
+        NotAuthenticated {
+            description("not logged in")
+            display("Not logged in")
+        }
+
+        SessionNotFound {
+            description("session not found")
+            display("Session not found")
+        }
+
+        TokenNotFound {
+            description("token not found")
+            display("Token not found")
+        }
+
+        InvalidOneTimePassword {
+            description("invalid one time password")
+            display("Invalid one time password")
+        }
+
+        LoginThrottled(retry_after: i64) {
+            description("too many failed logins")
+            display("Too many failed logins, try again in {} seconds", retry_after)
+        }
+
+        InvalidCsrfToken {
+            description("invalid csrf token")
+            display("The form has expired or did not come from this site")
+        }
+
+        CrossSiteRequest(origin: String) {
+            description("cross site request")
+            display("Request from another site rejected: {}", origin)
+        }
+
+        MailError(msg: String) {
+            description("mail error")
+            display("Error sending email: {}", msg)
+        }
+
+        HttpClientError(msg: String) {
+            description("http client error")
+            display("HTTP client error: {}", msg)
+        }
+
+        UpstreamLoginError(msg: String) {
+            description("upstream login error")
+            display("Login with upstream provider failed: {}", msg)
+        }
+
+        GroupAlreadyExists {
+            description("group already exists")
+            display("Group already exists")
+        }
+
+        GroupNotFound {
+            description("group not found")
+            display("Group not found")
+        }
+
+        ScimError(status: status::Status, scim_type: Option<&'static str>, detail: String) {
+            description("scim error")
+            display("{}", detail)
+        }
+
+        AccountDisabled {
+            description("account disabled")
+            display("This account has been disabled")
+        }
+
+        AccessDenied(msg: String) {
+            description("access denied")
+            display("Access denied: {}", msg)
+        }
+
         IronError(e: Box<iron::error::Error + Send>) {
             description("iron error")
             display("iron error: {:?}", e)
@@ -259,6 +369,27 @@ pub fn error_status_code(oic_err: &OpenIdConnectError) -> status::Status {
         OpenIdConnectError::JsonError(ref _err) => status::BadRequest,
         OpenIdConnectError::EmptyPostBody => status::BadRequest,
         OpenIdConnectError::ValidationError(ref _err) => status::BadRequest,
+        OpenIdConnectError::InvalidRequest(ref _msg) => status::BadRequest,
+        OpenIdConnectError::RegistrationError(_, _) => status::BadRequest,
+        OpenIdConnectError::MissingAccessToken => status::Unauthorized,
+        OpenIdConnectError::InvalidToken(ref _msg) => status::Unauthorized,
+        OpenIdConnectError::InsufficientScope(ref _scope) => status::Forbidden,
+        OpenIdConnectError::InvalidClient => status::Unauthorized,
+        OpenIdConnectError::NotAuthenticated => status::Unauthorized,
+        OpenIdConnectError::SessionNotFound => status::NotFound,
+        OpenIdConnectError::TokenNotFound => status::NotFound,
+        OpenIdConnectError::InvalidOneTimePassword => status::Unauthorized,
+        OpenIdConnectError::LoginThrottled(_) => status::TooManyRequests,
+        OpenIdConnectError::InvalidCsrfToken => status::Forbidden,
+        OpenIdConnectError::CrossSiteRequest(ref _origin) => status::Forbidden,
+        OpenIdConnectError::UpstreamLoginError(ref _msg) => status::BadGateway,
+        OpenIdConnectError::AccessDenied(ref _msg) => status::Forbidden,
+        OpenIdConnectError::AccountDisabled => status::Forbidden,
+        OpenIdConnectError::UserNotFound => status::NotFound,
+        OpenIdConnectError::UserAlreadyExists => status::Conflict,
+        OpenIdConnectError::GroupAlreadyExists => status::Conflict,
+        OpenIdConnectError::GroupNotFound => status::NotFound,
+        OpenIdConnectError::ScimError(status, _, _) => status,
         _ => status::InternalServerError
     }
 }
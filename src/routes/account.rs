@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use plugin::Plugin as PluginPlugin;
+use iron;
+use iron::prelude::*;
+use iron::status;
+use iron::modifiers::Redirect;
+use urlencoded::*;
+use serde_json::value;
+use chrono::*;
+use url;
+use jsonwebtoken::jwt::Jwt;
+use jsonwebtoken::json::*;
+use rbvt::params::*;
+
+use config::Config;
+use site_config::SiteConfig;
+use users::{User, PROFILE_CLAIMS, is_valid_email};
+use totp;
+use result::*;
+use view::View;
+use helpers::*;
+use urls::relative_url;
+use sessions::*;
+use back::*;
+use oauth2::repos::RefreshTokenEntry;
+use routes::email::send_verification_email;
+use routes::user_api::delete_user;
+
+/// Where the user is logged in, as shown on their account page
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionView {
+    pub session_id: String,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: String,
+    pub last_seen: String,
+    pub current: bool,
+}
+
+impl SessionView {
+    pub fn new(record: &SessionRecord, current_session_id: Option<&String>) -> SessionView {
+        SessionView {
+            session_id: record.session_id.clone(),
+            device: record.user_agent.clone(),
+            ip: record.ip.clone(),
+            created_at: record.created_at.to_rfc3339(),
+            last_seen: record.last_activity.to_rfc3339(),
+            current: current_session_id == Some(&record.session_id),
+        }
+    }
+}
+
+/// A live refresh token, identified by its handle rather than the token itself
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshTokenView {
+    pub token_id: String,
+    pub client_id: String,
+    pub client_name: Option<String>,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+}
+
+impl RefreshTokenView {
+    pub fn new(entry: &RefreshTokenEntry, client_name: Option<String>) -> RefreshTokenView {
+        RefreshTokenView {
+            token_id: entry.token_id.clone(),
+            client_id: entry.client_id.clone(),
+            client_name: client_name,
+            scopes: entry.scopes.clone(),
+            created_at: entry.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// One of the user's profile claims, as shown on the profile form
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileClaimView {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+pub fn account_redirect_url(req: &mut Request, account_path: &str, path: &str) -> Result<iron::Url> {
+    redirect_forwards_url(req, account_path, path, HashMap::new())
+}
+
+/// The logged in user's session, if there is one
+pub fn authenticated_session(req: &mut Request) -> Result<Option<UserSession>> {
+    let session = try!(UserSession::eval(req));
+
+    Ok(session.and_then(|s| if s.authenticated && s.user_id.is_some() { Some(s) } else { None }))
+}
+
+pub fn user_session_views(config: &Config, site_config: &SiteConfig, user_session: &UserSession) -> Result<Vec<SessionView>> {
+    let user_id = try!(user_session.user_id.as_ref().ok_or(OpenIdConnectError::UserNotFound));
+
+    let mut records = try!(config.session_controller.get_user_sessions(site_config, user_id));
+    records.sort_by(|a, b| (*b.last_activity).cmp(&*a.last_activity));
+
+    Ok(records.iter().map(|r| SessionView::new(r, user_session.session_id.as_ref())).collect())
+}
+
+pub fn user_refresh_token_views(config: &Config, user_id: &str) -> Result<Vec<RefreshTokenView>> {
+    let entries = try!(config.token_repo.get_user_refresh_tokens(user_id));
+
+    let mut views = vec![];
+    for entry in &entries {
+        let client_name = try!(config.application_repo.find_client_application(&entry.client_id)).and_then(|c| c.client_name);
+        views.push(RefreshTokenView::new(entry, client_name));
+    }
+
+    Ok(views)
+}
+
+/// Signs out one of the user's own sessions.
+/// Returns true if it was the session making the request.
+pub fn revoke_user_session(req: &mut Request, user_session: &UserSession, session_id: &str) -> Result<bool> {
+    let config = try!(Config::get(req));
+    let user_id = try!(user_session.user_id.as_ref().ok_or(OpenIdConnectError::UserNotFound));
+
+    let record = try!(try!(config.session_controller.store.find_session(session_id)).ok_or(OpenIdConnectError::SessionNotFound));
+
+    if record.user_id != *user_id {
+        return Err(OpenIdConnectError::SessionNotFound);
+    }
+
+    if user_session.session_id.as_ref().map(|s| s == session_id).unwrap_or(false) {
+        try!(config.session_controller.clear_session(req));
+
+        Ok(true)
+    } else {
+        try!(config.session_controller.revoke_session(session_id));
+
+        Ok(false)
+    }
+}
+
+/// Carries a new TOTP secret through enrollment.
+/// Nothing is stored against the user until they've shown their app has it.
+pub fn new_otp_enrollment_token(config: &Config, user_id: &str, secret: &str) -> Result<String> {
+    let now = UTC::now().timestamp();
+    
+    let mut t = Jwt::default();
+    t.header.typ = Some("otp_enrollment".to_owned());
+    t.claims.set_value("nbf", &now);
+    t.claims.set_value("exp", &(now + 600));
+    t.claims.set_value("sub", &user_id.to_owned());
+    t.claims.set_value("secret", &secret.to_owned());
+    
+    t.encode(&config.mac_signer).map_err(OpenIdConnectError::from)
+}
+
+/// The secret from an enrollment token, as long as it was issued to this user
+pub fn load_otp_enrollment(req: &mut Request, params: &HashMap<String, Vec<String>>, user_id: &str) -> Result<String> {
+    match try!(load_token(req, params, "enrollment")) {
+        Some(ref token) if token.header.typ.as_ref().map(|s| &s[..]) == Some("otp_enrollment") => {
+            if try!(token.claims.get_value::<String>("sub")).as_ref().map(|s| &s[..]) != Some(user_id) {
+                return Err(OpenIdConnectError::NotAuthenticated);
+            }
+            
+            let secret = try!(token.claims.get_value::<String>("secret"));
+            
+            secret.ok_or(OpenIdConnectError::InvalidOneTimePassword)
+        },
+        _ => Err(OpenIdConnectError::InvalidOneTimePassword)
+    }
+}
+
+/// How the site is labelled in the user's authenticator app
+pub fn otp_issuer_name(site_config: &SiteConfig) -> String {
+    let issuer = site_config.get_issuer();
+    
+    url::Url::parse(&issuer).ok().and_then(|u| u.host_str().map(|h| h.to_owned())).unwrap_or(issuer)
+}
+
+fn account_user(config: &Config, user_session: &UserSession) -> Result<User> {
+    let user_id = try!(user_session.user_id.as_ref().ok_or(OpenIdConnectError::UserNotFound));
+    
+    try!(config.user_repo.get_user(user_id)).ok_or(OpenIdConnectError::UserNotFound)
+}
+
+fn account_form_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedBody>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+/// Shows the secret to scan, along with a fresh enrollment token for the confirmation form
+fn otp_enrollment_view(req: &mut Request, config: &Config, user: &User, secret: &str, invalid_code: bool) -> Result<View> {
+    let site_config = try!(SiteConfig::get(req));
+    let mut view = try!(View::new_for_session("account/otp.html", req));
+    
+    let otpauth_uri = totp::otpauth_uri(&otp_issuer_name(&site_config), &user.username, secret);
+    
+    view.data.insert("enabled".to_owned(), try!(value::to_value(&false).map_err(OpenIdConnectError::from)));
+    view.data.insert("secret".to_owned(), try!(value::to_value(&secret).map_err(OpenIdConnectError::from)));
+    view.data.insert("otpauth_uri".to_owned(), try!(value::to_value(&otpauth_uri).map_err(OpenIdConnectError::from)));
+    view.data.insert("enrollment".to_owned(), try!(value::to_value(&try!(new_otp_enrollment_token(config, &user.id, secret))).map_err(OpenIdConnectError::from)));
+    view.data.insert("invalid_code".to_owned(), try!(value::to_value(&invalid_code).map_err(OpenIdConnectError::from)));
+    
+    Ok(view)
+}
+
+pub fn account_sessions_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account/sessions", "/login"));
+
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let user_id = try!(user_session.user_id.clone().ok_or(OpenIdConnectError::UserNotFound));
+
+    let mut view = try!(View::new_for_session("account/sessions.html", req));
+
+    let site_config = try!(SiteConfig::get(req));
+    view.data.insert("sessions".to_owned(), try!(value::to_value(&try!(user_session_views(&config, &site_config, &user_session))).map_err(OpenIdConnectError::from)));
+    view.data.insert("refresh_tokens".to_owned(), try!(value::to_value(&try!(user_refresh_token_views(&config, &user_id))).map_err(OpenIdConnectError::from)));
+
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+pub fn account_session_revoke_handler(req: &mut Request) -> IronResult<Response> {
+    let ref session_id = try!(get_url_param(req, "id"));
+    let login_url = try!(account_redirect_url(req, "/account/sessions", "/login"));
+    let sessions_url = try!(relative_url(req, "/account/sessions", None));
+
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+
+    if try!(revoke_user_session(req, &user_session, session_id)) {
+        let home_url = try!(relative_url(req, "/", None));
+
+        Ok(Response::with((status::Found, Redirect(home_url))))
+    } else {
+        Ok(Response::with((status::Found, Redirect(sessions_url))))
+    }
+}
+
+pub fn account_token_revoke_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let ref token_id = try!(get_url_param(req, "id"));
+    let login_url = try!(account_redirect_url(req, "/account/sessions", "/login"));
+    let sessions_url = try!(relative_url(req, "/account/sessions", None));
+
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+
+    try!(config.token_repo.revoke_refresh_token(&user_id, token_id));
+
+    Ok(Response::with((status::Found, Redirect(sessions_url))))
+}
+
+/// Two-step verification settings.
+/// Users without it are offered a new secret to add to their authenticator app.
+pub fn account_otp_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account/otp", "/login"));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let user = try!(account_user(&config, &user_session));
+    
+    let view = if user.has_second_factor() {
+        let mut view = try!(View::new_for_session("account/otp.html", req));
+        
+        view.data.insert("enabled".to_owned(), try!(value::to_value(&true).map_err(OpenIdConnectError::from)));
+        view.data.insert("recovery_codes_left".to_owned(), try!(value::to_value(&user.recovery_codes.len()).map_err(OpenIdConnectError::from)));
+        
+        view
+    } else {
+        try!(otp_enrollment_view(req, &config, &user, &totp::new_totp_secret(), false))
+    };
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Turns on two-step verification once the user has entered a code from their app,
+/// then shows the recovery codes. This is the only time they're shown.
+pub fn account_otp_enroll_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account/otp", "/login"));
+    let otp_url = try!(relative_url(req, "/account/otp", None));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let mut user = try!(account_user(&config, &user_session));
+    
+    if user.has_second_factor() {
+        return Ok(Response::with((status::Found, Redirect(otp_url))));
+    }
+    
+    let params = try!(account_form_params(req));
+    
+    let secret = match load_otp_enrollment(req, &params, &user.id) {
+        Ok(secret) => secret,
+        Err(err) => {
+            debug!("error loading otp enrollment: {:?}", err);
+            return Ok(Response::with((status::Found, Redirect(otp_url))));
+        }
+    };
+    
+    let code = try!(multimap_get_maybe_one(&params, "code").map_err(OpenIdConnectError::from)).unwrap_or("");
+    
+    let step = match totp::verify_totp(&secret, code, UTC::now().timestamp()) {
+        Some(step) => step,
+        None => {
+            let view = try!(otp_enrollment_view(req, &config, &user, &secret, true));
+            
+            return Ok(Response::with((status::Ok, try!(view.template()))));
+        }
+    };
+    
+    let recovery_codes = user.enable_totp(secret, step);
+    try!(config.user_repo.update_user(user));
+    
+    let mut view = try!(View::new_for_session("account/otp.html", req));
+    
+    view.data.insert("enabled".to_owned(), try!(value::to_value(&true).map_err(OpenIdConnectError::from)));
+    view.data.insert("recovery_codes".to_owned(), try!(value::to_value(&recovery_codes).map_err(OpenIdConnectError::from)));
+    view.data.insert("recovery_codes_left".to_owned(), try!(value::to_value(&recovery_codes.len()).map_err(OpenIdConnectError::from)));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Turns off two-step verification. Needs a current code, or a recovery code.
+pub fn account_otp_disable_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account/otp", "/login"));
+    let otp_url = try!(relative_url(req, "/account/otp", None));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let mut user = try!(account_user(&config, &user_session));
+    
+    let params = try!(account_form_params(req));
+    let code = try!(multimap_get_maybe_one(&params, "code").map_err(OpenIdConnectError::from)).unwrap_or("");
+    
+    if user.verify_totp(code, UTC::now().timestamp()) || user.use_recovery_code(code) {
+        user.reset_second_factor();
+        try!(config.user_repo.update_user(user));
+    } else {
+        debug!("wrong code given to turn off two-step verification");
+    }
+    
+    Ok(Response::with((status::Found, Redirect(otp_url))))
+}
+
+/// The profile page, with a message flag set if the last form needs one
+fn profile_view(req: &mut Request, user: &User, message: Option<&str>) -> Result<View> {
+    let mut view = try!(View::new_for_session("account/profile.html", req));
+    
+    let claims: Vec<ProfileClaimView> = PROFILE_CLAIMS.iter().map(|name| ProfileClaimView {
+        name: name.to_string(),
+        value: user.claims.get(*name).and_then(|v| v.as_str()).map(|v| v.to_owned()),
+    }).collect();
+    
+    view.data.insert("username".to_owned(), try!(value::to_value(&user.username)));
+    view.data.insert("email".to_owned(), try!(value::to_value(&user.email)));
+    view.data.insert("email_verified".to_owned(), try!(value::to_value(&user.email_verified)));
+    view.data.insert("claims".to_owned(), try!(value::to_value(&claims)));
+    
+    if let Some(message) = message {
+        view.data.insert(message.to_owned(), try!(value::to_value(&true)));
+    }
+    
+    Ok(view)
+}
+
+fn form_value(params: &HashMap<String, Vec<String>>, name: &str) -> Result<Option<String>> {
+    let value = try!(multimap_get_maybe_one(params, name));
+    
+    Ok(value.map(|v| v.trim()).and_then(|v| if v.is_empty() { None } else { Some(v.to_owned()) }))
+}
+
+pub fn account_profile_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account", "/login"));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let user = try!(account_user(&config, &user_session));
+    
+    let view = try!(profile_view(req, &user, None));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Saves the profile claims and email address.
+/// A new address is sent a verification link.
+pub fn account_profile_update_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account", "/login"));
+    let account_url = try!(relative_url(req, "/account", None));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let mut user = try!(account_user(&config, &user_session));
+    
+    let params = try!(account_form_params(req));
+    
+    let mut changed = false;
+    
+    for name in PROFILE_CLAIMS {
+        let value = try!(form_value(&params, name));
+        
+        if value.as_ref().map(|v| &v[..]) != user.claims.get(*name).and_then(|v| v.as_str()) {
+            user.set_claim(name, value);
+            changed = true;
+        }
+    }
+    
+    let email = try!(form_value(&params, "email"));
+    let email_changed = email.is_some() && email != user.email;
+    
+    if email_changed {
+        let email = email.unwrap();
+        
+        if !is_valid_email(&email) {
+            let view = try!(profile_view(req, &user, Some("email_invalid")));
+            return Ok(Response::with((status::Ok, try!(view.template()))));
+        }
+        
+        if try!(config.user_repo.find_user_by_email(&email)).map(|u| u.id != user.id).unwrap_or(false) {
+            let view = try!(profile_view(req, &user, Some("email_taken")));
+            return Ok(Response::with((status::Ok, try!(view.template()))));
+        }
+        
+        user.set_email(Some(email));
+        changed = true;
+    }
+    
+    if changed {
+        user.touch(UTC::now());
+        try!(config.user_repo.update_user(user.clone()));
+    }
+    
+    if email_changed {
+        if let Err(err) = send_verification_email(req, &user) {
+            error!("error sending verification email: {:?}", err);
+        }
+    }
+    
+    Ok(Response::with((status::Found, Redirect(account_url))))
+}
+
+/// Changes the password, given the current one.
+/// Every other session the user has is signed out.
+pub fn account_password_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    let login_url = try!(account_redirect_url(req, "/account", "/login"));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let mut user = try!(account_user(&config, &user_session));
+    
+    let params = try!(account_form_params(req));
+    let current_password = try!(multimap_get_maybe_one(&params, "current_password").map_err(OpenIdConnectError::from)).unwrap_or("");
+    let password = try!(multimap_get_maybe_one(&params, "password").map_err(OpenIdConnectError::from)).unwrap_or("");
+    let confirmation = try!(multimap_get_maybe_one(&params, "password_confirmation").map_err(OpenIdConnectError::from)).unwrap_or("");
+    
+    let message = if !user.verify_password(current_password) {
+        "password_incorrect"
+    } else if password.is_empty() || password != confirmation {
+        "password_mismatch"
+    } else {
+        user.set_password(password);
+        user.touch(UTC::now());
+        try!(config.user_repo.update_user(user.clone()));
+        
+        for record in try!(config.session_controller.get_user_sessions(&site_config, &user.id)) {
+            if user_session.session_id.as_ref() != Some(&record.session_id) {
+                try!(config.session_controller.revoke_session(&record.session_id));
+            }
+        }
+        
+        "password_changed"
+    };
+    
+    let view = try!(profile_view(req, &user, Some(message)));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Deletes the account, given the current password.
+/// The user's grants, tokens and sessions go with it.
+pub fn account_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(account_redirect_url(req, "/account", "/login"));
+    let home_url = try!(relative_url(req, "/", None));
+    
+    let user_session = match try!(authenticated_session(req)) {
+        Some(user_session) => user_session,
+        None => return Ok(Response::with((status::Found, Redirect(login_url)))),
+    };
+    let user = try!(account_user(&config, &user_session));
+    
+    let params = try!(account_form_params(req));
+    let password = try!(multimap_get_maybe_one(&params, "current_password").map_err(OpenIdConnectError::from)).unwrap_or("");
+    
+    if !user.verify_password(password) {
+        let view = try!(profile_view(req, &user, Some("delete_password_incorrect")));
+        
+        return Ok(Response::with((status::Ok, try!(view.template()))));
+    }
+    
+    try!(config.session_controller.clear_session(req));
+    try!(delete_user(&config, &user));
+    
+    Ok(Response::with((status::Found, Redirect(home_url))))
+}
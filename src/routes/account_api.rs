@@ -0,0 +1,63 @@
+use iron::prelude::*;
+use iron::status;
+use serde_json;
+
+use config::Config;
+use site_config::SiteConfig;
+use result::*;
+use helpers::*;
+use routes::account::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionList {
+    items: Vec<SessionView>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshTokenList {
+    items: Vec<RefreshTokenView>
+}
+
+pub fn account_sessions_get_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+
+    let sessions_list = SessionList { items: try!(user_session_views(&config, &site_config, &user_session)) };
+
+    let sessions_json = try!(serde_json::to_string(&sessions_list).map_err(OpenIdConnectError::from));
+
+    Ok(Response::with((status::Ok, sessions_json)))
+}
+
+pub fn account_sessions_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let ref session_id = try!(get_url_param(req, "id"));
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+
+    try!(revoke_user_session(req, &user_session, session_id));
+
+    Ok(Response::with(status::NoContent))
+}
+
+pub fn account_tokens_get_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+
+    let tokens_list = RefreshTokenList { items: try!(user_refresh_token_views(&config, &user_id)) };
+
+    let tokens_json = try!(serde_json::to_string(&tokens_list).map_err(OpenIdConnectError::from));
+
+    Ok(Response::with((status::Ok, tokens_json)))
+}
+
+pub fn account_tokens_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let ref token_id = try!(get_url_param(req, "id"));
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+
+    try!(config.token_repo.revoke_refresh_token(&user_id, token_id));
+
+    Ok(Response::with(status::NoContent))
+}
@@ -12,6 +12,21 @@ use oauth2::repos::*;
 use view::View;
 use helpers::*;
 use urls::relative_url;
+use oauth2::subject::*;
+
+/// A checkbox on the edit form for one of the privileged scopes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScopeOption {
+    pub scope: String,
+    pub allowed: bool,
+}
+
+fn privileged_scope_options(client_app: &ClientApplication) -> Vec<ScopeOption> {
+    PRIVILEGED_SCOPES.iter().map(|scope| ScopeOption {
+        scope: scope.to_string(),
+        allowed: client_app.allowed_scopes.iter().any(|s| s == scope),
+    }).collect()
+}
 
 pub fn applications_index_handler(req: &mut Request) -> IronResult<Response> {
     let config = try!(Config::get(req));
@@ -44,6 +59,10 @@ pub fn applications_show_handler(req: &mut Request) -> IronResult<Response> {
     view.data.insert("client_name".to_owned(), try!(value::to_value(&client_app.client_name).map_err(OpenIdConnectError::from)));
     view.data.insert("redirect_uris".to_owned(), try!(value::to_value(&client_app.redirect_uris).map_err(OpenIdConnectError::from)));
     view.data.insert("client_id".to_owned(), try!(value::to_value(&client_id).map_err(OpenIdConnectError::from)));
+    view.data.insert("subject_type".to_owned(), try!(value::to_value(&client_app.subject_type).map_err(OpenIdConnectError::from)));
+    view.data.insert("sector_identifier_uri".to_owned(), try!(value::to_value(&client_app.sector_identifier_uri).map_err(OpenIdConnectError::from)));
+    view.data.insert("pairwise".to_owned(), try!(value::to_value(&is_pairwise(&client_app)).map_err(OpenIdConnectError::from)));
+    view.data.insert("allowed_scopes".to_owned(), try!(value::to_value(&client_app.allowed_scopes).map_err(OpenIdConnectError::from)));
     
     Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
 }
@@ -61,6 +80,10 @@ pub fn applications_edit_handler(req: &mut Request) -> IronResult<Response> {
     view.data.insert("client_name".to_owned(), try!(value::to_value(&client_app.client_name).map_err(OpenIdConnectError::from)));
     view.data.insert("redirect_uris".to_owned(), try!(value::to_value(&client_app.redirect_uris).map_err(OpenIdConnectError::from)));
     view.data.insert("client_id".to_owned(), try!(value::to_value(&client_id).map_err(OpenIdConnectError::from)));
+    view.data.insert("subject_type".to_owned(), try!(value::to_value(&client_app.subject_type).map_err(OpenIdConnectError::from)));
+    view.data.insert("sector_identifier_uri".to_owned(), try!(value::to_value(&client_app.sector_identifier_uri).map_err(OpenIdConnectError::from)));
+    view.data.insert("pairwise".to_owned(), try!(value::to_value(&is_pairwise(&client_app)).map_err(OpenIdConnectError::from)));
+    view.data.insert("privileged_scopes".to_owned(), try!(value::to_value(&privileged_scope_options(&client_app)).map_err(OpenIdConnectError::from)));
     
     Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
 }
@@ -83,6 +106,11 @@ pub fn applications_update_handler(req: &mut Request) -> IronResult<Response> {
     
         client_app.client_name = builder.client_name;
         client_app.redirect_uris = builder.redirect_uris.unwrap_or(vec![]);
+        client_app.subject_type = builder.subject_type;
+        client_app.sector_identifier_uri = builder.sector_identifier_uri;
+        client_app.allowed_scopes = builder.allowed_scopes.unwrap_or(vec![]);
+        
+        try!(validate_client_subject_type(&**config.sector_identifier_fetcher, &client_app));
     
         try!(config.application_repo.update_client_application(&client_app));
     }
@@ -99,6 +127,11 @@ pub fn applications_create_handler(req: &mut Request) -> IronResult<Response> {
         let mut builder = ClientApplicationBuilder::new();
     
         try!(builder.load_params(&params));
+        
+        try!(validate_subject_type(&**config.sector_identifier_fetcher,
+            builder.subject_type.as_ref().map(|s| &s[..]),
+            builder.sector_identifier_uri.as_ref().map(|s| &s[..]),
+            builder.redirect_uris.as_ref().map(|r| &r[..]).unwrap_or(&[])));
     
         let ca = try!(config.application_repo.create_client_application(builder));
 
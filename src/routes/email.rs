@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::status;
+use iron::modifiers::Redirect;
+use urlencoded::*;
+use serde_json::value;
+use chrono::*;
+use rbvt::params::*;
+
+use config::Config;
+use site_config::SiteConfig;
+use result::{Result, OpenIdConnectError};
+use urls::*;
+use view::View;
+use users::User;
+use mailer::Email;
+use verification::*;
+use routes::account::authenticated_session;
+
+fn query_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+fn form_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedBody>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+fn param(params: &HashMap<String, Vec<String>>, name: &str) -> Result<Option<String>> {
+    Ok(try!(multimap_get_maybe_one(params, name)).map(|s| s.to_owned()))
+}
+
+/// Emails the user a link to prove they own their address
+pub fn send_verification_email(req: &mut Request, user: &User) -> Result<()> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    let email = try!(user.email.clone().ok_or(OpenIdConnectError::InvalidRequest("user has no email address".to_owned())));
+    
+    let token = try!(new_email_verification_token(&config.mac_signer, user, UTC::now(), site_config.get_email_verification_duration()));
+    
+    let mut params = HashMap::new();
+    params.insert("token".to_owned(), vec![token]);
+    let link = try!(relative_url(req, "/verify-email", Some(params)));
+    
+    let body = format!("Hello {},\n\nPlease confirm your email address by following this link:\n\n{}\n\nIf you didn't create an account, you can ignore this email.\n", user.username, link);
+    
+    config.mailer.send(&try!(Email::new(site_config.get_mail_from(), email, "Confirm your email address", body)))
+}
+
+/// Emails the user a link to choose a new password
+pub fn send_password_reset_email(req: &mut Request, user: &User) -> Result<()> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+    let email = try!(user.email.clone().ok_or(OpenIdConnectError::InvalidRequest("user has no email address".to_owned())));
+    
+    let token = try!(new_password_reset_token(&config.mac_signer, user, UTC::now(), site_config.get_password_reset_duration()));
+    
+    let mut params = HashMap::new();
+    params.insert("token".to_owned(), vec![token]);
+    let link = try!(relative_url(req, "/password-reset/confirm", Some(params)));
+    
+    let body = format!("Hello {},\n\nSomeone asked to reset the password for your account. To choose a new password, follow this link:\n\n{}\n\nIf it wasn't you, you can ignore this email and your password won't change.\n", user.username, link);
+    
+    config.mailer.send(&try!(Email::new(site_config.get_mail_from(), email, "Reset your password", body)))
+}
+
+/// Where the verification link in the email goes
+pub fn verify_email_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(query_params(req));
+    let token = try!(param(&params, "token")).unwrap_or(String::new());
+    
+    let verified = match load_email_verification_token(&config.mac_signer, &token, UTC::now()) {
+        Ok((user_id, email)) => {
+            match try!(config.user_repo.get_user(&user_id)) {
+                Some(mut user) => {
+                    // the address might have changed since the link was sent
+                    if user.email.as_ref() == Some(&email) {
+                        user.email_verified = true;
+                        try!(config.user_repo.update_user(user));
+                        true
+                    } else {
+                        false
+                    }
+                },
+                None => false
+            }
+        },
+        Err(err) => {
+            debug!("invalid email verification link: {:?}", err);
+            false
+        }
+    };
+    
+    let mut view = try!(View::new_for_session("verify_email.html", req));
+    view.data.insert("verified".to_owned(), try!(value::to_value(&verified).map_err(OpenIdConnectError::from)));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Sends the logged in user another verification link
+pub fn verify_email_resend_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    
+    let user_session = try!(try!(authenticated_session(req)).ok_or(OpenIdConnectError::NotAuthenticated));
+    let user_id = try!(user_session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+    let user = try!(try!(config.user_repo.get_user(&user_id)).ok_or(OpenIdConnectError::UserNotFound));
+    
+    if !user.email_verified {
+        try!(send_verification_email(req, &user));
+    }
+    
+    let mut view = try!(View::new_for_session("verify_email.html", req));
+    view.data.insert("sent".to_owned(), try!(value::to_value(&true).map_err(OpenIdConnectError::from)));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+pub fn password_reset_get_handler(req: &mut Request) -> IronResult<Response> {
+    let view = try!(View::new_for_session("password_reset.html", req));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Sends a reset link if the address belongs to a user.
+/// The page looks the same either way, so it can't be used to find out who has an account.
+pub fn password_reset_post_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(form_params(req));
+    
+    if let Some(email) = try!(param(&params, "email")) {
+        if let Some(user) = try!(config.user_repo.find_user_by_email(&email)) {
+            if let Err(err) = send_password_reset_email(req, &user) {
+                error!("error sending password reset email: {:?}", err);
+            }
+        } else {
+            debug!("password reset for unknown address");
+        }
+    }
+    
+    let mut view = try!(View::new_for_session("password_reset.html", req));
+    view.data.insert("sent".to_owned(), try!(value::to_value(&true).map_err(OpenIdConnectError::from)));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// The user for a reset link, as long as the link is still good
+fn password_reset_user(config: &Config, token: &str) -> Result<Option<User>> {
+    let now = UTC::now();
+    
+    let user_id = match load_password_reset_token(&config.mac_signer, token, now) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            debug!("invalid password reset link: {:?}", err);
+            return Ok(None);
+        }
+    };
+    
+    match try!(config.user_repo.get_user(&user_id)) {
+        Some(user) => {
+            if try!(password_reset_token_matches(&config.mac_signer, token, &user, now)) {
+                Ok(Some(user))
+            } else {
+                Ok(None)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+fn password_reset_confirm_view(req: &mut Request, token: &str, valid: bool, mismatch: bool) -> Result<View> {
+    let mut view = try!(View::new_for_session("password_reset_confirm.html", req));
+    
+    view.data.insert("token".to_owned(), try!(value::to_value(&token)));
+    view.data.insert("valid".to_owned(), try!(value::to_value(&valid)));
+    view.data.insert("mismatch".to_owned(), try!(value::to_value(&mismatch)));
+    
+    Ok(view)
+}
+
+/// Where the reset link in the email goes
+pub fn password_reset_confirm_get_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(query_params(req));
+    let token = try!(param(&params, "token")).unwrap_or(String::new());
+    
+    let valid = try!(password_reset_user(&config, &token)).is_some();
+    let view = try!(password_reset_confirm_view(req, &token, valid, false));
+    
+    Ok(Response::with((status::Ok, try!(view.template()))))
+}
+
+/// Sets the new password, then logs the user out everywhere and sends them to log in
+pub fn password_reset_confirm_post_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let login_url = try!(relative_url(req, "/login", None));
+    let params = try!(form_params(req));
+    let token = try!(param(&params, "token")).unwrap_or(String::new());
+    
+    let mut user = match try!(password_reset_user(&config, &token)) {
+        Some(user) => user,
+        None => {
+            let view = try!(password_reset_confirm_view(req, &token, false, false));
+            return Ok(Response::with((status::Ok, try!(view.template()))));
+        }
+    };
+    
+    let password = try!(param(&params, "password")).unwrap_or(String::new());
+    let confirmation = try!(param(&params, "password_confirmation")).unwrap_or(String::new());
+    
+    if password.is_empty() || password != confirmation {
+        let view = try!(password_reset_confirm_view(req, &token, true, true));
+        return Ok(Response::with((status::Ok, try!(view.template()))));
+    }
+    
+    info!("password reset for user {}", user.id);
+    
+    user.set_password(&password);
+    // they got the link, so they own the address
+    user.email_verified = true;
+    try!(config.user_repo.update_user(user.clone()));
+    
+    try!(config.session_controller.revoke_user_sessions(&user.id));
+    config.session_controller.throttle.unlock_user(&user.username);
+    
+    Ok(Response::with((status::Found, Redirect(login_url))))
+}
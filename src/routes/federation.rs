@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use iron;
+use iron::prelude::*;
+use iron::status;
+use iron::modifiers::Redirect;
+use iron_sessionstorage;
+use iron_sessionstorage::traits::*;
+use urlencoded::*;
+use serde_json;
+use chrono::*;
+use crypto::util::fixed_time_eq;
+use rbvt::params::*;
+
+use config::Config;
+use site_config::SiteConfig;
+use result::{Result, OpenIdConnectError};
+use urls::*;
+use helpers::*;
+use authentication;
+use federation::{link_user, UpstreamProvider};
+use back::*;
+use service::routes::login::*;
+
+/// An upstream login in progress, kept in the signed session cookie.
+/// The state ties the callback to the browser that started the login,
+/// and the nonce ties the id_token to this login.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamLoginState {
+    pub provider_id: String,
+    pub state: String,
+    pub nonce: String,
+    pub return_token: Option<String>,
+    pub expires_at: i64,
+}
+
+impl UpstreamLoginState {
+    pub fn new(provider_id: &str, return_token: Option<String>, now: DateTime<UTC>) -> UpstreamLoginState {
+        UpstreamLoginState {
+            provider_id: provider_id.to_owned(),
+            state: authentication::new_token(),
+            nonce: authentication::new_nonce(),
+            return_token: return_token,
+            expires_at: (now + Duration::minutes(10)).timestamp(),
+        }
+    }
+
+    /// Left in place of a state that's been used, so the callback can't be replayed
+    pub fn spent() -> UpstreamLoginState {
+        UpstreamLoginState {
+            provider_id: String::new(),
+            state: String::new(),
+            nonce: String::new(),
+            return_token: None,
+            expires_at: 0,
+        }
+    }
+
+    pub fn matches(&self, provider_id: &str, state: &str, now: DateTime<UTC>) -> bool {
+        !self.state.is_empty()
+            && self.provider_id == provider_id
+            && self.expires_at > now.timestamp()
+            && fixed_time_eq(self.state.as_bytes(), state.as_bytes())
+    }
+
+    /// The return token as params, for the redirect helpers in back and login
+    pub fn return_params(&self) -> HashMap<String, Vec<String>> {
+        let mut params = HashMap::new();
+
+        if let Some(ref return_token) = self.return_token {
+            params.insert("return".to_owned(), vec![return_token.clone()]);
+        }
+
+        params
+    }
+}
+
+impl iron_sessionstorage::Value for UpstreamLoginState {
+    fn get_key() -> &'static str { "upstream_login" }
+    fn into_raw(self) -> String { serde_json::to_string(&self).unwrap_or(String::new()) }
+    fn from_raw(value: String) -> Option<Self> {
+        if value.is_empty() {
+            None
+        } else {
+            serde_json::from_str(&value).map(|s| Some(s)).unwrap_or(None)
+        }
+    }
+}
+
+fn upstream_provider(req: &mut Request, provider_id: &str) -> Result<UpstreamProvider> {
+    let site_config = try!(SiteConfig::get(req));
+
+    site_config.find_upstream_provider(provider_id)
+        .map(|p| p.clone())
+        .ok_or(OpenIdConnectError::UpstreamLoginError(format!("no upstream provider {}", provider_id)))
+}
+
+/// Where the upstream provider sends the user back to.
+/// This has to be registered with the provider.
+pub fn upstream_callback_url(req: &mut Request, provider_id: &str) -> Result<String> {
+    let url = try!(relative_url(req, &format!("/login/upstream/{}/callback", provider_id), None));
+
+    Ok(format!("{}", url))
+}
+
+fn query_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+/// Sends the user to the upstream provider's login page.
+/// The return token from the login page comes along, to be used once they're back.
+pub fn upstream_login_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let ref provider_id = try!(get_url_param(req, "id"));
+    let provider = try!(upstream_provider(req, provider_id));
+
+    let params = try!(query_params(req));
+    let return_token = try!(multimap_get_maybe_one(&params, "return").map_err(OpenIdConnectError::from)).map(|s| s.to_owned());
+
+    let login_state = UpstreamLoginState::new(provider_id, return_token, UTC::now());
+    let redirect_uri = try!(upstream_callback_url(req, provider_id));
+
+    let url = try!(config.upstream.authorization_url(&provider, &redirect_uri, &login_state.state, &login_state.nonce));
+    let url = try!(iron::Url::from_generic_url(url).map_err(|e| OpenIdConnectError::UrlError(e)));
+
+    try!(req.session().set(login_state));
+
+    Ok(Response::with((status::Found, Redirect(url))))
+}
+
+/// The upstream provider sends the user back here with a code.
+/// Once the identity checks out and has a local user, that user is logged in
+/// and carries on to wherever they were going before the login page.
+pub fn upstream_callback_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let home_url = try!(relative_url(req, "/", None));
+    let ref provider_id = try!(get_url_param(req, "id"));
+    let provider = try!(upstream_provider(req, provider_id));
+    let now = UTC::now();
+
+    let params = try!(query_params(req));
+
+    let login_state = try!(try!(req.session().get::<UpstreamLoginState>()).ok_or(OpenIdConnectError::UpstreamLoginError("no upstream login in progress".to_owned())));
+    let return_params = login_state.return_params();
+
+    let state = try!(multimap_get_maybe_one(&params, "state").map_err(OpenIdConnectError::from)).unwrap_or("");
+
+    if !login_state.matches(provider_id, state, now) {
+        return Err(IronError::from(OpenIdConnectError::UpstreamLoginError("state doesn't match".to_owned())));
+    }
+
+    try!(req.session().set(UpstreamLoginState::spent()));
+
+    if let Some(error) = try!(multimap_get_maybe_one(&params, "error").map_err(OpenIdConnectError::from)) {
+        // e.g. access_denied when the user cancels
+        debug!("upstream login error from {}: {}", provider_id, error);
+
+        return Ok(Response::with((status::Found, Redirect(try!(login_again_url(req, &return_params))))));
+    }
+
+    let code = try!(try!(multimap_get_maybe_one(&params, "code").map_err(OpenIdConnectError::from)).ok_or(OpenIdConnectError::UpstreamLoginError("no code".to_owned())));
+    let redirect_uri = try!(upstream_callback_url(req, provider_id));
+
+    let identity = try!(config.upstream.identity(&provider, code, &redirect_uri, &login_state.nonce, now));
+    let user = try!(link_user(&**config.user_repo, &provider, &identity, now));
+    
+    if user.disabled {
+        return Err(IronError::from(OpenIdConnectError::AccountDisabled));
+    }
+
+    let session = config.session_controller.authenticate_upstream(&user);
+
+    if session.authenticated {
+        try!(config.session_controller.start_session(req, session));
+
+        Ok(Response::with((status::Found, Redirect(try!(redirect_back_url(req, &return_params)).unwrap_or(home_url)))))
+    } else {
+        let mfa_token = try!(new_second_factor_token(req, &user.id));
+
+        Ok(Response::with((status::Found, Redirect(try!(second_factor_url(req, mfa_token, &return_params))))))
+    }
+}
@@ -3,4 +3,10 @@ pub mod register;
 pub mod application_api;
 pub mod session;
 pub mod applications;
-pub mod grants;
\ No newline at end of file
+pub mod grants;
+pub mod account;
+pub mod account_api;
+pub mod user_api;
+pub mod users;
+pub mod email;
+pub mod federation;
@@ -3,7 +3,6 @@ use std::collections::HashMap;
 use iron::prelude::*;
 use iron::status;
 use iron::modifiers::Redirect;
-use iron_sessionstorage::traits::*;
 use urlencoded::{UrlEncodedBody, UrlEncodedQuery};
 use serde_json::value;
 
@@ -16,16 +15,19 @@ use config::Config;
 use users::*;
 use authentication::*;
 use view::View;
+use routes::email::send_verification_email;
 
 #[derive(Clone, Debug)]
 pub struct RegisterRequest {
     username: String,
+    email: String,
     password: String
 }
 
 #[derive(Clone, Debug)]
 pub struct RegisterRequestBuilder {
     username: Option<String>,
+    email: Option<String>,
     password: Option<String>,
 }
 
@@ -33,6 +35,7 @@ impl RegisterRequestBuilder {
     pub fn new() -> RegisterRequestBuilder {
         RegisterRequestBuilder {
             username: None,
+            email: None,
             password: None,
         }
     }
@@ -40,6 +43,7 @@ impl RegisterRequestBuilder {
     pub fn build(self) -> Result<RegisterRequest> {
         Ok(RegisterRequest {
             username: self.username.unwrap(),
+            email: self.email.unwrap(),
             password: self.password.unwrap(),
         })
     }
@@ -49,6 +53,16 @@ impl RegisterRequestBuilder {
             state.reject("username", ValidationError::MissingRequiredValue("username".to_owned()));
         }
         
+        match self.email {
+            Some(ref email) if !is_valid_email(email) => {
+                state.reject("email", ValidationError::InvalidValue("email".to_owned()));
+            },
+            None => {
+                state.reject("email", ValidationError::MissingRequiredValue("email".to_owned()));
+            },
+            _ => {}
+        }
+        
         if self.password.is_none() {
             state.reject("password", ValidationError::MissingRequiredValue("password".to_owned()));
         }
@@ -61,6 +75,10 @@ impl RegisterRequestBuilder {
             self.username = Some(username.to_owned());
         }
         
+        if let Some(email) = try!(multimap_get_maybe_one(params, "email")) {
+            self.email = Some(email.trim().to_owned());
+        }
+        
         if let Some(password) = try!(multimap_get_maybe_one(params, "password")) {
             self.password = Some(password.to_owned());
         }
@@ -84,6 +102,7 @@ impl RegisterRequestBuilder {
     
     pub fn populate_view(&self, view: &mut View) -> Result<()> {
         view.data.insert("username".to_owned(), try!(value::to_value(&self.username)));
+        view.data.insert("email".to_owned(), try!(value::to_value(&self.email)));
         view.data.insert("password".to_owned(), try!(value::to_value(&self.password)));
         Ok(())
     }
@@ -101,6 +120,7 @@ pub fn register_get_handler(req: &mut Request) -> IronResult<Response> {
                 Ok(register_request) => {
                     //TODO escape values to protect against cross-site-scripting
                     register_form.username = Some(register_request.username);
+                    register_form.email = Some(register_request.email);
                     register_form.password = Some(register_request.password);
                 },
                 Err(err) => {
@@ -127,26 +147,36 @@ pub fn register_post_handler(req: &mut Request) -> IronResult<Response> {
     match req.get::<UrlEncodedBody>() {
         Ok(params) => {
             debug!("registering new user with creds {:?}", params);
-            // TODO validate csrf
             // TODO create session and set cookie
             // TODO multistep registration flow
             // TODO redirect to flow caller
             
             match RegisterRequestBuilder::build_from_params(&params) {
                 Ok(register_request) => {
-                    let user = User::new(new_user_id(), register_request.username, Some(register_request.password));
+                    if try!(config.user_repo.find_user_by_email(&register_request.email)).is_some() {
+                        // TODO render error as flash message
+                        debug!("email address already registered");
+                        
+                        return Ok(Response::with((status::Found, Redirect(register_url))));
+                    }
+                    
+                    let mut user = User::new(new_user_id(), register_request.username, Some(register_request.password));
+                    user.set_email(Some(register_request.email));
                     
                     debug!("add user to repo: {:?}", user);
                 
                     // TODO render error as flash message
-                    try!(config.user_repo.add_user(user));
+                    try!(config.user_repo.add_user(user.clone()));
                     
-                    // TODO send email to user with confirmation token
+                    // the account works without it, so a failed delivery shouldn't fail registration
+                    if let Err(err) = send_verification_email(req, &user) {
+                        error!("error sending verification email: {:?}", err);
+                    }
                     
                     let login = try!(config.session_controller.login_with_credentials(req));
 
                     if let Some(session) = login.session {
-                        try!(req.session().set(session));
+                        try!(config.session_controller.start_session(req, session));
                     
                         Ok(Response::with((status::Found, Redirect(home_url))))
                     } else {
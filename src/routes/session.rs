@@ -53,7 +53,7 @@ pub fn session_post_handler(req: &mut Request) -> IronResult<Response> {
     debug!("received credentials: {:?}", creds);
     
     // TODO use generic session_controller.login
-    match config.session_controller.authenticate(&creds) {
+    match config.session_controller.authenticate_request(req, &creds) {
         Ok(session) => {
             let session_json = try!(serialize_session(&session));
             
@@ -63,6 +63,12 @@ pub fn session_post_handler(req: &mut Request) -> IronResult<Response> {
         },
         Err(OpenIdConnectError::InvalidUsernameOrPassword) => {
             Ok(Response::with((status::Forbidden, "invalid username or password")))   
+        },
+        Err(OpenIdConnectError::LoginThrottled(retry_after)) => {
+            let mut res = Response::with((status::TooManyRequests, "too many failed logins"));
+            res.headers.set_raw("Retry-After", vec![format!("{}", retry_after).into_bytes()]);
+            
+            Ok(res)
         },    
         _ => {
             Ok(Response::with((status::InternalServerError, "post session not implemented")))
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::status;
+use bodyparser;
+use urlencoded::*;
+use serde_json;
+use serde_json::Value;
+use chrono::*;
+use rbvt::params::*;
+
+use config::Config;
+use result::*;
+use helpers::*;
+use users::*;
+use truthy::Truthy;
+use authentication;
+use authorization::AuthorizedUser;
+use routes::email::send_password_reset_email;
+
+// Managing other users' accounts, for admins. See admin_api_handler.
+
+const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserView {
+    pub id: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub roles: Vec<String>,
+    pub disabled: bool,
+    pub second_factor: bool,
+    pub claims: HashMap<String, Value>,
+}
+
+impl UserView {
+    pub fn new(user: &User) -> UserView {
+        UserView {
+            id: user.id.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            email_verified: user.email_verified,
+            roles: user.roles.iter().map(|r| r.as_str().to_owned()).collect(),
+            disabled: user.disabled,
+            second_factor: user.has_second_factor(),
+            claims: user.claims.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserList {
+    pub items: Vec<UserView>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// New user, as posted to /api/users
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserCreate {
+    pub username: String,
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub roles: Option<Vec<String>>,
+    pub claims: Option<HashMap<String, Value>>,
+}
+
+/// Changes to a user, as put to /api/users/:id. Fields left out aren't changed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserUpdate {
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub roles: Option<Vec<String>>,
+    pub disabled: Option<bool>,
+    pub claims: Option<HashMap<String, Value>>,
+}
+
+pub fn parse_roles(names: &[String]) -> Result<Vec<Role>> {
+    let mut roles = vec![];
+    
+    for name in names {
+        let role = try!(Role::from_str(name).ok_or(OpenIdConnectError::InvalidRequest(format!("unknown role {}", name))));
+        
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+    
+    Ok(roles)
+}
+
+pub fn check_email(email: &str) -> Result<()> {
+    if is_valid_email(email) {
+        Ok(())
+    } else {
+        Err(OpenIdConnectError::InvalidRequest(format!("invalid email address {}", email)))
+    }
+}
+
+fn check_email_available(config: &Config, user_id: Option<&str>, email: &str) -> Result<()> {
+    try!(check_email(email));
+    
+    match try!(config.user_repo.find_user_by_email(email)) {
+        Some(ref other) if Some(&other.id[..]) != user_id => Err(OpenIdConnectError::InvalidRequest(format!("email address {} is already used", email))),
+        _ => Ok(())
+    }
+}
+
+impl UserCreate {
+    pub fn build(self, config: &Config) -> Result<User> {
+        if self.username.trim().is_empty() {
+            return Err(OpenIdConnectError::InvalidRequest("username is required".to_owned()));
+        }
+        
+        if try!(config.user_repo.find_user(&self.username)).is_some() {
+            return Err(OpenIdConnectError::UserAlreadyExists);
+        }
+        
+        let mut user = User::new(authentication::new_user_id(), self.username, self.password);
+        
+        if let Some(email) = self.email {
+            try!(check_email_available(config, None, &email));
+            user.set_email(Some(email));
+            user.email_verified = self.email_verified.unwrap_or(false);
+        }
+        
+        if let Some(roles) = self.roles {
+            user.roles = try!(parse_roles(&roles));
+        }
+        
+        if let Some(claims) = self.claims {
+            user.claims = claims;
+        }
+        
+        user.touch(UTC::now());
+        
+        Ok(user)
+    }
+}
+
+impl UserUpdate {
+    /// Applies the changes. Returns true if the user was disabled by them.
+    pub fn apply(self, config: &Config, user: &mut User) -> Result<bool> {
+        if let Some(email) = self.email {
+            if email.is_empty() {
+                user.set_email(None);
+            } else {
+                try!(check_email_available(config, Some(&user.id), &email));
+                user.set_email(Some(email));
+            }
+        }
+        
+        if let Some(email_verified) = self.email_verified {
+            user.email_verified = email_verified && user.email.is_some();
+        }
+        
+        if let Some(password) = self.password {
+            user.set_password(&password);
+        }
+        
+        if let Some(roles) = self.roles {
+            user.roles = try!(parse_roles(&roles));
+        }
+        
+        if let Some(claims) = self.claims {
+            user.claims = claims;
+        }
+        
+        let disabling = self.disabled == Some(true) && !user.disabled;
+        
+        if let Some(disabled) = self.disabled {
+            user.disabled = disabled;
+        }
+        
+        user.touch(UTC::now());
+        
+        Ok(disabling)
+    }
+}
+
+/// Filters and paging from the query string: q, role, disabled, offset and limit
+pub fn user_query_from_params(params: &HashMap<String, Vec<String>>) -> Result<UserQuery> {
+    let mut query = UserQuery::new();
+    
+    query.search = try!(multimap_get_maybe_one(params, "q")).map(|s| s.trim().to_owned()).and_then(|s| if s.is_empty() { None } else { Some(s) });
+    
+    if let Some(role) = try!(multimap_get_maybe_one(params, "role")) {
+        if !role.is_empty() {
+            query.role = Some(try!(Role::from_str(role).ok_or(OpenIdConnectError::InvalidRequest(format!("unknown role {}", role)))));
+        }
+    }
+    
+    if let Some(disabled) = try!(multimap_get_maybe_one(params, "disabled")) {
+        if !disabled.is_empty() {
+            query.disabled = Some(try!(disabled.parse::<Truthy>()).into());
+        }
+    }
+    
+    if let Some(offset) = try!(multimap_get_maybe_one(params, "offset")) {
+        query.offset = try!(offset.parse::<usize>());
+    }
+    
+    if let Some(limit) = try!(multimap_get_maybe_one(params, "limit")) {
+        query.limit = try!(limit.parse::<usize>());
+    }
+    
+    if query.limit == 0 || query.limit > MAX_PAGE_SIZE {
+        query.limit = MAX_PAGE_SIZE;
+    }
+    
+    Ok(query)
+}
+
+/// Ends the user's sessions and revokes their tokens, so they have to log in again everywhere
+pub fn force_relogin(config: &Config, user: &User) -> Result<()> {
+    info!("logging out user {} everywhere", user.id);
+    
+    try!(config.token_repo.revoke_user_tokens(&user.id));
+    try!(config.session_controller.revoke_user_sessions(&user.id));
+    
+    Ok(())
+}
+
+/// Removes the user's password and logs them out everywhere.
+/// They're sent a reset link if they have an email address, otherwise an admin has to set a new password.
+/// Returns whether the link was sent.
+pub fn reset_user_password(req: &mut Request, user: &mut User) -> Result<bool> {
+    let config = try!(Config::get(req));
+    
+    info!("resetting password for user {}", user.id);
+    
+    user.hashed_password = None;
+    try!(config.user_repo.update_user(user.clone()));
+    try!(force_relogin(&config, user));
+    
+    if user.email.is_some() {
+        try!(send_password_reset_email(req, user));
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Deletes the user along with their grants, tokens, sessions and group memberships
+pub fn delete_user(config: &Config, user: &User) -> Result<()> {
+    info!("deleting user {}", user.id);
+    
+    try!(config.grant_repo.remove_user_grants(&user.id));
+    try!(config.group_repo.remove_member_everywhere(&user.id));
+    try!(force_relogin(config, user));
+    try!(config.user_repo.remove_user(&user.username));
+    
+    Ok(())
+}
+
+/// Stops admins locking themselves out, so there's always someone left to manage users
+pub fn check_not_self(req: &Request, user: &User) -> Result<()> {
+    match req.extensions.get::<AuthorizedUser>() {
+        Some(admin) if admin.id == user.id => Err(OpenIdConnectError::AccessDenied("admins can't disable, delete or demote themselves".to_owned())),
+        _ => Ok(())
+    }
+}
+
+fn query_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+fn user_json(user: &User) -> Result<String> {
+    serde_json::to_string(&UserView::new(user)).map_err(OpenIdConnectError::from)
+}
+
+fn url_user(req: &mut Request) -> Result<User> {
+    let config = try!(Config::get(req));
+    let user_id = try!(get_url_param(req, "id"));
+    
+    try!(config.user_repo.get_user(&user_id)).ok_or(OpenIdConnectError::UserNotFound)
+}
+
+/// Lists users, filtered by ?q=, ?role= and ?disabled=, a page at a time with ?offset= and ?limit=
+pub fn users_get_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(query_params(req));
+    let query = try!(user_query_from_params(&params));
+    
+    let page = try!(config.user_repo.list_users(&query));
+    
+    let list = UserList {
+        items: page.users.iter().map(UserView::new).collect(),
+        total: page.total,
+        offset: query.offset,
+        limit: query.limit,
+    };
+    
+    let list_json = try!(serde_json::to_string(&list).map_err(OpenIdConnectError::from));
+    
+    Ok(Response::with((status::Ok, list_json)))
+}
+
+pub fn users_post_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    
+    let maybe_create = try!(req.get::<bodyparser::Struct<UserCreate>>().map_err(OpenIdConnectError::from));
+    let create = try!(maybe_create.ok_or(OpenIdConnectError::EmptyPostBody));
+    
+    let user = try!(create.build(&config));
+    
+    info!("admin created user {} {}", user.id, user.username);
+    try!(config.user_repo.add_user(user.clone()));
+    
+    Ok(Response::with((status::Created, try!(user_json(&user)))))
+}
+
+pub fn user_get_handler(req: &mut Request) -> IronResult<Response> {
+    let user = try!(url_user(req));
+    
+    Ok(Response::with((status::Ok, try!(user_json(&user)))))
+}
+
+/// Updates a user. Setting disabled logs them out everywhere too.
+pub fn user_put_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let mut user = try!(url_user(req));
+    
+    let maybe_update = try!(req.get::<bodyparser::Struct<UserUpdate>>().map_err(OpenIdConnectError::from));
+    let update = try!(maybe_update.ok_or(OpenIdConnectError::EmptyPostBody));
+    
+    if update.disabled == Some(true) || update.roles.as_ref().map(|r| !r.contains(&Role::Admin.as_str().to_owned())).unwrap_or(false) {
+        try!(check_not_self(req, &user));
+    }
+    
+    let disabling = try!(update.apply(&config, &mut user));
+    try!(config.user_repo.update_user(user.clone()));
+    
+    if disabling {
+        info!("disabled user {}", user.id);
+        try!(force_relogin(&config, &user));
+    }
+    
+    Ok(Response::with((status::Ok, try!(user_json(&user)))))
+}
+
+pub fn user_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let user = try!(url_user(req));
+    
+    try!(check_not_self(req, &user));
+    try!(delete_user(&config, &user));
+    
+    Ok(Response::with(status::NoContent))
+}
+
+/// Makes the user log in again everywhere
+pub fn user_sessions_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let user = try!(url_user(req));
+    
+    try!(force_relogin(&config, &user));
+    
+    Ok(Response::with(status::NoContent))
+}
+
+/// See reset_user_password
+pub fn user_password_reset_post_handler(req: &mut Request) -> IronResult<Response> {
+    let mut user = try!(url_user(req));
+    
+    try!(reset_user_password(req, &mut user));
+    
+    Ok(Response::with(status::NoContent))
+}
+
+/// Turns off two-step verification for a user who has lost their authenticator and recovery codes.
+/// They can log in with just their password and enroll again.
+pub fn user_otp_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let ref user_id = try!(get_url_param(req, "id"));
+    
+    let mut user = try!(try!(config.user_repo.get_user(user_id)).ok_or(OpenIdConnectError::UserNotFound));
+    
+    info!("resetting two-step verification for user {}", user.id);
+    user.reset_second_factor();
+    try!(config.user_repo.update_user(user));
+    
+    Ok(Response::with(status::NoContent))
+}
+
+/// Clears a user's failed logins, letting them straight back in if they were locked out
+pub fn user_lockout_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let ref user_id = try!(get_url_param(req, "id"));
+    
+    let user = try!(try!(config.user_repo.get_user(user_id)).ok_or(OpenIdConnectError::UserNotFound));
+    
+    info!("unlocking user {}", user.id);
+    config.session_controller.throttle.unlock_user(&user.username);
+    
+    Ok(Response::with(status::NoContent))
+}
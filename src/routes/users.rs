@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use iron::prelude::*;
+use iron::status;
+use iron::modifiers::Redirect;
+use urlencoded::*;
+use serde_json::value;
+use rbvt::params::*;
+
+use config::Config;
+use result::*;
+use view::View;
+use helpers::*;
+use urls::relative_url;
+use users::*;
+use routes::user_api::*;
+
+// Pages for admins to manage users, alongside the /api/users API
+
+fn query_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+fn form_params(req: &mut Request) -> Result<HashMap<String, Vec<String>>> {
+    match req.get::<UrlEncodedBody>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(OpenIdConnectError::from(e))
+    }
+}
+
+fn form_value(params: &HashMap<String, Vec<String>>, name: &str) -> Result<Option<String>> {
+    let value = try!(multimap_get_maybe_one(params, name));
+
+    Ok(value.map(|v| v.trim()).and_then(|v| if v.is_empty() { None } else { Some(v.to_owned()) }))
+}
+
+fn form_checkbox(params: &HashMap<String, Vec<String>>, name: &str) -> Result<bool> {
+    Ok(try!(multimap_get_maybe_one(params, name)).is_some())
+}
+
+fn url_user(req: &mut Request) -> Result<User> {
+    let config = try!(Config::get(req));
+    let user_id = try!(get_url_param(req, "id"));
+
+    try!(config.user_repo.get_user(&user_id)).ok_or(OpenIdConnectError::UserNotFound)
+}
+
+/// Link to another page of the same search
+fn users_page_url(req: &mut Request, query: &UserQuery, offset: usize) -> Result<String> {
+    let mut params = HashMap::new();
+
+    if let Some(ref search) = query.search {
+        params.insert("q".to_owned(), vec![search.clone()]);
+    }
+    if let Some(role) = query.role {
+        params.insert("role".to_owned(), vec![role.as_str().to_owned()]);
+    }
+    if let Some(disabled) = query.disabled {
+        params.insert("disabled".to_owned(), vec![format!("{}", disabled)]);
+    }
+    params.insert("offset".to_owned(), vec![format!("{}", offset)]);
+    params.insert("limit".to_owned(), vec![format!("{}", query.limit)]);
+
+    Ok(format!("{}", try!(relative_url(req, "/users", Some(params)))))
+}
+
+/// The user's page. message is a flag for the template, error some text to show.
+fn user_view(req: &mut Request, user: &User, message: Option<&str>, error: Option<String>) -> Result<View> {
+    let mut view = try!(View::new_for_session("users/show.html", req));
+
+    view.data.insert("user".to_owned(), try!(value::to_value(&UserView::new(user))));
+    view.data.insert("is_admin".to_owned(), try!(value::to_value(&user.is_admin())));
+
+    if let Some(message) = message {
+        view.data.insert(message.to_owned(), try!(value::to_value(&true)));
+    }
+
+    if let Some(error) = error {
+        view.data.insert("error".to_owned(), try!(value::to_value(&error)));
+    }
+
+    Ok(view)
+}
+
+pub fn users_index_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(query_params(req));
+    let mut query = try!(user_query_from_params(&params));
+    if try!(multimap_get_maybe_one(&params, "limit").map_err(OpenIdConnectError::from)).is_none() {
+        query.limit = 20;
+    }
+
+    let page = try!(config.user_repo.list_users(&query));
+    let users: Vec<UserView> = page.users.iter().map(UserView::new).collect();
+
+    let mut view = try!(View::new_for_session("users/index.html", req));
+
+    view.data.insert("users".to_owned(), try!(value::to_value(&users).map_err(OpenIdConnectError::from)));
+    view.data.insert("total".to_owned(), try!(value::to_value(&page.total).map_err(OpenIdConnectError::from)));
+    view.data.insert("q".to_owned(), try!(value::to_value(&query.search).map_err(OpenIdConnectError::from)));
+    // selected filters, as flags for the template
+    view.data.insert("role_admin".to_owned(), try!(value::to_value(&(query.role == Some(Role::Admin))).map_err(OpenIdConnectError::from)));
+    view.data.insert("role_user".to_owned(), try!(value::to_value(&(query.role == Some(Role::User))).map_err(OpenIdConnectError::from)));
+    view.data.insert("status_active".to_owned(), try!(value::to_value(&(query.disabled == Some(false))).map_err(OpenIdConnectError::from)));
+    view.data.insert("status_disabled".to_owned(), try!(value::to_value(&(query.disabled == Some(true))).map_err(OpenIdConnectError::from)));
+
+    if query.offset > 0 {
+        let offset = if query.offset > query.limit { query.offset - query.limit } else { 0 };
+        let previous_url = try!(users_page_url(req, &query, offset));
+        view.data.insert("previous_url".to_owned(), try!(value::to_value(&previous_url).map_err(OpenIdConnectError::from)));
+    }
+
+    if query.offset + query.limit < page.total {
+        let next_url = try!(users_page_url(req, &query, query.offset + query.limit));
+        view.data.insert("next_url".to_owned(), try!(value::to_value(&next_url).map_err(OpenIdConnectError::from)));
+    }
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+pub fn users_new_handler(req: &mut Request) -> IronResult<Response> {
+    let view = try!(View::new_for_session("users/new.html", req));
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+pub fn users_create_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let params = try!(form_params(req));
+
+    let mut roles = vec![Role::User.as_str().to_owned()];
+    if try!(form_checkbox(&params, "admin")) {
+        roles.push(Role::Admin.as_str().to_owned());
+    }
+
+    let create = UserCreate {
+        username: try!(form_value(&params, "username")).unwrap_or(String::new()),
+        password: try!(form_value(&params, "password")),
+        email: try!(form_value(&params, "email")),
+        email_verified: Some(try!(form_checkbox(&params, "email_verified"))),
+        roles: Some(roles),
+        claims: None,
+    };
+
+    match create.build(&config) {
+        Ok(user) => {
+            info!("admin created user {} {}", user.id, user.username);
+            try!(config.user_repo.add_user(user.clone()));
+
+            let show_url = try!(relative_url(req, &format!("/users/{}", user.id), None));
+
+            Ok(Response::with((status::Found, Redirect(show_url))))
+        },
+        Err(err) => {
+            let mut view = try!(View::new_for_session("users/new.html", req));
+            view.data.insert("error".to_owned(), try!(value::to_value(&format!("{}", err)).map_err(OpenIdConnectError::from)));
+
+            Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+        }
+    }
+}
+
+pub fn users_show_handler(req: &mut Request) -> IronResult<Response> {
+    let user = try!(url_user(req));
+
+    let view = try!(user_view(req, &user, None, None));
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+/// Saves the email address, the admin role and the disabled flag.
+/// Disabling a user logs them out everywhere.
+pub fn users_update_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let mut user = try!(url_user(req));
+    let params = try!(form_params(req));
+
+    let admin = try!(form_checkbox(&params, "admin"));
+    let disabled = try!(form_checkbox(&params, "disabled"));
+
+    let mut roles = vec![Role::User.as_str().to_owned()];
+    if admin {
+        roles.push(Role::Admin.as_str().to_owned());
+    }
+
+    if (disabled && !user.disabled) || (!admin && user.is_admin()) {
+        if let Err(err) = check_not_self(req, &user) {
+            let view = try!(user_view(req, &user, None, Some(format!("{}", err))));
+
+            return Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))));
+        }
+    }
+
+    let update = UserUpdate {
+        password: None,
+        email: Some(try!(form_value(&params, "email")).unwrap_or(String::new())),
+        email_verified: Some(try!(form_checkbox(&params, "email_verified"))),
+        roles: Some(roles),
+        disabled: Some(disabled),
+        claims: None,
+    };
+
+    let disabling = match update.apply(&config, &mut user) {
+        Ok(disabling) => disabling,
+        Err(err) => {
+            let user = try!(url_user(req));
+            let view = try!(user_view(req, &user, None, Some(format!("{}", err))));
+
+            return Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))));
+        }
+    };
+
+    try!(config.user_repo.update_user(user.clone()));
+
+    if disabling {
+        info!("disabled user {}", user.id);
+        try!(force_relogin(&config, &user));
+    }
+
+    let view = try!(user_view(req, &user, Some("user_saved"), None));
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+pub fn users_password_reset_handler(req: &mut Request) -> IronResult<Response> {
+    let mut user = try!(url_user(req));
+
+    let message = if try!(reset_user_password(req, &mut user)) {
+        "password_reset_sent"
+    } else {
+        "password_reset_no_email"
+    };
+
+    let view = try!(user_view(req, &user, Some(message), None));
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+pub fn users_logout_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let user = try!(url_user(req));
+
+    try!(force_relogin(&config, &user));
+
+    let view = try!(user_view(req, &user, Some("logged_out"), None));
+
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+pub fn users_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let user = try!(url_user(req));
+
+    if let Err(err) = check_not_self(req, &user) {
+        let view = try!(user_view(req, &user, None, Some(format!("{}", err))));
+
+        return Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))));
+    }
+
+    try!(delete_user(&config, &user));
+
+    let index_url = try!(relative_url(req, "/users", None));
+
+    Ok(Response::with((status::Found, Redirect(index_url))))
+}
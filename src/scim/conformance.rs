@@ -0,0 +1,214 @@
+// Runs the SCIM protocol scenarios of RFC 7644 against the in-memory repos,
+// the way a provisioning client would use /Users and /Groups.
+
+use std::sync::Arc;
+
+use serde_json;
+use serde_json::Value;
+
+use result::{Result, OpenIdConnectError};
+use users::*;
+use groups::*;
+use scim::service::*;
+
+fn service() -> ScimService {
+    let users: Arc<Box<UserRepo>> = Arc::new(Box::new(InMemoryUserRepo::new()));
+    let groups: Arc<Box<GroupRepo>> = Arc::new(Box::new(InMemoryGroupRepo::new()));
+
+    ScimService::new(users, groups, "https://example.com/scim/v2".to_owned())
+}
+
+fn json(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+fn user_json(user_name: &str) -> Value {
+    json(&format!(r#"{{
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+        "userName": "{}",
+        "externalId": "ext-{}",
+        "name": {{"givenName": "Given", "familyName": "{}"}},
+        "emails": [{{"value": "{}@example.com", "type": "work", "primary": true}}],
+        "active": true
+    }}"#, user_name, user_name, user_name, user_name))
+}
+
+fn id(resource: &Value) -> String {
+    resource["id"].as_str().unwrap().to_owned()
+}
+
+fn version(resource: &Value) -> String {
+    resource["meta"]["version"].as_str().unwrap().to_owned()
+}
+
+fn assert_scim_error<T: ::std::fmt::Debug>(result: Result<T>, status: u16, scim_type: Option<&str>) {
+    match result {
+        Err(OpenIdConnectError::ScimError(s, t, _)) => {
+            assert_eq!(s.to_u16(), status);
+            assert_eq!(t, scim_type);
+        },
+        other => panic!("expected a {} scim error, got {:?}", status, other),
+    }
+}
+
+const PATCH_SCHEMA: &'static str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+
+#[test]
+fn test_create_and_get_user() {
+    let service = service();
+
+    let created = service.create_user(&user_json("bjensen")).unwrap().resource;
+
+    assert_eq!(created["userName"], "bjensen");
+    assert_eq!(created["meta"]["resourceType"], "User");
+    assert_eq!(created["meta"]["location"], Value::String(format!("https://example.com/scim/v2/Users/{}", id(&created))));
+
+    let fetched = service.get_user(&id(&created)).unwrap();
+    assert_eq!(fetched, created);
+
+    assert_scim_error(service.create_user(&user_json("bjensen")), 409, Some("uniqueness"));
+    assert_scim_error(service.create_user(&json(r#"{"name": {"givenName": "Nobody"}}"#)), 400, Some("invalidValue"));
+    assert_scim_error(service.get_user("missing"), 404, None);
+}
+
+#[test]
+fn test_list_users_with_filters_and_paging() {
+    let service = service();
+
+    for name in &["alice", "bob", "carol", "dave"] {
+        service.create_user(&user_json(name)).unwrap();
+    }
+
+    let mut params = ListParams::new();
+    params.filter = Some(r#"userName eq "CAROL""#.to_owned());
+    let list = service.list_users(&params).unwrap();
+    assert_eq!(list["totalResults"], 1);
+    assert_eq!(list["Resources"][0]["userName"], "carol");
+
+    params.filter = Some(r#"emails[primary eq true and value sw "b"] or name.familyName eq "dave""#.to_owned());
+    assert_eq!(service.list_users(&params).unwrap()["totalResults"], 2);
+
+    params.filter = Some(r#"externalId eq "EXT-alice""#.to_owned());
+    assert_eq!(service.list_users(&params).unwrap()["totalResults"], 0);
+
+    params.filter = Some("userName zz 1".to_owned());
+    assert_scim_error(service.list_users(&params), 400, Some("invalidFilter"));
+
+    let mut params = ListParams::new();
+    params.start_index = 2;
+    params.count = 2;
+    let page = service.list_users(&params).unwrap();
+    assert_eq!(page["schemas"][0], "urn:ietf:params:scim:api:messages:2.0:ListResponse");
+    assert_eq!(page["totalResults"], 4);
+    assert_eq!(page["startIndex"], 2);
+    assert_eq!(page["itemsPerPage"], 2);
+    assert_eq!(page["Resources"][0]["userName"], "bob");
+    assert_eq!(page["Resources"][1]["userName"], "carol");
+}
+
+#[test]
+fn test_replace_user() {
+    let service = service();
+    let created = service.create_user(&user_json("bjensen")).unwrap().resource;
+
+    let mut replacement = user_json("barbara");
+    replacement.as_object_mut().unwrap().remove("name");
+
+    let replaced = service.replace_user(&id(&created), &replacement, Some(&version(&created)[..])).unwrap().resource;
+
+    assert_eq!(replaced["userName"], "barbara");
+    assert!(replaced.get("name").is_none());
+    assert!(version(&replaced) != version(&created));
+
+    // the old version no longer matches
+    assert_scim_error(service.replace_user(&id(&created), &replacement, Some(&version(&created)[..])), 412, None);
+
+    service.create_user(&user_json("other")).unwrap();
+    assert_scim_error(service.replace_user(&id(&created), &user_json("other"), None), 409, Some("uniqueness"));
+}
+
+#[test]
+fn test_patch_user_deactivates() {
+    let service = service();
+    let created = service.create_user(&user_json("bjensen")).unwrap().resource;
+
+    let saved = service.patch_user(&id(&created), &json(&format!(r#"{{
+        "schemas": ["{}"],
+        "Operations": [
+            {{"op": "replace", "path": "active", "value": false}},
+            {{"op": "add", "path": "nickName", "value": "Babs"}},
+            {{"op": "replace", "path": "emails[primary eq true].value", "value": "babs@example.com"}}
+        ]
+    }}"#, PATCH_SCHEMA)), None).unwrap();
+
+    assert!(saved.deactivated);
+    assert!(saved.user.disabled);
+    assert_eq!(saved.user.email, Some("babs@example.com".to_owned()));
+    assert_eq!(saved.resource["active"], false);
+    assert_eq!(saved.resource["nickName"], "Babs");
+
+    assert_scim_error(service.patch_user(&id(&created), &json(&format!(r#"{{
+        "schemas": ["{}"],
+        "Operations": [{{"op": "replace", "path": "id", "value": "1"}}]
+    }}"#, PATCH_SCHEMA)), None), 400, Some("mutability"));
+}
+
+#[test]
+fn test_caller_cant_deactivate_or_delete_themselves() {
+    let mut service = service();
+    let created = service.create_user(&user_json("admin")).unwrap().resource;
+    service.caller = Some(id(&created));
+
+    let mut inactive = user_json("admin");
+    inactive.as_object_mut().unwrap().insert("active".to_owned(), Value::Bool(false));
+
+    match service.replace_user(&id(&created), &inactive, None) {
+        Err(OpenIdConnectError::AccessDenied(_)) => {},
+        other => panic!("expected access denied, got {:?}", other),
+    }
+
+    assert!(service.delete_user(&id(&created), None).is_err());
+}
+
+#[test]
+fn test_groups() {
+    let service = service();
+    let alice = id(&service.create_user(&user_json("alice")).unwrap().resource);
+    let bob = id(&service.create_user(&user_json("bob")).unwrap().resource);
+
+    let group = service.create_group(&json(&format!(r#"{{
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:Group"],
+        "displayName": "Engineering",
+        "members": [{{"value": "{}"}}]
+    }}"#, alice))).unwrap();
+
+    assert_eq!(group["meta"]["resourceType"], "Group");
+    assert_eq!(group["members"][0]["display"], "alice");
+    assert_eq!(service.get_user(&alice).unwrap()["groups"][0]["display"], "Engineering");
+
+    assert_scim_error(service.create_group(&json(r#"{"displayName": "Engineering"}"#)), 409, Some("uniqueness"));
+    assert_scim_error(service.create_group(&json(r#"{"displayName": "Sales", "members": [{"value": "missing"}]}"#)), 400, Some("invalidValue"));
+
+    let patched = service.patch_group(&id(&group), &json(&format!(r#"{{
+        "schemas": ["{}"],
+        "Operations": [
+            {{"op": "add", "path": "members", "value": [{{"value": "{}"}}]}},
+            {{"op": "remove", "path": "members[value eq \"{}\"]"}}
+        ]
+    }}"#, PATCH_SCHEMA, bob, alice)), Some(&version(&group)[..])).unwrap();
+
+    assert_eq!(patched["members"].as_array().unwrap().len(), 1);
+    assert_eq!(patched["members"][0]["value"], Value::String(bob.clone()));
+
+    let mut params = ListParams::new();
+    params.filter = Some(format!(r#"members.value eq "{}""#, bob));
+    assert_eq!(service.list_groups(&params).unwrap()["totalResults"], 1);
+
+    // deleting a user takes them out of their groups
+    service.delete_user(&bob, None).unwrap();
+    assert_eq!(service.get_group(&id(&group)).unwrap()["members"].as_array().unwrap().len(), 0);
+    assert_scim_error(service.get_user(&bob), 404, None);
+
+    service.delete_group(&id(&group), None).unwrap();
+    assert_scim_error(service.get_group(&id(&group)), 404, None);
+}
@@ -0,0 +1,95 @@
+use iron::prelude::*;
+use iron::status;
+use iron::middleware::AfterMiddleware;
+use iron::mime::Mime;
+use serde_json;
+
+use result::*;
+use site_config::SiteConfig;
+use oauth2::bearer::bearer_challenge;
+use x_headers::WwwAuthenticate;
+use scim::ERROR_SCHEMA;
+
+// Errors as described in RFC 7644 section 3.12
+
+pub fn invalid_filter<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("invalidFilter"), detail.into())
+}
+
+pub fn invalid_path<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("invalidPath"), detail.into())
+}
+
+pub fn invalid_syntax<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("invalidSyntax"), detail.into())
+}
+
+pub fn invalid_value<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("invalidValue"), detail.into())
+}
+
+pub fn no_target<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("noTarget"), detail.into())
+}
+
+pub fn mutability<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::BadRequest, Some("mutability"), detail.into())
+}
+
+pub fn uniqueness<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::Conflict, Some("uniqueness"), detail.into())
+}
+
+pub fn not_found<S: Into<String>>(detail: S) -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::NotFound, None, detail.into())
+}
+
+pub fn precondition_failed() -> OpenIdConnectError {
+    OpenIdConnectError::ScimError(status::PreconditionFailed, None, "the resource has changed".to_owned())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScimErrorView {
+    pub schemas: Vec<String>,
+    pub status: String,
+    #[serde(rename = "scimType", skip_serializing_if = "Option::is_none")]
+    pub scim_type: Option<String>,
+    pub detail: String,
+}
+
+/// Renders errors as SCIM error responses, with a bearer challenge when the token was missing or no good
+pub struct ScimErrorRenderer;
+
+impl AfterMiddleware for ScimErrorRenderer {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        debug!("{:?} caught in ScimErrorRenderer AfterMiddleware.", &err);
+
+        let status_code = err.response.status.unwrap_or(status::InternalServerError);
+
+        let scim_type = match err.error.downcast_ref::<OpenIdConnectError>() {
+            Some(&OpenIdConnectError::ScimError(_, scim_type, _)) => scim_type.map(|s| s.to_owned()),
+            _ => None,
+        };
+
+        let error_view = ScimErrorView {
+            schemas: vec![ERROR_SCHEMA.to_owned()],
+            status: format!("{}", status_code.to_u16()),
+            scim_type: scim_type,
+            detail: format!("{}", err),
+        };
+
+        let new_body = try!(serde_json::to_string(&error_view).map_err(OpenIdConnectError::from));
+        let content_type = "application/scim+json".parse::<Mime>().unwrap();
+
+        let mut response = err.response.set(new_body).set(content_type);
+
+        if status_code == status::Unauthorized {
+            if let Some(oic_err) = err.error.downcast_ref::<OpenIdConnectError>() {
+                let site_config = try!(SiteConfig::get(req));
+                response.headers.set(WwwAuthenticate::new(bearer_challenge(&site_config.get_issuer(), oic_err)));
+            }
+        }
+
+        Ok(response)
+    }
+}
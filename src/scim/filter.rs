@@ -0,0 +1,597 @@
+use serde_json::Value;
+
+use result::Result;
+use scim::error::*;
+
+// SCIM filter expressions (RFC 7644 section 3.4.2.2), evaluated against resources in their JSON form.
+// e.g. userName eq "bjensen" and (emails.value co "@example.com" or not (active eq false))
+//
+// Attribute names ignore case, as do string comparisons except on id and externalId.
+
+/// How deeply parentheses, not and value paths can nest, as the parser recurses for each
+const MAX_NESTING: usize = 32;
+
+/// Long and/or chains don't recurse while parsing but make a filter as deep as they are long
+const MAX_TOKENS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl CompareOp {
+    fn from_word(word: &str) -> Option<CompareOp> {
+        match &word.to_lowercase()[..] {
+            "eq" => Some(CompareOp::Eq),
+            "ne" => Some(CompareOp::Ne),
+            "co" => Some(CompareOp::Co),
+            "sw" => Some(CompareOp::Sw),
+            "ew" => Some(CompareOp::Ew),
+            "gt" => Some(CompareOp::Gt),
+            "ge" => Some(CompareOp::Ge),
+            "lt" => Some(CompareOp::Lt),
+            "le" => Some(CompareOp::Le),
+            _ => None
+        }
+    }
+}
+
+/// An attribute, optionally a sub-attribute of a complex one, e.g. name.givenName
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttrPath {
+    pub attr: String,
+    pub sub_attr: Option<String>,
+}
+
+impl AttrPath {
+    /// Schema URNs in front of the name are dropped, e.g. urn:ietf:params:scim:schemas:core:2.0:User:userName
+    pub fn parse(s: &str) -> Result<AttrPath> {
+        let s = if s.to_lowercase().starts_with("urn:") {
+            s.rsplit(':').next().unwrap_or("")
+        } else {
+            s
+        };
+
+        let mut parts = s.splitn(2, '.');
+        let attr = parts.next().unwrap_or("");
+        let sub_attr = parts.next();
+
+        if attr.is_empty() || sub_attr == Some("") || !attr.chars().all(is_attr_char) {
+            return Err(invalid_filter(format!("invalid attribute {}", s)));
+        }
+
+        Ok(AttrPath {
+            attr: attr.to_owned(),
+            sub_attr: sub_attr.map(|s| s.to_owned()),
+        })
+    }
+
+    fn case_exact(&self) -> bool {
+        self.sub_attr.is_none() && (eq_ignore_case(&self.attr, "id") || eq_ignore_case(&self.attr, "externalId"))
+    }
+
+    /// The values this path points at. Multi-valued attributes give one value per element,
+    /// using an element's "value" sub-attribute when no sub-attribute is named.
+    pub fn resolve<'a>(&self, resource: &'a Value) -> Vec<&'a Value> {
+        let mut values = vec![];
+
+        if let Some(value) = get_ignore_case(resource, &self.attr) {
+            match *value {
+                Value::Array(ref elements) => {
+                    for element in elements {
+                        let sub = match self.sub_attr {
+                            Some(ref sub_attr) => get_ignore_case(element, sub_attr),
+                            None => get_ignore_case(element, "value").or(Some(element)),
+                        };
+
+                        if let Some(sub) = sub {
+                            values.push(sub);
+                        }
+                    }
+                },
+                _ => {
+                    match self.sub_attr {
+                        Some(ref sub_attr) => {
+                            if let Some(sub) = get_ignore_case(value, sub_attr) {
+                                values.push(sub);
+                            }
+                        },
+                        None => values.push(value),
+                    }
+                }
+            }
+        }
+
+        values.into_iter().filter(|v| is_present(v)).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Present(AttrPath),
+    Compare(AttrPath, CompareOp, Value),
+    ValuePath(String, Box<Filter>), // emails[type eq "work"]
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter> {
+        let tokens = try!(tokenize(s));
+
+        if tokens.len() > MAX_TOKENS {
+            return Err(invalid_filter("filter is too long"));
+        }
+
+        let mut parser = Parser::new(tokens);
+
+        let filter = try!(parser.parse_or());
+
+        if parser.pos < parser.tokens.len() {
+            return Err(invalid_filter(format!("unexpected {:?}", parser.tokens[parser.pos])));
+        }
+
+        Ok(filter)
+    }
+
+    pub fn matches(&self, resource: &Value) -> bool {
+        match *self {
+            Filter::Present(ref path) => !path.resolve(resource).is_empty(),
+            Filter::Compare(ref path, op, ref literal) => {
+                let values = path.resolve(resource);
+                let case_exact = path.case_exact();
+
+                match (op, literal) {
+                    (CompareOp::Eq, &Value::Null) => values.is_empty(),
+                    (CompareOp::Ne, &Value::Null) => !values.is_empty(),
+                    (CompareOp::Ne, _) => !values.iter().any(|v| compare(v, CompareOp::Eq, literal, case_exact)),
+                    _ => values.iter().any(|v| compare(v, op, literal, case_exact)),
+                }
+            },
+            Filter::ValuePath(ref attr, ref filter) => {
+                match get_ignore_case(resource, attr) {
+                    Some(&Value::Array(ref elements)) => elements.iter().any(|e| filter.matches(e)),
+                    Some(value) => filter.matches(value),
+                    None => false,
+                }
+            },
+            Filter::And(ref a, ref b) => a.matches(resource) && b.matches(resource),
+            Filter::Or(ref a, ref b) => a.matches(resource) || b.matches(resource),
+            Filter::Not(ref a) => !a.matches(resource),
+        }
+    }
+}
+
+/// A PATCH operation's path: an attribute, optionally narrowed to some elements of a
+/// multi-valued attribute, and optionally a sub-attribute, e.g. emails[type eq "work"].value
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchPath {
+    pub attr: String,
+    pub value_filter: Option<Filter>,
+    pub sub_attr: Option<String>,
+}
+
+impl PatchPath {
+    pub fn parse(s: &str) -> Result<PatchPath> {
+        let (attr_part, value_filter, rest) = match s.find('[') {
+            Some(open) => {
+                let close = try!(s.rfind(']').ok_or(invalid_path(format!("unclosed [ in {}", s))));
+
+                if close < open {
+                    return Err(invalid_path(format!("invalid path {}", s)));
+                }
+
+                let value_filter = try!(Filter::parse(&s[open + 1..close]).map_err(|e| invalid_path(format!("{}", e))));
+
+                (&s[..open], Some(value_filter), &s[close + 1..])
+            },
+            None => (s, None, ""),
+        };
+
+        let path = try!(AttrPath::parse(attr_part).map_err(|_| invalid_path(format!("invalid path {}", s))));
+
+        let sub_attr = if rest.is_empty() {
+            path.sub_attr
+        } else if rest.starts_with('.') && path.sub_attr.is_none() && rest.len() > 1 {
+            Some(rest[1..].to_owned())
+        } else {
+            return Err(invalid_path(format!("invalid path {}", s)));
+        };
+
+        Ok(PatchPath {
+            attr: path.attr,
+            value_filter: value_filter,
+            sub_attr: sub_attr,
+        })
+    }
+}
+
+fn is_attr_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '$'
+}
+
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// An attribute of a JSON object, whatever the case of its name
+pub fn get_ignore_case<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    value.as_object().and_then(|o| o.iter().find(|&(k, _)| eq_ignore_case(k, name)).map(|(_, v)| v))
+}
+
+fn is_present(value: &Value) -> bool {
+    match *value {
+        Value::Null => false,
+        Value::String(ref s) => !s.is_empty(),
+        Value::Array(ref a) => !a.is_empty(),
+        _ => true,
+    }
+}
+
+fn compare(value: &Value, op: CompareOp, literal: &Value, case_exact: bool) -> bool {
+    match (value, literal) {
+        (&Value::String(ref v), &Value::String(ref l)) => {
+            let (v, l) = if case_exact { (v.clone(), l.clone()) } else { (v.to_lowercase(), l.to_lowercase()) };
+
+            match op {
+                CompareOp::Eq => v == l,
+                CompareOp::Ne => v != l,
+                CompareOp::Co => v.contains(&l[..]),
+                CompareOp::Sw => v.starts_with(&l[..]),
+                CompareOp::Ew => v.ends_with(&l[..]),
+                CompareOp::Gt => v > l,
+                CompareOp::Ge => v >= l,
+                CompareOp::Lt => v < l,
+                CompareOp::Le => v <= l,
+            }
+        },
+        (&Value::Bool(v), &Value::Bool(l)) => {
+            match op {
+                CompareOp::Eq => v == l,
+                CompareOp::Ne => v != l,
+                _ => false,
+            }
+        },
+        (&Value::Number(_), &Value::Number(_)) => {
+            match (value.as_f64(), literal.as_f64()) {
+                (Some(v), Some(l)) => {
+                    match op {
+                        CompareOp::Eq => v == l,
+                        CompareOp::Ne => v != l,
+                        CompareOp::Gt => v > l,
+                        CompareOp::Ge => v >= l,
+                        CompareOp::Lt => v < l,
+                        CompareOp::Le => v <= l,
+                        _ => false,
+                    }
+                },
+                _ => false
+            }
+        },
+        _ => false
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {},
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '[' => tokens.push(Token::LBracket),
+            ']' => tokens.push(Token::RBracket),
+            '"' => {
+                let mut string = String::new();
+                let mut closed = false;
+
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        },
+                        '\\' => {
+                            match chars.next() {
+                                Some('n') => string.push('\n'),
+                                Some('t') => string.push('\t'),
+                                Some(c) => string.push(c),
+                                None => break,
+                            }
+                        },
+                        c => string.push(c),
+                    }
+                }
+
+                if !closed {
+                    return Err(invalid_filter("unterminated string"));
+                }
+
+                tokens.push(Token::Str(string));
+            },
+            c => {
+                let mut word = String::new();
+                word.push(c);
+
+                loop {
+                    match chars.peek() {
+                        Some(&next) if next != ' ' && next != '(' && next != ')' && next != '[' && next != ']' && next != '"' => {
+                            word.push(next);
+                        },
+                        _ => break,
+                    }
+
+                    chars.next();
+                }
+
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens: tokens,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    /// Parses a nested filter, e.g. in parentheses
+    fn parse_nested(&mut self) -> Result<Filter> {
+        if self.depth >= MAX_NESTING {
+            return Err(invalid_filter("filter is nested too deeply"));
+        }
+
+        self.depth += 1;
+        let filter = self.parse_or();
+        self.depth -= 1;
+
+        filter
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Word(ref word)) => eq_ignore_case(word, keyword),
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            other => Err(invalid_filter(format!("expected {:?}, found {:?}", expected, other))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter> {
+        let mut filter = try!(self.parse_and());
+
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            let right = try!(self.parse_and());
+            filter = Filter::Or(Box::new(filter), Box::new(right));
+        }
+
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter> {
+        let mut filter = try!(self.parse_not());
+
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            let right = try!(self.parse_not());
+            filter = Filter::And(Box::new(filter), Box::new(right));
+        }
+
+        Ok(filter)
+    }
+
+    fn parse_not(&mut self) -> Result<Filter> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            try!(self.expect(Token::LParen));
+            let filter = try!(self.parse_nested());
+            try!(self.expect(Token::RParen));
+
+            Ok(Filter::Not(Box::new(filter)))
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Filter> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let filter = try!(self.parse_nested());
+                try!(self.expect(Token::RParen));
+
+                Ok(filter)
+            },
+            Some(Token::Word(word)) => {
+                let path = try!(AttrPath::parse(&word));
+
+                if self.peek() == Some(&Token::LBracket) {
+                    if path.sub_attr.is_some() {
+                        return Err(invalid_filter(format!("invalid attribute {}", word)));
+                    }
+
+                    self.pos += 1;
+                    let filter = try!(self.parse_nested());
+                    try!(self.expect(Token::RBracket));
+
+                    return Ok(Filter::ValuePath(path.attr, Box::new(filter)));
+                }
+
+                let op_word = match self.next() {
+                    Some(Token::Word(op_word)) => op_word,
+                    other => return Err(invalid_filter(format!("expected an operator after {}, found {:?}", word, other))),
+                };
+
+                if eq_ignore_case(&op_word, "pr") {
+                    return Ok(Filter::Present(path));
+                }
+
+                let op = try!(CompareOp::from_word(&op_word).ok_or(invalid_filter(format!("unknown operator {}", op_word))));
+                let literal = try!(self.parse_literal());
+
+                Ok(Filter::Compare(path, op, literal))
+            },
+            other => Err(invalid_filter(format!("expected an attribute, found {:?}", other))),
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Value::String(s)),
+            Some(Token::Word(word)) => {
+                match &word.to_lowercase()[..] {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => {
+                        if let Ok(i) = word.parse::<i64>() {
+                            Ok(Value::Number(i.into()))
+                        } else {
+                            word.parse::<f64>().ok()
+                                .and_then(|f| ::serde_json::value::to_value(&f).ok())
+                                .ok_or(invalid_filter(format!("invalid value {}", word)))
+                        }
+                    }
+                }
+            },
+            other => Err(invalid_filter(format!("expected a value, found {:?}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn resource() -> Value {
+        serde_json::from_str(r#"{
+            "id": "2819c223",
+            "externalId": "E1234",
+            "userName": "bjensen",
+            "name": {"givenName": "Barbara", "familyName": "Jensen"},
+            "emails": [{"value": "bjensen@example.com", "type": "work", "primary": true}, {"value": "babs@home.example.org", "type": "home"}],
+            "active": true,
+            "meta": {"lastModified": "2017-03-01T12:00:00+00:00"}
+        }"#).unwrap()
+    }
+
+    fn matches(filter: &str) -> bool {
+        Filter::parse(filter).unwrap().matches(&resource())
+    }
+
+    #[test]
+    fn test_compare() {
+        assert!(matches(r#"userName eq "bjensen""#));
+        assert!(matches(r#"USERNAME Eq "BJensen""#));
+        assert!(!matches(r#"externalId eq "e1234""#));
+        assert!(matches(r#"name.familyName co "ens""#));
+        assert!(matches(r#"userName sw "bj""#));
+        assert!(matches(r#"emails.value ew "example.org""#));
+        assert!(matches(r#"emails co "home""#));
+        assert!(matches(r#"meta.lastModified gt "2017-01-01T00:00:00+00:00""#));
+        assert!(matches("active eq true"));
+        assert!(!matches("active ne true"));
+        assert!(matches(r#"userName ne "someone""#));
+        assert!(matches("title eq null"));
+        assert!(matches(r#"urn:ietf:params:scim:schemas:core:2.0:User:userName eq "bjensen""#));
+    }
+
+    #[test]
+    fn test_logic() {
+        assert!(matches(r#"userName eq "bjensen" and active eq true"#));
+        assert!(matches(r#"userName eq "nobody" or name.givenName pr"#));
+        assert!(!matches(r#"not (userName eq "bjensen")"#));
+        assert!(matches(r#"title pr or (userName sw "b" and not (active eq false))"#));
+        assert!(matches(r#"emails[type eq "work" and value co "@example.com"]"#));
+        assert!(!matches(r#"emails[type eq "work" and value co "@example.org"]"#));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert!(Filter::parse(r#"userName eq"#).is_err());
+        assert!(Filter::parse(r#"userName is "bjensen""#).is_err());
+        assert!(Filter::parse(r#"userName eq "bjensen"#).is_err());
+        assert!(Filter::parse(r#"(userName pr"#).is_err());
+        assert!(Filter::parse(r#"userName pr )"#).is_err());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let repeat = |s: &str, n: usize| (0..n).map(|_| s).collect::<String>();
+        let nested = |depth: usize| format!("{}userName pr{}", repeat("not (", depth), repeat(")", depth));
+
+        assert!(Filter::parse(&nested(32)).is_ok());
+        assert!(Filter::parse(&nested(33)).is_err());
+        assert!(Filter::parse(&nested(100000)).is_err());
+        assert!(Filter::parse(&format!("{}userName pr{}", repeat("(", 100000), repeat(")", 100000))).is_err());
+
+        let chain = vec!["userName pr"; 1000].join(" and ");
+        assert!(Filter::parse(&chain).is_err());
+    }
+
+    #[test]
+    fn test_patch_path() {
+        let path = PatchPath::parse(r#"emails[type eq "work"].value"#).unwrap();
+        assert_eq!(path.attr, "emails");
+        assert_eq!(path.sub_attr, Some("value".to_owned()));
+        assert!(path.value_filter.is_some());
+
+        let path = PatchPath::parse("name.givenName").unwrap();
+        assert_eq!(path.attr, "name");
+        assert_eq!(path.sub_attr, Some("givenName".to_owned()));
+        assert!(path.value_filter.is_none());
+
+        assert!(PatchPath::parse(r#"members[value eq "1""#).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use iron::prelude::*;
+use iron::middleware::Handler;
+use router::Router;
+
+use config::Config;
+use authorization::RequireScope;
+use users::Role;
+use scim::error::ScimErrorRenderer;
+
+pub mod error;
+pub mod filter;
+pub mod patch;
+pub mod resources;
+pub mod service;
+pub mod routes;
+
+#[cfg(test)]
+mod conformance;
+
+/// SCIM 2.0 (RFC 7643, RFC 7644) provisioning of users and groups, mounted at /scim/v2.
+///
+/// Provisioning systems call it with an access token issued to an admin with the "scim" scope.
+
+pub const USER_SCHEMA: &'static str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &'static str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_RESPONSE_SCHEMA: &'static str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const PATCH_OP_SCHEMA: &'static str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const ERROR_SCHEMA: &'static str = "urn:ietf:params:scim:api:messages:2.0:Error";
+pub const SERVICE_PROVIDER_CONFIG_SCHEMA: &'static str = "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+
+pub const SCIM_SCOPE: &'static str = "scim";
+
+pub fn scim_handler<T>(_config: &Config, route: T) -> Chain
+where T: Handler
+{
+    let mut chain = Chain::new(route);
+    chain.around(RequireScope::new(SCIM_SCOPE, Role::Admin));
+    chain.link_after(ScimErrorRenderer);
+    chain
+}
+
+pub fn scim_router(config: &Config) -> Router {
+    let mut scim_router = Router::new();
+    scim_router.get("/ServiceProviderConfig", scim_handler(&config, routes::service_provider_config_get_handler), "scim_service_provider_config");
+    scim_router.get("/Users", scim_handler(&config, routes::users_get_handler), "scim_users");
+    scim_router.post("/Users", scim_handler(&config, routes::users_post_handler), "scim_users_post");
+    scim_router.get("/Users/:id", scim_handler(&config, routes::user_get_handler), "scim_user");
+    scim_router.put("/Users/:id", scim_handler(&config, routes::user_put_handler), "scim_user_put");
+    scim_router.patch("/Users/:id", scim_handler(&config, routes::user_patch_handler), "scim_user_patch");
+    scim_router.delete("/Users/:id", scim_handler(&config, routes::user_delete_handler), "scim_user_delete");
+    scim_router.get("/Groups", scim_handler(&config, routes::groups_get_handler), "scim_groups");
+    scim_router.post("/Groups", scim_handler(&config, routes::groups_post_handler), "scim_groups_post");
+    scim_router.get("/Groups/:id", scim_handler(&config, routes::group_get_handler), "scim_group");
+    scim_router.put("/Groups/:id", scim_handler(&config, routes::group_put_handler), "scim_group_put");
+    scim_router.patch("/Groups/:id", scim_handler(&config, routes::group_patch_handler), "scim_group_patch");
+    scim_router.delete("/Groups/:id", scim_handler(&config, routes::group_delete_handler), "scim_group_delete");
+    scim_router
+}
@@ -0,0 +1,335 @@
+use serde_json::{Map, Value};
+
+use result::Result;
+use scim::PATCH_OP_SCHEMA;
+use scim::error::*;
+use scim::filter::*;
+use scim::resources::READ_ONLY_ATTRIBUTES;
+
+// PATCH operations (RFC 7644 section 3.5.2), applied to a resource in its JSON form.
+// The patched resource is then saved like a PUT.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchOp {
+    Add,
+    Replace,
+    Remove,
+}
+
+#[derive(Clone, Debug)]
+pub struct PatchOperation {
+    pub op: PatchOp,
+    pub path: Option<PatchPath>,
+    pub value: Option<Value>,
+}
+
+/// Reads the Operations of a PatchOp message
+pub fn parse_patch_request(body: &Value) -> Result<Vec<PatchOperation>> {
+    let schemas = get_ignore_case(body, "schemas").and_then(|s| s.as_array());
+
+    if !schemas.map(|s| s.iter().any(|s| s.as_str() == Some(PATCH_OP_SCHEMA))).unwrap_or(false) {
+        return Err(invalid_syntax(format!("schemas must include {}", PATCH_OP_SCHEMA)));
+    }
+
+    let operations = try!(get_ignore_case(body, "Operations").and_then(|o| o.as_array()).ok_or(invalid_syntax("Operations is required")));
+
+    let mut result = vec![];
+
+    for operation in operations {
+        let op = match get_ignore_case(operation, "op").and_then(|o| o.as_str()).map(|o| o.to_lowercase()) {
+            Some(ref op) if op == "add" => PatchOp::Add,
+            Some(ref op) if op == "replace" => PatchOp::Replace,
+            Some(ref op) if op == "remove" => PatchOp::Remove,
+            other => return Err(invalid_syntax(format!("unknown op {:?}", other))),
+        };
+
+        let path = match get_ignore_case(operation, "path") {
+            None | Some(&Value::Null) => None,
+            Some(&Value::String(ref path)) => Some(try!(PatchPath::parse(path))),
+            Some(_) => return Err(invalid_path("path must be a string")),
+        };
+
+        let value = get_ignore_case(operation, "value").map(|v| v.clone());
+
+        if op != PatchOp::Remove && value.is_none() {
+            return Err(invalid_value("add and replace need a value"));
+        }
+
+        result.push(PatchOperation {
+            op: op,
+            path: path,
+            value: value,
+        });
+    }
+
+    Ok(result)
+}
+
+fn check_writable(attr: &str) -> Result<()> {
+    if READ_ONLY_ATTRIBUTES.iter().any(|a| eq_ignore_case(a, attr)) {
+        Err(mutability(format!("{} can't be changed", attr)))
+    } else {
+        Ok(())
+    }
+}
+
+/// The name an attribute already has in the object, so changes don't add a second copy in another case
+fn key_for(object: &Map<String, Value>, name: &str) -> String {
+    object.keys().find(|k| eq_ignore_case(k, name)).map(|k| k.clone()).unwrap_or(name.to_owned())
+}
+
+/// Sets an attribute. Adding to a multi-valued attribute appends, adding to a complex one merges.
+fn set_attr(object: &mut Map<String, Value>, name: &str, value: Value, op: PatchOp) {
+    let key = key_for(object, name);
+
+    let merged = match (object.remove(&key), value) {
+        (Some(Value::Array(mut existing)), Value::Array(values)) if op == PatchOp::Add => {
+            for value in values {
+                if !existing.contains(&value) {
+                    existing.push(value);
+                }
+            }
+            Value::Array(existing)
+        },
+        (Some(Value::Object(mut existing)), Value::Object(values)) if op == PatchOp::Add => {
+            for (k, v) in values {
+                let k = key_for(&existing, &k);
+                existing.insert(k, v);
+            }
+            Value::Object(existing)
+        },
+        (_, value) => value,
+    };
+
+    object.insert(key, merged);
+}
+
+fn as_object_mut(value: &mut Value) -> Result<&mut Map<String, Value>> {
+    value.as_object_mut().ok_or(invalid_syntax("expected an object"))
+}
+
+/// Changes the elements of a multi-valued attribute that match the path's filter.
+/// Returns how many matched.
+fn update_elements(elements: &mut Vec<Value>, path: &PatchPath, filter: &Filter, value: &Option<Value>, op: PatchOp) -> Result<usize> {
+    let matched = elements.iter().filter(|e| filter.matches(e)).count();
+
+    if op == PatchOp::Remove && path.sub_attr.is_none() {
+        let kept = elements.iter().filter(|e| !filter.matches(e)).map(|e| e.clone()).collect();
+        *elements = kept;
+
+        return Ok(matched);
+    }
+
+    for element in elements.iter_mut().filter(|e| filter.matches(e)) {
+        let element = try!(as_object_mut(element));
+
+        match (op, &path.sub_attr, value) {
+            (PatchOp::Remove, &Some(ref sub_attr), _) => {
+                let key = key_for(element, sub_attr);
+                element.remove(&key);
+            },
+            (_, &Some(ref sub_attr), &Some(ref value)) => set_attr(element, sub_attr, value.clone(), op),
+            (_, &None, &Some(Value::Object(ref values))) => {
+                for (k, v) in values {
+                    set_attr(element, k, v.clone(), op);
+                }
+            },
+            _ => return Err(invalid_value("expected an object")),
+        }
+    }
+
+    Ok(matched)
+}
+
+/// A new element for a filter like type eq "work", so add and replace can create the element they name
+fn new_element(filter: &Filter, path: &PatchPath, value: &Value) -> Option<Value> {
+    match (filter, &path.sub_attr) {
+        (&Filter::Compare(ref attr, CompareOp::Eq, ref literal), &Some(ref sub_attr)) if attr.sub_attr.is_none() => {
+            let mut element = Map::new();
+            element.insert(attr.attr.clone(), literal.clone());
+            element.insert(sub_attr.clone(), value.clone());
+            Some(Value::Object(element))
+        },
+        _ => None
+    }
+}
+
+pub fn apply_operation(resource: &mut Value, operation: &PatchOperation) -> Result<()> {
+    let op = operation.op;
+    let object = try!(as_object_mut(resource));
+
+    let path = match operation.path {
+        Some(ref path) => path,
+        None => {
+            if op == PatchOp::Remove {
+                return Err(no_target("remove needs a path"));
+            }
+
+            match operation.value {
+                Some(Value::Object(ref values)) => {
+                    for (k, v) in values {
+                        try!(check_writable(k));
+                        let attr = try!(AttrPath::parse(k).map_err(|_| invalid_path(format!("invalid attribute {}", k))));
+
+                        match attr.sub_attr {
+                            // e.g. "name.givenName": "Barbara"
+                            Some(sub_attr) => {
+                                let mut inner = Map::new();
+                                inner.insert(sub_attr, v.clone());
+                                set_attr(object, &attr.attr, Value::Object(inner), PatchOp::Add);
+                            },
+                            None => set_attr(object, &attr.attr, v.clone(), op),
+                        }
+                    }
+
+                    return Ok(());
+                },
+                _ => return Err(invalid_value("without a path the value must be an object")),
+            }
+        }
+    };
+
+    try!(check_writable(&path.attr));
+
+    let key = key_for(object, &path.attr);
+
+    if let Some(ref filter) = path.value_filter {
+        let matched = match object.get_mut(&key) {
+            Some(&mut Value::Array(ref mut elements)) => try!(update_elements(elements, path, filter, &operation.value, op)),
+            _ => 0,
+        };
+
+        if matched == 0 {
+            let element = match (op, &operation.value) {
+                (PatchOp::Remove, _) => None,
+                (_, &Some(ref value)) => new_element(filter, path, value),
+                _ => None,
+            };
+
+            match element {
+                Some(element) => set_attr(object, &path.attr, Value::Array(vec![element]), PatchOp::Add),
+                None => return Err(no_target(format!("nothing matched the filter on {}", path.attr))),
+            }
+        }
+
+        return Ok(());
+    }
+
+    match (op, &path.sub_attr) {
+        (PatchOp::Remove, &Some(ref sub_attr)) => {
+            if let Some(inner) = object.get_mut(&key).and_then(|v| v.as_object_mut()) {
+                let sub_key = key_for(inner, sub_attr);
+                inner.remove(&sub_key);
+            }
+        },
+        (PatchOp::Remove, &None) => {
+            // Some clients remove members by value instead of with a filter, e.g.
+            // {"op": "remove", "path": "members", "value": [{"value": "2819c223"}]}
+            match (object.remove(&key), &operation.value) {
+                (Some(Value::Array(elements)), &Some(Value::Array(ref values))) => {
+                    let values: Vec<Option<&Value>> = values.iter().map(|v| get_ignore_case(v, "value")).collect();
+                    let kept = elements.into_iter().filter(|e| !values.contains(&get_ignore_case(e, "value"))).collect();
+                    object.insert(key, Value::Array(kept));
+                },
+                _ => {},
+            }
+        },
+        (_, &Some(ref sub_attr)) => {
+            let mut inner = Map::new();
+            inner.insert(sub_attr.clone(), try!(operation.value.clone().ok_or(invalid_value("missing value"))));
+            set_attr(object, &path.attr, Value::Object(inner), PatchOp::Add);
+        },
+        (_, &None) => {
+            set_attr(object, &path.attr, try!(operation.value.clone().ok_or(invalid_value("missing value"))), op);
+        },
+    }
+
+    Ok(())
+}
+
+/// Applies the operations in order. Either they all apply or the resource is left alone.
+pub fn apply_patch(resource: &Value, operations: &[PatchOperation]) -> Result<Value> {
+    let mut patched = resource.clone();
+
+    for operation in operations {
+        try!(apply_operation(&mut patched, operation));
+    }
+
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn patch(resource: &str, body: &str) -> Result<Value> {
+        let resource: Value = serde_json::from_str(resource).unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+
+        let operations = try!(parse_patch_request(&body));
+
+        apply_patch(&resource, &operations)
+    }
+
+    const USER: &'static str = r#"{
+        "id": "1",
+        "userName": "bjensen",
+        "name": {"givenName": "Barbara"},
+        "emails": [{"value": "bjensen@example.com", "type": "work"}],
+        "active": true
+    }"#;
+
+    #[test]
+    fn test_add_and_replace() {
+        let patched = patch(USER, r#"{
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": [
+                {"op": "Add", "path": "name.familyName", "value": "Jensen"},
+                {"op": "replace", "value": {"active": false, "nickName": "Babs"}},
+                {"op": "add", "path": "emails", "value": [{"value": "babs@example.org", "type": "home"}]},
+                {"op": "replace", "path": "emails[type eq \"work\"].value", "value": "barbara@example.com"},
+                {"op": "add", "path": "phoneNumbers[type eq \"mobile\"].value", "value": "555-0100"}
+            ]
+        }"#).unwrap();
+
+        assert_eq!(patched["name"]["givenName"], "Barbara");
+        assert_eq!(patched["name"]["familyName"], "Jensen");
+        assert_eq!(patched["active"], false);
+        assert_eq!(patched["nickName"], "Babs");
+        assert_eq!(patched["emails"].as_array().unwrap().len(), 2);
+        assert_eq!(patched["emails"][0]["value"], "barbara@example.com");
+        assert_eq!(patched["phoneNumbers"][0]["type"], "mobile");
+    }
+
+    #[test]
+    fn test_remove() {
+        let patched = patch(USER, r#"{
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": [
+                {"op": "remove", "path": "emails[type eq \"work\"]"},
+                {"op": "remove", "path": "name.givenName"}
+            ]
+        }"#).unwrap();
+
+        assert_eq!(patched["emails"].as_array().unwrap().len(), 0);
+        assert!(patched["name"].get("givenName").is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let remove_without_path = r#"{"schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"], "Operations": [{"op": "remove"}]}"#;
+        let read_only = r#"{"schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"], "Operations": [{"op": "replace", "path": "id", "value": "2"}]}"#;
+        let no_match = r#"{"schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"], "Operations": [{"op": "replace", "path": "emails[type eq \"home\"]", "value": {"primary": true}}]}"#;
+        let no_schema = r#"{"Operations": [{"op": "replace", "path": "active", "value": false}]}"#;
+
+        for &(body, expected) in &[(remove_without_path, "noTarget"), (read_only, "mutability"), (no_match, "noTarget"), (no_schema, "invalidSyntax")] {
+            match patch(USER, body) {
+                Err(::result::OpenIdConnectError::ScimError(_, Some(scim_type), _)) => assert_eq!(scim_type, expected),
+                other => panic!("expected {}, got {:?}", expected, other),
+            }
+        }
+    }
+}
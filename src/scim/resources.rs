@@ -0,0 +1,318 @@
+use serde_json;
+use serde_json::{Map, Value};
+use chrono::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use result::Result;
+use users::User;
+use groups::Group;
+use scim::*;
+use scim::error::*;
+use scim::filter::get_ignore_case;
+
+// Mappings between our users and groups and their SCIM representations (RFC 7643 sections 4.1 and 4.2).
+//
+// User attributes map onto standard claims where there's one to match,
+// e.g. name.givenName is the given_name claim and emails is the email address.
+
+/// The attributes a client can't set
+pub const READ_ONLY_ATTRIBUTES: &'static [&'static str] = &["id", "meta", "groups"];
+
+/// Weak ETag of a resource's JSON, without its meta.version
+pub fn etag(resource: &Value) -> Result<String> {
+    let mut resource = resource.clone();
+
+    if let Some(meta) = resource.as_object_mut().and_then(|o| o.get_mut("meta")).and_then(|m| m.as_object_mut()) {
+        meta.remove("version");
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input_str(&try!(serde_json::to_string(&resource)));
+
+    Ok(format!("W/\"{}\"", &hasher.result_str()[..16]))
+}
+
+/// Compares an If-Match or If-None-Match header value with a resource's ETag
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_left_matches("W/").to_owned();
+
+    header.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_owned())
+}
+
+fn insert_claim(object: &mut Map<String, Value>, name: &str, user: &User, claim: &str) {
+    if let Some(value) = user.claims.get(claim) {
+        object.insert(name.to_owned(), value.clone());
+    }
+}
+
+fn timestamp(secs: Option<i64>) -> Option<String> {
+    secs.map(|secs| UTC.timestamp(secs, 0).to_rfc3339())
+}
+
+/// Adds meta, with a version computed from the rest of the resource
+fn with_meta(mut resource: Map<String, Value>, resource_type: &str, location: String, created: Option<String>, last_modified: Option<String>) -> Result<Value> {
+    let mut meta = Map::new();
+
+    meta.insert("resourceType".to_owned(), string(resource_type));
+    if let Some(created) = created {
+        meta.insert("created".to_owned(), Value::String(created));
+    }
+    if let Some(last_modified) = last_modified {
+        meta.insert("lastModified".to_owned(), Value::String(last_modified));
+    }
+    meta.insert("location".to_owned(), Value::String(location));
+
+    resource.insert("meta".to_owned(), Value::Object(meta));
+
+    let mut resource = Value::Object(resource);
+    let version = try!(etag(&resource));
+
+    if let Some(meta) = resource.as_object_mut().and_then(|o| o.get_mut("meta")).and_then(|m| m.as_object_mut()) {
+        meta.insert("version".to_owned(), Value::String(version));
+    }
+
+    Ok(resource)
+}
+
+pub fn user_to_scim(user: &User, groups: &[Group], base_url: &str) -> Result<Value> {
+    let mut resource = Map::new();
+
+    resource.insert("schemas".to_owned(), Value::Array(vec![string(USER_SCHEMA)]));
+    resource.insert("id".to_owned(), string(&user.id));
+    if let Some(ref external_id) = user.external_id {
+        resource.insert("externalId".to_owned(), string(external_id));
+    }
+    resource.insert("userName".to_owned(), string(&user.username));
+
+    let mut name = Map::new();
+    insert_claim(&mut name, "givenName", user, "given_name");
+    insert_claim(&mut name, "familyName", user, "family_name");
+    insert_claim(&mut name, "middleName", user, "middle_name");
+    if !name.is_empty() {
+        resource.insert("name".to_owned(), Value::Object(name));
+    }
+
+    insert_claim(&mut resource, "displayName", user, "name");
+    insert_claim(&mut resource, "nickName", user, "nickname");
+    insert_claim(&mut resource, "profileUrl", user, "profile");
+    insert_claim(&mut resource, "locale", user, "locale");
+    insert_claim(&mut resource, "timezone", user, "zoneinfo");
+
+    if let Some(ref email) = user.email {
+        let mut value = Map::new();
+        value.insert("value".to_owned(), string(email));
+        value.insert("primary".to_owned(), Value::Bool(true));
+        resource.insert("emails".to_owned(), Value::Array(vec![Value::Object(value)]));
+    }
+
+    if let Some(phone_number) = user.claims.get("phone_number") {
+        let mut value = Map::new();
+        value.insert("value".to_owned(), phone_number.clone());
+        resource.insert("phoneNumbers".to_owned(), Value::Array(vec![Value::Object(value)]));
+    }
+
+    resource.insert("active".to_owned(), Value::Bool(!user.disabled));
+
+    if !groups.is_empty() {
+        let groups = groups.iter().map(|group| {
+            let mut value = Map::new();
+            value.insert("value".to_owned(), string(&group.id));
+            value.insert("display".to_owned(), string(&group.display_name));
+            value.insert("$ref".to_owned(), Value::String(format!("{}/Groups/{}", base_url, group.id)));
+            Value::Object(value)
+        }).collect();
+        resource.insert("groups".to_owned(), Value::Array(groups));
+    }
+
+    let last_modified = timestamp(user.claims.get("updated_at").and_then(|v| v.as_i64()));
+
+    with_meta(resource, "User", format!("{}/Users/{}", base_url, user.id), None, last_modified)
+}
+
+pub fn group_to_scim(group: &Group, members: &[User], base_url: &str) -> Result<Value> {
+    let mut resource = Map::new();
+
+    resource.insert("schemas".to_owned(), Value::Array(vec![string(GROUP_SCHEMA)]));
+    resource.insert("id".to_owned(), string(&group.id));
+    if let Some(ref external_id) = group.external_id {
+        resource.insert("externalId".to_owned(), string(external_id));
+    }
+    resource.insert("displayName".to_owned(), string(&group.display_name));
+
+    let members = members.iter().map(|user| {
+        let mut value = Map::new();
+        value.insert("value".to_owned(), string(&user.id));
+        value.insert("display".to_owned(), string(&user.username));
+        value.insert("$ref".to_owned(), Value::String(format!("{}/Users/{}", base_url, user.id)));
+        value.insert("type".to_owned(), string("User"));
+        Value::Object(value)
+    }).collect();
+    resource.insert("members".to_owned(), Value::Array(members));
+
+    with_meta(resource, "Group", format!("{}/Groups/{}", base_url, group.id),
+        Some(group.created_at.to_rfc3339()), Some(group.updated_at.to_rfc3339()))
+}
+
+fn get_string(resource: &Value, name: &str) -> Result<Option<String>> {
+    match get_ignore_case(resource, name) {
+        None | Some(&Value::Null) => Ok(None),
+        Some(&Value::String(ref s)) if s.is_empty() => Ok(None),
+        Some(&Value::String(ref s)) => Ok(Some(s.clone())),
+        Some(_) => Err(invalid_value(format!("{} must be a string", name))),
+    }
+}
+
+/// active is a boolean, but some provisioning systems send "true" and "false"
+fn get_bool(resource: &Value, name: &str) -> Result<Option<bool>> {
+    match get_ignore_case(resource, name) {
+        None | Some(&Value::Null) => Ok(None),
+        Some(&Value::Bool(b)) => Ok(Some(b)),
+        Some(&Value::String(ref s)) if s.to_lowercase() == "true" => Ok(Some(true)),
+        Some(&Value::String(ref s)) if s.to_lowercase() == "false" => Ok(Some(false)),
+        Some(_) => Err(invalid_value(format!("{} must be a boolean", name))),
+    }
+}
+
+/// The value of the primary element of a multi-valued attribute, or else the first
+fn get_primary_value(resource: &Value, name: &str) -> Result<Option<String>> {
+    let elements = match get_ignore_case(resource, name) {
+        None | Some(&Value::Null) => return Ok(None),
+        Some(&Value::Array(ref elements)) => elements,
+        Some(_) => return Err(invalid_value(format!("{} must be an array", name))),
+    };
+
+    let primary = elements.iter().find(|e| get_ignore_case(e, "primary").and_then(|p| p.as_bool()).unwrap_or(false));
+
+    match primary.or(elements.first()) {
+        Some(element) => get_string(element, "value"),
+        None => Ok(None),
+    }
+}
+
+/// Ids from a multi-valued attribute of references, e.g. a group's members
+pub fn get_values(resource: &Value, name: &str) -> Result<Vec<String>> {
+    let elements = match get_ignore_case(resource, name) {
+        None | Some(&Value::Null) => return Ok(vec![]),
+        Some(&Value::Array(ref elements)) => elements,
+        Some(_) => return Err(invalid_value(format!("{} must be an array", name))),
+    };
+
+    let mut values = vec![];
+
+    for element in elements {
+        let value = try!(try!(get_string(element, "value")).ok_or(invalid_value(format!("{} need a value", name))));
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    Ok(values)
+}
+
+/// Sets the user's attributes from a SCIM resource, replacing them all as for a PUT.
+/// Attributes that aren't in the resource are cleared; the password is only changed when one is given.
+pub fn apply_user(resource: &Value, user: &mut User) -> Result<()> {
+    if !resource.is_object() {
+        return Err(invalid_syntax("a user must be an object"));
+    }
+
+    user.username = try!(try!(get_string(resource, "userName")).ok_or(invalid_value("userName is required")));
+    user.external_id = try!(get_string(resource, "externalId"));
+
+    let no_name = Value::Object(Map::new());
+    let name = get_ignore_case(resource, "name").unwrap_or(&no_name);
+    user.set_claim("given_name", try!(get_string(name, "givenName")));
+    user.set_claim("family_name", try!(get_string(name, "familyName")));
+    user.set_claim("middle_name", try!(get_string(name, "middleName")));
+
+    user.set_claim("name", try!(get_string(resource, "displayName")));
+    user.set_claim("nickname", try!(get_string(resource, "nickName")));
+    user.set_claim("profile", try!(get_string(resource, "profileUrl")));
+    user.set_claim("locale", try!(get_string(resource, "locale")));
+    user.set_claim("zoneinfo", try!(get_string(resource, "timezone")));
+    user.set_claim("phone_number", try!(get_primary_value(resource, "phoneNumbers")));
+
+    let email = try!(get_primary_value(resource, "emails"));
+    if email != user.email {
+        user.set_email(email);
+    }
+
+    user.disabled = !try!(get_bool(resource, "active")).unwrap_or(true);
+
+    if let Some(password) = try!(get_string(resource, "password")) {
+        user.set_password(&password);
+    }
+
+    Ok(())
+}
+
+/// Sets the group's attributes from a SCIM resource, as for a PUT.
+/// Members are set too, but the caller has to check they exist.
+pub fn apply_group(resource: &Value, group: &mut Group) -> Result<()> {
+    if !resource.is_object() {
+        return Err(invalid_syntax("a group must be an object"));
+    }
+
+    group.display_name = try!(try!(get_string(resource, "displayName")).ok_or(invalid_value("displayName is required")));
+    group.external_id = try!(get_string(resource, "externalId"));
+    group.members = try!(get_values(resource, "members"));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use chrono::*;
+
+    use super::*;
+    use users::User;
+    use groups::Group;
+
+    #[test]
+    fn test_user_round_trip() {
+        let mut user = User::new("1".to_owned(), "bjensen".to_owned(), None);
+        let resource = serde_json::from_str(r#"{
+            "userName": "bjensen",
+            "externalId": "E1234",
+            "name": {"givenName": "Barbara", "familyName": "Jensen"},
+            "emails": [{"value": "home@example.org"}, {"value": "bjensen@example.com", "primary": true}],
+            "active": "false",
+            "password": "t1meMa$heen"
+        }"#).unwrap();
+
+        apply_user(&resource, &mut user).unwrap();
+
+        assert_eq!(user.email, Some("bjensen@example.com".to_owned()));
+        assert!(user.disabled);
+        assert!(user.verify_password("t1meMa$heen"));
+
+        let group = Group::new("g1".to_owned(), "Admins".to_owned(), UTC::now());
+        let scim = user_to_scim(&user, &[group], "https://example.com/scim/v2").unwrap();
+
+        assert_eq!(scim["name"]["givenName"], "Barbara");
+        assert_eq!(scim["externalId"], "E1234");
+        assert_eq!(scim["active"], false);
+        assert_eq!(scim["groups"][0]["value"], "g1");
+        assert_eq!(scim["meta"]["location"], "https://example.com/scim/v2/Users/1");
+        assert!(scim.get("password").is_none());
+    }
+
+    #[test]
+    fn test_etag() {
+        let user = User::new("1".to_owned(), "bjensen".to_owned(), None);
+        let scim = user_to_scim(&user, &[], "").unwrap();
+        let version = scim["meta"]["version"].as_str().unwrap().to_owned();
+
+        assert_eq!(etag(&scim).unwrap(), version);
+        assert!(etag_matches(&version, &version));
+        assert!(etag_matches("*", &version));
+        assert!(etag_matches(version.trim_left_matches("W/"), &version));
+        assert!(!etag_matches("W/\"abc\"", &version));
+    }
+}
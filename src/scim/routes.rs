@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::str;
+
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+use iron::headers;
+use urlencoded::*;
+use bodyparser;
+use serde_json;
+use serde_json::Value;
+use rbvt::params::*;
+
+use config::Config;
+use site_config::SiteConfig;
+use result::*;
+use helpers::*;
+use authorization::AuthorizedUser;
+use routes::user_api::force_relogin;
+use scim::SERVICE_PROVIDER_CONFIG_SCHEMA;
+use scim::error::*;
+use scim::service::*;
+use scim::resources::{etag, etag_matches};
+
+// Thin handlers over ScimService, which does the work
+
+fn scim_service(req: &mut Request) -> Result<ScimService> {
+    let config = try!(Config::get(req));
+    let site_config = try!(SiteConfig::get(req));
+
+    let mut service = ScimService::new(config.user_repo.clone(), config.group_repo.clone(), format!("{}/scim/v2", site_config.get_issuer()));
+    service.caller = req.extensions.get::<AuthorizedUser>().map(|user| user.id.clone());
+
+    Ok(service)
+}
+
+fn raw_header(req: &Request, name: &str) -> Option<String> {
+    req.headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().to_owned())
+}
+
+fn read_body(req: &mut Request) -> Result<Value> {
+    let body = try!(try!(req.get::<bodyparser::Raw>()).ok_or(invalid_syntax("a JSON body is required")));
+
+    serde_json::from_str(&body).map_err(|e| invalid_syntax(format!("{}", e)))
+}
+
+fn list_params(req: &mut Request) -> Result<ListParams> {
+    let params = match req.get::<UrlEncodedQuery>() {
+        Ok(params) => params,
+        Err(UrlDecodingError::EmptyQuery) => HashMap::new(),
+        Err(e) => return Err(OpenIdConnectError::from(e))
+    };
+
+    let mut list_params = ListParams::new();
+
+    list_params.filter = try!(multimap_get_maybe_one(&params, "filter")).map(|f| f.to_owned());
+
+    if let Some(start_index) = try!(multimap_get_maybe_one(&params, "startIndex")) {
+        list_params.start_index = try!(start_index.parse::<usize>().map_err(|_| invalid_value("startIndex must be a number")));
+    }
+
+    if let Some(count) = try!(multimap_get_maybe_one(&params, "count")) {
+        list_params.count = try!(count.parse::<usize>().map_err(|_| invalid_value("count must be a number")));
+    }
+
+    Ok(list_params)
+}
+
+fn scim_response(status_code: status::Status, resource: &Value) -> Result<Response> {
+    let body = try!(serde_json::to_string(resource));
+    let content_type = "application/scim+json".parse::<Mime>().unwrap();
+
+    let mut response = Response::with((content_type, status_code, body));
+
+    if let Some(version) = resource.get("meta").and_then(|m| m.get("version")).and_then(|v| v.as_str()) {
+        response.headers.set_raw("ETag", vec![version.as_bytes().to_vec()]);
+    }
+
+    if status_code == status::Created {
+        if let Some(location) = resource.get("meta").and_then(|m| m.get("location")).and_then(|l| l.as_str()) {
+            response.headers.set(headers::Location(location.to_owned()));
+        }
+    }
+
+    Ok(response)
+}
+
+/// A GET response, or 304 if the client already has this version
+fn get_response(req: &Request, resource: &Value) -> Result<Response> {
+    if let Some(if_none_match) = raw_header(req, "If-None-Match") {
+        if etag_matches(&if_none_match, &try!(etag(resource))) {
+            return Ok(Response::with(status::NotModified));
+        }
+    }
+
+    scim_response(status::Ok, resource)
+}
+
+/// Logs a user out everywhere once they've been deactivated
+fn saved_user_response(req: &mut Request, saved: SavedUser) -> Result<Response> {
+    if saved.deactivated {
+        let config = try!(Config::get(req));
+        info!("scim deactivated user {}", saved.user.id);
+        try!(force_relogin(&config, &saved.user));
+    }
+
+    scim_response(status::Ok, &saved.resource)
+}
+
+pub fn service_provider_config_get_handler(_req: &mut Request) -> IronResult<Response> {
+    let config: Value = try!(serde_json::from_str(&format!(r#"{{
+        "schemas": ["{}"],
+        "patch": {{"supported": true}},
+        "bulk": {{"supported": false, "maxOperations": 0, "maxPayloadSize": 0}},
+        "filter": {{"supported": true, "maxResults": {}}},
+        "changePassword": {{"supported": true}},
+        "sort": {{"supported": false}},
+        "etag": {{"supported": true}},
+        "authenticationSchemes": [{{
+            "type": "oauthbearertoken",
+            "name": "OAuth Bearer Token",
+            "description": "An access token with the scim scope, issued to an admin",
+            "primary": true
+        }}]
+    }}"#, SERVICE_PROVIDER_CONFIG_SCHEMA, MAX_COUNT)).map_err(OpenIdConnectError::from));
+
+    Ok(try!(scim_response(status::Ok, &config)))
+}
+
+pub fn users_get_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let params = try!(list_params(req));
+
+    let list = try!(service.list_users(&params));
+
+    Ok(try!(scim_response(status::Ok, &list)))
+}
+
+pub fn users_post_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let body = try!(read_body(req));
+
+    let saved = try!(service.create_user(&body));
+
+    Ok(try!(scim_response(status::Created, &saved.resource)))
+}
+
+pub fn user_get_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+
+    let resource = try!(service.get_user(&id));
+
+    Ok(try!(get_response(req, &resource)))
+}
+
+pub fn user_put_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let body = try!(read_body(req));
+    let if_match = raw_header(req, "If-Match");
+
+    let saved = try!(service.replace_user(&id, &body, if_match.as_ref().map(|s| &s[..])));
+
+    Ok(try!(saved_user_response(req, saved)))
+}
+
+pub fn user_patch_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let body = try!(read_body(req));
+    let if_match = raw_header(req, "If-Match");
+
+    let saved = try!(service.patch_user(&id, &body, if_match.as_ref().map(|s| &s[..])));
+
+    Ok(try!(saved_user_response(req, saved)))
+}
+
+/// Deletes the user with their grants, and logs them out everywhere
+pub fn user_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let if_match = raw_header(req, "If-Match");
+
+    let user = try!(service.delete_user(&id, if_match.as_ref().map(|s| &s[..])));
+
+    try!(config.grant_repo.remove_user_grants(&user.id));
+    try!(force_relogin(&config, &user));
+
+    Ok(Response::with(status::NoContent))
+}
+
+pub fn groups_get_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let params = try!(list_params(req));
+
+    let list = try!(service.list_groups(&params));
+
+    Ok(try!(scim_response(status::Ok, &list)))
+}
+
+pub fn groups_post_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let body = try!(read_body(req));
+
+    let resource = try!(service.create_group(&body));
+
+    Ok(try!(scim_response(status::Created, &resource)))
+}
+
+pub fn group_get_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+
+    let resource = try!(service.get_group(&id));
+
+    Ok(try!(get_response(req, &resource)))
+}
+
+pub fn group_put_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let body = try!(read_body(req));
+    let if_match = raw_header(req, "If-Match");
+
+    let resource = try!(service.replace_group(&id, &body, if_match.as_ref().map(|s| &s[..])));
+
+    Ok(try!(scim_response(status::Ok, &resource)))
+}
+
+pub fn group_patch_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let body = try!(read_body(req));
+    let if_match = raw_header(req, "If-Match");
+
+    let resource = try!(service.patch_group(&id, &body, if_match.as_ref().map(|s| &s[..])));
+
+    Ok(try!(scim_response(status::Ok, &resource)))
+}
+
+pub fn group_delete_handler(req: &mut Request) -> IronResult<Response> {
+    let service = try!(scim_service(req));
+    let id = try!(get_url_param(req, "id"));
+    let if_match = raw_header(req, "If-Match");
+
+    try!(service.delete_group(&id, if_match.as_ref().map(|s| &s[..])));
+
+    Ok(Response::with(status::NoContent))
+}
@@ -0,0 +1,355 @@
+use std::sync::Arc;
+use std::usize;
+
+use serde_json::{Map, Value};
+use chrono::*;
+
+use result::{Result, OpenIdConnectError};
+use authentication;
+use users::*;
+use groups::*;
+use scim::*;
+use scim::error::*;
+use scim::filter::Filter;
+use scim::patch::*;
+use scim::resources::*;
+
+// The SCIM operations on users and groups, apart from HTTP so they can be tested against the in-memory repos.
+//
+// Resources are read and written as SCIM JSON. Changes that are given an If-Match value
+// only apply if it matches the resource's current ETag.
+
+pub const DEFAULT_COUNT: usize = 100;
+pub const MAX_COUNT: usize = 200;
+
+/// startIndex is 1-based, count is the page size
+#[derive(Clone, Debug)]
+pub struct ListParams {
+    pub filter: Option<String>,
+    pub start_index: usize,
+    pub count: usize,
+}
+
+impl ListParams {
+    pub fn new() -> ListParams {
+        ListParams {
+            filter: None,
+            start_index: 1,
+            count: DEFAULT_COUNT,
+        }
+    }
+}
+
+/// A saved user, and whether the change deactivated them so they should be logged out
+#[derive(Clone, Debug)]
+pub struct SavedUser {
+    pub user: User,
+    pub resource: Value,
+    pub deactivated: bool,
+}
+
+pub struct ScimService {
+    pub users: Arc<Box<UserRepo>>,
+    pub groups: Arc<Box<GroupRepo>>,
+    pub base_url: String, // e.g. https://example.com/scim/v2, for meta.location
+    pub caller: Option<String>, // the admin making the request, who can't deactivate or delete themselves
+}
+
+fn uniqueness_error(err: OpenIdConnectError) -> OpenIdConnectError {
+    match err {
+        OpenIdConnectError::UserAlreadyExists => uniqueness("userName is already taken"),
+        OpenIdConnectError::GroupAlreadyExists => uniqueness("displayName is already taken"),
+        err => err
+    }
+}
+
+fn check_version(if_match: Option<&str>, resource: &Value) -> Result<()> {
+    match if_match {
+        Some(if_match) => {
+            if etag_matches(if_match, &try!(etag(resource))) {
+                Ok(())
+            } else {
+                Err(precondition_failed())
+            }
+        },
+        None => Ok(())
+    }
+}
+
+fn list_response(resources: Vec<Value>, params: &ListParams) -> Value {
+    let total = resources.len();
+    let start_index = if params.start_index < 1 { 1 } else { params.start_index };
+    let count = if params.count > MAX_COUNT { MAX_COUNT } else { params.count };
+
+    let page: Vec<Value> = resources.into_iter().skip(start_index - 1).take(count).collect();
+
+    let mut response = Map::new();
+    response.insert("schemas".to_owned(), Value::Array(vec![Value::String(LIST_RESPONSE_SCHEMA.to_owned())]));
+    response.insert("totalResults".to_owned(), Value::Number((total as u64).into()));
+    response.insert("startIndex".to_owned(), Value::Number((start_index as u64).into()));
+    response.insert("itemsPerPage".to_owned(), Value::Number((page.len() as u64).into()));
+    response.insert("Resources".to_owned(), Value::Array(page));
+
+    Value::Object(response)
+}
+
+impl ScimService {
+    pub fn new(users: Arc<Box<UserRepo>>, groups: Arc<Box<GroupRepo>>, base_url: String) -> ScimService {
+        ScimService {
+            users: users,
+            groups: groups,
+            base_url: base_url,
+            caller: None,
+        }
+    }
+
+    fn find_user(&self, id: &str) -> Result<User> {
+        try!(self.users.get_user(id)).ok_or(not_found(format!("user {} not found", id)))
+    }
+
+    fn find_group(&self, id: &str) -> Result<Group> {
+        try!(self.groups.get_group(id)).ok_or(not_found(format!("group {} not found", id)))
+    }
+
+    fn check_not_caller(&self, user: &User) -> Result<()> {
+        if self.caller.as_ref() == Some(&user.id) {
+            Err(OpenIdConnectError::AccessDenied("admins can't deactivate or delete themselves".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn user_resource(&self, user: &User) -> Result<Value> {
+        let groups = try!(self.groups.get_user_groups(&user.id));
+
+        user_to_scim(user, &groups, &self.base_url)
+    }
+
+    pub fn group_resource(&self, group: &Group) -> Result<Value> {
+        let mut members = vec![];
+
+        for member in group.members.iter() {
+            if let Some(user) = try!(self.users.get_user(member)) {
+                members.push(user);
+            }
+        }
+
+        group_to_scim(group, &members, &self.base_url)
+    }
+
+    pub fn get_user(&self, id: &str) -> Result<Value> {
+        let user = try!(self.find_user(id));
+
+        self.user_resource(&user)
+    }
+
+    pub fn list_users(&self, params: &ListParams) -> Result<Value> {
+        let filter = match params.filter {
+            Some(ref filter) => Some(try!(Filter::parse(filter))),
+            None => None,
+        };
+
+        let mut query = UserQuery::new();
+        query.limit = usize::MAX;
+
+        let mut resources = vec![];
+
+        for user in try!(self.users.list_users(&query)).users {
+            let resource = try!(self.user_resource(&user));
+
+            if filter.as_ref().map(|f| f.matches(&resource)).unwrap_or(true) {
+                resources.push(resource);
+            }
+        }
+
+        Ok(list_response(resources, params))
+    }
+
+    /// Checks a new or changed user doesn't clash with another, since usernames and email addresses identify users at login
+    fn check_unique_user(&self, user: &User) -> Result<()> {
+        match try!(self.users.find_user(&user.username)) {
+            Some(ref other) if other.id != user.id => return Err(uniqueness(format!("userName {} is already taken", user.username))),
+            _ => {}
+        }
+
+        if let Some(ref email) = user.email {
+            match try!(self.users.find_user_by_email(email)) {
+                Some(ref other) if other.id != user.id => return Err(uniqueness(format!("email address {} is already used", email))),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn create_user(&self, body: &Value) -> Result<SavedUser> {
+        let mut user = User::new(authentication::new_user_id(), String::new(), None);
+
+        try!(apply_user(body, &mut user));
+        try!(self.check_unique_user(&user));
+
+        user.touch(UTC::now());
+
+        info!("scim created user {} {}", user.id, user.username);
+        try!(self.users.add_user(user.clone()).map_err(uniqueness_error));
+
+        let resource = try!(self.user_resource(&user));
+
+        Ok(SavedUser {
+            user: user,
+            resource: resource,
+            deactivated: false,
+        })
+    }
+
+    fn save_user(&self, before: &User, mut user: User) -> Result<SavedUser> {
+        let deactivated = user.disabled && !before.disabled;
+
+        if deactivated {
+            try!(self.check_not_caller(&user));
+        }
+
+        try!(self.check_unique_user(&user));
+
+        user.touch(UTC::now());
+
+        try!(self.users.update_user(user.clone()).map_err(uniqueness_error));
+
+        let resource = try!(self.user_resource(&user));
+
+        Ok(SavedUser {
+            user: user,
+            resource: resource,
+            deactivated: deactivated,
+        })
+    }
+
+    pub fn replace_user(&self, id: &str, body: &Value, if_match: Option<&str>) -> Result<SavedUser> {
+        let before = try!(self.find_user(id));
+        try!(check_version(if_match, &try!(self.user_resource(&before))));
+
+        let mut user = before.clone();
+        try!(apply_user(body, &mut user));
+
+        self.save_user(&before, user)
+    }
+
+    pub fn patch_user(&self, id: &str, body: &Value, if_match: Option<&str>) -> Result<SavedUser> {
+        let before = try!(self.find_user(id));
+        let resource = try!(self.user_resource(&before));
+        try!(check_version(if_match, &resource));
+
+        let operations = try!(parse_patch_request(body));
+        let patched = try!(apply_patch(&resource, &operations));
+
+        let mut user = before.clone();
+        try!(apply_user(&patched, &mut user));
+
+        self.save_user(&before, user)
+    }
+
+    /// Removes the user and their group memberships. The caller cleans up the rest, e.g. their tokens.
+    pub fn delete_user(&self, id: &str, if_match: Option<&str>) -> Result<User> {
+        let user = try!(self.find_user(id));
+        try!(check_version(if_match, &try!(self.user_resource(&user))));
+        try!(self.check_not_caller(&user));
+
+        info!("scim deleting user {}", user.id);
+        try!(self.groups.remove_member_everywhere(&user.id));
+        try!(self.users.remove_user(&user.username));
+
+        Ok(user)
+    }
+
+    pub fn get_group(&self, id: &str) -> Result<Value> {
+        let group = try!(self.find_group(id));
+
+        self.group_resource(&group)
+    }
+
+    pub fn list_groups(&self, params: &ListParams) -> Result<Value> {
+        let filter = match params.filter {
+            Some(ref filter) => Some(try!(Filter::parse(filter))),
+            None => None,
+        };
+
+        let mut resources = vec![];
+
+        for group in try!(self.groups.get_groups()) {
+            let resource = try!(self.group_resource(&group));
+
+            if filter.as_ref().map(|f| f.matches(&resource)).unwrap_or(true) {
+                resources.push(resource);
+            }
+        }
+
+        Ok(list_response(resources, params))
+    }
+
+    fn check_group(&self, group: &Group) -> Result<()> {
+        match try!(self.groups.find_group(&group.display_name)) {
+            Some(ref other) if other.id != group.id => return Err(uniqueness(format!("displayName {} is already taken", group.display_name))),
+            _ => {}
+        }
+
+        for member in group.members.iter() {
+            if try!(self.users.get_user(member)).is_none() {
+                return Err(invalid_value(format!("member {} is not a user", member)));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn create_group(&self, body: &Value) -> Result<Value> {
+        let mut group = Group::new(authentication::new_group_id(), String::new(), UTC::now());
+
+        try!(apply_group(body, &mut group));
+        try!(self.check_group(&group));
+
+        info!("scim created group {} {}", group.id, group.display_name);
+        try!(self.groups.add_group(group.clone()).map_err(uniqueness_error));
+
+        self.group_resource(&group)
+    }
+
+    fn save_group(&self, mut group: Group) -> Result<Value> {
+        try!(self.check_group(&group));
+
+        group.updated_at = UTC::now();
+
+        try!(self.groups.update_group(group.clone()).map_err(uniqueness_error));
+
+        self.group_resource(&group)
+    }
+
+    pub fn replace_group(&self, id: &str, body: &Value, if_match: Option<&str>) -> Result<Value> {
+        let mut group = try!(self.find_group(id));
+        try!(check_version(if_match, &try!(self.group_resource(&group))));
+
+        try!(apply_group(body, &mut group));
+
+        self.save_group(group)
+    }
+
+    pub fn patch_group(&self, id: &str, body: &Value, if_match: Option<&str>) -> Result<Value> {
+        let mut group = try!(self.find_group(id));
+        let resource = try!(self.group_resource(&group));
+        try!(check_version(if_match, &resource));
+
+        let operations = try!(parse_patch_request(body));
+        let patched = try!(apply_patch(&resource, &operations));
+
+        try!(apply_group(&patched, &mut group));
+
+        self.save_group(group)
+    }
+
+    pub fn delete_group(&self, id: &str, if_match: Option<&str>) -> Result<()> {
+        let group = try!(self.find_group(id));
+        try!(check_version(if_match, &try!(self.group_resource(&group))));
+
+        info!("scim deleting group {}", group.id);
+        self.groups.remove_group(&group.id)
+    }
+}
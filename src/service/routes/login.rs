@@ -1,18 +1,22 @@
 use std::io::Read;
 use std::collections::HashMap;
 
+use iron;
 use iron::prelude::*;
 use iron::status;
 use iron::modifiers::Redirect;
-use iron_sessionstorage::traits::*;
 use urlencoded::*;
 use serde_json::value;
+use chrono::*;
 
+use jsonwebtoken::jwt::Jwt;
+use jsonwebtoken::json::*;
 use jsonwebtoken::validation::*;
 use rbvt::params::*;
 use result::{Result, OpenIdConnectError};
 use urls::*;
 use config::Config;
+use site_config::SiteConfig;
 use view::View;
 use back::*;
 
@@ -94,7 +98,6 @@ pub fn parse_login_request(req: &mut Request) -> Result<LoginRequest> {
     
 //    let hashmap = try!(req.get_ref::<UrlEncodedBody>());
     
-    //TODO validate csrf_token
     //TODO check credentials
     
 //    let login_request = try!(LoginRequest::from_params(hashmap));
@@ -103,10 +106,98 @@ pub fn parse_login_request(req: &mut Request) -> Result<LoginRequest> {
     Err(OpenIdConnectError::NotImplemented)
 }
 
+/// Looks up a parameter of the request that sent the user to the login page,
+/// e.g. the ui_locales of an authorize request, from the return token in the query string
+pub fn return_request_param(req: &mut Request, name: &str) -> Option<String> {
+    let query = match req.get::<UrlEncodedQuery>() {
+        Ok(query) => query,
+        Err(_) => return None,
+    };
+    
+    match load_return_params(req, &query) {
+        Ok(Some(params)) => multimap_get_maybe_one(&params, name).unwrap_or(None).map(|s| s.to_owned()),
+        Ok(None) => None,
+        Err(err) => {
+            debug!("error loading return token: {:?}", err);
+            None
+        }
+    }
+}
+
+/// A "Sign in with ..." button on the login page
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpstreamProviderLink {
+    pub id: String,
+    pub name: String,
+}
+
+impl UpstreamProviderLink {
+    pub fn new(id: &str, name: &str) -> UpstreamProviderLink {
+        UpstreamProviderLink {
+            id: id.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+}
+
 pub fn login_path() -> &'static str {
     "/login"
 }
 
+pub fn second_factor_path() -> &'static str {
+    "/login/otp"
+}
+
+/// Carries a user who gave the right password on to the otp step.
+/// It's short lived and no good for anything else, the user isn't logged in until the otp is checked.
+pub fn new_second_factor_token(req: &mut Request, user_id: &str) -> Result<String> {
+    let config = try!(Config::get(req));
+    let now = UTC::now().timestamp();
+    
+    let mut t = Jwt::default();
+    t.header.typ = Some("second_factor".to_owned());
+    t.claims.set_value("nbf", &now);
+    t.claims.set_value("exp", &(now + 300));
+    t.claims.set_value("sub", &user_id.to_owned());
+    
+    t.encode(&config.mac_signer).map_err(OpenIdConnectError::from)
+}
+
+pub fn load_second_factor_user(req: &mut Request, params: &HashMap<String, Vec<String>>) -> Result<String> {
+    match try!(load_token(req, params, "mfa")) {
+        Some(ref token) if token.header.typ.as_ref().map(|s| &s[..]) == Some("second_factor") => {
+            let user_id = try!(token.claims.get_value::<String>("sub"));
+            
+            user_id.ok_or(OpenIdConnectError::NotAuthenticated)
+        },
+        _ => Err(OpenIdConnectError::NotAuthenticated)
+    }
+}
+
+/// The otp step, keeping hold of the return token so the user still ends up back where they started
+pub fn second_factor_url(req: &mut Request, mfa_token: String, params: &HashMap<String, Vec<String>>) -> Result<iron::Url> {
+    let mut query = HashMap::new();
+    
+    query.insert("mfa".to_owned(), vec![mfa_token]);
+    
+    if let Some(return_token) = try!(multimap_get_maybe_one(params, "return")) {
+        query.insert("return".to_owned(), vec![return_token.to_owned()]);
+    }
+    
+    relative_url(req, second_factor_path(), Some(query))
+}
+
+/// Back to the password step, e.g. when the otp step has timed out
+pub fn login_again_url(req: &mut Request, params: &HashMap<String, Vec<String>>) -> Result<iron::Url> {
+    let mut query = HashMap::new();
+    
+    if let Some(return_token) = try!(multimap_get_maybe_one(params, "return")) {
+        query.insert("return".to_owned(), vec![return_token.to_owned()]);
+    }
+    
+    relative_url(req, login_path(), Some(query))
+}
+
 /// called by user agent, probably redirected from authorize
 /// login with cookie if possible
 /// if not logged in or reprompting for credentials, render login form
@@ -115,6 +206,16 @@ pub fn login_path() -> &'static str {
 pub fn login_get_handler(req: &mut Request) -> IronResult<Response> {
     let mut view = try!(View::new_for_session("login.html", req));
     
+    let ui_locales = return_request_param(req, "ui_locales");
+    view.localize(req, ui_locales.as_ref().map(|s| &s[..]));
+    
+    let display = return_request_param(req, "display");
+    view.set_display(display.as_ref().map(|s| &s[..]));
+    
+    let site_config = try!(SiteConfig::get(req));
+    let upstream_providers: Vec<UpstreamProviderLink> = site_config.upstream_providers.iter().map(|p| UpstreamProviderLink::new(&p.id, &p.name)).collect();
+    view.data.insert("upstream_providers".to_owned(), try!(value::to_value(&upstream_providers).map_err(OpenIdConnectError::from)));
+    
     match req.get_ref::<UrlEncodedQuery>() {
         Ok(params) => {
             view.data.insert("return".to_owned(), 
@@ -164,9 +265,16 @@ pub fn login_post_handler(req: &mut Request) -> IronResult<Response> {
     match config.session_controller.login_with_credentials(req) {
         Ok(login) => {
             if let Some(session) = login.session {
-                try!(req.session().set(session));
+                if session.authenticated {
+                    try!(config.session_controller.start_session(req, session));
 
-                Ok(Response::with((status::Found, Redirect(try!(redirect_back_url(req, &params)).unwrap_or(home_url)))))
+                    Ok(Response::with((status::Found, Redirect(try!(redirect_back_url(req, &params)).unwrap_or(home_url)))))
+                } else {
+                    let user_id = try!(session.user_id.ok_or(OpenIdConnectError::UserNotFound));
+                    let mfa_token = try!(new_second_factor_token(req, &user_id));
+                    
+                    Ok(Response::with((status::Found, Redirect(try!(second_factor_url(req, mfa_token, &params))))))
+                }
             } else {
                 Err(IronError::from(OpenIdConnectError::NoSessionLoaded))
             }
@@ -177,3 +285,67 @@ pub fn login_post_handler(req: &mut Request) -> IronResult<Response> {
         }
     }
 }
+
+/// Second login step for users with an authenticator app.
+/// The mfa token from the password step says who they are.
+pub fn second_factor_get_handler(req: &mut Request) -> IronResult<Response> {
+    let params = try!(match req.get::<UrlEncodedQuery>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(e)
+    }.map_err(OpenIdConnectError::from));
+    
+    if let Err(err) = load_second_factor_user(req, &params) {
+        debug!("no second factor token: {:?}", err);
+        return Ok(Response::with((status::Found, Redirect(try!(login_again_url(req, &params))))));
+    }
+    
+    let mut view = try!(View::new_for_session("login_otp.html", req));
+    
+    let ui_locales = return_request_param(req, "ui_locales");
+    view.localize(req, ui_locales.as_ref().map(|s| &s[..]));
+    
+    let display = return_request_param(req, "display");
+    view.set_display(display.as_ref().map(|s| &s[..]));
+    
+    view.data.insert("return".to_owned(), try!(value::to_value(&try!(multimap_get_maybe_one(&params, "return").map_err(OpenIdConnectError::from))).map_err(OpenIdConnectError::from)));
+    view.data.insert("mfa".to_owned(), try!(value::to_value(&try!(multimap_get_maybe_one(&params, "mfa").map_err(OpenIdConnectError::from))).map_err(OpenIdConnectError::from)));
+    
+    Ok(Response::with((status::Ok, try!(view.template().map_err(OpenIdConnectError::from)))))
+}
+
+/// Checks the otp or recovery code, then logs in and carries on as the password step would have
+pub fn second_factor_post_handler(req: &mut Request) -> IronResult<Response> {
+    let config = try!(Config::get(req));
+    let home_url = try!(relative_url(req, "/", None));
+    
+    let params = try!(match req.get::<UrlEncodedBody>() {
+        Ok(params) => Ok(params),
+        Err(UrlDecodingError::EmptyQuery) => Ok(HashMap::new()),
+        Err(e) => Err(e)
+    }.map_err(OpenIdConnectError::from));
+    
+    let user_id = match load_second_factor_user(req, &params) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            debug!("no second factor token: {:?}", err);
+            return Ok(Response::with((status::Found, Redirect(try!(login_again_url(req, &params))))));
+        }
+    };
+    
+    let code = try!(multimap_get_maybe_one(&params, "code").map_err(OpenIdConnectError::from)).unwrap_or("");
+    
+    match config.session_controller.authenticate_second_factor(req, &user_id, code) {
+        Ok(session) => {
+            try!(config.session_controller.start_session(req, session));
+            
+            Ok(Response::with((status::Found, Redirect(try!(redirect_back_url(req, &params)).unwrap_or(home_url)))))
+        },
+        Err(err) => {
+            debug!("error checking second factor: {:?}", err);
+            let mfa_token = try!(try!(multimap_get_maybe_one(&params, "mfa").map_err(OpenIdConnectError::from)).ok_or(OpenIdConnectError::NotAuthenticated)).to_owned();
+            
+            Ok(Response::with((status::Found, Redirect(try!(second_factor_url(req, mfa_token, &params))))))
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::collections::HashMap;
 use std::borrow::Cow;
+use chrono::*;
 use rand;
 use rand::Rng;
 use crypto::digest::Digest;
@@ -11,8 +12,10 @@ use serde_json;
 use rustc_serialize::base64;
 use rustc_serialize::base64::ToBase64;
 use iron::prelude::*;
-use iron::BeforeMiddleware;
+use iron::{BeforeMiddleware, AfterMiddleware};
 use iron::typemap::Key;
+use iron::headers::{Cookie as CookieHeader, SetCookie, UserAgent};
+use cookie::Cookie;
 use iron_sessionstorage;
 use iron_sessionstorage::traits::*;
 use persistent;
@@ -23,8 +26,19 @@ use urlencoded::*;
 use result::*;
 use login_manager::*;
 use users::*;
+use authentication;
+use authentication::*;
+use htpasswd::HtpasswdAuthenticator;
+use site_config::SiteConfig;
+use serialisation::UTCDateTime;
+use throttle::LoginThrottle;
+use clock::{Clock, SystemClock};
 use rbvt::params::*;
 
+/// Readable by the check_session_iframe, so not http only.
+/// Rotated whenever the user logs in or out, which changes the session_state the RPs compute.
+pub const BROWSER_STATE_COOKIE: &'static str = "op_browser_state";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Credentials {
     username: String,
@@ -58,6 +72,12 @@ pub struct UserSession {
     pub session_id: Option<String>,
     pub authenticated: bool,
     pub gravatar: Option<String>,
+    #[serde(default)]
+    pub clients: Vec<String>, // clients that were issued tokens during this session
+    #[serde(default)]
+    pub auth_methods: Vec<String>, // e.g. pwd
+    #[serde(default)]
+    pub sid: Option<String>, // the OIDC session id given to clients, never the store key
 }
 
 impl UserSession {
@@ -68,11 +88,29 @@ impl UserSession {
             user_id: Some(user_id),
             session_id: Some(session_id),
             authenticated: false,
+            clients: vec![],
+            auth_methods: vec![],
+            sid: None,
+        }
+    }
+}
+
+/// The cookie only carries an opaque session id.
+/// Everything else about the session is kept server side in the SessionStore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionCookie {
+    pub session_id: String,
+}
+
+impl SessionCookie {
+    pub fn new(session_id: String) -> SessionCookie {
+        SessionCookie {
+            session_id: session_id,
         }
     }
 }
 
-impl iron_sessionstorage::Value for UserSession {
+impl iron_sessionstorage::Value for SessionCookie {
     fn get_key() -> &'static str { "logged_in_user" }
     fn into_raw(self) -> String { serde_json::to_string(&self).unwrap_or(String::new()) }
     fn from_raw(value: String) -> Option<Self> {
@@ -92,6 +130,9 @@ impl Default for UserSession {
             session_id: None,
             authenticated: false,
             gravatar: None,
+            clients: vec![],
+            auth_methods: vec![],
+            sid: None,
         }
     }
 }
@@ -106,6 +147,12 @@ pub type SessionLogin = Login<UserSession>;
 
 impl Key for UserSession { type Value = Option<UserSession>; }
 
+/// A browser state set during this request, to be sent back in a cookie
+#[derive(Clone, Debug)]
+pub struct BrowserState(pub String);
+
+impl Key for BrowserState { type Value = BrowserState; }
+
 impl<'a, 'b> plugin::Plugin<Request<'a, 'b>> for UserSession {
     type Error = OpenIdConnectError;
     
@@ -116,104 +163,440 @@ impl<'a, 'b> plugin::Plugin<Request<'a, 'b>> for UserSession {
 }
 
 
-pub trait Sessions: Send + Sync + 'static {
-    fn authenticate(&self, creds: &Credentials) -> Result<UserSession>;
+/// A logged in session, as kept by the SessionStore
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub sid: String, // a separate random id for the sid claim, so tokens and logout urls don't leak the cookie's session id
+    pub user_id: String,
+    pub username: String,
+    pub created_at: UTCDateTime,
+    pub last_activity: UTCDateTime,
+    pub auth_methods: Vec<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub clients: Vec<String>,
+}
+
+impl SessionRecord {
+    pub fn new(session: &UserSession, user_agent: Option<String>, ip: Option<String>, now: DateTime<UTC>) -> Result<SessionRecord> {
+        Ok(SessionRecord {
+            session_id: try!(session.session_id.clone().ok_or(OpenIdConnectError::NoSessionLoaded)),
+            sid: authentication::new_token(),
+            user_id: try!(session.user_id.clone().ok_or(OpenIdConnectError::UserNotFound)),
+            username: try!(session.username.clone().ok_or(OpenIdConnectError::UserNotFound)),
+            created_at: UTCDateTime::new(now),
+            last_activity: UTCDateTime::new(now),
+            auth_methods: session.auth_methods.clone(),
+            user_agent: user_agent,
+            ip: ip,
+            clients: session.clients.clone(),
+        })
+    }
     
-    fn lookup(&self, session_id: &str) -> Result<Option<UserSession>>;
+    /// Sessions end after a period of inactivity, and after a maximum lifetime however active they are
+    pub fn is_expired(&self, now: DateTime<UTC>, idle_timeout: Duration, absolute_timeout: Duration) -> bool {
+        let created_at: DateTime<UTC> = *self.created_at;
+        let last_activity: DateTime<UTC> = *self.last_activity;
+        
+        created_at + absolute_timeout <= now || last_activity + idle_timeout <= now
+    }
     
-    fn remove(&self, session_id: &str) -> Result<bool>;
+    pub fn to_user_session(&self) -> UserSession {
+        let mut session = UserSession::new(self.user_id.clone(), self.username.clone(), self.session_id.clone());
+        session.authenticated = true;
+        session.clients = self.clients.clone();
+        session.auth_methods = self.auth_methods.clone();
+        session.sid = Some(self.sid.clone());
+        session
+    }
 }
 
-pub struct InMemorySessions {
-    users: Arc<Box<UserRepo>>,
-    sessions: Arc<Mutex<HashMap<String, UserSession>>>,
+pub trait SessionStore: Send + Sync + 'static {
+    fn create_session(&self, record: SessionRecord) -> Result<()>;
+    
+    fn find_session(&self, session_id: &str) -> Result<Option<SessionRecord>>;
+    
+    fn touch_session(&self, session_id: &str, at: DateTime<UTC>) -> Result<()>;
+    
+    fn add_session_client(&self, session_id: &str, client_id: &str) -> Result<()>;
+    
+    fn get_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>>;
+    
+    /// Removes the session, returning it if it was there
+    fn revoke_session(&self, session_id: &str) -> Result<Option<SessionRecord>>;
+    
+    /// Removes all the user's sessions, returning them
+    fn revoke_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>>;
+    
+    /// Removes the sessions that have timed out, returning them
+    fn remove_expired_sessions(&self, now: DateTime<UTC>, idle_timeout: Duration, absolute_timeout: Duration) -> Result<Vec<SessionRecord>>;
 }
 
-impl InMemorySessions {
-    pub fn new(users: Arc<Box<UserRepo>>) -> InMemorySessions {
-        InMemorySessions {
-            users: users,
+/// Told about every session that ends, whether the user logged out, it expired,
+/// or it was revoked, e.g. to send back-channel logout tokens to the session's clients
+pub trait SessionEndListener where Self: Send + Sync {
+    fn session_ended(&self, record: &SessionRecord);
+}
+
+#[derive(Clone)]
+pub struct InMemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, SessionRecord>>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> InMemorySessionStore {
+        InMemorySessionStore {
             sessions: Arc::new(Mutex::new(HashMap::new()))
         }
     }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn create_session(&self, record: SessionRecord) -> Result<()> {
+        debug!("sessions: create for user {}", record.user_id);
+        
+        let mut sessions = self.sessions.lock().unwrap();
+        
+        sessions.insert(record.session_id.clone(), record);
+        
+        Ok(())
+    }
     
-    fn new_session_id(&self) -> String {
-        let mut id = vec![0u8; 16];
-        rand::thread_rng().fill_bytes(id.as_mut_slice());
-        id.to_base64(base64::STANDARD)
+    fn find_session(&self, session_id: &str) -> Result<Option<SessionRecord>> {
+        let sessions = self.sessions.lock().unwrap();
+        
+        Ok(sessions.get(session_id).map(|s| s.clone()))
     }
     
-    fn new_session(&self, user_id: &str, username: &str) -> UserSession {
-        UserSession::new(user_id.to_owned(), username.to_owned(), self.new_session_id())
+    fn touch_session(&self, session_id: &str, at: DateTime<UTC>) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        
+        let record = try!(sessions.get_mut(session_id).ok_or(OpenIdConnectError::NoSessionLoaded));
+        record.last_activity = UTCDateTime::new(at);
+        
+        Ok(())
     }
-}
-
-impl Sessions for InMemorySessions {
     
-    fn authenticate(&self, creds: &Credentials) -> Result<UserSession> {
-        if let Some(user) = try!(self.users.find_user(&creds.username)) {
-            if user.password.as_ref() == Some(&creds.password) {
-                let mut session = self.new_session(&user.id, &user.username);
-                session.authenticated = true;
-                
-                let mut sessions = self.sessions.lock().unwrap();
-                let session_id = try!(session.session_id.as_ref().ok_or(OpenIdConnectError::InvalidUsernameOrPassword)).to_owned();
-                sessions.insert(session_id, session.clone());
-                
-                Ok(session)
-            } else {
-                // TODO add random wait jitter
-                Err(OpenIdConnectError::InvalidUsernameOrPassword)
-            }
-        } else {
-            // TODO add random wait jitter
-            Err(OpenIdConnectError::InvalidUsernameOrPassword)
+    fn add_session_client(&self, session_id: &str, client_id: &str) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        
+        let record = try!(sessions.get_mut(session_id).ok_or(OpenIdConnectError::NoSessionLoaded));
+        if !record.clients.iter().any(|c| c == client_id) {
+            record.clients.push(client_id.to_owned());
         }
+        
+        Ok(())
     }
     
-    fn lookup(&self, session_id: &str) -> Result<Option<UserSession>> {
+    fn get_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>> {
         let sessions = self.sessions.lock().unwrap();
         
-        Ok(sessions.get(session_id).map(|u| (*u).clone()))
+        Ok(sessions.values().filter(|s| s.user_id == user_id).map(|s| s.clone()).collect())
+    }
+    
+    fn revoke_session(&self, session_id: &str) -> Result<Option<SessionRecord>> {
+        debug!("sessions: revoke {}", session_id);
+        
+        let mut sessions = self.sessions.lock().unwrap();
+        
+        Ok(sessions.remove(session_id))
+    }
+    
+    fn revoke_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>> {
+        debug!("sessions: revoke all for user {}", user_id);
+        
+        let mut sessions = self.sessions.lock().unwrap();
+        
+        let session_ids: Vec<String> = sessions.values().filter(|s| s.user_id == user_id).map(|s| s.session_id.clone()).collect();
+        
+        Ok(session_ids.iter().filter_map(|session_id| sessions.remove(session_id)).collect())
     }
     
-    fn remove(&self, session_id: &str) -> Result<bool> {
+    fn remove_expired_sessions(&self, now: DateTime<UTC>, idle_timeout: Duration, absolute_timeout: Duration) -> Result<Vec<SessionRecord>> {
         let mut sessions = self.sessions.lock().unwrap();
         
-        Ok(sessions.remove(session_id).is_some())
+        let session_ids: Vec<String> = sessions.values()
+            .filter(|s| s.is_expired(now, idle_timeout, absolute_timeout))
+            .map(|s| s.session_id.clone())
+            .collect();
+        
+        if !session_ids.is_empty() {
+            debug!("sessions: removing {} expired", session_ids.len());
+        }
+        
+        Ok(session_ids.iter().filter_map(|session_id| sessions.remove(session_id)).collect())
+    }
+}
+
+/// The local user for someone an authenticator vouched for.
+/// Backends other than the UserRepo only know a username, so the first time one of their users
+/// logs in a local user without a password is made for them, owned by that backend.
+/// After that they're matched by username, but only to the user their backend owns:
+/// a local user of the same name that came from somewhere else, e.g. federation or the repo itself,
+/// is never taken over. Backends that decide roles have them copied onto the local user at every login.
+pub fn resolve_local_user(users: &Arc<Box<UserRepo>>, attributes: &UserAttributes) -> Result<User> {
+    if let Some(ref user_id) = attributes.user_id {
+        return try!(users.get_user(user_id)).ok_or(OpenIdConnectError::UserNotFound);
+    }
+    
+    if let Some(mut user) = try!(users.find_user(&attributes.username)) {
+        if attributes.authenticator.is_none() || user.authenticator != attributes.authenticator {
+            warn!("{:?} vouched for {}, but the local user of that name belongs to {:?}; refusing the login",
+                attributes.authenticator, attributes.username, user.authenticator);
+            
+            return Err(OpenIdConnectError::UserAlreadyExists);
+        }
+        
+        if !attributes.roles.is_empty() && user.roles != attributes.roles {
+            info!("updating roles of user {} to {:?}", user.id, attributes.roles);
+            
+            user.roles = attributes.roles.clone();
+            try!(users.update_user(user.clone()));
+        }
+        
+        return Ok(user);
+    }
+    
+    let mut user = User::new(authentication::new_user_id(), attributes.username.clone(), None);
+    user.set_email(attributes.email.clone());
+    user.claims = attributes.claims.clone();
+    user.authenticator = attributes.authenticator.clone();
+    
+    if !attributes.roles.is_empty() {
+        user.roles = attributes.roles.clone();
+    }
+    
+    info!("creating local user {} for {} from {:?}", user.id, user.username, user.authenticator);
+    
+    try!(users.add_user(user.clone()));
+    
+    Ok(user)
+}
+
+/// The authenticators named in the site config, in order.
+/// "users" checks the UserRepo and "htpasswd" the site's htpasswd_file, whose htpasswd_admins are admins.
+/// Without any configured, just "users".
+pub fn authenticators_for_site(site_config: &SiteConfig, user_repo: Arc<Box<UserRepo>>) -> Result<AuthenticatorChain> {
+    let mut chain = AuthenticatorChain::new();
+    
+    let names = if site_config.authenticators.is_empty() {
+        vec!["users".to_owned()]
+    } else {
+        site_config.authenticators.clone()
+    };
+    
+    for name in names {
+        let authenticator = match &name[..] {
+            "users" => Box::new(UserRepoAuthenticator::new(user_repo.clone())) as Box<Authenticator>,
+            "htpasswd" => {
+                let path = try!(site_config.htpasswd_file.as_ref().ok_or(OpenIdConnectError::ConfigError("no htpasswd_file configured".to_owned())));
+                
+                let mut htpasswd = try!(HtpasswdAuthenticator::new(path.to_owned()));
+                htpasswd.admins = site_config.htpasswd_admins.clone();
+                
+                Box::new(htpasswd) as Box<Authenticator>
+            },
+            _ => return Err(OpenIdConnectError::ConfigError(format!("unknown authenticator {}", name))),
+        };
+        
+        chain.push(name, Arc::new(authenticator));
     }
+    
+    Ok(chain)
 }
 
 #[derive(Clone)]
 pub struct SessionController {
     pub users: Arc<Box<UserRepo>>,
     pub login_manager: LoginManager,
+    pub store: Arc<Box<SessionStore>>,
+    pub throttle: LoginThrottle,
+    pub authenticator: Arc<Box<Authenticator>>, // see authenticators_for_site
+    pub session_end_listener: Option<Arc<Box<SessionEndListener>>>, // see oauth2::backchannel::BackchannelSessionEndListener
 }
 
 impl SessionController {
-    pub fn new(user_repo: Arc<Box<UserRepo>>, login_manager: LoginManager) -> Self {
+    pub fn new(user_repo: Arc<Box<UserRepo>>, login_manager: LoginManager, store: Arc<Box<SessionStore>>) -> Self {
+        let authenticator = Arc::new(Box::new(UserRepoAuthenticator::new(user_repo.clone())) as Box<Authenticator>);
+        
         SessionController {
             users: user_repo,
             login_manager: login_manager,
+            store: store,
+            throttle: LoginThrottle::new(Arc::new(Box::new(SystemClock) as Box<Clock>)),
+            authenticator: authenticator,
+            session_end_listener: None,
         }
     }
 
-    pub fn load_session_cookie(&self, req: &mut Request) -> Result<Option<UserSession>> {
-        req.session().get::<UserSession>().map_err(OpenIdConnectError::from)
+    pub fn load_session_cookie(&self, req: &mut Request) -> Result<Option<SessionCookie>> {
+        req.session().get::<SessionCookie>().map_err(OpenIdConnectError::from)
     }
 
+    /// Looks up the session named in the cookie.
+    /// Expired or revoked sessions are cleared out, otherwise this counts as activity.
     pub fn load_session(&self, req: &mut Request) -> Result<Option<UserSession>> {
         debug!("loading session");
+        
+        let cookie = match try!(self.load_session_cookie(req)) {
+            Some(cookie) => cookie,
+            None => return Ok(None),
+        };
+        
+        let site_config = try!(SiteConfig::get(req));
+        let now = UTC::now();
+        
+        match try!(self.store.find_session(&cookie.session_id)) {
+            Some(ref record) if !record.is_expired(now, site_config.get_session_idle_timeout(), site_config.get_session_absolute_timeout()) => {
+                try!(self.store.touch_session(&record.session_id, now));
+                
+                Ok(Some(record.to_user_session()))
+            },
+            Some(_) => {
+                debug!("session expired");
+                try!(self.revoke_session(&cookie.session_id));
+                try!(req.session().clear());
+                
+                Ok(None)
+            },
+            None => {
+                debug!("session not found or revoked");
+                try!(req.session().clear());
+                
+                Ok(None)
+            }
+        }
+    }
     
-        self.load_session_cookie(req).map_err(OpenIdConnectError::from)
+    /// Stores a newly authenticated session and gives the browser its id
+    pub fn start_session(&self, req: &mut Request, session: UserSession) -> Result<()> {
+        if !session.authenticated {
+            return Err(OpenIdConnectError::NotAuthenticated);
+        }
+        
+        let user_agent = req.headers.get::<UserAgent>().map(|ua| ua.0.clone());
+        let ip = Some(format!("{}", req.remote_addr.ip()));
+        
+        let now = UTC::now();
+        try!(self.remove_expired_sessions(&try!(SiteConfig::get(req)), now));
+        
+        let record = try!(SessionRecord::new(&session, user_agent, ip, now));
+        let cookie = SessionCookie::new(record.session_id.clone());
+        
+        try!(self.store.create_session(record));
+        try!(req.session().set(cookie));
+        
+        self.rotate_browser_state(req);
+        
+        Ok(())
+    }
+    
+    /// Remembers that a client was issued tokens in this session, so it can be told about logout
+    pub fn add_session_client(&self, session: &UserSession, client_id: &str) -> Result<()> {
+        if let Some(ref session_id) = session.session_id {
+            try!(self.store.add_session_client(session_id, client_id));
+        }
+        
+        Ok(())
     }
     
     pub fn clear_session(&self, req: &mut Request) -> Result<bool> {
         debug!("clearing session");
+        
+        let revoked = match try!(self.load_session_cookie(req)) {
+            Some(cookie) => try!(self.revoke_session(&cookie.session_id)),
+            None => false,
+        };
 
         try!(req.session().clear());
+        
+        self.rotate_browser_state(req);
 
-        Ok(true)
+        Ok(revoked)
+    }
+    
+    /// The user's live sessions. Expired sessions are cleared out of the store on the way,
+    /// as they're otherwise only noticed when their own cookie comes back.
+    pub fn get_user_sessions(&self, site_config: &SiteConfig, user_id: &str) -> Result<Vec<SessionRecord>> {
+        let now = UTC::now();
+        try!(self.remove_expired_sessions(site_config, now));
+        
+        let idle_timeout = site_config.get_session_idle_timeout();
+        let absolute_timeout = site_config.get_session_absolute_timeout();
+        
+        Ok(try!(self.store.get_user_sessions(user_id)).into_iter()
+            .filter(|record| !record.is_expired(now, idle_timeout, absolute_timeout))
+            .collect())
+    }
+    
+    fn remove_expired_sessions(&self, site_config: &SiteConfig, now: DateTime<UTC>) -> Result<()> {
+        let expired = try!(self.store.remove_expired_sessions(now, site_config.get_session_idle_timeout(), site_config.get_session_absolute_timeout()));
+        
+        for record in &expired {
+            self.session_ended(record);
+        }
+        
+        Ok(())
+    }
+    
+    pub fn revoke_session(&self, session_id: &str) -> Result<bool> {
+        match try!(self.store.revoke_session(session_id)) {
+            Some(record) => {
+                self.session_ended(&record);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+    
+    /// Ends every session the user has, e.g. after their password changes
+    pub fn revoke_user_sessions(&self, user_id: &str) -> Result<usize> {
+        let records = try!(self.store.revoke_user_sessions(user_id));
+        
+        for record in &records {
+            self.session_ended(record);
+        }
+        
+        Ok(records.len())
+    }
+    
+    /// Every way a session ends comes through here, so its clients are always told
+    fn session_ended(&self, record: &SessionRecord) {
+        if let Some(ref listener) = self.session_end_listener {
+            listener.session_ended(record);
+        }
+    }
+    
+    pub fn rotate_browser_state(&self, req: &mut Request) -> String {
+        let browser_state = authentication::new_token();
+        
+        debug!("new browser state");
+        req.extensions_mut().insert::<BrowserState>(BrowserState(browser_state.clone()));
+        
+        browser_state
+    }
+    
+    /// The browser state set during this request, or else the one from the cookie
+    pub fn browser_state(&self, req: &Request) -> Option<String> {
+        if let Some(browser_state) = req.extensions.get::<BrowserState>() {
+            return Some(browser_state.0.clone());
+        }
+        
+        req.headers.get::<CookieHeader>().and_then(|cookies| {
+            cookies.iter()
+                .filter_map(|c| Cookie::parse(c.to_owned()).ok())
+                .find(|c| c.name() == BROWSER_STATE_COOKIE)
+                .map(|c| c.value().to_owned())
+        })
+    }
+    
+    /// Sessions started before the cookie existed get one now
+    pub fn ensure_browser_state(&self, req: &mut Request) -> String {
+        match self.browser_state(req) {
+            Some(browser_state) => browser_state,
+            None => self.rotate_browser_state(req),
+        }
     }
     
     /// Login with credentials if provided.
@@ -223,28 +606,32 @@ impl SessionController {
         
         let login_config = try!(LoginConfig::get_config(req));
         
-        let params = try!(match req.get_ref::<UrlEncodedBody>() {
-            Ok(params) => Ok(Cow::Borrowed(params)),
-            Err(UrlDecodingError::EmptyQuery) => Ok(Cow::Owned(HashMap::new())),
-            Err(e) => Err(e),
-        });
-        
-        // TODO validate csrf
-        
-        let username = try!(multimap_get_maybe_one(&params, "username").map_err(|e| {
-            debug!("error reading username: {:?}", e);
-            OpenIdConnectError::InvalidUsernameOrPassword
-        }));
-        
-        let password = try!(multimap_get_maybe_one(&params, "password").map_err(|e| {
-            debug!("error reading password: {:?}", e);
-            OpenIdConnectError::InvalidUsernameOrPassword
-        }));
+        let (username, password) = {
+            let params = try!(match req.get_ref::<UrlEncodedBody>() {
+                Ok(params) => Ok(Cow::Borrowed(params)),
+                Err(UrlDecodingError::EmptyQuery) => Ok(Cow::Owned(HashMap::new())),
+                Err(e) => Err(e),
+            });
+            
+            // the csrf token was checked by CsrfProtection
+            
+            let username = try!(multimap_get_maybe_one(&params, "username").map_err(|e| {
+                debug!("error reading username: {:?}", e);
+                OpenIdConnectError::InvalidUsernameOrPassword
+            }));
+            
+            let password = try!(multimap_get_maybe_one(&params, "password").map_err(|e| {
+                debug!("error reading password: {:?}", e);
+                OpenIdConnectError::InvalidUsernameOrPassword
+            }));
+            
+            (username.map(|s| s.to_owned()), password.map(|s| s.to_owned()))
+        };
         
         let session = if username.is_some() || password.is_some() {
-            let creds = Credentials::new(username.unwrap_or(""), password.unwrap_or(""));
+            let creds = Credentials::new(username.unwrap_or(String::new()), password.unwrap_or(String::new()));
             
-            let session = try!(self.authenticate(&creds));
+            let session = try!(self.authenticate_request(req, &creds));
             
             Some(session)
         } else {
@@ -272,21 +659,95 @@ impl SessionController {
         Ok(login_modifier)
     }
 
-    pub fn authenticate(&self, creds: &Credentials) -> Result<UserSession> {
-        if let Some(user) = try!(self.users.find_user(&creds.username)) {
-            if user.password.as_ref() == Some(&creds.password) {
-                let mut session = self.new_session(&user.id, &user.username);
-                session.authenticated = true;
-                
+    /// Checks credentials sent in a request, with brute force protection
+    /// keyed on the username and the client's ip address
+    pub fn authenticate_request(&self, req: &mut Request, creds: &Credentials) -> Result<UserSession> {
+        let settings = try!(SiteConfig::get(req)).get_login_throttle();
+        let ip = format!("{}", req.remote_addr.ip());
+        
+        try!(self.throttle.check(&settings, &creds.username, Some(&ip)));
+        
+        match self.authenticate(creds) {
+            Ok(session) => {
+                self.throttle.record_success(&creds.username);
                 Ok(session)
-            } else {
-                // TODO add random wait jitter
+            },
+            Err(OpenIdConnectError::InvalidUsernameOrPassword) => {
+                self.throttle.record_failure(&settings, &creds.username, Some(&ip));
                 Err(OpenIdConnectError::InvalidUsernameOrPassword)
-            }
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn authenticate(&self, creds: &Credentials) -> Result<UserSession> {
+        let authentication = try!(self.authenticator.authenticate(&creds.username, &creds.password));
+        
+        if !authentication.is_success() {
+            return Err(OpenIdConnectError::InvalidUsernameOrPassword);
+        }
+        
+        let attributes = try!(authentication.attributes.ok_or(OpenIdConnectError::UserNotFound));
+        let user = try!(resolve_local_user(&self.users, &attributes));
+        
+        if user.disabled {
+            return Err(OpenIdConnectError::AccountDisabled);
+        }
+        
+        let mut session = self.new_session(&user.id, &user.username);
+        // users with a second factor aren't logged in until they've given an otp too
+        session.authenticated = authentication.status == AuthenticationStatus::Success && !user.has_second_factor();
+        session.auth_methods = vec!["pwd".to_owned()];
+        
+        Ok(session)
+    }
+    
+    /// A session for a user vouched for by an upstream identity provider.
+    /// Like authenticate, users with a second factor still have to give an otp.
+    pub fn authenticate_upstream(&self, user: &User) -> UserSession {
+        let mut session = self.new_session(&user.id, &user.username);
+        session.authenticated = !user.has_second_factor();
+        session.auth_methods = vec!["fed".to_owned()];
+        
+        session
+    }
+
+    /// Second login step, after the password was checked by authenticate.
+    /// Takes either a TOTP code or one of the user's recovery codes.
+    /// Wrong codes count towards the same brute force limits as wrong passwords.
+    pub fn authenticate_second_factor(&self, req: &mut Request, user_id: &str, code: &str) -> Result<UserSession> {
+        let settings = try!(SiteConfig::get(req)).get_login_throttle();
+        let ip = format!("{}", req.remote_addr.ip());
+        
+        let mut user = try!(try!(self.users.get_user(user_id)).ok_or(OpenIdConnectError::UserNotFound));
+        
+        if !user.has_second_factor() {
+            return Err(OpenIdConnectError::InvalidOneTimePassword);
+        }
+        
+        if user.disabled {
+            return Err(OpenIdConnectError::AccountDisabled);
+        }
+        
+        try!(self.throttle.check(&settings, &user.username, Some(&ip)));
+        
+        if user.verify_totp(code, UTC::now().timestamp()) {
+            try!(self.users.update_user(user.clone()));
+        } else if user.use_recovery_code(code) {
+            info!("recovery code used by user {}", user.id);
+            try!(self.users.update_user(user.clone()));
         } else {
-            // TODO add random wait jitter
-            Err(OpenIdConnectError::InvalidUsernameOrPassword)
+            self.throttle.record_failure(&settings, &user.username, Some(&ip));
+            return Err(OpenIdConnectError::InvalidOneTimePassword);
         }
+        
+        self.throttle.record_success(&user.username);
+        
+        let mut session = self.new_session(&user.id, &user.username);
+        session.authenticated = true;
+        session.auth_methods = vec!["pwd".to_owned(), "otp".to_owned()];
+        
+        Ok(session)
     }
 
     fn new_session(&self, user_id: &str, username: &str) -> UserSession {
@@ -296,7 +757,7 @@ impl SessionController {
     fn new_session_id(&self) -> String {
         let mut id = vec![0u8; 16];
         rand::thread_rng().fill_bytes(id.as_mut_slice());
-        id.to_base64(base64::STANDARD)
+        id.to_base64(base64::URL_SAFE)
     }
 }
 
@@ -320,3 +781,164 @@ impl BeforeMiddleware for SessionController {
         }
     }
 }
+
+impl AfterMiddleware for SessionController {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        if let Some(browser_state) = req.extensions.get::<BrowserState>() {
+            let cookie = Cookie::build(BROWSER_STATE_COOKIE, browser_state.0.clone())
+                .path("/")
+                .secure(true)
+                .finish();
+            
+            debug!("setting browser state cookie");
+            
+            if res.headers.has::<SetCookie>() {
+                if let Some(set_cookie) = res.headers.get_mut::<SetCookie>() {
+                    set_cookie.push(cookie.to_string());
+                }
+            } else {
+                res.headers.set(SetCookie(vec![cookie.to_string()]));
+            }
+        }
+        
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    
+    use chrono::*;
+    use super::*;
+    use users::*;
+    use authentication::*;
+    use login_manager::LoginManager;
+    use site_config::SiteConfig;
+
+    fn record(session_id: &str, user_id: &str, now: DateTime<UTC>) -> SessionRecord {
+        let mut session = UserSession::new(user_id.to_owned(), "user@example.com".to_owned(), session_id.to_owned());
+        session.authenticated = true;
+        
+        SessionRecord::new(&session, None, None, now).unwrap()
+    }
+
+    #[test]
+    fn test_sid_is_not_the_session_id() {
+        let record = record("s1", "u1", UTC::now());
+        assert!(!record.sid.is_empty());
+        assert!(record.sid != record.session_id);
+        assert_eq!(record.to_user_session().sid, Some(record.sid.clone()));
+    }
+
+    #[test]
+    fn test_session_timeouts() {
+        let now = UTC::now();
+        let record = record("s1", "u1", now);
+        
+        assert!(!record.is_expired(now + Duration::minutes(10), Duration::minutes(30), Duration::hours(12)));
+        assert!(record.is_expired(now + Duration::minutes(31), Duration::minutes(30), Duration::hours(12)));
+        
+        let store = InMemorySessionStore::new();
+        store.create_session(record).unwrap();
+        store.touch_session("s1", now + Duration::hours(12)).unwrap();
+        
+        let touched = store.find_session("s1").unwrap().unwrap();
+        assert!(!touched.is_expired(now + Duration::hours(12), Duration::minutes(30), Duration::days(1)));
+        assert!(touched.is_expired(now + Duration::hours(12), Duration::minutes(30), Duration::hours(12)));
+    }
+
+    #[test]
+    fn test_revoke_user_sessions() {
+        let now = UTC::now();
+        let store = InMemorySessionStore::new();
+        store.create_session(record("s1", "u1", now)).unwrap();
+        store.create_session(record("s2", "u1", now)).unwrap();
+        store.create_session(record("s3", "u2", now)).unwrap();
+        
+        assert_eq!(store.revoke_session("s1").unwrap().unwrap().session_id, "s1");
+        assert!(store.find_session("s1").unwrap().is_none());
+        
+        assert_eq!(store.revoke_user_sessions("u1").unwrap().len(), 1);
+        assert!(store.get_user_sessions("u1").unwrap().is_empty());
+        assert_eq!(store.get_user_sessions("u2").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_resolve_local_user() {
+        let users = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        
+        let mut attributes = UserAttributes::new("admin");
+        attributes.authenticator = Some("htpasswd".to_owned());
+        attributes.roles = vec![Role::User, Role::Admin];
+        
+        let admin = resolve_local_user(&users, &attributes).unwrap();
+        assert_eq!(admin.authenticator, Some("htpasswd".to_owned()));
+        assert_eq!(resolve_local_user(&users, &attributes).unwrap().id, admin.id);
+        
+        // e.g. provisioned from an upstream preferred_username, or with a password an admin cleared
+        let mut federated = User::new("fed".to_owned(), "root".to_owned(), None);
+        federated.add_upstream_link("upstream", "root");
+        users.add_user(federated).unwrap();
+        
+        let mut attributes = UserAttributes::new("root");
+        attributes.authenticator = Some("htpasswd".to_owned());
+        attributes.roles = vec![Role::User, Role::Admin];
+        
+        assert!(resolve_local_user(&users, &attributes).is_err());
+        assert_eq!(users.get_user("fed").unwrap().unwrap().roles, vec![Role::User]);
+    }
+
+    struct RecordingListener {
+        ended: Arc<Mutex<Vec<String>>>,
+    }
+    
+    impl SessionEndListener for RecordingListener {
+        fn session_ended(&self, record: &SessionRecord) {
+            self.ended.lock().unwrap().push(record.session_id.clone());
+        }
+    }
+    
+    #[test]
+    fn test_revoked_sessions_are_announced() {
+        let now = UTC::now();
+        let users = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        let store = Arc::new(Box::new(InMemorySessionStore::new()) as Box<SessionStore>);
+        store.create_session(record("s1", "u1", now)).unwrap();
+        store.create_session(record("s2", "u1", now)).unwrap();
+        store.create_session(record("s3", "u2", now)).unwrap();
+        
+        let ended = Arc::new(Mutex::new(vec![]));
+        let mut controller = SessionController::new(users, LoginManager::new(b"key".to_vec()), store);
+        controller.session_end_listener = Some(Arc::new(Box::new(RecordingListener { ended: ended.clone() }) as Box<SessionEndListener>));
+        
+        assert!(controller.revoke_session("s3").unwrap());
+        assert!(!controller.revoke_session("s3").unwrap());
+        assert_eq!(controller.revoke_user_sessions("u1").unwrap(), 2);
+        
+        let mut ended = ended.lock().unwrap().clone();
+        ended.sort();
+        assert_eq!(ended, vec!["s1".to_owned(), "s2".to_owned(), "s3".to_owned()]);
+    }
+
+    #[test]
+    fn test_expired_sessions_are_purged() {
+        let now = UTC::now();
+        let users = Arc::new(Box::new(InMemoryUserRepo::new()) as Box<UserRepo>);
+        let store = Arc::new(Box::new(InMemorySessionStore::new()) as Box<SessionStore>);
+        store.create_session(record("old", "u1", now - Duration::days(2))).unwrap();
+        store.create_session(record("abandoned", "u2", now - Duration::days(2))).unwrap();
+        store.create_session(record("live", "u1", now)).unwrap();
+        
+        let ended = Arc::new(Mutex::new(vec![]));
+        let mut controller = SessionController::new(users, LoginManager::new(b"key".to_vec()), store.clone());
+        controller.session_end_listener = Some(Arc::new(Box::new(RecordingListener { ended: ended.clone() }) as Box<SessionEndListener>));
+        
+        let sessions = controller.get_user_sessions(&SiteConfig::new(), "u1").unwrap();
+        assert_eq!(sessions.iter().map(|s| &s.session_id[..]).collect::<Vec<&str>>(), vec!["live"]);
+        
+        // another user's abandoned session goes too
+        assert!(store.find_session("abandoned").unwrap().is_none());
+        assert_eq!(ended.lock().unwrap().len(), 2);
+    }
+}
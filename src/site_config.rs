@@ -17,6 +17,8 @@ use result;
 use result::{OpenIdConnectError};
 use serialisation::*;
 use grant_type::*;
+use throttle::ThrottleSettings;
+use federation::UpstreamProvider;
 
 #[derive(Clone, Debug)]
 pub struct SiteUrl {
@@ -133,6 +135,26 @@ pub struct SiteConfig {
     pub enabled_grants: Vec<GrantType>, // permitted grant types (authorization_code, client_credentials, ...)
     pub enable_dynamic_client_registration: bool, // client dynamic registration endpoint
     pub jwks: Option<String>, // Key Set location containing RSA private keys for signing and encryption. see jsonwebtoken/bin/jwktool
+    pub pairwise_subject_salt: Option<String>, // secret mixed into pairwise sub values. changing it changes every pairwise sub.
+    pub session_idle_timeout: Option<TokenDuration>, // login sessions end after this long without a request
+    pub session_absolute_timeout: Option<TokenDuration>, // login sessions end this long after login regardless
+    pub login_free_attempts: Option<u32>, // failed logins for a username before it has to wait between attempts. default 3
+    pub login_backoff_base: Option<TokenDuration>, // first wait, doubled by each further failure. default 1 second
+    pub login_backoff_max: Option<TokenDuration>, // longest wait between attempts. default 1 minute
+    pub login_lockout_threshold: Option<u32>, // failed logins for a username before it's locked. default 10
+    pub login_ip_lockout_threshold: Option<u32>, // failed logins from one ip before it's locked. default 100
+    pub login_lockout_duration: Option<TokenDuration>, // how long lockouts last. default 15 minutes
+    pub mail_from: Option<String>, // sender address for verification and password reset emails
+    pub smtp_host: Option<String>, // relay for outgoing email. see mailer::mailer_for_site
+    pub smtp_port: Option<u16>, // default 25
+    pub mail_dir: Option<String>, // write emails to files here instead of sending them
+    pub email_verification_duration: Option<TokenDuration>, // how long verification links work. default 1 day
+    pub password_reset_duration: Option<TokenDuration>, // how long password reset links work. default 1 hour
+    pub upstream_providers: Vec<UpstreamProvider>, // other OIDC/OAuth2 providers users can log in with
+    pub authenticators: Vec<String>, // password checks to try in order, "users" and/or "htpasswd". default ["users"]
+    pub htpasswd_file: Option<String>, // bcrypt htpasswd file for the htpasswd authenticator
+    pub htpasswd_admins: Vec<String>, // htpasswd usernames given the admin role
+    pub introspection_clients: Vec<String>, // resource servers that may introspect any client's tokens
 }
 
 impl Default for SiteConfig {
@@ -150,6 +172,26 @@ impl Default for SiteConfig {
             enabled_grants: vec![GrantType::AuthorizationCode, GrantType::ClientCredentials],
             enable_dynamic_client_registration: true, // probably a bad default
             jwks: None,
+            pairwise_subject_salt: None,
+            session_idle_timeout: None,
+            session_absolute_timeout: None,
+            login_free_attempts: None,
+            login_backoff_base: None,
+            login_backoff_max: None,
+            login_lockout_threshold: None,
+            login_ip_lockout_threshold: None,
+            login_lockout_duration: None,
+            mail_from: None,
+            smtp_host: None,
+            smtp_port: None,
+            mail_dir: None,
+            email_verification_duration: None,
+            password_reset_duration: None,
+            upstream_providers: vec![],
+            authenticators: vec![],
+            htpasswd_file: None,
+            htpasswd_admins: vec![],
+            introspection_clients: vec![],
         }
     }
 }
@@ -191,6 +233,49 @@ impl SiteConfig {
         self.code_duration.as_ref().map(|d| d.to_owned()).unwrap_or(Duration::minutes(10).into()).into()
     }
     
+    pub fn get_session_idle_timeout(&self) -> Duration {
+        self.session_idle_timeout.as_ref().map(|d| d.to_owned()).unwrap_or(Duration::hours(1).into()).into()
+    }
+    
+    pub fn get_session_absolute_timeout(&self) -> Duration {
+        self.session_absolute_timeout.as_ref().map(|d| d.to_owned()).unwrap_or(Duration::hours(12).into()).into()
+    }
+    
+    pub fn get_login_throttle(&self) -> ThrottleSettings {
+        let defaults = ThrottleSettings::default();
+        
+        ThrottleSettings {
+            free_attempts: self.login_free_attempts.unwrap_or(defaults.free_attempts),
+            backoff_base: self.login_backoff_base.map(|d| d.into()).unwrap_or(defaults.backoff_base),
+            backoff_max: self.login_backoff_max.map(|d| d.into()).unwrap_or(defaults.backoff_max),
+            lockout_threshold: self.login_lockout_threshold.unwrap_or(defaults.lockout_threshold),
+            lockout_duration: self.login_lockout_duration.map(|d| d.into()).unwrap_or(defaults.lockout_duration),
+            ip_lockout_threshold: self.login_ip_lockout_threshold.unwrap_or(defaults.ip_lockout_threshold),
+        }
+    }
+    
+    pub fn get_mail_from(&self) -> String {
+        self.mail_from.as_ref().map(|s| s.to_owned()).unwrap_or("noreply@localhost".to_owned())
+    }
+    
+    pub fn get_email_verification_duration(&self) -> Duration {
+        self.email_verification_duration.as_ref().map(|d| d.to_owned()).unwrap_or(Duration::days(1).into()).into()
+    }
+    
+    pub fn get_password_reset_duration(&self) -> Duration {
+        self.password_reset_duration.as_ref().map(|d| d.to_owned()).unwrap_or(Duration::hours(1).into()).into()
+    }
+    
+    pub fn find_upstream_provider(&self, id: &str) -> Option<&UpstreamProvider> {
+        self.upstream_providers.iter().find(|p| p.id == id)
+    }
+    
+    pub fn get_pairwise_subject_salt(&self) -> result::Result<String> {
+        self.pairwise_subject_salt.as_ref()
+            .map(|s| s.to_owned())
+            .ok_or(OpenIdConnectError::ConfigError("no pairwise_subject_salt configured".to_owned()))
+    }
+    
     pub fn get(req: &mut Request) -> result::Result<Arc<SiteConfig>> {
         req.get::<persistent::Read<SiteConfig>>().map_err(OpenIdConnectError::from)
     }
@@ -0,0 +1,307 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use chrono::*;
+
+use result::{Result, OpenIdConnectError};
+use clock::*;
+
+/// Brute force protection for logins.
+///
+/// Failures are counted per username and per client ip.
+/// After a few free attempts each failure for a username doubles the wait before the next attempt,
+/// and too many failures lock the username or ip out for a while.
+/// Unknown usernames are counted the same as real ones so they can't be told apart.
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThrottleSettings {
+    pub free_attempts: u32, // failures for a username before any wait
+    pub backoff_base: Duration, // wait after the first failure past the free attempts
+    pub backoff_max: Duration,
+    pub lockout_threshold: u32, // failures for a username before it's locked
+    pub lockout_duration: Duration,
+    pub ip_lockout_threshold: u32, // failures from an ip, across all usernames, before it's locked
+}
+
+impl Default for ThrottleSettings {
+    fn default() -> ThrottleSettings {
+        ThrottleSettings {
+            free_attempts: 3,
+            backoff_base: Duration::seconds(1),
+            backoff_max: Duration::minutes(1),
+            lockout_threshold: 10,
+            lockout_duration: Duration::minutes(15),
+            ip_lockout_threshold: 100,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FailureCounter {
+    pub failures: u32,
+    pub last_failure: DateTime<UTC>,
+    pub locked_until: Option<DateTime<UTC>>,
+}
+
+impl FailureCounter {
+    pub fn new(now: DateTime<UTC>) -> FailureCounter {
+        FailureCounter {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        }
+    }
+    
+    /// When the next attempt is allowed, if it has to wait
+    pub fn retry_at(&self, settings: &ThrottleSettings, backoff: bool) -> Option<DateTime<UTC>> {
+        if self.locked_until.is_some() {
+            return self.locked_until;
+        }
+        
+        if !backoff || self.failures < settings.free_attempts {
+            return None;
+        }
+        
+        let doublings = cmp::min(self.failures - settings.free_attempts, 16);
+        let delay = cmp::min(settings.backoff_base * (1i32 << doublings), settings.backoff_max);
+        
+        Some(self.last_failure + delay)
+    }
+    
+    /// Counters are forgotten once a lockout has run out,
+    /// or when nothing has failed for as long as a lockout would last
+    pub fn is_stale(&self, settings: &ThrottleSettings, now: DateTime<UTC>) -> bool {
+        match self.locked_until {
+            Some(locked_until) => locked_until <= now,
+            None => self.last_failure + settings.lockout_duration <= now,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoginThrottle {
+    clock: Arc<Box<Clock>>,
+    usernames: Arc<Mutex<HashMap<String, FailureCounter>>>,
+    ips: Arc<Mutex<HashMap<String, FailureCounter>>>,
+}
+
+impl LoginThrottle {
+    pub fn new(clock: Arc<Box<Clock>>) -> LoginThrottle {
+        LoginThrottle {
+            clock: clock,
+            usernames: Arc::new(Mutex::new(HashMap::new())),
+            ips: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    
+    fn username_key(username: &str) -> String {
+        username.trim().to_lowercase()
+    }
+    
+    fn check_counter(counters: &mut HashMap<String, FailureCounter>, key: &str, settings: &ThrottleSettings, backoff: bool, now: DateTime<UTC>) -> Result<()> {
+        let stale = match counters.get(key) {
+            Some(counter) => {
+                if let Some(retry_at) = counter.retry_at(settings, backoff) {
+                    if retry_at > now {
+                        return Err(OpenIdConnectError::LoginThrottled(cmp::max((retry_at - now).num_seconds(), 1)));
+                    }
+                }
+                
+                counter.is_stale(settings, now)
+            },
+            None => false
+        };
+        
+        if stale {
+            counters.remove(key);
+        }
+        
+        Ok(())
+    }
+    
+    /// Counters are otherwise only cleared when their own username or ip comes back,
+    /// so without this, failures for usernames and ips that are never seen again would pile up forever
+    fn purge_stale(counters: &mut HashMap<String, FailureCounter>, settings: &ThrottleSettings, now: DateTime<UTC>) {
+        let stale: Vec<String> = counters.iter()
+            .filter(|&(_, counter)| counter.is_stale(settings, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        
+        for key in stale {
+            counters.remove(&key);
+        }
+    }
+    
+    fn count_failure(counters: &mut HashMap<String, FailureCounter>, key: &str, threshold: u32, settings: &ThrottleSettings, now: DateTime<UTC>) {
+        Self::purge_stale(counters, settings, now);
+        
+        let counter = counters.entry(key.to_owned()).or_insert_with(|| FailureCounter::new(now));
+        
+        counter.failures += 1;
+        counter.last_failure = now;
+        
+        if counter.failures >= threshold {
+            counter.locked_until = Some(now + settings.lockout_duration);
+        }
+    }
+    
+    /// Err(LoginThrottled) if the username or ip has to wait before trying again
+    pub fn check(&self, settings: &ThrottleSettings, username: &str, ip: Option<&str>) -> Result<()> {
+        let now = self.clock.now();
+        
+        try!(Self::check_counter(&mut self.usernames.lock().unwrap(), &Self::username_key(username), settings, true, now));
+        
+        if let Some(ip) = ip {
+            try!(Self::check_counter(&mut self.ips.lock().unwrap(), ip, settings, false, now));
+        }
+        
+        Ok(())
+    }
+    
+    pub fn record_failure(&self, settings: &ThrottleSettings, username: &str, ip: Option<&str>) {
+        let now = self.clock.now();
+        
+        info!("failed login for {}", username);
+        
+        Self::count_failure(&mut self.usernames.lock().unwrap(), &Self::username_key(username), settings.lockout_threshold, settings, now);
+        
+        if let Some(ip) = ip {
+            Self::count_failure(&mut self.ips.lock().unwrap(), ip, settings.ip_lockout_threshold, settings, now);
+        }
+    }
+    
+    /// A successful login clears the username's failures, but not the ip's
+    pub fn record_success(&self, username: &str) {
+        self.usernames.lock().unwrap().remove(&Self::username_key(username));
+    }
+    
+    pub fn get_user_failures(&self, username: &str) -> Option<FailureCounter> {
+        self.usernames.lock().unwrap().get(&Self::username_key(username)).map(|c| c.clone())
+    }
+    
+    /// For admins, to let a locked out user straight back in
+    pub fn unlock_user(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().remove(&Self::username_key(username)).is_some()
+    }
+    
+    pub fn unlock_ip(&self, ip: &str) -> bool {
+        self.ips.lock().unwrap().remove(ip).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use chrono::*;
+
+    use super::*;
+    use clock::Clock;
+    use result::OpenIdConnectError;
+
+    struct StandInClock {
+        now: Arc<Mutex<DateTime<UTC>>>,
+    }
+
+    impl Clock for StandInClock {
+        fn now(&self) -> DateTime<UTC> {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn throttle() -> (LoginThrottle, Arc<Mutex<DateTime<UTC>>>) {
+        let now = Arc::new(Mutex::new(UTC::now()));
+        let clock = StandInClock { now: now.clone() };
+
+        (LoginThrottle::new(Arc::new(Box::new(clock) as Box<Clock>)), now)
+    }
+
+    fn advance(now: &Arc<Mutex<DateTime<UTC>>>, d: Duration) {
+        let mut now = now.lock().unwrap();
+        *now = *now + d;
+    }
+
+    fn retry_after(throttle: &LoginThrottle, settings: &ThrottleSettings, username: &str, ip: &str) -> Option<i64> {
+        match throttle.check(settings, username, Some(ip)) {
+            Ok(()) => None,
+            Err(OpenIdConnectError::LoginThrottled(secs)) => Some(secs),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_backoff_and_lockout() {
+        let settings = ThrottleSettings::default();
+        let (throttle, now) = throttle();
+
+        for _ in 0..3 {
+            assert_eq!(retry_after(&throttle, &settings, "alice", "10.0.0.1"), None);
+            throttle.record_failure(&settings, "alice", Some("10.0.0.1"));
+        }
+
+        assert_eq!(retry_after(&throttle, &settings, "Alice", "10.0.0.2"), Some(1));
+        advance(&now, Duration::seconds(1));
+        assert_eq!(retry_after(&throttle, &settings, "alice", "10.0.0.1"), None);
+
+        throttle.record_failure(&settings, "alice", Some("10.0.0.1"));
+        assert_eq!(retry_after(&throttle, &settings, "alice", "10.0.0.1"), Some(2));
+
+        for _ in 0..6 {
+            throttle.record_failure(&settings, "alice", Some("10.0.0.1"));
+        }
+        assert_eq!(retry_after(&throttle, &settings, "alice", "10.0.0.1"), Some(15 * 60));
+        assert_eq!(retry_after(&throttle, &settings, "bob", "10.0.0.1"), None);
+
+        advance(&now, Duration::minutes(15));
+        assert_eq!(retry_after(&throttle, &settings, "alice", "10.0.0.1"), None);
+        assert!(throttle.get_user_failures("alice").is_none());
+    }
+
+    #[test]
+    fn test_unlock_and_ip_lockout() {
+        let mut settings = ThrottleSettings::default();
+        settings.ip_lockout_threshold = 5;
+        let (throttle, _now) = throttle();
+
+        for i in 0..5 {
+            throttle.record_failure(&settings, &format!("user{}", i), Some("10.0.0.1"));
+        }
+        assert!(retry_after(&throttle, &settings, "someone", "10.0.0.1").is_some());
+        assert_eq!(retry_after(&throttle, &settings, "someone", "10.0.0.2"), None);
+        assert!(throttle.unlock_ip("10.0.0.1"));
+
+        for _ in 0..10 {
+            throttle.record_failure(&settings, "carol", None);
+        }
+        assert!(retry_after(&throttle, &settings, "carol", "10.0.0.3").is_some());
+        assert!(throttle.unlock_user("carol"));
+        assert_eq!(retry_after(&throttle, &settings, "carol", "10.0.0.3"), None);
+
+        throttle.record_failure(&settings, "dave", None);
+        throttle.record_success("dave");
+        assert!(throttle.get_user_failures("dave").is_none());
+    }
+
+    #[test]
+    fn test_stale_counters_are_purged() {
+        let settings = ThrottleSettings::default();
+        let (throttle, now) = throttle();
+
+        for i in 0..50 {
+            throttle.record_failure(&settings, &format!("user{}", i), Some(&format!("10.0.0.{}", i)[..]));
+        }
+        for _ in 0..10 {
+            throttle.record_failure(&settings, "erin", Some("10.0.1.1"));
+        }
+        assert_eq!(throttle.usernames.lock().unwrap().len(), 51);
+        assert_eq!(throttle.ips.lock().unwrap().len(), 51);
+
+        advance(&now, Duration::minutes(15));
+        throttle.record_failure(&settings, "frank", Some("10.0.2.1"));
+
+        // erin's lockout has just run out too
+        assert_eq!(throttle.usernames.lock().unwrap().len(), 1);
+        assert_eq!(throttle.ips.lock().unwrap().len(), 1);
+        assert!(throttle.get_user_failures("frank").is_some());
+    }
+}
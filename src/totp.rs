@@ -0,0 +1,182 @@
+use rand::{Rng, thread_rng};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use url::form_urlencoded;
+
+/// Time-based one time passwords for a second login factor
+/// See https://tools.ietf.org/html/rfc6238
+/// Codes are 6 digits from HMAC-SHA1 over 30 second steps, which is what authenticator apps expect.
+
+pub const TOTP_STEP_SECONDS: i64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+/// codes from the neighbouring steps are accepted too, for clock drift
+pub const TOTP_SKEW_STEPS: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for b in bytes {
+        buffer = (buffer << 8) | (*b as u32);
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    out
+}
+
+/// Case insensitive, ignoring padding and spaces as people type them
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.chars().filter(|c| *c != '=' && *c != ' ') {
+        let c = c.to_ascii_uppercase() as u8;
+        let value = match BASE32_ALPHABET.iter().position(|a| *a == c) {
+            Some(value) => value as u32,
+            None => return None,
+        };
+
+        buffer = (buffer << 5) | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push(((buffer >> bits) & 0xff) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+/// A new shared secret, base32 encoded for the authenticator app
+pub fn new_totp_secret() -> String {
+    let mut bytes = [0u8; 20];
+    thread_rng().fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+/// RFC 4226 HOTP value for a counter
+pub fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut hmac = Hmac::new(Sha1::new(), key);
+
+    let mut message = [0u8; 8];
+    for i in 0..8 {
+        message[i] = (counter >> (8 * (7 - i))) as u8;
+    }
+    hmac.input(&message);
+
+    let result = hmac.result();
+    let digest = result.code();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = ((digest[offset] as u32 & 0x7f) << 24)
+        | ((digest[offset + 1] as u32) << 16)
+        | ((digest[offset + 2] as u32) << 8)
+        | (digest[offset + 3] as u32);
+
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+pub fn totp_code(key: &[u8], unix_time: i64) -> String {
+    let counter = (unix_time / TOTP_STEP_SECONDS) as u64;
+
+    format!("{:01$}", hotp(key, counter), TOTP_DIGITS as usize)
+}
+
+pub fn time_step(unix_time: i64) -> i64 {
+    unix_time / TOTP_STEP_SECONDS
+}
+
+/// The time step the code belongs to, if it's valid around now
+pub fn verify_totp(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let key = match base32_decode(secret) {
+        Some(key) => key,
+        None => return None,
+    };
+
+    let code = code.trim();
+
+    (-TOTP_SKEW_STEPS..TOTP_SKEW_STEPS + 1)
+        .map(|skew| unix_time + skew * TOTP_STEP_SECONDS)
+        .find(|t| {
+            let expected = totp_code(&key, *t);
+
+            fixed_time_eq(expected.as_bytes(), code.as_bytes())
+        })
+        .map(time_step)
+}
+
+/// Key URI for authenticator apps, usually shown as a QR code
+/// See https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let label: String = form_urlencoded::byte_serialize(format!("{}:{}", issuer, account).as_bytes()).collect();
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("digits", &format!("{}", TOTP_DIGITS))
+        .append_pair("period", &format!("{}", TOTP_STEP_SECONDS))
+        .finish();
+
+    format!("otpauth://totp/{}?{}", label, query)
+}
+
+/// Single use codes for when the authenticator is lost.
+/// They have enough entropy not to need a slow hash.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let mut bytes = [0u8; 5];
+        thread_rng().fill_bytes(&mut bytes);
+        base32_encode(&bytes).to_lowercase()
+    }).collect()
+}
+
+pub fn hash_recovery_code(code: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(&code.trim().to_lowercase());
+    sha.result_str()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar".to_vec());
+        assert!(base32_decode("not base32!").is_none());
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let key = b"12345678901234567890";
+
+        assert_eq!(totp_code(key, 59), "287082");
+        assert_eq!(totp_code(key, 1111111109), "081804");
+        assert_eq!(totp_code(key, 1234567890), "005924");
+
+        let secret = base32_encode(key);
+        assert_eq!(verify_totp(&secret, "287082", 59), Some(1));
+        assert_eq!(verify_totp(&secret, "287082", 59 + TOTP_STEP_SECONDS), Some(1));
+        assert!(verify_totp(&secret, "287082", 59 + 3 * TOTP_STEP_SECONDS).is_none());
+    }
+}
@@ -3,16 +3,101 @@ use std::collections::HashMap;
 
 use rbvt::params::*;
 use jsonwebtoken::validation::*;
+use serde_json::Value;
+use chrono::*;
 
 use result::{Result, OpenIdConnectError};
 use authentication::*;
+use totp;
+
+/// Profile claims a user can edit for themselves
+pub const PROFILE_CLAIMS: &'static [&'static str] = &["name", "given_name", "family_name", "middle_name", "nickname",
+    "profile", "picture", "website", "gender", "birthdate", "zoneinfo", "locale", "phone_number"];
+
+/// A plain addr-spec, local@domain, as used in SMTP envelopes and To: headers.
+/// Quoted local parts, comments and address literals aren't accepted, and neither is anything
+/// that could end a header or an SMTP command early, like CR, LF, < or >.
+pub fn is_valid_email(email: &str) -> bool {
+    if email.len() > 254 {
+        return false;
+    }
+    
+    let mut parts = email.splitn(2, '@');
+    let (local, domain) = match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => (local, domain),
+        _ => return false,
+    };
+    
+    local.len() <= 64
+        && valid_dot_atoms(local, "!#$%&'*+/=?^_`{|}~-")
+        && valid_dot_atoms(domain, "-")
+}
+
+/// Non-empty runs of letters, digits and the extra characters, separated by single dots
+fn valid_dot_atoms(s: &str, extra: &str) -> bool {
+    s.split('.').all(|atom| {
+        !atom.is_empty() && atom.chars().all(|c| {
+            (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || extra.contains(c)
+        })
+    })
+}
+
+/// What a user may do. Everyone is a User; Admins also manage applications and other users.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    User,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Admin => "admin",
+            Role::User => "user",
+        }
+    }
+    
+    pub fn from_str(s: &str) -> Option<Role> {
+        match s {
+            "admin" => Some(Role::Admin),
+            "user" => Some(Role::User),
+            _ => None
+        }
+    }
+}
+
+/// An account at an upstream identity provider that can log in as this user
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamLink {
+    pub provider_id: String,
+    pub subject: String,
+}
+
+impl UpstreamLink {
+    pub fn new(provider_id: String, subject: String) -> UpstreamLink {
+        UpstreamLink {
+            provider_id: provider_id,
+            subject: subject,
+        }
+    }
+}
 
 #[derive(Clone,Debug)]
 pub struct User {
     pub id: String,
     pub username: String,
-    pub password: Option<String>,
-    pub hashed_password: Option<String>,
+    pub hashed_password: Option<String>, // see authentication::hash_password
+    pub email: Option<String>,
+    pub email_verified: bool, // set by following the link sent to the email address
+    pub claims: HashMap<String, Value>, // standard claims e.g. email, phone_number
+    pub totp_secret: Option<String>, // base32, set once the user has confirmed enrollment
+    pub totp_last_step: Option<i64>, // time step of the last code accepted, so it can't be replayed
+    pub recovery_codes: Vec<String>, // see totp::hash_recovery_code
+    pub upstream_links: Vec<UpstreamLink>, // see federation
+    pub authenticator: Option<String>, // the login backend that created the user, if it wasn't made here; see sessions::resolve_local_user
+    pub roles: Vec<Role>,
+    pub disabled: bool, // can't log in or use tokens, set by an admin
+    pub external_id: Option<String>, // the provisioning system's id for the user, see scim
 }
 
 impl User {
@@ -20,8 +105,141 @@ impl User {
         User {
             id: id,
             username: username,
-            hashed_password: Some(hash_password(password.as_ref().map(|s| &s[..]).unwrap_or(""))),
-            password: password,
+            hashed_password: password.map(|p| hash_password(&p)),
+            email: None,
+            email_verified: false,
+            claims: HashMap::new(),
+            totp_secret: None,
+            totp_last_step: None,
+            recovery_codes: vec![],
+            upstream_links: vec![],
+            authenticator: None,
+            roles: vec![Role::User],
+            disabled: false,
+            external_id: None,
+        }
+    }
+    
+    pub fn verify_password(&self, password: &str) -> bool {
+        match self.hashed_password {
+            Some(ref hashed_password) => verify_password(password, hashed_password),
+            None => verify_dummy_password(password),
+        }
+    }
+    
+    pub fn set_password(&mut self, password: &str) {
+        self.hashed_password = Some(hash_password(password));
+    }
+    
+    pub fn has_second_factor(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+    
+    /// Checks a code from the authenticator app. Each code only works once,
+    /// and nor do codes from earlier time steps once a later one has been used.
+    pub fn verify_totp(&mut self, code: &str, unix_time: i64) -> bool {
+        let step = match self.totp_secret.as_ref().and_then(|s| totp::verify_totp(s, code, unix_time)) {
+            Some(step) => step,
+            None => return false,
+        };
+        
+        if self.totp_last_step.map(|last| step <= last).unwrap_or(false) {
+            return false;
+        }
+        
+        self.totp_last_step = Some(step);
+        true
+    }
+    
+    /// Checks a recovery code and crosses it off, so it can't be used again
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hashed = totp::hash_recovery_code(code);
+        
+        match self.recovery_codes.iter().position(|c| *c == hashed) {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            },
+            None => false
+        }
+    }
+    
+    /// Turns on the second factor.
+    /// Returns the new recovery codes, which are only stored hashed.
+    /// The step is that of the code which confirmed enrollment.
+    pub fn enable_totp(&mut self, secret: String, step: i64) -> Vec<String> {
+        let recovery_codes = totp::new_recovery_codes();
+        
+        self.totp_secret = Some(secret);
+        self.totp_last_step = Some(step);
+        self.recovery_codes = recovery_codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+        
+        recovery_codes
+    }
+    
+    pub fn reset_second_factor(&mut self) {
+        self.totp_secret = None;
+        self.totp_last_step = None;
+        self.recovery_codes = vec![];
+    }
+    
+    /// A new address has to be verified again
+    pub fn set_email(&mut self, email: Option<String>) {
+        if email != self.email {
+            self.email = email;
+            self.email_verified = false;
+        }
+    }
+    
+    pub fn has_upstream_link(&self, provider_id: &str, subject: &str) -> bool {
+        self.upstream_links.iter().any(|l| l.provider_id == provider_id && l.subject == subject)
+    }
+    
+    pub fn add_upstream_link(&mut self, provider_id: &str, subject: &str) {
+        if !self.has_upstream_link(provider_id, subject) {
+            self.upstream_links.push(UpstreamLink::new(provider_id.to_owned(), subject.to_owned()));
+        }
+    }
+    
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+    
+    pub fn is_admin(&self) -> bool {
+        self.has_role(Role::Admin)
+    }
+    
+    pub fn add_role(&mut self, role: Role) {
+        if !self.has_role(role) {
+            self.roles.push(role);
+        }
+    }
+    
+    pub fn remove_role(&mut self, role: Role) {
+        self.roles = self.roles.iter().filter(|r| **r != role).map(|r| *r).collect();
+    }
+    
+    /// Sets or clears a string claim, e.g. one of PROFILE_CLAIMS
+    pub fn set_claim(&mut self, name: &str, value: Option<String>) {
+        match value {
+            Some(value) => { self.claims.insert(name.to_owned(), Value::String(value)); },
+            None => { self.claims.remove(name); },
+        }
+    }
+    
+    /// Bumps the updated_at claim
+    pub fn touch(&mut self, at: DateTime<UTC>) {
+        self.claims.insert("updated_at".to_owned(), Value::Number(at.timestamp().into()));
+    }
+    
+    /// Value of a standard claim for this user, if the user has one
+    pub fn get_claim(&self, name: &str) -> Option<Value> {
+        match name {
+            "email" if self.email.is_some() => self.email.as_ref().map(|e| Value::String(e.clone())),
+            "email_verified" if self.email.is_some() => Some(Value::Bool(self.email_verified)),
+            "name" => Some(self.claims.get("name").map(|v| v.clone()).unwrap_or(Value::String(self.username.clone()))),
+            "preferred_username" => Some(Value::String(self.username.clone())),
+            _ => self.claims.get(name).map(|v| v.clone()),
         }
     }
 }
@@ -31,6 +249,7 @@ pub struct UserBuilder {
     pub username: Option<String>,
     pub password: Option<String>,
     pub hashed_password: Option<String>,
+    pub email: Option<String>,
     
     validation_state: ValidationState,
 }
@@ -41,6 +260,7 @@ impl UserBuilder {
             username: None,
             password: None,
             hashed_password: None,
+            email: None,
             validation_state: ValidationState::new(),
         }
     }
@@ -49,8 +269,18 @@ impl UserBuilder {
         Ok(User {
             id: new_user_id(),
             username: try!(self.username.ok_or(ValidationError::MissingRequiredValue("username".to_owned()))),
-            password: self.password,
-            hashed_password: self.hashed_password,
+            hashed_password: self.hashed_password.or_else(|| self.password.map(|p| hash_password(&p))),
+            email: self.email,
+            email_verified: false,
+            claims: HashMap::new(),
+            totp_secret: None,
+            totp_last_step: None,
+            recovery_codes: vec![],
+            upstream_links: vec![],
+            authenticator: None,
+            roles: vec![Role::User],
+            disabled: false,
+            external_id: None,
         })
     }
     
@@ -63,6 +293,7 @@ impl UserBuilder {
         
         self.password = try!(multimap_get_maybe_one(params, "password")).map(|s| s.to_owned());
         self.hashed_password = try!(multimap_get_maybe_one(params, "hashed_password")).map(|s| s.to_owned());
+        self.email = try!(multimap_get_maybe_one(params, "email")).map(|s| s.to_owned());
         
         Ok(self.validation_state.valid)
     }
@@ -76,6 +307,73 @@ impl UserBuilder {
     }
 }
 
+/// Which users to list, and which page of them
+#[derive(Clone, Debug)]
+pub struct UserQuery {
+    pub search: Option<String>, // part of the username, email or name, ignoring case
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl UserQuery {
+    pub fn new() -> UserQuery {
+        UserQuery {
+            search: None,
+            role: None,
+            disabled: None,
+            offset: 0,
+            limit: 50,
+        }
+    }
+    
+    pub fn matches(&self, user: &User) -> bool {
+        if let Some(role) = self.role {
+            if !user.has_role(role) {
+                return false;
+            }
+        }
+        
+        if let Some(disabled) = self.disabled {
+            if user.disabled != disabled {
+                return false;
+            }
+        }
+        
+        match self.search {
+            Some(ref search) => {
+                let search = search.trim().to_lowercase();
+                let name = user.get_claim("name").and_then(|v| v.as_str().map(|s| s.to_owned()));
+                
+                user.username.to_lowercase().contains(&search)
+                    || user.email.as_ref().map(|e| e.to_lowercase().contains(&search)).unwrap_or(false)
+                    || name.map(|n| n.to_lowercase().contains(&search)).unwrap_or(false)
+            },
+            None => true
+        }
+    }
+    
+    /// The matching users in username order, cut down to the requested page
+    pub fn page(&self, users: &[User]) -> UserPage {
+        let mut matching: Vec<User> = users.iter().filter(|u| self.matches(u)).map(|u| u.clone()).collect();
+        matching.sort_by(|a, b| a.username.cmp(&b.username));
+        
+        let total = matching.len();
+        
+        UserPage {
+            users: matching.into_iter().skip(self.offset).take(self.limit).collect(),
+            total: total,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub total: usize, // matching users on all pages
+}
+
 pub trait UserRepo where Self: Send + Sync {
     fn add_user(&self, u: User) -> Result<()>;
     
@@ -83,9 +381,15 @@ pub trait UserRepo where Self: Send + Sync {
     
     fn find_user(&self, username: &str) -> Result<Option<User>>;
     
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>>;
+    
+    fn find_user_by_upstream_link(&self, provider_id: &str, subject: &str) -> Result<Option<User>>;
+    
     fn update_user(&self, u: User) -> Result<()>;
     
     fn remove_user(&self, username: &str) -> Result<()>;
+    
+    fn list_users(&self, query: &UserQuery) -> Result<UserPage>;
 }
 
 #[derive(Clone)]
@@ -138,10 +442,27 @@ impl UserRepo for InMemoryUserRepo {
         Ok(Self::find_index(&users, username).map(|i| users[i].clone()))
     }
     
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let users = self.users.lock().unwrap();
+        let email = email.trim().to_lowercase();
+        
+        Ok(users.iter().find(|u| u.email.as_ref().map(|e| e.to_lowercase() == email).unwrap_or(false)).map(|u| u.clone()))
+    }
+    
+    fn find_user_by_upstream_link(&self, provider_id: &str, subject: &str) -> Result<Option<User>> {
+        let users = self.users.lock().unwrap();
+        
+        Ok(users.iter().find(|u| u.has_upstream_link(provider_id, subject)).map(|u| u.clone()))
+    }
+    
     fn update_user(&self, u: User) -> Result<()> {
         let mut users = self.users.lock().unwrap();
         
-        let index = try!(Self::get_index(&users, &u.username));
+        if users.iter().any(|other| other.id != u.id && other.username == u.username) {
+            return Err(OpenIdConnectError::UserAlreadyExists);
+        }
+        
+        let index = try!(users.iter().position(|other| other.id == u.id).ok_or(OpenIdConnectError::UserNotFound));
         
         users[index] = u;
         
@@ -157,25 +478,152 @@ impl UserRepo for InMemoryUserRepo {
         
         Ok(())
     }
+    
+    fn list_users(&self, query: &UserQuery) -> Result<UserPage> {
+        let users = self.users.lock().unwrap();
+        
+        Ok(query.page(&users))
+    }
 }
 
-impl Authenticator for InMemoryUserRepo {
-    fn authenticate(&self, username: &str, password: &str) -> Result<AuthenticationStatus> {
-        let users = self.users.lock().unwrap();
+/// Checks passwords against the users in a UserRepo
+pub struct UserRepoAuthenticator {
+    users: Arc<Box<UserRepo>>,
+}
+
+impl UserRepoAuthenticator {
+    pub fn new(users: Arc<Box<UserRepo>>) -> UserRepoAuthenticator {
+        UserRepoAuthenticator {
+            users: users,
+        }
+    }
+    
+    /// Re-hashes the password with the current parameters if it was stored with weaker ones
+    fn upgrade_password_hash(&self, mut user: User, password: &str) -> Result<()> {
+        if user.hashed_password.as_ref().map(|h| password_needs_rehash(h)).unwrap_or(false) {
+            debug!("upgrading password hash for user {}", user.id);
+            
+            user.set_password(password);
+            try!(self.users.update_user(user));
+        }
         
-        match users.iter().find(|u| {
-            u.username == username
-        }) {
-            Some(user) => {
-                if user.hashed_password == Some(hash_password(password)) {
-                    Ok(AuthenticationStatus::Success)
-                } else {
-                    Ok(AuthenticationStatus::IncorrectPassword)
-                }
-            },
-            None => {
-                Ok(AuthenticationStatus::PrincipalNotFound)
+        Ok(())
+    }
+}
+
+impl Authenticator for UserRepoAuthenticator {
+    fn authenticate(&self, username: &str, password: &str) -> Result<Authentication> {
+        let user = match try!(self.users.find_user(username)) {
+            Some(user) => user,
+            None => return Ok(Authentication::not_found()),
+        };
+        
+        // users without a password here log in some other way, e.g. through htpasswd or an upstream provider,
+        // so leave them to the next authenticator. the dummy check keeps the timing the same as a real one.
+        if user.hashed_password.is_none() {
+            verify_dummy_password(password);
+            return Ok(Authentication::not_found());
+        }
+        
+        let attributes = Some(UserAttributes {
+            user_id: Some(user.id.clone()),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            claims: user.claims.clone(),
+            roles: vec![],
+            authenticator: None,
+        });
+        
+        if !user.verify_password(password) {
+            Ok(Authentication::new(AuthenticationStatus::IncorrectPassword, attributes))
+        } else {
+            let second_factor = user.has_second_factor();
+            
+            try!(self.upgrade_password_hash(user, password));
+            
+            if second_factor {
+                Ok(Authentication::new(AuthenticationStatus::SecondFactorRequired, attributes))
+            } else {
+                Ok(Authentication::new(AuthenticationStatus::Success, attributes))
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_list_users() {
+        let repo = InMemoryUserRepo::new();
+
+        for username in &["carol", "alice", "bob", "dave"] {
+            let mut user = User::new(format!("id-{}", username), username.to_string(), None);
+            user.set_email(Some(format!("{}@example.com", username)));
+            repo.add_user(user).unwrap();
+        }
+
+        let mut bob = repo.find_user("bob").unwrap().unwrap();
+        bob.disabled = true;
+        bob.add_role(Role::Admin);
+        repo.update_user(bob).unwrap();
+
+        let mut query = UserQuery::new();
+        query.limit = 2;
+        let page = repo.list_users(&query).unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.users.iter().map(|u| &u.username[..]).collect::<Vec<&str>>(), vec!["alice", "bob"]);
+
+        query.offset = 2;
+        let page = repo.list_users(&query).unwrap();
+        assert_eq!(page.users.iter().map(|u| &u.username[..]).collect::<Vec<&str>>(), vec!["carol", "dave"]);
+
+        let mut query = UserQuery::new();
+        query.search = Some("CAROL@".to_owned());
+        assert_eq!(repo.list_users(&query).unwrap().total, 1);
+
+        let mut query = UserQuery::new();
+        query.disabled = Some(false);
+        assert_eq!(repo.list_users(&query).unwrap().total, 3);
+
+        let mut query = UserQuery::new();
+        query.role = Some(Role::Admin);
+        assert_eq!(repo.list_users(&query).unwrap().users[0].username, "bob");
+    }
+
+    #[test]
+    fn test_totp_codes_work_once() {
+        let secret = totp::base32_encode(b"12345678901234567890");
+        let mut user = User::new("id-alice".to_owned(), "alice".to_owned(), None);
+        user.enable_totp(secret, 0);
+
+        let code = totp::totp_code(b"12345678901234567890", 59);
+        assert!(user.verify_totp(&code, 59));
+        assert!(!user.verify_totp(&code, 59));
+        assert!(!user.verify_totp(&code, 59 + totp::TOTP_STEP_SECONDS));
+
+        let earlier = totp::totp_code(b"12345678901234567890", 59 - totp::TOTP_STEP_SECONDS);
+        assert!(!user.verify_totp(&earlier, 59));
+
+        let later = totp::totp_code(b"12345678901234567890", 59 + totp::TOTP_STEP_SECONDS);
+        assert!(user.verify_totp(&later, 59 + totp::TOTP_STEP_SECONDS));
+    }
+
+    #[test]
+    fn test_is_valid_email() {
+        assert!(is_valid_email("alice@example.com"));
+        assert!(is_valid_email("alice.o'hara+tag@mail.example.co.uk"));
+
+        assert!(!is_valid_email("alice"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("alice@"));
+        assert!(!is_valid_email("alice@@example.com"));
+        assert!(!is_valid_email("alice..b@example.com"));
+        assert!(!is_valid_email("alice@example..com"));
+        assert!(!is_valid_email("alice@example.com\r\nBcc: everyone@example.com"));
+        assert!(!is_valid_email("alice@example.com>\r\nRCPT TO:<bob@example.com"));
+        assert!(!is_valid_email("alice bob@example.com"));
+        assert!(!is_valid_email("<alice@example.com>"));
+    }
+}
@@ -0,0 +1,136 @@
+use chrono::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use jsonwebtoken::jwt::Jwt;
+use jsonwebtoken::json::*;
+use jsonwebtoken::crypto::mac_signer::MacSigner;
+
+use result::{Result, OpenIdConnectError};
+use users::User;
+
+/// Signed, time limited links for email verification and password reset.
+///
+/// Nothing is stored server side. A verification link names the address it was sent to,
+/// so it stops working if the user changes their email. A reset link carries a fingerprint
+/// of the current password hash, so it stops working once the password has been changed with it.
+
+pub const EMAIL_VERIFICATION_TYPE: &'static str = "email_verification";
+pub const PASSWORD_RESET_TYPE: &'static str = "password_reset";
+
+fn new_token(typ: &str, user: &User, now: DateTime<UTC>, duration: Duration) -> Jwt {
+    let mut t = Jwt::default();
+    t.header.typ = Some(typ.to_owned());
+    t.claims.set_value("iat", &now.timestamp());
+    t.claims.set_value("exp", &(now + duration).timestamp());
+    t.claims.set_value("sub", &user.id);
+    t
+}
+
+/// Decodes a link token, checking its signature, type and expiry.
+/// Returns the user id it was issued for.
+fn load_token(mac_signer: &MacSigner, typ: &str, token: &str, now: DateTime<UTC>) -> Result<(String, Jwt)> {
+    let jwt = try!(Jwt::decode(token, mac_signer).map_err(|_| OpenIdConnectError::InvalidToken("link is not valid".to_owned())));
+    
+    if jwt.header.typ.as_ref().map(|s| &s[..]) != Some(typ) {
+        return Err(OpenIdConnectError::InvalidToken("link is not valid".to_owned()));
+    }
+    
+    let exp = try!(jwt.claims.get_value::<i64>("exp"));
+    if exp.map(|exp| exp <= now.timestamp()).unwrap_or(true) {
+        return Err(OpenIdConnectError::InvalidToken("link has expired".to_owned()));
+    }
+    
+    let user_id = try!(try!(jwt.claims.get_value::<String>("sub")).ok_or(OpenIdConnectError::InvalidToken("link is not valid".to_owned())));
+    
+    Ok((user_id, jwt))
+}
+
+pub fn new_email_verification_token(mac_signer: &MacSigner, user: &User, now: DateTime<UTC>, duration: Duration) -> Result<String> {
+    let email = try!(user.email.as_ref().ok_or(OpenIdConnectError::InvalidRequest("user has no email address".to_owned())));
+    
+    let mut t = new_token(EMAIL_VERIFICATION_TYPE, user, now, duration);
+    t.claims.set_value("email", email);
+    
+    t.encode(mac_signer).map_err(OpenIdConnectError::from)
+}
+
+/// The user id and the address that was verified
+pub fn load_email_verification_token(mac_signer: &MacSigner, token: &str, now: DateTime<UTC>) -> Result<(String, String)> {
+    let (user_id, jwt) = try!(load_token(mac_signer, EMAIL_VERIFICATION_TYPE, token, now));
+    
+    let email = try!(try!(jwt.claims.get_value::<String>("email")).ok_or(OpenIdConnectError::InvalidToken("link is not valid".to_owned())));
+    
+    Ok((user_id, email))
+}
+
+fn password_fingerprint(user: &User) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(user.hashed_password.as_ref().map(|s| &s[..]).unwrap_or(""));
+    sha.result_str()
+}
+
+pub fn new_password_reset_token(mac_signer: &MacSigner, user: &User, now: DateTime<UTC>, duration: Duration) -> Result<String> {
+    let mut t = new_token(PASSWORD_RESET_TYPE, user, now, duration);
+    t.claims.set_value("pwd", &password_fingerprint(user));
+    
+    t.encode(mac_signer).map_err(OpenIdConnectError::from)
+}
+
+/// The user id a reset link was issued for
+pub fn load_password_reset_token(mac_signer: &MacSigner, token: &str, now: DateTime<UTC>) -> Result<String> {
+    let (user_id, _jwt) = try!(load_token(mac_signer, PASSWORD_RESET_TYPE, token, now));
+    
+    Ok(user_id)
+}
+
+/// Checks a reset link against the user's current password, so each link only works once
+pub fn password_reset_token_matches(mac_signer: &MacSigner, token: &str, user: &User, now: DateTime<UTC>) -> Result<bool> {
+    let (user_id, jwt) = try!(load_token(mac_signer, PASSWORD_RESET_TYPE, token, now));
+    let fingerprint = try!(jwt.claims.get_value::<String>("pwd"));
+    
+    Ok(user_id == user.id && fingerprint == Some(password_fingerprint(user)))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::*;
+    use jsonwebtoken::crypto::mac_signer::MacSigner;
+
+    use super::*;
+    use users::User;
+
+    fn user() -> User {
+        let mut user = User::new("1".to_owned(), "alice".to_owned(), Some("password".to_owned()));
+        user.email = Some("alice@example.com".to_owned());
+        user
+    }
+
+    #[test]
+    fn test_email_verification_token() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let now = UTC::now();
+        let token = new_email_verification_token(&mac_signer, &user(), now, Duration::hours(24)).unwrap();
+
+        let (user_id, email) = load_email_verification_token(&mac_signer, &token, now + Duration::hours(1)).unwrap();
+        assert_eq!(user_id, "1");
+        assert_eq!(email, "alice@example.com");
+
+        assert!(load_email_verification_token(&mac_signer, &token, now + Duration::hours(25)).is_err());
+        assert!(load_password_reset_token(&mac_signer, &token, now).is_err());
+    }
+
+    #[test]
+    fn test_password_reset_token_is_single_use() {
+        let mac_signer = MacSigner::new("secret").unwrap();
+        let now = UTC::now();
+        let mut user = user();
+        let token = new_password_reset_token(&mac_signer, &user, now, Duration::hours(1)).unwrap();
+
+        assert_eq!(load_password_reset_token(&mac_signer, &token, now).unwrap(), "1");
+        assert!(password_reset_token_matches(&mac_signer, &token, &user, now).unwrap());
+
+        user.set_password("new password");
+        assert!(!password_reset_token_matches(&mac_signer, &token, &user, now).unwrap());
+        assert!(load_password_reset_token(&mac_signer, &token, now + Duration::hours(2)).is_err());
+    }
+}
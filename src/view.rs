@@ -4,6 +4,10 @@ use iron::prelude::*;
 use result::Result;
 use handlebars_iron::Template;
 use serde_json::value::{self, Value};
+use i18n::*;
+use display::Display;
+use csrf;
+use config::Config;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct View {
@@ -12,6 +16,9 @@ pub struct View {
     pub view: String,
     pub layout: Option<String>,
     pub csrf_token: Option<String>,
+    pub locale: Option<String>,
+    pub messages: HashMap<String, String>,
+    pub admin: bool, // shows the management links
 }
 
 impl View {
@@ -21,6 +28,9 @@ impl View {
             data: HashMap::new(),
             session: session,
             csrf_token: None,
+            locale: None,
+            messages: HashMap::new(),
+            admin: false,
             layout: Some("_layout.html".to_owned()),
         }
     }
@@ -28,7 +38,36 @@ impl View {
     pub fn new_for_session(name: &str, req: &mut Request) -> Result<View> {
         let session = try!(req.get::<UserSession>());
         
-        Ok(View::new(name, session))
+        let admin = match (Config::get(req), session.as_ref().and_then(|s| if s.authenticated { s.user_id.clone() } else { None })) {
+            (Ok(config), Some(user_id)) => try!(config.user_repo.get_user(&user_id)).map(|u| u.is_admin()).unwrap_or(false),
+            _ => false
+        };
+        
+        let mut view = View::new(name, session);
+        view.csrf_token = csrf::csrf_token(req);
+        view.admin = admin;
+        view.localize(req, None);
+        
+        Ok(view)
+    }
+    
+    /// Login and consent pages are shown in the layout the relying party asked for
+    pub fn set_display(&mut self, display: Option<&str>) {
+        let display = Display::from_param(display);
+        
+        self.layout = Some(display.layout().to_owned());
+        self.data.insert("display".to_owned(), Value::String(display.to_string()));
+    }
+    
+    /// Picks the messages for the best locale out of ui_locales and Accept-Language.
+    /// Does nothing if no translations are loaded.
+    pub fn localize(&mut self, req: &mut Request, ui_locales: Option<&str>) {
+        if let Ok(translations) = Translations::get(req) {
+            let locale = translations.negotiate(&requested_locales(req, ui_locales));
+            
+            self.messages = translations.catalog(&locale);
+            self.locale = Some(locale);
+        }
     }
     
     pub fn template(self) -> Result<Template> {
@@ -42,6 +81,9 @@ impl View {
         
         data.insert("view".to_owned(), try!(value::to_value(&self.view)));
         data.insert("session".to_owned(), try!(value::to_value(&self.session)));
+        data.insert("locale".to_owned(), try!(value::to_value(&self.locale.unwrap_or("en".to_owned()))));
+        data.insert("t".to_owned(), try!(value::to_value(&self.messages)));
+        data.insert("admin".to_owned(), Value::Bool(self.admin));
         
         if let Some(csrf_token) = self.csrf_token {
             data.insert("csrf_token".to_owned(), try!(value::to_value(&csrf_token)));
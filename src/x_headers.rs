@@ -65,4 +65,31 @@ impl headers::HeaderFormat for XForwardedPort {
     fn fmt_header(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
         write!(f, "{}", self.forwarded_port)
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug)]
+pub struct WwwAuthenticate {
+    pub challenge: String,
+}
+
+impl WwwAuthenticate {
+    pub fn new<S>(challenge: S) -> WwwAuthenticate where S: Into<String> {
+        WwwAuthenticate {
+            challenge: challenge.into()
+        }
+    }
+}
+
+impl headers::Header for WwwAuthenticate {
+    fn header_name() -> &'static str { "WWW-Authenticate" }
+    
+    fn parse_header(raw: &[Vec<u8>]) -> std::result::Result<WwwAuthenticate, error::HttpError> {
+        from_one_raw_str(raw).map(|s: String| WwwAuthenticate::new(s) )
+    }
+}
+
+impl headers::HeaderFormat for WwwAuthenticate {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(f, "{}", self.challenge)
+    }
+}